@@ -1,34 +1,443 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StorageConfig {
     pub path: PathBuf,
-    #[allow(dead_code)]
+    /// Format the recorder writes: the crash-safe WAL or MCAP files
+    #[serde(default)]
+    pub backend: StorageBackendKind,
+    /// Layout of MCAP files written by the MCAP backend or the converter
+    #[serde(default)]
+    pub mcap: McapConfig,
+    /// Rotate once the active segment's records would grow past this many bytes
+    /// (the footer written on seal comes on top)
     pub wal_segment_size: usize,
-    #[allow(dead_code)]
+    /// Rotate once the active segment has been open this long
+    #[serde(default)]
+    pub max_segment_duration_secs: Option<u64>,
+    /// Rotate once the active segment holds this many messages
+    #[serde(default)]
+    pub max_segment_messages: Option<u64>,
     pub compress: bool,
-    #[allow(dead_code)]
+    /// Codec used for frame payloads when `compress` is enabled
+    #[serde(default)]
+    pub compression: CompressionCodec,
+    /// zstd compression level (ignored by lz4)
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+    /// When appended frames are forced to stable storage
+    #[serde(default)]
+    pub durability: DurabilityPolicy,
+    /// Bound of the queue between `append_record` callers and the segment writer
+    #[serde(default = "default_write_queue_capacity")]
+    pub write_queue_capacity: usize,
+    /// Sealed-segment indexes keep the offset of every Nth message
+    #[serde(default = "default_index_stride")]
+    pub index_stride: usize,
+    /// Limits enforced by the background janitor
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Rolling-window recording with triggered captures
+    #[serde(default)]
+    pub blackbox: BlackBoxConfig,
+    /// Content-addressed storage of large payloads
+    #[serde(default)]
+    pub blobs: BlobConfig,
+    /// Background rewrite of sealed segments into per-topic or per-namespace files
+    #[serde(default)]
+    pub compaction: CompactionConfig,
+    /// Id of the credential-vault master key that encrypts new segments;
+    /// empty or unset writes plaintext
     pub encryption: Option<String>,
+    /// Encrypt new segments with AES-256-GCM when `encryption` names a key
     #[serde(default = "default_encryption_enabled")]
-    #[allow(dead_code)]
     pub enable_aes_gcm: bool,
 }
 
+impl StorageConfig {
+    /// Master key new segments are encrypted with, if encryption is on
+    pub fn encryption_key_id(&self) -> Option<&str> {
+        self.encryption.as_deref().filter(|id| self.enable_aes_gcm && !id.is_empty())
+    }
+
+    /// Drop `require_upload` from retention and compaction, for when nothing
+    /// can be uploaded and no segment would ever qualify. Whether it was set.
+    pub fn waive_upload_requirement(&mut self) -> bool {
+        let required = self.retention.require_upload || self.compaction.require_upload;
+        self.retention.require_upload = false;
+        self.compaction.require_upload = false;
+        required
+    }
+}
+
 fn default_encryption_enabled() -> bool {
     true
 }
 
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_write_queue_capacity() -> usize {
+    1024
+}
+
+fn default_index_stride() -> usize {
+    64
+}
+
+/// Retention limits for sealed segments. Unset limits are not enforced.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RetentionConfig {
+    /// Delete oldest segments while the data directory is larger than this
+    #[serde(default)]
+    pub max_total_bytes: Option<u64>,
+    /// Delete segments sealed longer ago than this
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+    /// Delete oldest segments while the disk has less free space than this
+    #[serde(default)]
+    pub min_free_bytes: Option<u64>,
+    /// Only delete segments that were uploaded successfully
+    #[serde(default)]
+    pub require_upload: bool,
+    #[serde(default = "default_retention_interval")]
+    pub check_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            max_total_bytes: None,
+            max_age_secs: None,
+            min_free_bytes: None,
+            require_upload: false,
+            check_interval_secs: default_retention_interval(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Whether any limit is configured
+    pub fn is_enabled(&self) -> bool {
+        self.max_total_bytes.is_some() || self.max_age_secs.is_some() || self.min_free_bytes.is_some()
+    }
+}
+
+fn default_retention_interval() -> u64 {
+    60
+}
+
+/// Black-box mode: loose segments only cover the last `window_secs` until a
+/// trigger freezes them into a capture session
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BlackBoxConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Length of the rolling pre-trigger window
+    #[serde(default = "default_blackbox_window")]
+    pub window_secs: u64,
+    /// How long a capture keeps recording after its trigger
+    #[serde(default = "default_post_trigger")]
+    pub post_trigger_secs: u64,
+    /// Address of the HTTP trigger endpoint, e.g. "127.0.0.1:8787"
+    #[serde(default)]
+    pub http_listen: Option<String>,
+}
+
+impl Default for BlackBoxConfig {
+    fn default() -> Self {
+        BlackBoxConfig {
+            enabled: false,
+            window_secs: default_blackbox_window(),
+            post_trigger_secs: default_post_trigger(),
+            http_listen: None,
+        }
+    }
+}
+
+impl BlackBoxConfig {
+    /// Segment duration used when `max_segment_duration_secs` is unset; the
+    /// window can only be trimmed a whole segment at a time
+    pub fn segment_secs(&self) -> u64 {
+        (self.window_secs / 10).max(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    /// `segment-N.log` write-ahead log with sessions, retention and sync
+    #[default]
+    Wal,
+    /// `mcap/segment-N.mcap`, readable by Foxglove and `ros2 bag`
+    Mcap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct McapConfig {
+    /// Uncompressed bytes of messages collected into one chunk
+    #[serde(default = "default_mcap_chunk_size")]
+    pub chunk_size: usize,
+}
+
+impl Default for McapConfig {
+    fn default() -> Self {
+        McapConfig { chunk_size: default_mcap_chunk_size() }
+    }
+}
+
+fn default_mcap_chunk_size() -> usize {
+    1024 * 1024
+}
+
+/// Payloads of at least `min_size_bytes` are stored once per content in a
+/// blob directory and referenced from the WAL by digest
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BlobConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_blob_min_size")]
+    pub min_size_bytes: usize,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        BlobConfig { enabled: false, min_size_bytes: default_blob_min_size() }
+    }
+}
+
+fn default_blob_min_size() -> usize {
+    256 * 1024
+}
+
+/// Rewrites sealed loose segments into batches of time-sorted partition files
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CompactionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub partition_by: CompactionPartition,
+    /// Only segments sealed at least this long ago are compacted
+    #[serde(default = "default_compaction_min_age")]
+    pub min_age_secs: u64,
+    /// Wait until this many segments are eligible
+    #[serde(default = "default_compaction_min_segments")]
+    pub min_segments: usize,
+    /// Bound on the segment bytes of one batch, which is sorted in memory
+    #[serde(default = "default_compaction_max_batch")]
+    pub max_batch_bytes: u64,
+    /// Only compact segments that were uploaded successfully
+    #[serde(default)]
+    pub require_upload: bool,
+    #[serde(default = "default_compaction_interval")]
+    pub check_interval_secs: u64,
+    /// Thinning applied to old data while it is compacted
+    #[serde(default)]
+    pub downsample: Vec<DownsampleRule>,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            enabled: false,
+            partition_by: CompactionPartition::default(),
+            min_age_secs: default_compaction_min_age(),
+            min_segments: default_compaction_min_segments(),
+            max_batch_bytes: default_compaction_max_batch(),
+            require_upload: false,
+            check_interval_secs: default_compaction_interval(),
+            downsample: Vec::new(),
+        }
+    }
+}
+
+/// What compacted files are split by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionPartition {
+    /// One file per topic name, across namespaces
+    #[default]
+    Topic,
+    /// One file per namespace
+    Namespace,
+}
+
+/// Keep at most one message per `min_interval_ms` of `topic` (in each
+/// namespace) from segments sealed longer than `older_than_secs` ago
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DownsampleRule {
+    pub topic: String,
+    pub older_than_secs: u64,
+    pub min_interval_ms: u64,
+}
+
+fn default_compaction_min_age() -> u64 {
+    600
+}
+
+fn default_compaction_min_segments() -> usize {
+    4
+}
+
+fn default_compaction_max_batch() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_compaction_interval() -> u64 {
+    300
+}
+
+fn default_blackbox_window() -> u64 {
+    300
+}
+
+fn default_post_trigger() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum DurabilityPolicy {
+    /// fsync before acknowledging each record; concurrent records share one fsync
+    #[default]
+    PerRecord,
+    /// fsync every `interval_ms` or once `max_bytes` are unsynced, whichever comes first
+    Periodic { interval_ms: u64, max_bytes: u64 },
+    /// Never fsync on our own; the OS page cache decides
+    OsBuffered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionCodec {
+    Lz4,
+    #[default]
+    Zstd,
+}
+
+/// Clock that receive times are taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Wall clock, nanoseconds since the Unix epoch
+    #[default]
+    System = 0,
+    /// ROS time from `/clock`, for recording simulations run with
+    /// `use_sim_time`; needs the ros2 feature
+    RosSim = 1,
+    /// `CLOCK_MONOTONIC`, nanoseconds since boot; matches ROS steady time
+    /// but not other machines
+    Monotonic = 2,
+}
+
+impl ClockSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClockSource::System => "system",
+            ClockSource::RosSim => "ros_sim",
+            ClockSource::Monotonic => "monotonic",
+        }
+    }
+}
+
+/// How incoming messages are stamped
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct RecorderConfig {
+    #[serde(default)]
+    pub clock: ClockSource,
+}
+
+impl RecorderConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.clock == ClockSource::RosSim && !cfg!(feature = "ros2") {
+            anyhow::bail!("recorder.clock = \"ros_sim\" follows /clock and needs the ros2 feature");
+        }
+        Ok(())
+    }
+}
+
+/// Queue between subscriber callbacks and the storage writer
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct IngestConfig {
+    /// Messages the queue holds before overflow policies kick in
+    #[serde(default = "default_ingest_capacity")]
+    pub queue_capacity: usize,
+    /// Policy of topics without an entry in `topics`
+    #[serde(default)]
+    pub default_policy: OverflowPolicy,
+    /// Per-topic overflow policies, keyed by full topic name
+    #[serde(default)]
+    pub topics: HashMap<String, OverflowPolicy>,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            queue_capacity: default_ingest_capacity(),
+            default_policy: OverflowPolicy::default(),
+            topics: HashMap::new(),
+        }
+    }
+}
+
+impl IngestConfig {
+    pub fn policy(&self, topic: &str) -> OverflowPolicy {
+        self.topics.get(topic).copied().unwrap_or(self.default_policy)
+    }
+}
+
+fn default_ingest_capacity() -> usize {
+    4096
+}
+
+/// What happens to a message arriving while the ingest queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for room; the publisher's callback stalls
+    #[default]
+    Block,
+    /// Evict the topic's oldest queued message
+    DropOldest,
+    /// Discard the arriving message
+    DropNewest,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SyncConfig {
     #[allow(dead_code)]
     pub endpoint: String,
     #[allow(dead_code)]
     pub bucket: Option<String>,
+    /// Prepended to the object keys of this robot, so robots can share a
+    /// bucket. Defaults to the host name; empty puts keys at the bucket root.
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
     pub chunk_size: usize,
-    #[allow(dead_code)]
+    /// Failed attempts before a segment moves to the dead-letter list
     pub max_retries: usize,
+    /// Delay before the first retry, doubled for each further one
+    #[serde(default = "default_backoff_base")]
+    pub backoff_base_ms: u64,
+    /// Upper bound of the retry delay, before jitter
+    #[serde(default = "default_backoff_max")]
+    pub backoff_max_ms: u64,
+    /// Parts of a segment in flight at once; each holds a chunk in memory
+    #[serde(default = "default_concurrent_parts")]
+    pub concurrent_parts: usize,
+    /// Time an S3 request may take, on top of sending its body at the
+    /// bandwidth cap
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout_secs: u64,
+    /// Upload bandwidth cap, unlimited when unset or 0. The dashboard can
+    /// change it at runtime.
+    #[serde(default)]
+    pub max_upload_bytes_per_sec: Option<u64>,
     #[serde(default = "default_use_vault")]
     #[allow(dead_code)]
     pub use_credential_vault: bool,
@@ -40,6 +449,40 @@ fn default_use_vault() -> bool {
     true
 }
 
+fn default_key_prefix() -> String {
+    std::fs::read_to_string("/etc/hostname").map(|name| name.trim().to_string()).unwrap_or_default()
+}
+
+fn default_backoff_base() -> u64 {
+    2_000
+}
+
+fn default_backoff_max() -> u64 {
+    300_000
+}
+
+fn default_concurrent_parts() -> usize {
+    4
+}
+
+fn default_request_timeout() -> u64 {
+    60
+}
+
+impl SyncConfig {
+    /// Reject settings S3 would only refuse once an upload is under way
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.chunk_size < crate::s3::MIN_PART_SIZE {
+            anyhow::bail!(
+                "sync.chunk_size {} is below the S3 minimum part size of {} bytes",
+                self.chunk_size,
+                crate::s3::MIN_PART_SIZE
+            );
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SecurityConfig {
     #[serde(default = "default_encryption_enabled")]
@@ -52,6 +495,10 @@ pub struct SecurityConfig {
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppConfig {
     pub storage: StorageConfig,
+    #[serde(default)]
+    pub recorder: RecorderConfig,
+    #[serde(default)]
+    pub ingest: IngestConfig,
     pub sync: SyncConfig,
     #[allow(dead_code)]
     pub security: Option<SecurityConfig>,
@@ -61,6 +508,7 @@ impl AppConfig {
     pub fn load_default() -> anyhow::Result<Self> {
         let default = include_str!("../config/default.toml");
         let cfg: AppConfig = toml::from_str(default)?;
+        cfg.validate()?;
         Ok(cfg)
     }
 
@@ -69,9 +517,15 @@ impl AppConfig {
         let p = path.into();
         let s = fs::read_to_string(&p)?;
         let cfg: AppConfig = toml::from_str(&s)?;
+        cfg.validate()?;
         Ok(cfg)
     }
 
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.recorder.validate()?;
+        self.sync.validate()
+    }
+
     /// Get vault password from environment or prompt
     #[allow(dead_code)]
     pub fn get_vault_password(&self) -> anyhow::Result<Option<String>> {
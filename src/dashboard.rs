@@ -1,11 +1,33 @@
+use crate::ingest::IngestQueue;
 use crate::storage::Storage;
 use crate::sync::SyncDaemon;
+#[cfg(feature = "ui")]
+use crate::sync::{DeadLetter, SyncStatus};
 
 #[cfg(feature = "ui")]
 use eframe::egui;
 
+#[cfg(feature = "ui")]
+type SyncSnapshot = (SyncStatus, Vec<DeadLetter>);
+
+/// How often the Sync tab asks the daemon for a new snapshot
+#[cfg(feature = "ui")]
+const SYNC_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 #[cfg(feature = "ui")]
 pub struct DashboardApp {
+    storage: Storage,
+    ingest: IngestQueue,
+    runtime: tokio::runtime::Handle,
+    /// Outcome of the last black-box trigger, filled in by the spawned task
+    capture_status: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+    sync_daemon: SyncDaemon,
+    /// Upload status and dead letters, refreshed while the Sync tab is open
+    sync_snapshot: std::sync::Arc<std::sync::Mutex<Option<SyncSnapshot>>>,
+    /// When the last snapshot was requested
+    sync_refreshed_at: Option<std::time::Instant>,
+    /// Upload bandwidth cap being edited, kept while the cap is off
+    bandwidth_cap_kib: u64,
     ros2_available: bool,
     selected_tab: usize,
     // Metrics history for charts
@@ -18,8 +40,9 @@ pub struct DashboardApp {
 
 #[cfg(feature = "ui")]
 pub fn run_dashboard(
-    _storage: Storage,
-    _sync_daemon: SyncDaemon,
+    storage: Storage,
+    ingest: IngestQueue,
+    sync_daemon: SyncDaemon,
     ros2_available: bool,
 ) -> anyhow::Result<()> {
     if !ros2_available {
@@ -29,19 +52,34 @@ pub fn run_dashboard(
         return Ok(());
     }
 
+    let runtime = tokio::runtime::Handle::current();
     let options = eframe::NativeOptions::default();
     let _ = eframe::run_native(
         "ROS2 Recording Dashboard",
         options,
-        Box::new(move |_cc| Box::new(DashboardApp::new(ros2_available))),
+        Box::new(move |_cc| Box::new(DashboardApp::new(storage, ingest, sync_daemon, runtime, ros2_available))),
     );
     Ok(())
 }
 
 #[cfg(feature = "ui")]
 impl DashboardApp {
-    fn new(ros2_available: bool) -> Self {
+    fn new(
+        storage: Storage,
+        ingest: IngestQueue,
+        sync_daemon: SyncDaemon,
+        runtime: tokio::runtime::Handle,
+        ros2_available: bool,
+    ) -> Self {
         Self {
+            storage,
+            ingest,
+            runtime,
+            capture_status: Default::default(),
+            bandwidth_cap_kib: sync_daemon.bandwidth_limit().map_or(1024, |rate| rate / 1024),
+            sync_daemon,
+            sync_snapshot: Default::default(),
+            sync_refreshed_at: None,
             ros2_available,
             selected_tab: 0,
             message_rate_history: Vec::new(),
@@ -52,6 +90,44 @@ impl DashboardApp {
         }
     }
 
+    fn trigger_capture(&self) {
+        let storage = self.storage.clone();
+        let status = self.capture_status.clone();
+        self.runtime.spawn(async move {
+            let message = match storage.trigger_capture(None, "dashboard").await {
+                Ok(metadata) => format!("Capturing into session {}", metadata.recording_id),
+                Err(e) => format!("Trigger failed: {:#}", e),
+            };
+            *status.lock().unwrap() = Some(message);
+        });
+    }
+
+    /// Request a new snapshot, at most once per `SYNC_REFRESH_INTERVAL`
+    fn refresh_sync(&mut self) {
+        if self.sync_refreshed_at.is_some_and(|at| at.elapsed() < SYNC_REFRESH_INTERVAL) {
+            return;
+        }
+        self.sync_refreshed_at = Some(std::time::Instant::now());
+        let daemon = self.sync_daemon.clone();
+        let snapshot = self.sync_snapshot.clone();
+        self.runtime.spawn(async move {
+            let status = daemon.get_status().await;
+            let dead_letters = daemon.dead_letters().await;
+            *snapshot.lock().unwrap() = Some((status, dead_letters));
+        });
+    }
+
+    fn requeue(&self, segment_paths: Vec<String>) {
+        let daemon = self.sync_daemon.clone();
+        self.runtime.spawn(async move {
+            for path in segment_paths {
+                if let Err(e) = daemon.requeue_dead_letter(&path).await {
+                    tracing::error!("failed to requeue {}: {:#}", path, e);
+                }
+            }
+        });
+    }
+
     fn update_metrics(&mut self) {
         // Add new data points to history (keep last 60 samples)
         if self.message_rate_history.len() > 60 {
@@ -80,7 +156,7 @@ impl DashboardApp {
         self.message_rate_history.push(message_rate.max(0.0));
         self.bandwidth_history.push(bandwidth.max(0.0));
         self.latency_history.push(latency.max(0.0));
-        self.cpu_usage_history.push(cpu.max(0.0).min(100.0));
+        self.cpu_usage_history.push(cpu.clamp(0.0, 100.0));
         self.memory_usage_history.push(memory.max(0.0));
     }
 
@@ -313,6 +389,34 @@ impl eframe::App for DashboardApp {
                         ui.label("  Bandwidth: 0.5 KB/s");
                         ui.label("  Status: Recording");
                     });
+                    ui.group(|ui| {
+                        let stats = self.ingest.stats();
+                        ui.heading("Ingest Queue");
+                        ui.separator();
+                        ui.label(format!("Queued: {} / {}", stats.queued, stats.capacity));
+                        ui.add(egui::ProgressBar::new(stats.queued as f32 / stats.capacity.max(1) as f32));
+                        if stats.failed > 0 {
+                            ui.label(format!("Failed to record: {}", stats.failed));
+                        }
+                        ui.separator();
+                        for (topic, topic_stats) in &stats.topics {
+                            let mut line = format!(
+                                "{} ({:?}): {} queued, {} dropped",
+                                topic, topic_stats.policy, topic_stats.enqueued, topic_stats.dropped
+                            );
+                            if let Some(receive_ns) = topic_stats.last_receive_ns {
+                                line += &format!(", last received at {}.{:09} s", receive_ns / 1_000_000_000, receive_ns % 1_000_000_000);
+                            }
+                            if let Some(delay_ns) = topic_stats.header_delay_ns {
+                                line += &format!(", {:.3} ms after header stamp", delay_ns as f64 / 1e6);
+                            }
+                            if topic_stats.dropped > 0 {
+                                ui.colored_label(egui::Color32::YELLOW, line);
+                            } else {
+                                ui.label(line);
+                            }
+                        }
+                    });
                 }
                 6 => {
                     ui.group(|ui| {
@@ -325,20 +429,79 @@ impl eframe::App for DashboardApp {
                         ui.colored_label(egui::Color32::LIGHT_BLUE, 
                             "WAL provides crash-safe recording and resumable uploads");
                     });
+                    ui.group(|ui| {
+                        ui.heading("Black Box");
+                        ui.separator();
+                        ui.label("Freeze the recent window and the next seconds into a session");
+                        if ui.button("Trigger Capture").clicked() {
+                            self.trigger_capture();
+                        }
+                        if let Some(status) = self.capture_status.lock().unwrap().as_ref() {
+                            ui.label(status);
+                        }
+                    });
                 }
                 7 => {
+                    self.refresh_sync();
+                    let snapshot = self.sync_snapshot.lock().unwrap().clone();
                     ui.group(|ui| {
                         ui.heading("Cloud Sync");
                         ui.separator();
-                        ui.label("Configure S3 credentials for cloud uploads:");
-                        ui.label("Environment Variables:");
-                        ui.code("export S3_ENDPOINT=https://your-minio.example.com");
-                        ui.code("export S3_BUCKET=ros2-recordings");
-                        ui.code("export AWS_ACCESS_KEY_ID=your-key");
-                        ui.code("export AWS_SECRET_ACCESS_KEY=your-secret");
+                        if !self.sync_daemon.is_configured() {
+                            ui.colored_label(egui::Color32::YELLOW,
+                                "No S3 credentials in the credential vault; recordings stay local");
+                            return;
+                        }
+                        let Some((status, dead_letters)) = snapshot else {
+                            ui.label("Loading...");
+                            return;
+                        };
+                        ui.label(format!("Queued segments: {}", status.queued));
+                        ui.label(format!("Uploaded this run: {}", status.total_segments_synced));
+                        ui.label(format!("Failed attempts: {}", status.upload_errors));
+                        if status.is_syncing {
+                            ui.colored_label(egui::Color32::LIGHT_BLUE, "Uploading...");
+                        }
+                        ui.horizontal(|ui| {
+                            let was_capped = self.sync_daemon.bandwidth_limit().is_some();
+                            let mut capped = was_capped;
+                            ui.checkbox(&mut capped, "Cap upload bandwidth");
+                            let edited = ui
+                                .add_enabled(
+                                    capped,
+                                    egui::DragValue::new(&mut self.bandwidth_cap_kib)
+                                        .clamp_range(16..=1_048_576)
+                                        .suffix(" KiB/s"),
+                                )
+                                .changed();
+                            if capped != was_capped || edited {
+                                self.sync_daemon.set_bandwidth_limit(capped.then_some(self.bandwidth_cap_kib * 1024));
+                            }
+                        });
                         ui.separator();
-                        ui.colored_label(egui::Color32::LIGHT_BLUE, 
-                            "Recordings are automatically synced when configured");
+                        ui.heading(format!("Dead letters ({})", status.dead_letters));
+                        if dead_letters.is_empty() {
+                            ui.label("No segments failed permanently");
+                            return;
+                        }
+                        if ui.button("Requeue All").clicked() {
+                            self.requeue(dead_letters.iter().map(|l| l.upload.segment_path.clone()).collect());
+                        }
+                        egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                            for letter in &dead_letters {
+                                ui.horizontal(|ui| {
+                                    if ui.button("Requeue").clicked() {
+                                        self.requeue(vec![letter.upload.segment_path.clone()]);
+                                    }
+                                    ui.label(format!(
+                                        "{} after {} attempts: {}",
+                                        letter.upload.segment_path,
+                                        letter.upload.attempts,
+                                        letter.upload.last_error.as_deref().unwrap_or("unknown error"),
+                                    ));
+                                });
+                            }
+                        });
                     });
                 }
                 _ => {}
@@ -352,6 +515,7 @@ impl eframe::App for DashboardApp {
 #[cfg(not(feature = "ui"))]
 pub fn run_dashboard(
     _storage: Storage,
+    _ingest: IngestQueue,
     _sync_daemon: SyncDaemon,
     _ros2_available: bool,
 ) -> anyhow::Result<()> {
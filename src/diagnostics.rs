@@ -1,6 +1,6 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -17,6 +17,9 @@ pub struct MetricsSnapshot {
     pub active_topics: usize,
     pub network_latency_ms: f32,
     pub upload_bandwidth_mbps: f32,
+    /// Messages the ingest queue dropped so far, by topic (see `IngestStats`)
+    #[serde(default)]
+    pub dropped_by_topic: BTreeMap<String, u64>,
 }
 
 /// Circular history buffer for metrics
@@ -72,6 +75,7 @@ impl MetricsCollector {
             active_topics: history.back().map(|s| s.active_topics).unwrap_or(0),
             network_latency_ms: history.iter().map(|s| s.network_latency_ms).sum::<f32>() / count,
             upload_bandwidth_mbps: history.iter().map(|s| s.upload_bandwidth_mbps).sum::<f32>() / count,
+            dropped_by_topic: history.back().map(|s| s.dropped_by_topic.clone()).unwrap_or_default(),
         };
 
         Some(avg)
@@ -113,6 +117,7 @@ mod tests {
                 active_topics: 20 + i,
                 network_latency_ms: 10.0 + i as f32,
                 upload_bandwidth_mbps: 5.0 + i as f32,
+                dropped_by_topic: BTreeMap::from([("/camera".to_string(), i as u64)]),
             };
             collector.record_snapshot(snap).await;
         }
@@ -121,6 +126,7 @@ mod tests {
         assert_eq!(history.len(), 5);
         assert_eq!(history[0].cpu_percent, 0.0);
         assert_eq!(history[4].cpu_percent, 40.0);
+        assert_eq!(history[4].dropped_by_topic["/camera"], 4);
     }
 
     #[tokio::test]
@@ -138,6 +144,7 @@ mod tests {
                 active_topics: 20,
                 network_latency_ms: 15.0,
                 upload_bandwidth_mbps: 10.0,
+                dropped_by_topic: BTreeMap::new(),
             };
             collector.record_snapshot(snap).await;
         }
@@ -1,6 +1,11 @@
+use crate::storage::{Record, Storage};
+use crate::utils::RecordingMetadata;
 use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
+use tokio::io::AsyncWriteExt;
 
 /// Metadata about exported dataset
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,12 +19,16 @@ pub struct ExportManifest {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum ExportFormat {
     Parquet,
     CSV,
     TFRecord,
     Numpy,
+    /// One `<session>.mcap` file, readable by Foxglove and `ros2 bag`
+    Mcap,
+    /// A `<session>/` rosbag2 directory (sqlite3), for `ros2 bag play`
+    Rosbag2,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,23 +40,60 @@ pub struct TopicExportInfo {
     pub sample_rate_hz: f32,
 }
 
-/// Export recorded session to ML-ready format
+/// Export a recording session of `storage` to ML-ready format. Records are
+/// read through `storage`, so encrypted segments are decrypted with its keys.
 #[allow(dead_code)]
 pub async fn export_session(
+    storage: &Storage,
     session_id: &str,
     output_dir: &Path,
     format: ExportFormat,
 ) -> Result<ExportManifest> {
+    let session = storage.session(session_id).await?;
     match format {
-        ExportFormat::Parquet => export_to_parquet(session_id, output_dir).await,
-        ExportFormat::CSV => export_to_csv(session_id, output_dir).await,
-        ExportFormat::TFRecord => export_to_tfrecord(session_id, output_dir).await,
-        ExportFormat::Numpy => export_to_numpy(session_id, output_dir).await,
+        ExportFormat::Parquet => export_to_parquet(&session, output_dir).await,
+        ExportFormat::CSV => {
+            write_csv_records(storage.session_records(session_id).await?, output_dir).await?;
+            export_to_csv(&session, output_dir).await
+        }
+        ExportFormat::TFRecord => export_to_tfrecord(&session, output_dir).await,
+        ExportFormat::Numpy => export_to_numpy(&session, output_dir).await,
+        ExportFormat::Mcap => {
+            let types = session
+                .topics
+                .iter()
+                .filter(|t| !t.msg_type.is_empty())
+                .map(|t| (t.topic.clone(), t.msg_type.clone()))
+                .collect();
+            let dst = output_dir.join(format!("{}.mcap", session_id));
+            storage.convert_to_mcap(&storage.session_segments(session_id).await?, &dst, &types).await?;
+            export_to_mcap(&session, output_dir).await
+        }
+        ExportFormat::Rosbag2 => {
+            let dst = output_dir.join(session_id);
+            storage.convert_to_rosbag2(&storage.session_segments(session_id).await?, &dst, &session.topics).await?;
+            export_to_rosbag2(&session, output_dir).await
+        }
     }
 }
 
+/// Per-topic export info from the session manifest
+fn topic_info(session: &RecordingMetadata) -> Vec<TopicExportInfo> {
+    session
+        .topics
+        .iter()
+        .map(|t| TopicExportInfo {
+            topic: t.topic.clone(),
+            message_type: t.msg_type.clone(),
+            sample_count: t.message_count,
+            sample_rate_hz: t.sample_rate_hz.unwrap_or(0.0),
+        })
+        .collect()
+}
+
 #[allow(dead_code)]
-async fn export_to_parquet(session_id: &str, output_dir: &Path) -> Result<ExportManifest> {
+async fn export_to_parquet(session: &RecordingMetadata, output_dir: &Path) -> Result<ExportManifest> {
+    let session_id = &session.recording_id;
     tracing::info!("exporting session {} to Parquet in {}", session_id, output_dir.display());
 
     let manifest = ExportManifest {
@@ -56,8 +102,8 @@ async fn export_to_parquet(session_id: &str, output_dir: &Path) -> Result<Export
         timestamp_utc: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis(),
-        num_records: 0,
-        topics: vec![],
+        num_records: session.topics.iter().map(|t| t.message_count).sum(),
+        topics: topic_info(session),
     };
 
     // Write manifest
@@ -69,7 +115,34 @@ async fn export_to_parquet(session_id: &str, output_dir: &Path) -> Result<Export
     Ok(manifest)
 }
 
-async fn export_to_csv(session_id: &str, output_dir: &Path) -> Result<ExportManifest> {
+/// Write `records.csv`: one row per message, times in nanoseconds and
+/// payload base64-encoded. Header stamp and sequence are empty when unknown.
+async fn write_csv_records(mut records: impl Stream<Item = Result<Record>> + Unpin, output_dir: &Path) -> Result<u64> {
+    let file = tokio::fs::File::create(output_dir.join("records.csv")).await?;
+    let mut out = tokio::io::BufWriter::new(file);
+    out.write_all(b"receive_ns,header_ns,sequence,clock,namespace,topic,payload_base64\n").await?;
+    let mut rows = 0;
+    while let Some(record) = records.next().await {
+        let record = record?;
+        let row = format!(
+            "{},{},{},{},{},{},{}\n",
+            record.info.receive_ns,
+            record.info.header_ns.map(|ns| ns.to_string()).unwrap_or_default(),
+            record.info.sequence.map(|seq| seq.to_string()).unwrap_or_default(),
+            record.info.clock.as_str(),
+            record.namespace,
+            record.topic,
+            general_purpose::STANDARD.encode(&record.payload)
+        );
+        out.write_all(row.as_bytes()).await?;
+        rows += 1;
+    }
+    out.flush().await?;
+    Ok(rows)
+}
+
+async fn export_to_csv(session: &RecordingMetadata, output_dir: &Path) -> Result<ExportManifest> {
+    let session_id = &session.recording_id;
     tracing::info!("exporting session {} to CSV in {}", session_id, output_dir.display());
 
     let manifest = ExportManifest {
@@ -78,8 +151,8 @@ async fn export_to_csv(session_id: &str, output_dir: &Path) -> Result<ExportMani
         timestamp_utc: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis(),
-        num_records: 0,
-        topics: vec![],
+        num_records: session.topics.iter().map(|t| t.message_count).sum(),
+        topics: topic_info(session),
     };
 
     let manifest_path = output_dir.join("manifest.json");
@@ -91,7 +164,8 @@ async fn export_to_csv(session_id: &str, output_dir: &Path) -> Result<ExportMani
 }
 
 #[allow(dead_code)]
-async fn export_to_tfrecord(session_id: &str, output_dir: &Path) -> Result<ExportManifest> {
+async fn export_to_tfrecord(session: &RecordingMetadata, output_dir: &Path) -> Result<ExportManifest> {
+    let session_id = &session.recording_id;
     tracing::info!("exporting session {} to TFRecord in {}", session_id, output_dir.display());
 
     let manifest = ExportManifest {
@@ -100,8 +174,8 @@ async fn export_to_tfrecord(session_id: &str, output_dir: &Path) -> Result<Expor
         timestamp_utc: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis(),
-        num_records: 0,
-        topics: vec![],
+        num_records: session.topics.iter().map(|t| t.message_count).sum(),
+        topics: topic_info(session),
     };
 
     let manifest_path = output_dir.join("manifest.json");
@@ -113,7 +187,8 @@ async fn export_to_tfrecord(session_id: &str, output_dir: &Path) -> Result<Expor
 }
 
 #[allow(dead_code)]
-async fn export_to_numpy(session_id: &str, output_dir: &Path) -> Result<ExportManifest> {
+async fn export_to_numpy(session: &RecordingMetadata, output_dir: &Path) -> Result<ExportManifest> {
+    let session_id = &session.recording_id;
     tracing::info!("exporting session {} to Numpy in {}", session_id, output_dir.display());
 
     let manifest = ExportManifest {
@@ -122,8 +197,8 @@ async fn export_to_numpy(session_id: &str, output_dir: &Path) -> Result<ExportMa
         timestamp_utc: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis(),
-        num_records: 0,
-        topics: vec![],
+        num_records: session.topics.iter().map(|t| t.message_count).sum(),
+        topics: topic_info(session),
     };
 
     let manifest_path = output_dir.join("manifest.json");
@@ -134,19 +209,215 @@ async fn export_to_numpy(session_id: &str, output_dir: &Path) -> Result<ExportMa
     Ok(manifest)
 }
 
+async fn export_to_mcap(session: &RecordingMetadata, output_dir: &Path) -> Result<ExportManifest> {
+    let session_id = &session.recording_id;
+    tracing::info!("exporting session {} to MCAP in {}", session_id, output_dir.display());
+
+    let manifest = ExportManifest {
+        export_id: format!("{}-mcap", session_id),
+        format: ExportFormat::Mcap,
+        timestamp_utc: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis(),
+        num_records: session.topics.iter().map(|t| t.message_count).sum(),
+        topics: topic_info(session),
+    };
+
+    let manifest_path = output_dir.join("manifest.json");
+    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    tokio::fs::write(&manifest_path, manifest_json).await?;
+
+    tracing::info!("mcap export complete: {}", manifest_path.display());
+    Ok(manifest)
+}
+
+async fn export_to_rosbag2(session: &RecordingMetadata, output_dir: &Path) -> Result<ExportManifest> {
+    let session_id = &session.recording_id;
+    tracing::info!("exporting session {} to rosbag2 in {}", session_id, output_dir.display());
+
+    let manifest = ExportManifest {
+        export_id: format!("{}-rosbag2", session_id),
+        format: ExportFormat::Rosbag2,
+        timestamp_utc: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis(),
+        num_records: session.topics.iter().map(|t| t.message_count).sum(),
+        topics: topic_info(session),
+    };
+
+    let manifest_path = output_dir.join("manifest.json");
+    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    tokio::fs::write(&manifest_path, manifest_json).await?;
+
+    tracing::info!("rosbag2 export complete: {}", manifest_path.display());
+    Ok(manifest)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::security::{Keyring, StoredCredentials};
+    use crate::storage::{test_config, MessageInfo};
     use tempfile::TempDir;
 
     #[tokio::test]
     async fn test_export_manifest_creation() -> Result<()> {
         let tmpdir = TempDir::new()?;
-        let manifest = export_to_csv("test_session", tmpdir.path()).await?;
+        let session = RecordingMetadata {
+            recording_id: "test_session".to_string(),
+            start_time_unix_ms: 0,
+            end_time_unix_ms: Some(1000),
+            topics: vec![],
+            robot: None,
+            tags: vec![],
+            capture: None,
+        };
+        let manifest = export_to_csv(&session, tmpdir.path()).await?;
 
         assert_eq!(manifest.export_id, "test_session-csv");
         assert!(tmpdir.path().join("manifest.json").exists());
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_export_resolves_recorded_session() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&test_config(&tmpdir.path().join("data"), 1024 * 1024)).await?;
+        storage.start_session("run-1", Some("robot1"), &[]).await?;
+        for ts in 0..3 {
+            storage.append_record("/odometry", "robot1", b"pose", ts * 1_000_000).await?;
+        }
+        storage.stop_session().await?;
+
+        let manifest = export_session(&storage, "run-1", tmpdir.path(), ExportFormat::CSV).await?;
+        assert_eq!(manifest.export_id, "run-1-csv");
+        assert_eq!(manifest.num_records, 3);
+        assert_eq!(manifest.topics[0].topic, "/odometry");
+
+        assert!(export_session(&storage, "no-such-run", tmpdir.path(), ExportFormat::CSV).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_csv_export_decrypts_segments() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut creds = StoredCredentials::default();
+        creds.add_master_key("site-1");
+        let mut cfg = test_config(&tmpdir.path().join("data"), 1024 * 1024);
+        cfg.encryption = Some("site-1".to_string());
+        cfg.enable_aes_gcm = true;
+        let storage = Storage::with_keyring(&cfg, Keyring::from_credentials(&creds)?).await?;
+        storage.start_session("run-1", None, &[]).await?;
+        let info = MessageInfo::received(5_000_000).with_header_stamp(4_500_000).with_sequence(12);
+        storage.append_message("/odometry", "robot1", b"secret pose", info).await?;
+        storage.stop_session().await?;
+
+        let segment = &storage.session_segments("run-1").await?[0];
+        assert!(!std::fs::read(segment)?.windows(11).any(|w| w == b"secret pose"));
+
+        export_session(&storage, "run-1", tmpdir.path(), ExportFormat::CSV).await?;
+        let csv = std::fs::read_to_string(tmpdir.path().join("records.csv"))?;
+        let expected = format!("5000000,4500000,12,system,robot1,/odometry,{}", general_purpose::STANDARD.encode(b"secret pose"));
+        assert_eq!(csv.lines().nth(1), Some(expected.as_str()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mcap_export_converts_session_segments() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(&tmpdir.path().join("data"), 1024 * 1024);
+        cfg.compress = true;
+        cfg.max_segment_messages = Some(4);
+        let storage = Storage::new(&cfg).await?;
+        storage.start_session("run-1", None, &[]).await?;
+        for ts in 0..10 {
+            let info = MessageInfo::received(ts * 1_000_000).with_sequence(ts as u64);
+            storage.append_message("/odometry", "robot1", format!("pose {}", ts).as_bytes(), info).await?;
+        }
+        storage.append_record("/odometry", "robot2", b"other robot", 10_000_000).await?;
+        storage.stop_session().await?;
+        assert!(storage.session_segments("run-1").await?.len() > 1);
+
+        let manifest = export_session(&storage, "run-1", tmpdir.path(), ExportFormat::Mcap).await?;
+        assert_eq!(manifest.export_id, "run-1-mcap");
+
+        let path = tmpdir.path().join("run-1.mcap");
+        let records = crate::storage::mcap::read_records(&path)?;
+        assert_eq!(records.len(), 11);
+        assert_eq!(records[3].payload, b"pose 3");
+        assert_eq!(records[3].info, MessageInfo::received(3_000_000).with_sequence(3));
+        assert_eq!(records[10].namespace, "robot2");
+        let summary = crate::storage::mcap::read_summary(&path)?.expect("export writes a summary");
+        assert_eq!(summary.message_count, 11);
+        assert_eq!(summary.channel_count, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rosbag2_round_trip_of_mock_recording() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&test_config(&tmpdir.path().join("data"), 1024 * 1024)).await?;
+        // Declared before and during the session, as subscriptions come up
+        storage.declare_topic("/odometry", "nav_msgs/msg/Odometry").await?;
+        storage.start_session("mock-run", Some("robot1"), &[]).await?;
+        for (topic, msg_type) in crate::recorder::MOCK_TOPICS {
+            storage.declare_topic(topic, msg_type).await?;
+        }
+        let mut recorded = Vec::new();
+        for round in 0..25u128 {
+            let ts = 1_700_000_000_000_000_000 + round * 50_000_000;
+            for (topic, ns, payload) in crate::recorder::mock_messages() {
+                storage.append_record(topic, ns, &payload, ts).await?;
+                recorded.push(Record { topic: topic.into(), namespace: ns.into(), info: MessageInfo::received(ts), payload });
+            }
+        }
+        storage.stop_session().await?;
+
+        let manifest = export_session(&storage, "mock-run", tmpdir.path(), ExportFormat::Rosbag2).await?;
+        assert_eq!(manifest.num_records, 200);
+        let bag = tmpdir.path().join("mock-run");
+        let yaml = std::fs::read_to_string(bag.join("metadata.yaml"))?;
+        assert!(yaml.contains("storage_identifier: sqlite3"));
+        assert!(yaml.contains("name: /robot2/sensor/lidar"));
+        assert!(yaml.contains("offered_qos_profiles:"));
+        assert!(yaml.contains("nanoseconds: 1200000000"));
+        assert!(yaml.contains("message_count: 200"));
+
+        assert!(yaml.contains("type: nav_msgs/msg/Odometry"));
+        assert!(yaml.contains("type: sensor_msgs/msg/PointCloud2"));
+        let session = storage.session("mock-run").await?;
+        assert_eq!(session.topics.len(), 4);
+        assert!(session.topics.iter().all(|t| !t.msg_type.is_empty() && t.message_count == 50));
+
+        let imported = storage.import_rosbag2(&bag, "mock-run-imported", &[]).await?;
+        assert_eq!(imported.tags, vec!["rosbag2".to_string()]);
+        assert_eq!(imported.start_time_unix_ms, 1_700_000_000_000);
+        assert_eq!(imported.end_time_unix_ms, Some(1_700_000_001_200));
+        let odometry = imported.topics.iter().find(|t| t.topic == "/odometry").expect("odometry topic");
+        assert_eq!(odometry.msg_type, "nav_msgs/msg/Odometry");
+        assert_eq!(odometry.message_count, 50);
+        assert!(odometry.qos_profiles.as_deref().is_some_and(|q| q.contains("reliability: 1")));
+
+        let mut reimported = Vec::new();
+        let mut records = storage.session_records("mock-run-imported").await?;
+        while let Some(record) = records.next().await {
+            reimported.push(record?);
+        }
+        // The bag is ordered by timestamp; ties keep recording order
+        assert_eq!(reimported, recorded);
+
+        // Types and QoS survive a second export
+        export_session(&storage, "mock-run-imported", tmpdir.path(), ExportFormat::Rosbag2).await?;
+        let yaml = std::fs::read_to_string(tmpdir.path().join("mock-run-imported").join("metadata.yaml"))?;
+        assert!(yaml.contains("type: nav_msgs/msg/Odometry"));
+
+        assert!(storage.import_rosbag2(&tmpdir.path().join("no-such-bag"), "missing", &[]).await.is_err());
+        assert!(storage.active_session().await.is_none());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,400 @@
+//! Ingest queue between the recorder and the storage backend.
+//!
+//! Subscriber callbacks hand their messages to a bounded multi-producer queue
+//! and return; one writer task drains it into the `StorageBackend` (the WAL
+//! or MCAP files), so a slow disk backs up here instead of stalling the
+//! callbacks. While the queue is full, each topic's `OverflowPolicy` decides
+//! what happens to an arriving message: `block` waits for room, `drop_newest`
+//! discards it and `drop_oldest` evicts the oldest queued message of the same
+//! topic (or the arriving one if none is queued), so one topic's policy never
+//! costs another topic data. Drops are counted per topic, along with the
+//! receive time of the last message queued and how long after its header
+//! stamp it arrived. Messages the backend failed to record are counted as
+//! well, including the ones the WAL accepted and then lost to a failed write
+//! or fsync.
+
+use crate::config::{IngestConfig, OverflowPolicy};
+use crate::storage::{MessageInfo, StorageBackend};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Messages the writer task takes off the queue per lock
+const DRAIN_BATCH: usize = 64;
+
+struct IngestMessage {
+    topic: String,
+    namespace: String,
+    payload: Vec<u8>,
+    info: MessageInfo,
+}
+
+/// Per-topic counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TopicIngestStats {
+    pub policy: OverflowPolicy,
+    /// Messages accepted into the queue, including ones evicted later
+    pub enqueued: u64,
+    /// Messages discarded by `drop_oldest` or `drop_newest`
+    pub dropped: u64,
+    /// Receive time (ns) of the last message queued
+    pub last_receive_ns: Option<u128>,
+    /// Receive time minus header stamp of the last queued message that had one
+    pub header_delay_ns: Option<u128>,
+}
+
+/// Snapshot of the queue for diagnostics
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IngestStats {
+    pub queued: usize,
+    pub capacity: usize,
+    pub topics: BTreeMap<String, TopicIngestStats>,
+    /// Messages taken off the queue that never reached the disk
+    pub failed: u64,
+}
+
+impl IngestStats {
+    /// Dropped message count of every topic that lost messages
+    #[allow(dead_code)]
+    pub fn dropped_by_topic(&self) -> BTreeMap<String, u64> {
+        self.topics
+            .iter()
+            .filter(|(_, stats)| stats.dropped > 0)
+            .map(|(topic, stats)| (topic.clone(), stats.dropped))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<IngestMessage>,
+    topics: BTreeMap<String, TopicIngestStats>,
+    /// Appends that returned an error
+    failed: u64,
+    closed: bool,
+}
+
+impl State {
+    fn counters(&mut self, topic: &str, policy: OverflowPolicy) -> &mut TopicIngestStats {
+        self.topics
+            .entry(topic.to_string())
+            .or_insert(TopicIngestStats {
+                policy,
+                enqueued: 0,
+                dropped: 0,
+                last_receive_ns: None,
+                header_delay_ns: None,
+            })
+    }
+
+    fn drop_message(&mut self, topic: &str, policy: OverflowPolicy) {
+        let counters = self.counters(topic, policy);
+        counters.dropped += 1;
+        if counters.dropped == 1 {
+            tracing::warn!("ingest queue full, dropping messages on {} ({:?})", topic, policy);
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cfg: IngestConfig,
+    /// What the writer task drains into, asked for its write losses and told
+    /// about message types
+    backend: Option<Arc<dyn StorageBackend>>,
+    /// Wakes the writer task
+    readable: Notify,
+    /// Wakes producers blocked on a full queue
+    writable: Notify,
+}
+
+/// Producer handle of the ingest queue; cheap to clone
+#[derive(Clone)]
+pub struct IngestQueue {
+    shared: Arc<Shared>,
+}
+
+/// Create the queue and spawn the task that drains it into `backend`
+pub fn spawn(backend: Arc<dyn StorageBackend>, cfg: &IngestConfig) -> (IngestQueue, JoinHandle<()>) {
+    let queue = IngestQueue::new(cfg, Some(backend.clone()));
+    let handle = tokio::spawn(queue.clone().drain(backend));
+    (queue, handle)
+}
+
+impl IngestQueue {
+    fn new(cfg: &IngestConfig, backend: Option<Arc<dyn StorageBackend>>) -> Self {
+        let mut cfg = cfg.clone();
+        cfg.queue_capacity = cfg.queue_capacity.max(1);
+        IngestQueue {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                cfg,
+                backend,
+                readable: Notify::new(),
+                writable: Notify::new(),
+            }),
+        }
+    }
+
+    /// Hand the message type of a topic straight to the backend, ahead of the
+    /// topic's queued messages
+    pub async fn declare_topic(&self, topic: &str, namespace: &str, message_type: &str) -> Result<()> {
+        match &self.shared.backend {
+            Some(backend) => backend.declare_topic(topic, namespace, message_type).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Queue a message for recording. Only `block` topics ever wait; the
+    /// others return at once, whether the message was queued or dropped.
+    pub async fn push(&self, topic: &str, namespace: &str, payload: Vec<u8>, info: MessageInfo) -> Result<()> {
+        let policy = self.shared.cfg.policy(topic);
+        let mut message = IngestMessage {
+            topic: topic.to_string(),
+            namespace: namespace.to_string(),
+            payload,
+            info,
+        };
+        loop {
+            // Registered before looking at the queue so a drain in between is not missed
+            let writable = self.shared.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            match self.offer(message, policy)? {
+                None => return Ok(()),
+                Some(rejected) => message = rejected,
+            }
+            writable.await;
+        }
+    }
+
+    /// Queue `message` or apply `policy`; hands the message back if it has to wait
+    fn offer(&self, message: IngestMessage, policy: OverflowPolicy) -> Result<Option<IngestMessage>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(anyhow!("ingest queue closed"));
+        }
+        if state.queue.len() >= self.shared.cfg.queue_capacity {
+            match policy {
+                OverflowPolicy::Block => return Ok(Some(message)),
+                OverflowPolicy::DropNewest => {
+                    state.drop_message(&message.topic, policy);
+                    return Ok(None);
+                }
+                OverflowPolicy::DropOldest => {
+                    state.drop_message(&message.topic, policy);
+                    match state.queue.iter().position(|queued| queued.topic == message.topic) {
+                        Some(oldest) => drop(state.queue.remove(oldest)),
+                        None => return Ok(None),
+                    }
+                }
+            }
+        }
+        let info = message.info;
+        let counters = state.counters(&message.topic, policy);
+        counters.enqueued += 1;
+        counters.last_receive_ns = Some(info.receive_ns);
+        if let Some(header_ns) = info.header_ns {
+            counters.header_delay_ns = Some(info.receive_ns.saturating_sub(header_ns));
+        }
+        state.queue.push_back(message);
+        drop(state);
+        self.shared.readable.notify_one();
+        Ok(None)
+    }
+
+    /// Current queue depth and per-topic counters
+    pub fn stats(&self) -> IngestStats {
+        let state = self.shared.state.lock().unwrap();
+        IngestStats {
+            queued: state.queue.len(),
+            capacity: self.shared.cfg.queue_capacity,
+            topics: state.topics.clone(),
+            failed: state.failed + self.shared.backend.as_ref().map_or(0, |backend| backend.lost_messages()),
+        }
+    }
+
+    /// Refuse new messages; the writer task exits once the queue is drained
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.readable.notify_one();
+        self.shared.writable.notify_waiters();
+    }
+
+    async fn drain(self, backend: Arc<dyn StorageBackend>) {
+        loop {
+            let batch: Vec<IngestMessage> = {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.queue.is_empty() && state.closed {
+                    break;
+                }
+                let n = state.queue.len().min(DRAIN_BATCH);
+                state.queue.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                self.shared.readable.notified().await;
+                continue;
+            }
+            self.shared.writable.notify_waiters();
+            for message in batch {
+                if let Err(e) = backend
+                    .append(&message.topic, &message.namespace, &message.payload, message.info)
+                    .await
+                {
+                    tracing::error!("failed to record message on {}: {:#}", message.topic, e);
+                    self.shared.state.lock().unwrap().failed += 1;
+                }
+            }
+        }
+        tracing::debug!("ingest queue drained");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{test_config, Storage};
+    use crate::config::{DurabilityPolicy, StorageConfig};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn ingest_config(capacity: usize) -> IngestConfig {
+        let mut cfg = IngestConfig { queue_capacity: capacity, ..Default::default() };
+        cfg.topics.insert("/camera".to_string(), OverflowPolicy::DropOldest);
+        cfg.topics.insert("/diagnostics".to_string(), OverflowPolicy::DropNewest);
+        cfg
+    }
+
+    fn queued(queue: &IngestQueue) -> Vec<(String, u128)> {
+        let state = queue.shared.state.lock().unwrap();
+        state.queue.iter().map(|m| (m.topic.clone(), m.info.receive_ns)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_overflow_policies_drop_per_topic() -> Result<()> {
+        // No writer task: the queue only fills up
+        let queue = IngestQueue::new(&ingest_config(3), None);
+        let at = MessageInfo::received;
+        queue.push("/camera", "robot1", vec![1], at(1)).await?;
+        queue.push("/tf", "robot1", vec![2], at(2)).await?;
+        queue.push("/camera", "robot1", vec![3], at(3).with_header_stamp(1)).await?;
+
+        queue.push("/diagnostics", "robot1", vec![4], at(4)).await?;
+        queue.push("/camera", "robot1", vec![5], at(5)).await?;
+        queue.push("/camera", "robot1", vec![6], at(6)).await?;
+        assert_eq!(
+            queued(&queue),
+            vec![("/tf".to_string(), 2), ("/camera".to_string(), 5), ("/camera".to_string(), 6)]
+        );
+
+        // A full queue blocks topics with the default policy
+        let blocked = tokio::time::timeout(Duration::from_millis(50), queue.push("/tf", "robot1", vec![7], at(7))).await;
+        assert!(blocked.is_err());
+
+        let stats = queue.stats();
+        assert_eq!(stats.queued, 3);
+        assert_eq!(
+            stats.topics["/camera"],
+            TopicIngestStats {
+                policy: OverflowPolicy::DropOldest,
+                enqueued: 4,
+                dropped: 2,
+                last_receive_ns: Some(6),
+                header_delay_ns: Some(2),
+            }
+        );
+        assert_eq!(stats.topics["/diagnostics"].dropped, 1);
+        assert_eq!(stats.topics["/tf"].dropped, 0);
+        assert_eq!(
+            stats.dropped_by_topic(),
+            BTreeMap::from([("/camera".to_string(), 2), ("/diagnostics".to_string(), 1)])
+        );
+
+        queue.close();
+        assert!(queue.push("/tf", "robot1", vec![8], at(8)).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writer_task_drains_blocked_producers_in_order() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&StorageConfig {
+            durability: DurabilityPolicy::OsBuffered,
+            write_queue_capacity: 2,
+            ..test_config(tmpdir.path(), 1024 * 1024)
+        })
+        .await?;
+        let (queue, writer) = spawn(Arc::new(storage.clone()), &ingest_config(2));
+
+        let producers: Vec<_> = ["robot1", "robot2", "robot3"]
+            .into_iter()
+            .map(|ns| {
+                let queue = queue.clone();
+                tokio::spawn(async move {
+                    for ts in 0..50 {
+                        queue.push("/tf", ns, ts.to_string().into_bytes(), MessageInfo::received(ts)).await?;
+                    }
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.await??;
+        }
+        queue.close();
+        writer.await?;
+        storage.sync().await?;
+
+        let records = Storage::replay_segment(&storage.active_segment_path().await).await?;
+        assert_eq!(records.len(), 150);
+        for ns in ["robot1", "robot2", "robot3"] {
+            let stamps: Vec<u128> = records.iter().filter(|r| r.namespace == ns).map(|r| r.info.receive_ns).collect();
+            assert_eq!(stamps, (0..50).collect::<Vec<_>>());
+        }
+        assert!(queue.stats().dropped_by_topic().is_empty());
+        assert_eq!(queue.stats().failed, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lost_writes_are_counted_as_failed() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&StorageConfig {
+            durability: DurabilityPolicy::OsBuffered,
+            ..test_config(tmpdir.path(), 1024 * 1024)
+        })
+        .await?;
+        let (queue, writer) = spawn(Arc::new(storage.clone()), &ingest_config(8));
+        let drained = |queue: IngestQueue| async move {
+            while queue.stats().queued > 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+
+        queue.push("/tf", "robot1", b"kept".to_vec(), MessageInfo::received(1)).await?;
+        drained(queue.clone()).await;
+        storage.sync().await?;
+        // The drain appends without waiting for the write, like a disk that fills up later
+        storage.fail_next_write().await;
+        queue.push("/tf", "robot1", b"lost".to_vec(), MessageInfo::received(2)).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while queue.stats().failed == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await?;
+
+        queue.push("/tf", "robot1", b"after".to_vec(), MessageInfo::received(3)).await?;
+        queue.close();
+        writer.await?;
+        storage.sync().await?;
+        assert_eq!(queue.stats().failed, 1);
+        let records = Storage::replay_segment(&storage.active_segment_path().await).await?;
+        let payloads: Vec<&[u8]> = records.iter().map(|r| r.payload.as_slice()).collect();
+        assert_eq!(payloads, [&b"kept"[..], b"after"]);
+        Ok(())
+    }
+}
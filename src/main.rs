@@ -1,56 +1,179 @@
 use anyhow::Result;
+use clap::{Parser, Subcommand};
 use tracing::info;
 
 mod config;
 mod dashboard;
 mod diagnostics;
 mod exporter;
+mod ingest;
 mod recorder;
+mod s3;
 mod security;
 mod storage;
 mod sync;
 mod network;
+mod trigger;
 mod utils;
 
-use config::AppConfig;
+use config::{AppConfig, StorageBackendKind};
+use std::sync::Arc;
+use storage::StorageBackend;
 use sync::SyncDaemon;
 use diagnostics::detect_ros2_available;
 
+#[derive(Parser)]
+#[command(version, about = "ROS 2 recorder with a live status dashboard")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check every segment of the data directory and print a JSON report
+    Verify {
+        /// Rewrite damaged segments and quarantine unrecoverable ones
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    match cli.command {
+        // Logs go to stderr so stdout carries only the report
+        Some(_) => tracing_subscriber::fmt().with_writer(std::io::stderr).init(),
+        None => tracing_subscriber::fmt::init(),
+    }
     info!("Starting rust_ros2_recorder");
 
-    let config = AppConfig::load_default()?;
+    let mut config = AppConfig::load_default()?;
+    let s3 = s3::S3Client::from_config(&config)?;
+    if s3.is_none() {
+        tracing::warn!("no S3 credentials in the credential vault; segments stay local");
+        // Nothing is ever marked uploaded, so require_upload would keep every segment
+        if config.storage.waive_upload_requirement() {
+            tracing::warn!("ignoring require_upload: retention and compaction act on segments that were not uploaded");
+        }
+    }
+
+    // Segment master keys come from the credential vault
+    let keyring = security::load_keyring(&config)?;
+
+    if let Some(Command::Verify { repair }) = cli.command {
+        // Only a repair may run recovery and change the data directory
+        let storage = if repair {
+            storage::Storage::with_keyring(&config.storage, keyring).await?
+        } else {
+            storage::Storage::open_read_only(&config.storage, keyring).await?
+        };
+        let report = storage.verify(repair).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if !report.healthy && !repair {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     // Initialize storage and WAL
-    let storage = storage::Storage::new(&config.storage).await?;
+    let storage = storage::Storage::with_keyring(&config.storage, keyring).await?;
 
     // Start background sync daemon
-    let sync_daemon = SyncDaemon::new(storage.clone(), config.sync.clone());
+    let sync_daemon = SyncDaemon::new(storage.clone(), config.sync.clone(), s3);
+    let resumed = sync_daemon.restore().await?;
+    if resumed > 0 {
+        info!("{} segments waiting for upload from the previous run", resumed);
+    }
     let sync_handle = {
         let daemon = sync_daemon.clone();
         tokio::spawn(async move {
-            daemon.sync_loop(7).await;
+            daemon.sync_loop().await;
+        })
+    };
+    // Without credentials nothing is queued or pinned, and require_upload was waived above
+    let enqueue_handle = sync_daemon.is_configured().then(|| {
+        let daemon = sync_daemon.clone();
+        tokio::spawn(async move {
+            daemon.watch_segments().await;
+        })
+    });
+
+    // Enforce disk quota and retention on sealed segments
+    let janitor_handle = {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            storage.run_janitor().await;
+        })
+    };
+
+    // Rewrite old segments into per-topic or per-namespace files
+    let compactor_handle = {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            storage.run_compactor().await;
         })
     };
 
+    // Accept black-box capture triggers over HTTP
+    let trigger_handle = match (&config.storage.blackbox.http_listen, config.storage.blackbox.enabled) {
+        (Some(addr), true) => {
+            let storage = storage.clone();
+            let addr = addr.clone();
+            Some(tokio::spawn(async move {
+                if let Err(e) = trigger::serve_http(storage, &addr).await {
+                    tracing::error!("black-box trigger endpoint stopped: {:#}", e);
+                }
+            }))
+        }
+        _ => None,
+    };
+
+    // Recorded messages go to the WAL or straight into MCAP files
+    let backend: Arc<dyn StorageBackend> = match config.storage.backend {
+        StorageBackendKind::Wal => Arc::new(storage.clone()),
+        StorageBackendKind::Mcap => Arc::new(storage::McapStorage::open(&config.storage).await?),
+    };
+
+    // Subscriber callbacks hand messages to a bounded queue drained by its own writer task
+    let (ingest_queue, ingest_handle) = ingest::spawn(backend.clone(), &config.ingest);
+
     // Start recorder (ROS2) - may be stubbed if ROS2 not enabled
-    let recorder_handle = recorder::start_recorder(storage.clone(), config.clone());
+    let recorder_handle = recorder::start_recorder(storage.clone(), ingest_queue.clone(), config.clone());
 
     // Detect if ROS2 is available
     let ros2_available = detect_ros2_available();
 
     // Run dashboard UI (blocking on UI thread)
     // When dashboard closes, app exits
-    match dashboard::run_dashboard(storage.clone(), sync_daemon.clone(), ros2_available) {
+    match dashboard::run_dashboard(storage.clone(), ingest_queue.clone(), sync_daemon.clone(), ros2_available) {
         Ok(_) => info!("Dashboard closed cleanly"),
         Err(e) => eprintln!("Dashboard error: {:#?}", e),
     }
 
     // Cancel background tasks
     sync_handle.abort();
+    if let Some(handle) = enqueue_handle {
+        handle.abort();
+    }
+    janitor_handle.abort();
+    compactor_handle.abort();
+    if let Some(handle) = trigger_handle {
+        handle.abort();
+    }
     recorder_handle.abort();
 
+    // Let the ingest writer hand over what is still queued
+    ingest_queue.close();
+    if let Err(e) = ingest_handle.await {
+        eprintln!("Ingest writer failed: {:#?}", e);
+    }
+
+    // Make everything the recorder handed over durable before exiting
+    if let Err(e) = backend.close().await {
+        eprintln!("Final storage sync failed: {:#?}", e);
+    }
+
     Ok(())
 }
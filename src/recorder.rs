@@ -1,5 +1,6 @@
-use crate::config::AppConfig;
-use crate::storage::Storage;
+use crate::config::{AppConfig, ClockSource};
+use crate::ingest::IngestQueue;
+use crate::storage::{MessageInfo, Storage};
 use tokio::task::JoinHandle;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -28,11 +29,76 @@ impl RecorderState {
     }
 }
 
-pub fn start_recorder(storage: Storage, _cfg: AppConfig) -> JoinHandle<()> {
+/// Clock receive times are read from. `RosSim` follows the latest `/clock`
+/// message; until one arrives ROS time is zero, as for any node running with
+/// `use_sim_time`.
+#[derive(Debug, Clone)]
+pub struct RecorderClock {
+    source: ClockSource,
+    sim_ns: Arc<AtomicU64>,
+}
+
+impl RecorderClock {
+    pub fn new(source: ClockSource) -> Self {
+        RecorderClock { source, sim_ns: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+
+    /// Current time in nanoseconds of the clock
+    pub fn now_ns(&self) -> u128 {
+        match self.source {
+            ClockSource::System => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            ClockSource::RosSim => self.sim_ns.load(Ordering::Acquire) as u128,
+            ClockSource::Monotonic => {
+                let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+                // SAFETY: `ts` is a valid timespec and CLOCK_MONOTONIC always exists on Linux
+                unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+                ts.tv_sec as u128 * 1_000_000_000 + ts.tv_nsec as u128
+            }
+        }
+    }
+
+    /// Advance ROS time to a `/clock` message's stamp
+    #[cfg_attr(not(feature = "ros2"), allow(dead_code))]
+    fn set_sim_time(&self, sim_ns: u64) {
+        self.sim_ns.store(sim_ns, Ordering::Release);
+    }
+}
+
+/// `header.stamp` of a CDR-serialized message whose first field is a
+/// `std_msgs/Header`, in nanoseconds. None for a truncated payload, an
+/// unknown encapsulation or a stamp before the epoch.
+#[cfg_attr(not(feature = "ros2"), allow(dead_code))]
+pub fn cdr_header_stamp_ns(payload: &[u8]) -> Option<u128> {
+    // Encapsulation: CDR_BE/CDR_LE and PL_CDR_BE/PL_CDR_LE
+    let little_endian = match payload.get(..2)? {
+        [0, 0] | [0, 2] => false,
+        [0, 1] | [0, 3] => true,
+        _ => return None,
+    };
+    let word = |at: usize| -> Option<[u8; 4]> { payload.get(at..at + 4)?.try_into().ok() };
+    let (sec, nanosec) = if little_endian {
+        (i32::from_le_bytes(word(4)?), u32::from_le_bytes(word(8)?))
+    } else {
+        (i32::from_be_bytes(word(4)?), u32::from_be_bytes(word(8)?))
+    };
+    Some(u128::try_from(sec).ok()? * 1_000_000_000 + nanosec as u128)
+}
+
+/// Run the recorder; messages go through `ingest` so a slow disk never
+/// stalls subscriptions. Receive times are taken from the configured clock.
+pub fn start_recorder(storage: Storage, ingest: IngestQueue, cfg: AppConfig) -> JoinHandle<()> {
+    let clock = RecorderClock::new(cfg.recorder.clock);
     tokio::spawn(async move {
         #[cfg(feature = "ros2")]
         {
-            match run_ros2_recorder(storage).await {
+            match run_ros2_recorder(storage, ingest, clock).await {
                 Ok(_) => tracing::info!("ROS2 recorder stopped cleanly"),
                 Err(e) => tracing::error!("ROS2 recorder error: {:#?}", e),
             }
@@ -40,13 +106,15 @@ pub fn start_recorder(storage: Storage, _cfg: AppConfig) -> JoinHandle<()> {
 
         #[cfg(not(feature = "ros2"))]
         {
-            run_mock_recorder(storage).await;
+            let _ = storage;
+            run_mock_recorder(ingest, clock).await;
         }
     })
 }
 
 #[cfg(feature = "ros2")]
-async fn run_ros2_recorder(storage: Storage) -> anyhow::Result<()> {
+async fn run_ros2_recorder(storage: Storage, ingest: IngestQueue, clock: RecorderClock) -> anyhow::Result<()> {
+    use futures::StreamExt;
     use r2r::Context;
     use std::sync::Mutex as StdMutex;
 
@@ -56,6 +124,24 @@ async fn run_ros2_recorder(storage: Storage) -> anyhow::Result<()> {
     // Create a node for topic discovery and subscriptions
     let mut node = ctx.create_node("ros2_recorder")?;
 
+    // Black-box captures can be triggered with `ros2 service call`
+    let trigger_requests = node.create_service::<r2r::std_srvs::srv::Trigger::Service>(
+        "~/trigger_capture",
+        r2r::QosProfile::default(),
+    )?;
+    tokio::spawn(crate::trigger::serve_ros_service(storage, trigger_requests));
+
+    if clock.source() == ClockSource::RosSim {
+        let mut ticks = node.subscribe::<r2r::rosgraph_msgs::msg::Clock>("/clock", r2r::QosProfile::default())?;
+        let clock = clock.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = ticks.next().await {
+                let sim_ns = u64::try_from(msg.clock.sec).unwrap_or(0) * 1_000_000_000 + msg.clock.nanosec as u64;
+                clock.set_sim_time(sim_ns);
+            }
+        });
+    }
+
     tracing::info!("discovering ROS2 topics");
 
     // Get graph information to discover available topics
@@ -77,9 +163,7 @@ async fn run_ros2_recorder(storage: Storage) -> anyhow::Result<()> {
 
         tracing::info!("subscribing to topic: {} (types: {:?})", topic_name, types);
 
-        // For now, we'll subscribe to generic messages since r2r requires type stubs
-        // In production, you'd generate type-specific subscribers for each message type
-        match create_generic_subscription(&mut node, topic_name, types).await {
+        match create_generic_subscription(&mut node, topic_name, types, ingest.clone(), clock.clone()) {
             Ok(sub) => {
                 subscribers.push(sub);
             }
@@ -125,52 +209,99 @@ async fn run_ros2_recorder(storage: Storage) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Subscribe to `topic_name` as serialized CDR and push every message into
+/// `ingest`. Topics are recorded whole under the empty namespace. The header
+/// stamp is read for types with a top-level `header`, which by convention is
+/// their first field. r2r does not expose the rmw publication sequence
+/// number, so `sequence` counts the messages this subscription received,
+/// across all of the topic's publishers.
 #[cfg(feature = "ros2")]
-async fn create_generic_subscription(
+fn create_generic_subscription(
     node: &mut r2r::Node,
     topic_name: &str,
-    _types: &[String],
+    types: &[String],
+    ingest: IngestQueue,
+    clock: RecorderClock,
 ) -> anyhow::Result<Box<dyn std::any::Any>> {
-    // This is a simplified stub: r2r typically requires concrete types
-    // In a real implementation, you'd generate message types or use a schema registry
-    tracing::debug!("creating subscription for {}", topic_name);
+    use futures::StreamExt;
+
+    let msg_type = types
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("{} has no message type", topic_name))?;
+    // A default message serializes to JSON with every field, header included
+    let has_header = r2r::WrappedNativeMsgUntyped::new_from(msg_type)?
+        .to_json()?
+        .get("header")
+        .is_some_and(|header| header.get("stamp").is_some());
+    tracing::debug!("creating subscription for {} ({}, header: {})", topic_name, msg_type, has_header);
+
+    let mut messages = node.subscribe_raw(topic_name, msg_type, r2r::QosProfile::default())?;
+    let topic = topic_name.to_string();
+    let msg_type = msg_type.clone();
+    let task = tokio::spawn(async move {
+        if let Err(e) = ingest.declare_topic(&topic, "", &msg_type).await {
+            tracing::warn!("could not record the message type of {}: {:#}", topic, e);
+        }
+        let mut sequence = 0;
+        while let Some(payload) = messages.next().await {
+            let mut info = MessageInfo::received(clock.now_ns()).with_clock(clock.source()).with_sequence(sequence);
+            if let Some(header_ns) = has_header.then(|| cdr_header_stamp_ns(&payload)).flatten() {
+                info = info.with_header_stamp(header_ns);
+            }
+            sequence += 1;
+            if let Err(e) = ingest.push(&topic, "", payload, info).await {
+                tracing::error!("failed to record message on {}: {}", topic, e);
+            }
+        }
+    });
+    Ok(Box::new(task))
+}
 
-    // For now, return a dummy subscription that won't actually receive data
-    // TODO: Integrate with concrete ROS2 message types or a message registry
-    Ok(Box::new(topic_name.to_string()))
+/// Topics of the mock recorder and their message types
+#[cfg_attr(feature = "ros2", allow(dead_code))]
+pub const MOCK_TOPICS: [(&str, &str); 4] = [
+    ("/sensor/lidar", "sensor_msgs/msg/PointCloud2"),
+    ("/tf", "tf2_msgs/msg/TFMessage"),
+    ("/odometry", "nav_msgs/msg/Odometry"),
+    ("/diagnostics", "diagnostic_msgs/msg/DiagnosticArray"),
+];
+
+#[cfg_attr(feature = "ros2", allow(dead_code))]
+const MOCK_NAMESPACES: [&str; 2] = ["robot1", "robot2"];
+
+/// One round of mock recorder messages: `(topic, namespace, payload)`
+#[cfg_attr(feature = "ros2", allow(dead_code))]
+pub fn mock_messages() -> Vec<(&'static str, &'static str, Vec<u8>)> {
+    MOCK_TOPICS
+        .iter()
+        .flat_map(|(topic, _)| MOCK_NAMESPACES.iter().map(move |ns| (*topic, *ns, format!("mock_data_{}_{}", ns, topic).into_bytes())))
+        .collect()
 }
 
 #[cfg(not(feature = "ros2"))]
-async fn run_mock_recorder(storage: Storage) {
+async fn run_mock_recorder(ingest: IngestQueue, clock: RecorderClock) {
     let state = RecorderState::new();
     *state.is_active.lock().await = true;
 
     tracing::info!("starting mock recorder (ROS2 feature not enabled)");
-
-    let _now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_millis();
+    for (topic, msg_type) in MOCK_TOPICS {
+        for ns in MOCK_NAMESPACES {
+            if let Err(e) = ingest.declare_topic(topic, ns, msg_type).await {
+                tracing::warn!("could not record the message type of {}: {:#}", topic, e);
+            }
+        }
+    }
 
     // Simulate recording messages
     loop {
         tokio::time::sleep(Duration::from_millis(50)).await;
 
-        // Mock: simulate recording sensor messages
-        let topics = ["/sensor/lidar", "/tf", "/odometry", "/diagnostics"];
-        let namespaces = ["robot1", "robot2"];
-
-        for topic in &topics {
-            for ns in &namespaces {
-                let mock_data = format!("mock_data_{}_{}", ns, topic).into_bytes();
-                let ts = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_millis();
-
-                if let Err(e) = storage.append_record(topic, ns, &mock_data, ts).await {
-                    tracing::error!("failed to record message: {}", e);
-                }
+        // Mock: simulate recording sensor messages; each round is one message per publisher
+        let sequence = state.get_total_messages().await;
+        for (topic, ns, mock_data) in mock_messages() {
+            let info = MessageInfo::received(clock.now_ns()).with_clock(clock.source()).with_sequence(sequence);
+            if let Err(e) = ingest.push(topic, ns, mock_data, info).await {
+                tracing::error!("failed to record message: {}", e);
             }
         }
 
@@ -218,4 +349,41 @@ mod tests {
         
         assert_eq!(state.get_total_messages().await, 100);
     }
+
+    #[test]
+    fn test_cdr_header_stamp() {
+        // A header in CDR_LE: stamp 12.5 s, then the frame id
+        let mut payload = vec![0, 1, 0, 0];
+        payload.extend(12i32.to_le_bytes());
+        payload.extend(500_000_000u32.to_le_bytes());
+        payload.extend(5u32.to_le_bytes());
+        payload.extend(b"imu\0");
+        assert_eq!(cdr_header_stamp_ns(&payload), Some(12_500_000_000));
+
+        let mut big_endian = vec![0, 0, 0, 0];
+        big_endian.extend(3i32.to_be_bytes());
+        big_endian.extend(7u32.to_be_bytes());
+        assert_eq!(cdr_header_stamp_ns(&big_endian), Some(3_000_000_007));
+
+        assert_eq!(cdr_header_stamp_ns(&payload[..10]), None);
+        payload[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+        assert_eq!(cdr_header_stamp_ns(&payload), None);
+    }
+
+    #[test]
+    fn test_sim_clock_follows_clock_messages() {
+        let clock = RecorderClock::new(ClockSource::RosSim);
+        assert_eq!(clock.now_ns(), 0);
+        clock.set_sim_time(42_000_000_000);
+        assert_eq!(clock.now_ns(), 42_000_000_000);
+        assert!(RecorderClock::new(ClockSource::System).now_ns() > 42_000_000_000);
+    }
+
+    #[cfg(not(feature = "ros2"))]
+    #[test]
+    fn test_sim_clock_needs_ros2() {
+        let cfg = crate::config::RecorderConfig { clock: ClockSource::RosSim };
+        assert!(cfg.validate().is_err());
+        assert!(crate::config::RecorderConfig::default().validate().is_ok());
+    }
 }
@@ -1,5 +1,6 @@
 use aes_gcm::{Aes256Gcm, Key, Nonce};
-use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use crate::config::AppConfig;
 use base64::{engine::general_purpose, Engine as _};
 use generic_array::typenum::U12;
 use anyhow::{anyhow, Result};
@@ -7,8 +8,10 @@ use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use argon2::password_hash::SaltString;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::sync::Arc;
 
 #[allow(dead_code)]
 const NONCE_SIZE: usize = 12; // 96 bits for GCM
@@ -28,7 +31,7 @@ pub struct CredentialVault {
 }
 
 /// Stored credentials
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct StoredCredentials {
     pub s3_access_key: String,
@@ -36,18 +39,115 @@ pub struct StoredCredentials {
     pub s3_bucket: String,
     pub s3_region: String,
     pub api_keys: std::collections::HashMap<String, String>,
+    /// AES-256 master keys (base64) that wrap segment data keys, by key id
+    #[serde(default)]
+    pub master_keys: HashMap<String, String>,
+}
+
+impl StoredCredentials {
+    /// Generate a random master key under `id` unless one exists already
+    #[allow(dead_code)]
+    pub fn add_master_key(&mut self, id: &str) {
+        self.master_keys.entry(id.to_string()).or_insert_with(|| {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill(&mut key);
+            general_purpose::STANDARD.encode(key)
+        });
+    }
+}
+
+/// Master keys for segment encryption. Every key can unwrap the data keys of
+/// segments it encrypted; the active one, if any, wraps those of new segments.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: Arc<HashMap<String, Key<Aes256Gcm>>>,
+    active: Option<String>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("key_ids", &self.keys.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
 }
 
-impl Default for StoredCredentials {
-    fn default() -> Self {
-        Self {
-            s3_access_key: String::new(),
-            s3_secret_key: String::new(),
-            s3_bucket: String::new(),
-            s3_region: String::new(),
-            api_keys: std::collections::HashMap::new(),
+impl Keyring {
+    /// Keyring holding every master key stored in the vault
+    pub fn from_credentials(creds: &StoredCredentials) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (id, encoded) in &creds.master_keys {
+            let bytes = general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|e| anyhow!("master key {} is not valid base64: {}", id, e))?;
+            let key = <[u8; 32]>::try_from(bytes.as_slice())
+                .map_err(|_| anyhow!("master key {} is {} bytes, expected 32", id, bytes.len()))?;
+            keys.insert(id.clone(), Key::<Aes256Gcm>::from(key));
         }
+        Ok(Keyring { keys: Arc::new(keys), active: None })
     }
+
+    /// Encrypt new segments under master key `id` (`None` writes plaintext)
+    pub fn with_active(mut self, id: Option<&str>) -> Result<Self> {
+        if let Some(id) = id {
+            if !self.keys.contains_key(id) {
+                return Err(anyhow!("master key {} is not in the credential vault", id));
+            }
+        }
+        self.active = id.map(str::to_string);
+        Ok(self)
+    }
+
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Encrypt a data key with the active master key; returns `nonce || ciphertext`
+    pub fn wrap_data_key(&self, data_key: &[u8]) -> Result<(String, Vec<u8>)> {
+        let id = self.active.as_deref().ok_or_else(|| anyhow!("no active master key"))?;
+        let wrapped = aes_gcm_encrypt(&Aes256Gcm::new(&self.keys[id]), data_key, &wrap_aad(id))
+            .map_err(|e| anyhow!("wrapping data key failed: {}", e))?;
+        Ok((id.to_string(), wrapped))
+    }
+
+    /// Recover a data key wrapped by master key `id`
+    pub fn unwrap_data_key(&self, id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| anyhow!("segment is encrypted with master key {}, which is not loaded", id))?;
+        aes_gcm_decrypt(&Aes256Gcm::new(key), wrapped, &wrap_aad(id))
+            .map_err(|_| anyhow!("data key does not authenticate under master key {}", id))
+    }
+}
+
+/// Binds a wrapped data key to the id of the key that wrapped it
+fn wrap_aad(id: &str) -> Vec<u8> {
+    [b"segment-data-key:".as_slice(), id.as_bytes()].concat()
+}
+
+/// Master keys from the credential vault. Empty when the vault is not in use,
+/// which is an error only if `storage.encryption` asks for a key.
+pub fn load_keyring(cfg: &AppConfig) -> Result<Keyring> {
+    let wanted = cfg.storage.encryption_key_id();
+    let Some(creds) = load_credentials(cfg)? else {
+        if let Some(id) = wanted {
+            return Err(anyhow!("segment encryption with master key {} needs the credential vault and its password", id));
+        }
+        return Ok(Keyring::default());
+    };
+    Keyring::from_credentials(&creds)
+}
+
+/// Unlock the credential vault, or `None` when it is disabled, missing, or
+/// its password is not set
+pub fn load_credentials(cfg: &AppConfig) -> Result<Option<StoredCredentials>> {
+    let vault_path = cfg.sync.vault_path.as_deref().filter(|p| cfg.sync.use_credential_vault && p.exists());
+    let (Some(vault_path), Some(password)) = (vault_path, cfg.get_vault_password()?) else {
+        return Ok(None);
+    };
+    CredentialVault::load(vault_path, &password)?.unlock(&password).map(Some)
 }
 
 impl CredentialVault {
@@ -63,7 +163,7 @@ impl CredentialVault {
 
         // Store empty credentials initially
         let creds = StoredCredentials::default();
-        let encrypted_creds = Self::encrypt_credentials(&creds, master_password, &salt.to_string())?;
+        let encrypted_creds = Self::encrypt_credentials(&creds, master_password, salt.as_ref())?;
 
         Ok(CredentialVault {
             master_hash: password_hash,
@@ -135,13 +235,7 @@ impl CredentialVault {
         let json = serde_json::to_string(creds)
             .map_err(|e| anyhow!("Failed to serialize credentials: {}", e))?;
 
-        let nonce = generate_nonce();
-        let ciphertext = cipher
-            .encrypt(&nonce, json.as_bytes())
-            .map_err(|e| anyhow!("AES-GCM encryption failed: {}", e))?;
-
-        let mut encrypted = nonce.to_vec();
-        encrypted.extend_from_slice(&ciphertext);
+        let encrypted = aes_gcm_encrypt(&cipher, json.as_bytes(), &[])?;
 
         Ok(general_purpose::STANDARD.encode(&encrypted))
     }
@@ -155,19 +249,7 @@ impl CredentialVault {
         let encrypted = general_purpose::STANDARD.decode(encrypted_b64)
             .map_err(|e| anyhow!("Base64 decode failed: {}", e))?;
 
-        if encrypted.len() < NONCE_SIZE {
-            return Err(anyhow!("Encrypted data too short"));
-        }
-
-        let (nonce_bytes, ciphertext) = encrypted.split_at(NONCE_SIZE);
-        let nonce = Nonce::<U12>::from(
-            <[u8; 12]>::try_from(nonce_bytes)
-                .map_err(|_| anyhow!("Invalid nonce size"))?
-        );
-
-        let plaintext = cipher
-            .decrypt(&nonce, ciphertext)
-            .map_err(|e| anyhow!("AES-GCM decryption failed: {}", e))?;
+        let plaintext = aes_gcm_decrypt(&cipher, &encrypted, &[])?;
 
         let json = String::from_utf8(plaintext)
             .map_err(|e| anyhow!("UTF-8 decode failed: {}", e))?;
@@ -206,32 +288,21 @@ fn generate_nonce() -> Nonce<U12> {
     Nonce::<U12>::from(nonce_bytes)
 }
 
-/// Encrypt arbitrary data with a password
-#[allow(dead_code)]
-pub fn encrypt_data(data: &[u8], password: &str, salt: &str) -> Result<String> {
-    let key = derive_key(password, salt)?;
-    let cipher = Aes256Gcm::new(&key);
-
+/// Encrypt `plaintext` under a fresh nonce, authenticating `aad` with it;
+/// returns `nonce || ciphertext`
+pub fn aes_gcm_encrypt(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
     let nonce = generate_nonce();
     let ciphertext = cipher
-        .encrypt(&nonce, data)
-        .map_err(|e| anyhow!("Encryption failed: {}", e))?;
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|e| anyhow!("AES-GCM encryption failed: {}", e))?;
 
     let mut encrypted = nonce.to_vec();
     encrypted.extend_from_slice(&ciphertext);
-
-    Ok(general_purpose::STANDARD.encode(&encrypted))
+    Ok(encrypted)
 }
 
-/// Decrypt arbitrary data with a password
-#[allow(dead_code)]
-pub fn decrypt_data(encrypted_b64: &str, password: &str, salt: &str) -> Result<Vec<u8>> {
-    let key = derive_key(password, salt)?;
-    let cipher = Aes256Gcm::new(&key);
-
-    let encrypted = general_purpose::STANDARD.decode(encrypted_b64)
-        .map_err(|e| anyhow!("Base64 decode failed: {}", e))?;
-
+/// Decrypt `nonce || ciphertext` from `aes_gcm_encrypt` with the same `aad`
+pub fn aes_gcm_decrypt(cipher: &Aes256Gcm, encrypted: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
     if encrypted.len() < NONCE_SIZE {
         return Err(anyhow!("Encrypted data too short"));
     }
@@ -243,8 +314,29 @@ pub fn decrypt_data(encrypted_b64: &str, password: &str, salt: &str) -> Result<V
     );
 
     cipher
-        .decrypt(&nonce, ciphertext)
-        .map_err(|e| anyhow!("Decryption failed: {}", e))
+        .decrypt(&nonce, Payload { msg: ciphertext, aad })
+        .map_err(|e| anyhow!("AES-GCM decryption failed: {}", e))
+}
+
+/// Encrypt arbitrary data with a password
+#[allow(dead_code)]
+pub fn encrypt_data(data: &[u8], password: &str, salt: &str) -> Result<String> {
+    let key = derive_key(password, salt)?;
+    let encrypted = aes_gcm_encrypt(&Aes256Gcm::new(&key), data, &[])?;
+
+    Ok(general_purpose::STANDARD.encode(&encrypted))
+}
+
+/// Decrypt arbitrary data with a password
+#[allow(dead_code)]
+pub fn decrypt_data(encrypted_b64: &str, password: &str, salt: &str) -> Result<Vec<u8>> {
+    let key = derive_key(password, salt)?;
+    let cipher = Aes256Gcm::new(&key);
+
+    let encrypted = general_purpose::STANDARD.decode(encrypted_b64)
+        .map_err(|e| anyhow!("Base64 decode failed: {}", e))?;
+
+    aes_gcm_decrypt(&cipher, &encrypted, &[])
 }
 
 #[cfg(test)]
@@ -269,6 +361,7 @@ mod tests {
             s3_bucket: "my-bucket".to_string(),
             s3_region: "us-east-1".to_string(),
             api_keys: Default::default(),
+            master_keys: Default::default(),
         };
 
         let password = "secure_password";
@@ -293,6 +386,25 @@ mod tests {
         assert_eq!(decrypted, data);
     }
 
+    #[test]
+    fn test_keyring_wraps_data_keys() -> Result<()> {
+        let mut creds = StoredCredentials::default();
+        creds.add_master_key("site-1");
+        creds.add_master_key("site-2");
+        let keyring = Keyring::from_credentials(&creds)?;
+        assert!(keyring.wrap_data_key(&[7u8; 32]).is_err(), "no active key yet");
+        assert!(keyring.clone().with_active(Some("missing")).is_err());
+
+        let writer = keyring.clone().with_active(Some("site-1"))?;
+        let (id, wrapped) = writer.wrap_data_key(&[7u8; 32])?;
+        assert_eq!(id, "site-1");
+        assert_eq!(keyring.unwrap_data_key("site-1", &wrapped)?, vec![7u8; 32]);
+        // Bound to the wrapping key's id
+        assert!(keyring.unwrap_data_key("site-2", &wrapped).is_err());
+        assert!(Keyring::default().unwrap_data_key("site-1", &wrapped).is_err());
+        Ok(())
+    }
+
     #[test]
     #[ignore] // AES-GCM with different password may succeed but produce garbage
     fn test_decrypt_with_wrong_password() {
@@ -1,67 +1,158 @@
-use crate::config::StorageConfig;
-use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::io::Read;
+mod backend;
+mod blackbox;
+mod blob;
+mod compaction;
+mod crypto;
+mod footer;
+mod frame;
+mod index;
+pub mod mcap;
+mod reader;
+mod recovery;
+mod retention;
+mod rosbag2;
+mod salvage;
+mod session;
+mod verify;
+mod writer;
+
+use crate::config::{
+    BlackBoxConfig, ClockSource, CompactionConfig, CompressionCodec, DurabilityPolicy, RetentionConfig, StorageConfig,
+};
+use blob::BlobStore;
+use crate::security::Keyring;
+use crate::utils::{CaptureTrigger, RecordingMetadata, TopicManifestEntry};
+use anyhow::{anyhow, Context, Result};
+use frame::SegmentDictionary;
+use futures::Stream;
+use index::IndexBuilder;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::fs::OpenOptions;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
-
-const RECORD_FRAME_HEADER: u32 = 0xDEADBEEF;
-const MAX_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
-
-/// Per-record metadata and payload framing
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct RecordFrame {
-    magic: u32,
-    timestamp: u128,
-    topic: String,
-    namespace: String,
-    payload_len: u32,
-    payload_crc32: u32,
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, RwLock};
+use writer::{PendingRecord, RotateTarget, SegmentWriter, WriteCommand};
+
+pub use backend::{McapStorage, StorageBackend};
+#[allow(unused_imports)]
+pub use compaction::{BatchManifest, CompactionReport};
+pub use footer::SegmentFooter;
+#[allow(unused_imports)]
+pub use index::{index_path, SegmentIndex};
+#[allow(unused_imports)]
+pub use reader::{record_stream, RecordingReader, SegmentReader};
+pub use recovery::RecoveryReport;
+#[allow(unused_imports)]
+pub use retention::{uploaded_marker, RetentionReport};
+#[allow(unused_imports)]
+pub use rosbag2::BagMetadata;
+pub use salvage::SalvageReport;
+#[allow(unused_imports)]
+pub use verify::{RepairAction, SegmentHealth, SegmentStatus, VerifyReport};
+
+/// Capacity of the rotation event channel; slow subscribers see `Lagged`
+const SEGMENT_EVENT_CAPACITY: usize = 64;
+
+/// Why a segment was sealed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum RotationReason {
+    Size,
+    Duration,
+    MessageCount,
+    Manual,
+    /// A recording session started or stopped
+    Session,
 }
 
-impl RecordFrame {
-    fn to_bytes(&self, payload: &[u8]) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.extend_from_slice(&self.magic.to_le_bytes());
-        let meta_json = serde_json::to_string(&self).expect("frame serialization");
-        buf.extend_from_slice(&(meta_json.len() as u32).to_le_bytes());
-        buf.extend_from_slice(meta_json.as_bytes());
-        buf.extend_from_slice(payload);
-        buf
+/// Emitted every time the active segment is sealed and a new one opened
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct SegmentRotated {
+    pub sealed_segment: u64,
+    pub sealed_path: PathBuf,
+    pub size_bytes: u64,
+    pub message_count: u64,
+    pub reason: RotationReason,
+    pub new_path: PathBuf,
+}
+
+/// Change to the sealed segments, broadcast in the order it happened
+#[derive(Debug, Clone)]
+pub enum SegmentEvent {
+    Rotated(SegmentRotated),
+    /// A black-box trigger moved sealed segments into a capture, as
+    /// (old, new) paths
+    Moved(Vec<(PathBuf, PathBuf)>),
+}
+
+/// A decoded WAL message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub topic: String,
+    pub namespace: String,
+    pub info: MessageInfo,
+    pub payload: Vec<u8>,
+}
+
+/// When a message was received and published, and its place in its
+/// publisher's stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageInfo {
+    /// Receive time in nanoseconds of `clock`; what time ranges, indexes and
+    /// conversions go by
+    pub receive_ns: u128,
+    /// Source stamp (`header.stamp`) in nanoseconds, for messages that have one
+    pub header_ns: Option<u128>,
+    /// Sequence number within the message's publisher, or within its topic for
+    /// messages from ROS subscriptions; gaps mean messages were lost on the way
+    pub sequence: Option<u64>,
+    pub clock: ClockSource,
+}
+
+impl MessageInfo {
+    /// A message received at `receive_ns` on the system clock, with no header
+    /// stamp or sequence number
+    pub fn received(receive_ns: u128) -> Self {
+        MessageInfo { receive_ns, ..Default::default() }
     }
 
     #[allow(dead_code)]
-    fn from_reader(reader: &mut dyn Read) -> Result<Option<(RecordFrame, Vec<u8>)>> {
-        let mut magic_buf = [0u8; 4];
-        if reader.read_exact(&mut magic_buf).is_err() {
-            return Ok(None);
-        }
-        let magic = u32::from_le_bytes(magic_buf);
-        if magic != RECORD_FRAME_HEADER {
-            return Ok(None);
-        }
+    pub fn with_header_stamp(mut self, header_ns: u128) -> Self {
+        self.header_ns = Some(header_ns);
+        self
+    }
 
-        let mut len_buf = [0u8; 4];
-        reader.read_exact(&mut len_buf)?;
-        let meta_len = u32::from_le_bytes(len_buf) as usize;
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
 
-        let mut meta_buf = vec![0u8; meta_len];
-        reader.read_exact(&mut meta_buf)?;
-        let frame: RecordFrame = serde_json::from_slice(&meta_buf)?;
+    pub fn with_clock(mut self, clock: ClockSource) -> Self {
+        self.clock = clock;
+        self
+    }
+}
 
-        let mut payload = vec![0u8; frame.payload_len as usize];
-        reader.read_exact(&mut payload)?;
+/// Point an append waits for before returning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum DurabilityPoint {
+    /// Accepted by the writer queue
+    Queued,
+    /// Handed to the OS (survives a process crash)
+    Written,
+    /// fsynced to disk (survives power loss)
+    Synced,
+}
 
-        let crc = crc32fast::hash(&payload);
-        if crc != frame.payload_crc32 {
-            return Err(anyhow!("payload CRC mismatch: expected {}, got {}", frame.payload_crc32, crc));
+impl From<DurabilityPolicy> for DurabilityPoint {
+    fn from(policy: DurabilityPolicy) -> Self {
+        match policy {
+            DurabilityPolicy::PerRecord => DurabilityPoint::Synced,
+            DurabilityPolicy::Periodic { .. } | DurabilityPolicy::OsBuffered => DurabilityPoint::Written,
         }
-
-        Ok(Some((frame, payload)))
     }
 }
 
@@ -69,73 +160,397 @@ impl RecordFrame {
 pub struct Storage {
     pub root: Arc<PathBuf>,
     inner: Arc<Mutex<StorageInner>>,
+    compression: Option<CompressionCodec>,
+    compression_level: i32,
+    default_point: DurabilityPoint,
+    index_stride: usize,
+    writer: mpsc::Sender<WriteCommand>,
+    /// Messages the writer lost to failed writes and fsyncs
+    lost_messages: Arc<AtomicU64>,
+    events: broadcast::Sender<SegmentEvent>,
+    recovery: Option<Arc<RecoveryReport>>,
+    /// Serializes session start/stop/tag/delete
+    session_lock: Arc<Mutex<()>>,
+    /// Message types of subscribed topics, written into session manifests
+    message_types: Arc<std::sync::Mutex<BTreeMap<String, String>>>,
+    retention: RetentionConfig,
+    /// Segments waiting in the sync queue; retention leaves them alone
+    pinned: Arc<Mutex<HashSet<PathBuf>>>,
+    blackbox: BlackBoxConfig,
+    /// Master keys for encrypting new segments and reading encrypted ones
+    keys: Keyring,
+    /// Reference counts of the blob directory, when it is in use
+    blobs: Option<Arc<std::sync::Mutex<BlobStore>>>,
+    /// Payloads at least this large go to the blob store
+    blob_min_size: Option<usize>,
+    /// Chunk layout of files written by `convert_to_mcap`
+    mcap: mcap::McapOptions,
+    compaction: CompactionConfig,
+    /// Held shared while readers list and read files, exclusively while
+    /// compaction or retention swaps them out
+    swap_lock: Arc<RwLock<()>>,
+    /// Opened by `open_read_only`
+    read_only: bool,
 }
 
 struct StorageInner {
     current_segment: u64,
     current_segment_size: u64,
+    current_segment_messages: u64,
+    segment_opened: Instant,
+    dictionary: SegmentDictionary,
+    /// Index of the active segment, written as a sidecar when it is sealed
+    index: IndexBuilder,
+    /// Directory new segments are opened in: the data root or a session directory
+    dir: PathBuf,
+    /// Recording session the active segment belongs to
+    session: Option<String>,
+}
+
+impl StorageInner {
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("segment-{}.log", segment))
+    }
+}
+
+/// Parse the number out of a `segment-N.log` file name
+pub fn segment_number(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("segment-")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
 }
 
 impl Storage {
+    #[allow(dead_code)]
     pub async fn new(cfg: &StorageConfig) -> Result<Self> {
-        let root = cfg.path.clone();
-        tokio::fs::create_dir_all(&root).await?;
+        Self::with_keyring(cfg, Keyring::default()).await
+    }
 
-        let (segment_num, _) = Self::recover_checkpoint(&root).await?;
+    /// Open the data directory with master keys from the credential vault.
+    /// New segments are encrypted when `cfg` names one of them.
+    pub async fn with_keyring(cfg: &StorageConfig, keys: Keyring) -> Result<Self> {
+        Self::open(cfg, keys, false).await
+    }
 
-        let inner = StorageInner { current_segment: segment_num, current_segment_size: 0 };
-        Ok(Storage { root: Arc::new(root), inner: Arc::new(Mutex::new(inner)) })
+    /// Open the data directory for inspection only. Startup recovery,
+    /// compaction recovery and session finalization are skipped and no writer
+    /// runs, so nothing on disk changes; appends fail.
+    pub async fn open_read_only(cfg: &StorageConfig, keys: Keyring) -> Result<Self> {
+        Self::open(cfg, keys, true).await
     }
 
-    pub async fn append_record(&self, topic: &str, namespace: &str, data: &[u8], timestamp: u128) -> Result<()> {
-        let mut inner = self.inner.lock().await;
+    async fn open(cfg: &StorageConfig, keys: Keyring, read_only: bool) -> Result<Self> {
+        let keys = keys.with_active(cfg.encryption_key_id())?;
+        let root = cfg.path.clone();
+        if !read_only {
+            tokio::fs::create_dir_all(&root).await?;
+        }
+
+        let (checkpoint_segment, checkpoint_session) = Self::recover_checkpoint(&root).await?;
+        let checkpoint_dir = match &checkpoint_session {
+            Some(id) => session::session_dir(&root, id),
+            None => root.clone(),
+        };
+        // Never resume into a segment that was already sealed by a rotation
+        let highest_on_disk = Self::scan_segments(&root)
+            .await?
+            .into_iter()
+            .filter_map(|p| Some((segment_number(&p)?, p)))
+            .max_by_key(|(n, _)| *n);
+        let (mut segment_num, mut dir) = match highest_on_disk {
+            Some((n, path)) if n >= checkpoint_segment => {
+                let in_checkpoint_dir = checkpoint_dir.join(format!("segment-{}.log", n));
+                let dir = if n == checkpoint_segment && in_checkpoint_dir.exists() {
+                    checkpoint_dir
+                } else {
+                    path.parent().map_or_else(|| root.clone(), Path::to_path_buf)
+                };
+                (n, dir)
+            }
+            _ => (checkpoint_segment, checkpoint_dir),
+        };
+        let mut active_session = (dir != root).then(|| dir.file_name().map(|n| n.to_string_lossy().to_string())).flatten();
+
+        let (mut recovered, report) = if read_only {
+            Default::default()
+        } else {
+            tokio::fs::create_dir_all(&dir).await?;
+            recovery::recover_active_segment(&dir, segment_num).await?
+        };
+        // Keep appending to the active segment only if it can stay in one mode
+        let mut data_key = None;
+        if recovered.size > 0 && !recovered.seal {
+            match (&recovered.key_frame, keys.active()) {
+                (Some(body), Some(_)) => match crypto::DataKey::open(&keys, body) {
+                    Ok(key) => data_key = Some(key),
+                    Err(e) => {
+                        tracing::warn!("cannot continue encrypted segment {}: {:#}", segment_num, e);
+                        recovered.seal = true;
+                    }
+                },
+                (None, None) => {}
+                _ => {
+                    tracing::info!("encryption setting changed, starting a new segment after {}", segment_num);
+                    recovered.seal = true;
+                }
+            }
+        }
+        if recovered.seal {
+            segment_num += 1;
+            // A session stopped right before the crash does not get the next segment
+            if let Some(id) = &active_session {
+                if session::read_manifest(&dir).await.is_ok_and(|m| m.end_time_unix_ms.is_some()) {
+                    tracing::info!("session {} was stopped before shutdown", id);
+                    dir = root.clone();
+                    active_session = None;
+                }
+            }
+            Self::write_checkpoint(&root, segment_num, active_session.as_deref()).await?;
+            recovered = Default::default();
+        }
 
-        let projected_size = inner.current_segment_size + data.len() as u64 + 100;
-        if projected_size > MAX_SEGMENT_SIZE {
-            drop(inner);
-            self.rotate_segment().await?;
-            inner = self.inner.lock().await;
+        let index = if recovered.size > 0 {
+            let path = dir.join(format!("segment-{}.log", segment_num));
+            let stride = cfg.index_stride;
+            tokio::task::spawn_blocking(move || IndexBuilder::scan(&path, stride)).await??.0
+        } else {
+            IndexBuilder::new(cfg.index_stride)
+        };
+        if !read_only {
+            Self::finalize_interrupted_sessions(&root, active_session.as_deref(), cfg.index_stride).await?;
+            let root = root.clone();
+            tokio::task::spawn_blocking(move || compaction::recover(&root)).await??;
         }
 
-        let segment_file = self.root.join(format!("segment-{}.log", inner.current_segment));
+        // Blobs stay unencrypted, so encrypted segments keep payloads inline
+        let blob_min_size = (cfg.blobs.enabled && keys.active().is_none()).then_some(cfg.blobs.min_size_bytes);
+        if cfg.blobs.enabled && blob_min_size.is_none() {
+            tracing::warn!("blob store disabled while segments are encrypted");
+        }
+        let blobs = if read_only {
+            None
+        } else if cfg.blobs.enabled || tokio::fs::try_exists(blob::blob_dir(&root)).await? {
+            let sync = !matches!(cfg.durability, DurabilityPolicy::OsBuffered);
+            let store = Self::load_blob_store(&root, segment_num, &index, cfg.index_stride, sync).await?;
+            Some(Arc::new(std::sync::Mutex::new(store)))
+        } else {
+            None
+        };
 
-        let crc = crc32fast::hash(data);
-        let frame = RecordFrame {
-            magic: RECORD_FRAME_HEADER,
-            timestamp,
-            topic: topic.to_string(),
-            namespace: namespace.to_string(),
-            payload_len: data.len() as u32,
-            payload_crc32: crc,
+        let inner = StorageInner {
+            current_segment: segment_num,
+            current_segment_size: recovered.size,
+            current_segment_messages: recovered.messages,
+            segment_opened: Instant::now(),
+            dictionary: recovered.dictionary,
+            index,
+            dir,
+            session: active_session,
+        };
+        let root = Arc::new(root);
+        let inner = Arc::new(Mutex::new(inner));
+        let (events, _) = broadcast::channel(SEGMENT_EVENT_CAPACITY);
+        let (writer, rx) = mpsc::channel(cfg.write_queue_capacity.max(1));
+        let segment_writer = SegmentWriter::new(
+            root.clone(),
+            inner.clone(),
+            cfg,
+            events.clone(),
+            recovered.digest,
+            keys.clone(),
+            data_key,
+        )
+        .with_blobs(blobs.clone());
+        let lost_messages = segment_writer.lost_messages();
+        if !read_only {
+            tokio::spawn(segment_writer.run(rx));
+        }
+
+        let storage = Storage {
+            root,
+            inner,
+            compression: cfg.compress.then_some(cfg.compression),
+            compression_level: cfg.compression_level,
+            default_point: cfg.durability.into(),
+            index_stride: cfg.index_stride,
+            writer,
+            lost_messages,
+            events,
+            recovery: report.map(Arc::new),
+            session_lock: Arc::new(Mutex::new(())),
+            message_types: Arc::new(std::sync::Mutex::new(BTreeMap::new())),
+            retention: cfg.retention.clone(),
+            pinned: Arc::new(Mutex::new(HashSet::new())),
+            blackbox: cfg.blackbox.clone(),
+            keys,
+            blobs,
+            blob_min_size,
+            mcap: mcap::McapOptions {
+                compression: cfg.compress.then_some(cfg.compression),
+                compression_level: cfg.compression_level,
+                chunk_size: cfg.mcap.chunk_size.max(1),
+            },
+            compaction: cfg.compaction.clone(),
+            swap_lock: Arc::new(RwLock::new(())),
+            read_only,
         };
+        // A capture interrupted by a restart still stops on schedule
+        if let Some(id) = storage.active_session().await.filter(|_| !read_only) {
+            let dir = session::session_dir(&storage.root, &id);
+            if let Some(capture) = session::read_manifest(&dir).await.ok().and_then(|m| m.capture) {
+                storage.schedule_capture_stop(id, capture.stop_at_unix_ms);
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Damage found in the active segment when this instance started, if any
+    #[allow(dead_code)]
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery.as_deref()
+    }
+
+    /// Subscribe to segment rotations and moves
+    #[allow(dead_code)]
+    pub fn subscribe_segment_events(&self) -> broadcast::Receiver<SegmentEvent> {
+        self.events.subscribe()
+    }
 
-        let frame_data = frame.to_bytes(data);
+    /// Path of the segment currently being written
+    #[allow(dead_code)]
+    pub async fn active_segment_path(&self) -> PathBuf {
+        let inner = self.inner.lock().await;
+        inner.segment_path(inner.current_segment)
+    }
 
-        let mut f = OpenOptions::new().create(true).append(true).open(&segment_file).await?;
-        f.write_all(&frame_data).await?;
-        f.sync_all().await?;
+    /// Append a record received at `receive_ns` (system clock) and wait for
+    /// the durability point implied by the configured policy
+    #[allow(dead_code)]
+    pub async fn append_record(&self, topic: &str, namespace: &str, data: &[u8], receive_ns: u128) -> Result<()> {
+        self.append_message(topic, namespace, data, MessageInfo::received(receive_ns)).await
+    }
 
-        inner.current_segment_size += frame_data.len() as u64;
+    /// Append a record with its full `info` and wait for the durability point
+    /// implied by the configured policy
+    #[allow(dead_code)]
+    pub async fn append_message(&self, topic: &str, namespace: &str, data: &[u8], info: MessageInfo) -> Result<()> {
+        self.append_record_with(topic, namespace, data, info, self.default_point).await
+    }
 
+    /// Append a record and wait until it reaches `point`
+    pub async fn append_record_with(
+        &self,
+        topic: &str,
+        namespace: &str,
+        data: &[u8],
+        info: MessageInfo,
+        point: DurabilityPoint,
+    ) -> Result<()> {
+        frame::check_message(topic, namespace, data.len())?;
+        let (codec, stored, blob) = match &self.blobs {
+            Some(blobs) if self.blob_min_size.is_some_and(|min| data.len() >= min) => {
+                let blobs = blobs.clone();
+                let data = data.to_vec();
+                let id = tokio::task::spawn_blocking(move || {
+                    let id = blob::blob_id(&data);
+                    blobs.lock().unwrap().put(&id, &data).map(|_| id)
+                })
+                .await??;
+                (frame::PayloadCodec::None, id.to_vec(), Some(id))
+            }
+            _ => {
+                let (codec, stored) = frame::compress_payload(self.compression, self.compression_level, data)?;
+                frame::check_stored_len(stored.len())?;
+                (codec, stored, None)
+            }
+        };
+        let (done, ack) = match point {
+            DurabilityPoint::Queued => (None, None),
+            _ => {
+                let (tx, rx) = oneshot::channel();
+                (Some(tx), Some(rx))
+            }
+        };
+        let record = PendingRecord {
+            topic: topic.to_string(),
+            namespace: namespace.to_string(),
+            info,
+            codec,
+            stored,
+            raw_len: data.len(),
+            blob,
+            wait: point,
+            done,
+        };
+        if let Err(mpsc::error::SendError(cmd)) = self.writer.send(WriteCommand::Append(record)).await {
+            if let (Some(blobs), WriteCommand::Append(PendingRecord { blob: Some(id), .. })) = (&self.blobs, cmd) {
+                blobs.lock().unwrap().discard(&id);
+            }
+            return Err(anyhow!("segment writer stopped"));
+        }
+        if let Some(ack) = ack {
+            ack.await.map_err(|_| anyhow!("segment writer stopped"))??;
+        }
         Ok(())
     }
 
-    pub async fn rotate_segment(&self) -> Result<PathBuf> {
-        let mut inner = self.inner.lock().await;
+    /// Wait until everything appended so far is fsynced
+    pub async fn sync(&self) -> Result<()> {
+        let (done, ack) = oneshot::channel();
+        self.writer
+            .send(WriteCommand::Sync { done })
+            .await
+            .map_err(|_| anyhow!("segment writer stopped"))?;
+        ack.await.map_err(|_| anyhow!("segment writer stopped"))?
+    }
+
+    /// Messages lost to failed rotations, writes and fsyncs so far, including
+    /// ones whose append returned before they reached the file
+    pub fn lost_messages(&self) -> u64 {
+        self.lost_messages.load(Ordering::Relaxed)
+    }
+
+    /// Make the writer's next batch fail halfway through, like a full disk
+    #[cfg(test)]
+    pub(crate) async fn fail_next_write(&self) {
+        let _ = self.writer.send(WriteCommand::FailNextWrite).await;
+    }
 
-        Self::write_checkpoint(&self.root, inner.current_segment).await?;
+    #[allow(dead_code)]
+    pub async fn rotate_segment(&self) -> Result<PathBuf> {
+        self.rotate_into(None).await
+    }
 
-        inner.current_segment += 1;
-        inner.current_segment_size = 0;
-        let new_path = self.root.join(format!("segment-{}.log", inner.current_segment));
-        let _ = tokio::fs::File::create(&new_path).await?;
-        tracing::info!("rotated to segment {}", inner.current_segment);
-        Ok(new_path)
+    /// Seal the active segment and open the next one in `target`'s directory
+    async fn rotate_into(&self, target: Option<RotateTarget>) -> Result<PathBuf> {
+        let (done, ack) = oneshot::channel();
+        self.writer
+            .send(WriteCommand::Rotate { target, done })
+            .await
+            .map_err(|_| anyhow!("segment writer stopped"))?;
+        ack.await.map_err(|_| anyhow!("segment writer stopped"))?
     }
 
     #[allow(dead_code)]
     pub async fn list_segments(&self) -> Result<Vec<PathBuf>> {
-        let mut entries = tokio::fs::read_dir(&*self.root).await?;
+        Self::scan_segments(&self.root).await
+    }
+
+    /// Segment files under `root` and its session directories, ordered by segment number
+    async fn scan_segments(root: &Path) -> Result<Vec<PathBuf>> {
+        let mut out = Self::scan_dir(root).await?;
+        for dir in session::session_dirs(root).await? {
+            out.extend(Self::scan_dir(&dir).await?);
+        }
+        out.sort_by_key(|p| segment_number(p));
+        Ok(out)
+    }
+
+    async fn scan_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut entries = tokio::fs::read_dir(dir).await?;
         let mut out = Vec::new();
         loop {
             match entries.next_entry().await {
@@ -151,21 +566,28 @@ impl Storage {
                 Err(e) => return Err(e.into()),
             }
         }
-        out.sort();
+        out.sort_by_key(|p| segment_number(p));
         Ok(out)
     }
 
+    /// SHA-256 of a segment's frames, excluding the footer, as the footer
+    /// records it. `None` for the active segment and footer-less segments.
+    pub async fn segment_checksum(path: &Path) -> Result<Option<String>> {
+        Ok(Self::segment_footer(path).await?.map(|footer| footer.sha256))
+    }
+
+    /// Footer of a sealed segment, `None` for the active segment and for
+    /// segments sealed without one
     #[allow(dead_code)]
-    pub async fn segment_checksum(path: &Path) -> Result<String> {
-        let data = tokio::fs::read(path).await?;
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        Ok(format!("{:x}", hasher.finalize()))
+    pub async fn segment_footer(path: &Path) -> Result<Option<SegmentFooter>> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || footer::read_footer(&path)).await?
     }
 
-    async fn write_checkpoint(root: &Path, segment: u64) -> Result<()> {
+    async fn write_checkpoint(root: &Path, segment: u64, session: Option<&str>) -> Result<()> {
         let manifest = serde_json::json!({
             "current_segment": segment,
+            "session": session,
             "timestamp": std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)?
                 .as_millis(),
@@ -185,179 +607,2048 @@ impl Storage {
         let data = tokio::fs::read_to_string(&path).await?;
         let manifest: serde_json::Value = serde_json::from_str(&data)?;
         let segment = manifest["current_segment"].as_u64().unwrap_or(0);
-        tracing::info!("recovered checkpoint: segment {}", segment);
-        Ok((segment, Some(data)))
+        let session = manifest["session"].as_str().map(str::to_string);
+        tracing::info!("recovered checkpoint: segment {} (session {:?})", segment, session);
+        Ok((segment, session))
     }
 
+    /// Start recording into a new session directory. New records go to a
+    /// fresh segment inside it until `stop_session`.
     #[allow(dead_code)]
-    pub async fn replay_segment(path: &Path) -> Result<Vec<(String, String, u128, Vec<u8>)>> {
-        let mut file = std::fs::File::open(path)?;
-        let mut records = Vec::new();
-        while let Some((frame, payload)) = RecordFrame::from_reader(&mut file)? {
-            records.push((frame.topic, frame.namespace, frame.timestamp, payload));
+    pub async fn start_session(&self, id: &str, robot: Option<&str>, tags: &[&str]) -> Result<RecordingMetadata> {
+        let _guard = self.session_lock.lock().await;
+        session::validate_id(id)?;
+        if let Some(active) = self.active_session().await {
+            return Err(anyhow!("session {} is already recording", active));
+        }
+        let dir = session::session_dir(&self.root, id);
+        if tokio::fs::try_exists(&dir).await? {
+            return Err(anyhow!("session {} already exists", id));
         }
-        Ok(records)
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut metadata = RecordingMetadata {
+            recording_id: id.to_string(),
+            start_time_unix_ms: session::now_ms()?,
+            end_time_unix_ms: None,
+            topics: self.declared_topics(),
+            robot: robot.map(str::to_string),
+            tags: Vec::new(),
+            capture: None,
+        };
+        add_tags(&mut metadata, tags);
+        session::write_manifest(&dir, &metadata).await?;
+        self.rotate_into(Some(RotateTarget { dir, session: Some(id.to_string()) }))
+            .await?;
+        tracing::info!("started recording session {}", id);
+        Ok(metadata)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::TempDir;
+    /// Seal the active session's last segment and record its end time and
+    /// topic summary. Recording continues outside any session.
+    #[allow(dead_code)]
+    pub async fn stop_session(&self) -> Result<RecordingMetadata> {
+        let _guard = self.session_lock.lock().await;
+        let id = self
+            .active_session()
+            .await
+            .ok_or_else(|| anyhow!("no recording session is active"))?;
+        self.stop_session_locked(&id).await
+    }
 
-    #[tokio::test]
-    async fn test_storage_append_and_replay() -> Result<()> {
-        let tmpdir = TempDir::new()?;
-        let cfg = StorageConfig {
-            path: tmpdir.path().to_path_buf(),
-            wal_segment_size: 1024 * 1024,
-            compress: false,
-            encryption: None,
-            enable_aes_gcm: false,
-        };
+    async fn stop_session_locked(&self, id: &str) -> Result<RecordingMetadata> {
+        self.rotate_into(Some(RotateTarget { dir: self.root.to_path_buf(), session: None }))
+            .await?;
+        let dir = session::session_dir(&self.root, id);
+        let segments = Self::scan_dir(&dir).await?;
+        let metadata = session::finalize(&dir, segments, self.index_stride, session::now_ms()?).await?;
+        tracing::info!("stopped recording session {}", id);
+        Ok(metadata)
+    }
 
-        let storage = Storage::new(&cfg).await?;
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_millis();
+    /// Freeze the black-box window into a new session named `id` (generated
+    /// from the trigger time when `None`). Everything recorded in the last
+    /// `window_secs` moves into the session and recording continues there for
+    /// `post_trigger_secs`. `source` records who pulled the trigger.
+    pub async fn trigger_capture(&self, id: Option<&str>, source: &str) -> Result<RecordingMetadata> {
+        if !self.blackbox.enabled {
+            return Err(anyhow!("black-box mode is not enabled"));
+        }
+        let _guard = self.session_lock.lock().await;
+        let triggered_at = session::now_ms()?;
+        let id = id.map_or_else(|| format!("capture-{}", triggered_at), str::to_string);
+        session::validate_id(&id)?;
+        if let Some(active) = self.active_session().await {
+            return Err(anyhow!("session {} is still recording, capture {} not started", active, id));
+        }
+        let dir = session::session_dir(&self.root, &id);
+        if tokio::fs::try_exists(&dir).await? {
+            return Err(anyhow!("session {} already exists", id));
+        }
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let window = Duration::from_secs(self.blackbox.window_secs);
+        let stop_at = triggered_at + self.blackbox.post_trigger_secs as u128 * 1000;
+        let metadata = RecordingMetadata {
+            recording_id: id.clone(),
+            start_time_unix_ms: triggered_at.saturating_sub(window.as_millis()),
+            end_time_unix_ms: None,
+            topics: self.declared_topics(),
+            robot: None,
+            tags: vec!["blackbox".to_string()],
+            capture: Some(CaptureTrigger {
+                source: source.to_string(),
+                triggered_at_unix_ms: triggered_at,
+                stop_at_unix_ms: stop_at,
+            }),
+        };
+        session::write_manifest(&dir, &metadata).await?;
+
+        // Seal the active segment so the whole window is on disk, then move it
+        // over while the writer already records into the capture
+        self.rotate_into(Some(RotateTarget { dir: dir.clone(), session: Some(id.clone()) }))
+            .await?;
+        let mut pinned = self.pinned.clone().lock_owned().await;
+        let active = self.inner.lock().await.current_segment;
+        let segments = Self::scan_dir(&self.root).await?;
+        let frozen =
+            tokio::task::spawn_blocking(move || blackbox::freeze(&segments, active, &dir, &mut pinned, window)).await??;
+        if !frozen.is_empty() {
+            let _ = self.events.send(SegmentEvent::Moved(frozen.clone()));
+        }
+        tracing::info!(
+            "black-box capture {} triggered by {}: {} segments frozen, recording {}s more",
+            id,
+            source,
+            frozen.len(),
+            self.blackbox.post_trigger_secs
+        );
+        self.schedule_capture_stop(id, stop_at);
+        Ok(metadata)
+    }
 
-        storage.append_record("topic1", "robot1", b"hello", now).await?;
-        storage.append_record("topic2", "robot1", b"world", now + 1).await?;
+    /// Stop capture `id` once the wall clock reaches `stop_at` (ms)
+    fn schedule_capture_stop(&self, id: String, stop_at: u128) {
+        let storage = self.clone();
+        tokio::spawn(async move {
+            let delay = stop_at.saturating_sub(session::now_ms().unwrap_or(stop_at));
+            tokio::time::sleep(Duration::from_millis(delay as u64)).await;
+            let _guard = storage.session_lock.lock().await;
+            // Stopped by hand in the meantime
+            if storage.active_session().await.as_deref() != Some(id.as_str()) {
+                return;
+            }
+            if let Err(e) = storage.stop_session_locked(&id).await {
+                tracing::error!("could not stop black-box capture {}: {:#}", id, e);
+            }
+        });
+    }
 
-        let segments = storage.list_segments().await?;
-        assert_eq!(segments.len(), 1);
+    /// Delete loose segments that fell out of the black-box window
+    pub async fn trim_ring_buffer(&self) -> Result<Vec<PathBuf>> {
+        // Not while a trigger is moving the window into a capture
+        let _guard = self.session_lock.lock().await;
+        let pinned = self.pinned.clone().lock_owned().await;
+        let active = self.inner.lock().await.current_segment;
+        let segments = Self::scan_dir(&self.root).await?;
+        let window = Duration::from_secs(self.blackbox.window_secs);
+        let deleted = tokio::task::spawn_blocking(move || blackbox::trim(&segments, active, &pinned, window)).await?;
+        self.release_blobs(&deleted).await?;
+        Ok(deleted)
+    }
 
-        let records = Storage::replay_segment(&segments[0]).await?;
-        assert_eq!(records.len(), 2);
-        assert_eq!(records[0].0, "topic1");
-        assert_eq!(records[0].3, b"hello");
-        assert_eq!(records[1].0, "topic2");
-        assert_eq!(records[1].3, b"world");
+    /// Record the message type of `topic`, for the manifest of the active
+    /// session and of every session started later. The WAL itself stores no
+    /// types, so rosbag2 and MCAP exports take them from the manifest.
+    pub async fn declare_topic(&self, topic: &str, message_type: &str) -> Result<()> {
+        self.message_types
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), message_type.to_string());
+        let _guard = self.session_lock.lock().await;
+        let Some(id) = self.active_session().await else { return Ok(()) };
+        let dir = session::session_dir(&self.root, &id);
+        let mut metadata = session::read_manifest(&dir).await?;
+        match metadata.topics.iter_mut().find(|t| t.topic == topic) {
+            Some(entry) => entry.msg_type = message_type.to_string(),
+            None => metadata.topics.push(session::declared_topic(topic, message_type)),
+        }
+        session::write_manifest(&dir, &metadata).await
+    }
 
-        Ok(())
+    /// Manifest entries of every declared topic, before any message counts
+    fn declared_topics(&self) -> Vec<TopicManifestEntry> {
+        let types = self.message_types.lock().unwrap();
+        types.iter().map(|(topic, message_type)| session::declared_topic(topic, message_type)).collect()
     }
 
-    #[tokio::test]
-    async fn test_segment_rotation() -> Result<()> {
-        let tmpdir = TempDir::new()?;
-        let cfg = StorageConfig {
-            path: tmpdir.path().to_path_buf(),
-            wal_segment_size: 512,
-            compress: false,
-            encryption: None,
-            enable_aes_gcm: false,
-        };
+    /// Id of the session currently recording, if any
+    #[allow(dead_code)]
+    pub async fn active_session(&self) -> Option<String> {
+        self.inner.lock().await.session.clone()
+    }
 
-        let storage = Storage::new(&cfg).await?;
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_millis();
+    /// Every session, oldest first
+    #[allow(dead_code)]
+    pub async fn list_sessions(&self) -> Result<Vec<RecordingMetadata>> {
+        session::list(&self.root).await
+    }
 
-        storage.append_record("topic1", "robot1", b"record1", now).await?;
-        let segments_before = storage.list_segments().await?;
-        assert_eq!(segments_before.len(), 1);
+    #[allow(dead_code)]
+    pub async fn session(&self, id: &str) -> Result<RecordingMetadata> {
+        session::validate_id(id)?;
+        session::read_manifest(&session::session_dir(&self.root, id))
+            .await
+            .map_err(|e| anyhow!("unknown session {}: {:#}", id, e))
+    }
 
-        storage.rotate_segment().await?;
+    /// Segments recorded in a session, in order
+    #[allow(dead_code)]
+    pub async fn session_segments(&self, id: &str) -> Result<Vec<PathBuf>> {
+        self.session(id).await?;
+        Self::scan_dir(&session::session_dir(&self.root, id)).await
+    }
 
-        storage.append_record("topic2", "robot1", b"record2", now + 1).await?;
-        let segments_after = storage.list_segments().await?;
-        assert_eq!(segments_after.len(), 2);
+    /// Add tags to a session; tags it already has are ignored
+    #[allow(dead_code)]
+    pub async fn tag_session(&self, id: &str, tags: &[&str]) -> Result<RecordingMetadata> {
+        let _guard = self.session_lock.lock().await;
+        let mut metadata = self.session(id).await?;
+        add_tags(&mut metadata, tags);
+        session::write_manifest(&session::session_dir(&self.root, id), &metadata).await?;
+        Ok(metadata)
+    }
 
-        let records0 = Storage::replay_segment(&segments_before[0]).await?;
-        assert_eq!(records0[0].0, "topic1");
+    /// Remove a stopped session and all of its segments
+    #[allow(dead_code)]
+    pub async fn delete_session(&self, id: &str) -> Result<()> {
+        let _guard = self.session_lock.lock().await;
+        self.session(id).await?;
+        if self.active_session().await.as_deref() == Some(id) {
+            return Err(anyhow!("cannot delete session {} while it is recording", id));
+        }
+        let segments = self.session_segments(id).await?;
+        tokio::fs::remove_dir_all(session::session_dir(&self.root, id)).await?;
+        self.release_blobs(&segments).await?;
+        tracing::info!("deleted recording session {}", id);
+        Ok(())
+    }
 
-        let records1 = Storage::replay_segment(&segments_after[1]).await?;
-        assert_eq!(records1[0].0, "topic2");
+    /// Keep a segment from being deleted by retention, e.g. while it waits for upload
+    #[allow(dead_code)]
+    pub async fn pin_segment(&self, path: &Path) {
+        self.pinned.lock().await.insert(path.to_path_buf());
+    }
 
-        Ok(())
+    #[allow(dead_code)]
+    pub async fn unpin_segment(&self, path: &Path) {
+        self.pinned.lock().await.remove(path);
     }
 
-    #[tokio::test]
-    async fn test_checkpoint_recovery() -> Result<()> {
-        let tmpdir = TempDir::new()?;
-        let cfg = StorageConfig {
-            path: tmpdir.path().to_path_buf(),
-            wal_segment_size: 512,
-            compress: false,
-            encryption: None,
-            enable_aes_gcm: false,
-        };
+    #[cfg(test)]
+    pub(crate) async fn is_pinned(&self, path: &Path) -> bool {
+        self.pinned.lock().await.contains(path)
+    }
 
-        let storage = Storage::new(&cfg).await?;
-        let now = std::time::SystemTime::now()
+    /// Record that a segment reached the cloud, making it eligible for
+    /// retention under `require_upload`
+    #[allow(dead_code)]
+    pub async fn mark_uploaded(&self, path: &Path) -> Result<()> {
+        let uploaded_at = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis();
+        tokio::fs::write(uploaded_marker(path), uploaded_at.to_string()).await?;
+        Ok(())
+    }
 
-        storage.append_record("topic1", "robot1", b"data1", now).await?;
-        storage.rotate_segment().await?;
-        storage.append_record("topic2", "robot1", b"data2", now + 1).await?;
-
-        let storage2 = Storage::new(&cfg).await?;
+    #[allow(dead_code)]
+    pub async fn is_uploaded(path: &Path) -> bool {
+        tokio::fs::try_exists(uploaded_marker(path)).await.unwrap_or(false)
+    }
 
-        let segments = storage2.list_segments().await?;
-        assert_eq!(segments.len(), 2);
+    /// Delete sealed segments, oldest first, until the retention limits hold
+    pub async fn enforce_retention(&self) -> Result<RetentionReport> {
+        // Holding the pin set for the whole pass keeps a segment from being
+        // queued for upload while it is deleted
+        let pinned = self.pinned.clone().lock_owned().await;
+        let _swap = self.swap_lock.write().await;
+        let active = self.inner.lock().await.current_segment;
+        let mut segments = self.compacted_batches().await?;
+        segments.extend(self.list_segments().await?);
+        let root = self.root.clone();
+        let cfg = self.retention.clone();
+        let mut report =
+            tokio::task::spawn_blocking(move || retention::enforce(&root, &segments, active, &pinned, &cfg)).await??;
+        report.freed_bytes += self.release_blobs(&report.deleted).await?;
+        if report.unsatisfied {
+            tracing::warn!(
+                "retention limits not met: {} bytes left, remaining segments are active, queued for sync or not uploaded",
+                report.total_bytes
+            );
+        }
+        Ok(report)
+    }
 
-        Ok(())
+    /// Background janitor trimming the black-box window and enforcing the
+    /// retention limits until aborted
+    pub async fn run_janitor(&self) {
+        if !self.retention.is_enabled() && !self.blackbox.enabled {
+            tracing::info!("no retention limits configured, janitor not running");
+            return;
+        }
+        let mut period = Duration::from_secs(self.retention.check_interval_secs.max(1));
+        if self.blackbox.enabled {
+            period = period.min(Duration::from_secs(self.blackbox.segment_secs()));
+        }
+        let mut tick = tokio::time::interval(period);
+        loop {
+            tick.tick().await;
+            if self.blackbox.enabled {
+                match self.trim_ring_buffer().await {
+                    Ok(deleted) if !deleted.is_empty() => {
+                        tracing::debug!("black-box ring dropped {} segments", deleted.len())
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("black-box trim failed: {:#}", e),
+                }
+            }
+            if !self.retention.is_enabled() {
+                continue;
+            }
+            match self.enforce_retention().await {
+                Ok(report) if !report.deleted.is_empty() => tracing::info!(
+                    "retention freed {} bytes from {} segments",
+                    report.freed_bytes,
+                    report.deleted.len()
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::error!("retention pass failed: {:#}", e),
+            }
+        }
     }
 
-    #[tokio::test]
-    async fn test_payload_corruption_detection() -> Result<()> {
-        let tmpdir = TempDir::new()?;
-        let cfg = StorageConfig {
-            path: tmpdir.path().to_path_buf(),
-            wal_segment_size: 1024 * 1024,
-            compress: false,
-            encryption: None,
-            enable_aes_gcm: false,
+    /// Rewrite the oldest eligible loose segments into a compacted batch and
+    /// delete them once the batch is in place
+    pub async fn compact(&self) -> Result<CompactionReport> {
+        if self.blackbox.enabled {
+            return Err(anyhow!("compaction is not available in black-box mode"));
+        }
+        let active = self.inner.lock().await.current_segment;
+        let pinned = self.pinned.lock().await.clone();
+        let segments = Self::scan_dir(&self.root).await?;
+        let (root, cfg, keys) = (self.root.clone(), self.compaction.clone(), self.keys.clone());
+        let opts = compaction::PartitionOptions {
+            compression: self.compression,
+            compression_level: self.compression_level,
+            index_stride: self.index_stride,
         };
+        let prepared = tokio::task::spawn_blocking(move || {
+            compaction::prepare(&root, &segments, active, &pinned, &cfg, &keys, opts)
+        })
+        .await??;
+        let Some(batch) = prepared else { return Ok(CompactionReport::default()) };
+
+        // Same order as retention: pins first, then the swap
+        let pinned = self.pinned.clone().lock_owned().await;
+        let swap = self.swap_lock.clone().write_owned().await;
+        let report = tokio::task::spawn_blocking(move || {
+            let _swap = swap;
+            compaction::commit(batch, &pinned)
+        })
+        .await??;
+        self.release_blobs(&report.sources).await?;
+        if let Some(batch) = &report.batch {
+            tracing::info!(
+                "compacted {} segments into {} ({} messages, {} downsampled, {} -> {} bytes)",
+                report.sources.len(),
+                batch.display(),
+                report.messages,
+                report.downsampled,
+                report.bytes_before,
+                report.bytes_after
+            );
+        }
+        Ok(report)
+    }
 
-        let storage = Storage::new(&cfg).await?;
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_millis();
+    /// Background compactor, running `compact` until aborted
+    pub async fn run_compactor(&self) {
+        if !self.compaction.enabled {
+            tracing::info!("compaction disabled");
+            return;
+        }
+        if self.blackbox.enabled {
+            tracing::warn!("compaction does not run in black-box mode");
+            return;
+        }
+        let mut tick = tokio::time::interval(Duration::from_secs(self.compaction.check_interval_secs.max(1)));
+        loop {
+            tick.tick().await;
+            // A pass handles one batch; keep going while segments are waiting
+            loop {
+                match self.compact().await {
+                    Ok(report) if report.batch.is_some() => continue,
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("compaction pass failed: {:#}", e),
+                }
+                break;
+            }
+        }
+    }
 
-        storage.append_record("topic1", "robot1", b"original_data", now).await?;
+    /// Compacted batch directories, oldest first
+    #[allow(dead_code)]
+    pub async fn compacted_batches(&self) -> Result<Vec<PathBuf>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || compaction::batches(&root)).await?
+    }
 
-        let segments = storage.list_segments().await?;
-        let segment_path = &segments[0];
+    /// Manifest of a compacted batch
+    #[allow(dead_code)]
+    pub async fn batch_manifest(batch: &Path) -> Result<BatchManifest> {
+        let batch = batch.to_path_buf();
+        tokio::task::spawn_blocking(move || compaction::read_manifest(&batch)).await?
+    }
 
-        let mut data = fs::read(segment_path)?;
-        if !data.is_empty() {
-            let idx = data.len() - 10;
-            data[idx] ^= 0xFF;
-            fs::write(segment_path, data)?;
-        }
+    /// Rebuild blob reference counts from the indexes of every segment and
+    /// the active one's builder, then delete blobs nothing refers to
+    async fn load_blob_store(root: &Path, active: u64, index: &IndexBuilder, stride: usize, sync: bool) -> Result<BlobStore> {
+        let segments = Self::scan_segments(root).await?;
+        let mut store = BlobStore::new(blob::blob_dir(root), sync);
+        store.load(None, index.blobs());
+        tokio::task::spawn_blocking(move || {
+            for path in segments {
+                match segment_number(&path) {
+                    Some(n) if n < active => {
+                        let index = index::load_or_rebuild(&path, stride)
+                            .with_context(|| format!("counting blob references of {}", path.display()))?;
+                        store.load(Some(n), &index.blobs);
+                    }
+                    _ => {}
+                }
+            }
+            let freed = store.sweep()?;
+            if freed > 0 {
+                tracing::info!("removed {} bytes of unreferenced blobs", freed);
+            }
+            Ok(store)
+        })
+        .await?
+    }
 
-        let _result = Storage::replay_segment(segment_path).await;
+    /// Release the blob references of deleted segments; returns the blob
+    /// bytes freed
+    async fn release_blobs(&self, deleted: &[PathBuf]) -> Result<u64> {
+        let Some(blobs) = self.blobs.clone() else { return Ok(0) };
+        let segments: Vec<u64> = deleted.iter().filter_map(|p| segment_number(p)).collect();
+        Ok(tokio::task::spawn_blocking(move || {
+            let mut blobs = blobs.lock().unwrap();
+            segments.into_iter().map(|n| blobs.release(n)).sum()
+        })
+        .await?)
+    }
 
+    /// Close out sessions left open by a crash that are no longer recording,
+    /// dating their end to the last sealed segment
+    async fn finalize_interrupted_sessions(root: &Path, active: Option<&str>, stride: usize) -> Result<()> {
+        for metadata in session::list(root).await? {
+            if metadata.end_time_unix_ms.is_some() || active == Some(metadata.recording_id.as_str()) {
+                continue;
+            }
+            let dir = session::session_dir(root, &metadata.recording_id);
+            let segments = Self::scan_dir(&dir).await?;
+            let mut end_time = metadata.start_time_unix_ms;
+            for path in &segments {
+                if let Some(footer) = Self::segment_footer(path).await? {
+                    end_time = end_time.max(footer.sealed_at);
+                }
+            }
+            session::finalize(&dir, segments, stride, end_time).await?;
+            tracing::warn!("closed interrupted session {}", metadata.recording_id);
+        }
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_segment_checksum() -> Result<()> {
-        let tmpdir = TempDir::new()?;
-        let cfg = StorageConfig {
-            path: tmpdir.path().to_path_buf(),
-            wal_segment_size: 1024 * 1024,
-            compress: false,
-            encryption: None,
-            enable_aes_gcm: false,
-        };
+    /// Read all records of a segment into memory. Prefer `SegmentReader` or
+    /// `stream_records` for anything that may be large.
+    #[allow(dead_code)]
+    pub async fn replay_segment(path: &Path) -> Result<Vec<Record>> {
+        Self::replay_segment_with_keys(path, &Keyring::default()).await
+    }
 
-        let storage = Storage::new(&cfg).await?;
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_millis();
+    /// `replay_segment` for segments that may be encrypted under one of `keys`
+    #[allow(dead_code)]
+    pub async fn replay_segment_with_keys(path: &Path, keys: &Keyring) -> Result<Vec<Record>> {
+        let path = path.to_path_buf();
+        let keys = keys.clone();
+        tokio::task::spawn_blocking(move || SegmentReader::open_with_keys(&path, &keys)?.collect()).await?
+    }
 
-        storage.append_record("topic1", "robot1", b"test_data", now).await?;
+    /// Stream every record of every compacted batch (one partition after
+    /// another) and then every segment, oldest first
+    #[allow(dead_code)]
+    pub async fn stream_records(&self) -> Result<impl Stream<Item = Result<Record>> + Unpin> {
+        let _swap = self.swap_lock.read().await;
+        let root = self.root.clone();
+        let mut files = tokio::task::spawn_blocking(move || -> Result<Vec<PathBuf>> {
+            let mut files = Vec::new();
+            for batch in compaction::batches(&root)? {
+                files.extend(compaction::partition_files(&batch)?);
+            }
+            Ok(files)
+        })
+        .await??;
+        files.extend(self.list_segments().await?);
+        Ok(record_stream(files, self.keys.clone()))
+    }
 
-        let segments = storage.list_segments().await?;
-        let checksum1 = Storage::segment_checksum(&segments[0]).await?;
-        let checksum2 = Storage::segment_checksum(&segments[0]).await?;
+    /// Stream the records of one session, decrypting with this instance's keys
+    #[allow(dead_code)]
+    pub async fn session_records(&self, id: &str) -> Result<impl Stream<Item = Result<Record>> + Unpin> {
+        Ok(record_stream(self.session_segments(id).await?, self.keys.clone()))
+    }
 
-        assert_eq!(checksum1, checksum2);
+    /// Index of a sealed segment, rebuilt from the segment if the sidecar is
+    /// missing or stale
+    #[allow(dead_code)]
+    pub async fn segment_index(&self, path: &Path) -> Result<SegmentIndex> {
+        let path = path.to_path_buf();
+        let stride = self.index_stride;
+        tokio::task::spawn_blocking(move || index::load_or_rebuild(&path, stride)).await?
+    }
 
+    /// Records of `topics` (every topic when empty) received within
+    /// `[t_start, t_end)` nanoseconds: compacted batches first, each in time
+    /// order, then segments, oldest first and in write order within a
+    /// segment. Batch partitions without the topics are not opened; sealed
+    /// segments are skipped or entered mid-file using their indexes; the
+    /// active segment is scanned.
+    #[allow(dead_code)]
+    pub async fn read_range(&self, topics: &[&str], t_start: u128, t_end: u128) -> Result<Vec<Record>> {
+        // Files cannot be swapped out between listing and reading them
+        let swap = self.swap_lock.clone().read_owned().await;
+        // Read the active segment number first: anything from it onwards is unsealed
+        let active = self.inner.lock().await.current_segment;
+        let segments = self.list_segments().await?;
+        let topics: Vec<String> = topics.iter().map(|t| t.to_string()).collect();
+        let stride = self.index_stride;
+        let keys = self.keys.clone();
+        let root = self.root.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let _swap = swap;
+            let mut out = Vec::new();
+            for batch in compaction::batches(&root)? {
+                out.extend(compaction::read_span(&batch, stride, &keys, &topics, t_start, t_end)?);
+            }
+            for path in segments {
+                let sealed = segment_number(&path).is_some_and(|n| n < active);
+                let result = if sealed {
+                    index::load_or_rebuild(&path, stride)
+                        .and_then(|idx| index::read_span(&path, Some(&idx), &keys, &topics, t_start, t_end))
+                } else {
+                    index::read_span(&path, None, &keys, &topics, t_start, t_end)
+                };
+                match result {
+                    Ok(records) => out.extend(records),
+                    Err(e) => {
+                        tracing::warn!("range read of {} failed ({:#}), salvaging", path.display(), e);
+                        let report = salvage::salvage(&std::fs::read(&path)?, &keys, &blob::blob_dir_for(&path));
+                        out.extend(
+                            report
+                                .records
+                                .into_iter()
+                                .filter(|r| index::matches_range(r, &topics, t_start, t_end)),
+                        );
+                    }
+                }
+            }
+            Ok(out)
+        })
+        .await?
+    }
+
+    /// Convert WAL `segments`, in the order given, into one MCAP file at
+    /// `dst`, decrypting with this instance's keys. Topics in `types` get a
+    /// schema naming their message type. Returns the messages written.
+    #[allow(dead_code)]
+    pub async fn convert_to_mcap(&self, segments: &[PathBuf], dst: &Path, types: &HashMap<String, String>) -> Result<u64> {
+        let reader = RecordingReader::new(segments.to_vec()).with_keys(self.keys.clone());
+        let (dst, types, opts) = (dst.to_path_buf(), types.clone(), self.mcap);
+        let count = tokio::task::spawn_blocking(move || mcap::write_file(&dst, reader, &types, opts)).await??;
+        tracing::info!("converted {} segments ({} messages) to MCAP", segments.len(), count);
+        Ok(count)
+    }
+
+    /// Write WAL `segments`, in the order given, into a new rosbag2 bag
+    /// directory `dst` (sqlite3 storage), decrypting with this instance's
+    /// keys. `topics` supplies message types and QoS profiles, e.g. from a
+    /// session manifest.
+    #[allow(dead_code)]
+    pub async fn convert_to_rosbag2(
+        &self,
+        segments: &[PathBuf],
+        dst: &Path,
+        topics: &[TopicManifestEntry],
+    ) -> Result<BagMetadata> {
+        let reader = RecordingReader::new(segments.to_vec()).with_keys(self.keys.clone());
+        let (dst, topics) = (dst.to_path_buf(), topics.to_vec());
+        let metadata = tokio::task::spawn_blocking(move || rosbag2::write_bag(&dst, reader, &topics)).await??;
+        tracing::info!("converted {} segments ({} messages) to rosbag2", segments.len(), metadata.message_count);
+        Ok(metadata)
+    }
+
+    /// Record the rosbag2 bag at `bag` into a new session `id`. Bag topic
+    /// names starting with one of `namespaces` (or a namespace the bag lists
+    /// itself) are split into namespace and topic. The session takes the
+    /// bag's time span, message types and QoS profiles; nothing else may be
+    /// recording into a session meanwhile.
+    #[allow(dead_code)]
+    pub async fn import_rosbag2(&self, bag: &Path, id: &str, namespaces: &[&str]) -> Result<RecordingMetadata> {
+        let bag = bag.to_path_buf();
+        let metadata = {
+            let bag = bag.clone();
+            tokio::task::spawn_blocking(move || rosbag2::read_metadata(&bag)).await??
+        };
+        let mut namespaces: Vec<String> = namespaces.iter().map(|ns| ns.to_string()).collect();
+        namespaces.extend(metadata.namespaces());
+
+        self.start_session(id, None, &["rosbag2"]).await?;
+        let (tx, mut rx) = mpsc::channel(reader::STREAM_BUFFER);
+        let reader = {
+            let (metadata, namespaces) = (metadata.clone(), namespaces.clone());
+            tokio::task::spawn_blocking(move || {
+                rosbag2::read_messages(&bag, &metadata, &namespaces, |record| {
+                    tx.blocking_send(record).map_err(|_| anyhow!("import stopped"))
+                })
+            })
+        };
+        let mut appended = Ok(());
+        while let Some(record) = rx.recv().await {
+            appended = self
+                .append_record_with(&record.topic, &record.namespace, &record.payload, record.info, DurabilityPoint::Queued)
+                .await;
+            if appended.is_err() {
+                break;
+            }
+        }
+        drop(rx);
+        let read = reader.await?;
+        let stopped = self.stop_session().await;
+        appended?;
+        let bag_topics = read?;
+        let mut session = stopped?;
+
+        // The session covers the bag's time span and keeps what the WAL does not
+        let _guard = self.session_lock.lock().await;
+        let start_ms = metadata.starting_time.nanoseconds_since_epoch.max(0) as u128 / 1_000_000;
+        session.start_time_unix_ms = start_ms;
+        session.end_time_unix_ms = Some(start_ms + metadata.duration.nanoseconds as u128 / 1_000_000);
+        for entry in &mut session.topics {
+            let bag_topic = bag_topics
+                .iter()
+                .find(|t| rosbag2::split_topic_name(&t.name, &namespaces).1 == entry.topic);
+            if let Some(bag_topic) = bag_topic {
+                entry.msg_type = bag_topic.msg_type.clone();
+                entry.qos_profiles = Some(bag_topic.offered_qos_profiles.clone()).filter(|q| !q.is_empty());
+            }
+        }
+        session::write_manifest(&session::session_dir(&self.root, id), &session).await?;
+        tracing::info!("imported {} messages from rosbag2 into session {}", metadata.message_count, id);
+        Ok(session)
+    }
+
+    /// Check every segment and the checkpoint. With `repair`, damaged sealed
+    /// segments are rewritten from their salvageable records and those
+    /// without any are moved to `quarantine/`.
+    pub async fn verify(&self, repair: bool) -> Result<VerifyReport> {
+        if repair && self.read_only {
+            return Err(anyhow!("cannot repair a data directory opened read-only"));
+        }
+        let active = self.inner.lock().await.current_segment;
+        let session = self.active_session().await;
+        let swap = self.swap_lock.clone().read_owned().await;
+        let segments = self.list_segments().await?;
+        let root = self.root.clone();
+        let mut report = tokio::task::spawn_blocking(move || {
+            let _swap = swap;
+            let checkpoint = verify::check_checkpoint(&root, active, session.as_deref(), &segments);
+            let segments: Vec<SegmentStatus> = segments
+                .iter()
+                .map(|path| verify::check_segment(path, segment_number(path).is_some_and(|n| n < active)))
+                .collect();
+            let healthy = checkpoint.problems.is_empty()
+                && segments.iter().all(|s| !matches!(s.status, SegmentHealth::Damaged | SegmentHealth::Unrecoverable));
+            VerifyReport { root: root.to_path_buf(), checkpoint, segments, healthy }
+        })
+        .await?;
+        if !repair || report.healthy {
+            return Ok(report);
+        }
+
+        let pinned = self.pinned.clone().lock_owned().await;
+        let swap = self.swap_lock.clone().write_owned().await;
+        let (root, keys) = (self.root.clone(), self.keys.clone());
+        let opts = compaction::PartitionOptions {
+            compression: self.compression,
+            compression_level: self.compression_level,
+            index_stride: self.index_stride,
+        };
+        let (report, repaired) = tokio::task::spawn_blocking(move || -> Result<(VerifyReport, Vec<PathBuf>)> {
+            let _swap = swap;
+            let mut repaired = Vec::new();
+            for status in &mut report.segments {
+                let broken = matches!(status.status, SegmentHealth::Damaged | SegmentHealth::Unrecoverable);
+                // Compacted or deleted since it was checked
+                if !broken || !status.path.exists() {
+                    continue;
+                }
+                verify::repair(&root, status, &keys, opts, &pinned)?;
+                if matches!(status.repair, Some(RepairAction::Rewritten { .. } | RepairAction::Quarantined { .. })) {
+                    repaired.push(status.path.clone());
+                }
+            }
+            Ok((report, repaired))
+        })
+        .await??;
+        // Rewritten segments keep their payloads inline
+        self.release_blobs(&repaired).await?;
+        Ok(report)
+    }
+
+    /// Read every intact record of a possibly damaged segment, skipping over
+    /// corrupted frames instead of failing on the first one
+    #[allow(dead_code)]
+    pub async fn salvage_segment(path: &Path) -> Result<SalvageReport> {
+        Self::salvage_segment_with_keys(path, &Keyring::default()).await
+    }
+
+    /// `salvage_segment` for segments that may be encrypted under one of `keys`
+    #[allow(dead_code)]
+    pub async fn salvage_segment_with_keys(path: &Path, keys: &Keyring) -> Result<SalvageReport> {
+        let buf = tokio::fs::read(path).await?;
+        let keys = keys.clone();
+        let blob_dir = blob::blob_dir_for(path);
+        let report = tokio::task::spawn_blocking(move || salvage::salvage(&buf, &keys, &blob_dir)).await?;
+        if !report.damaged.is_empty() {
+            tracing::warn!(
+                "salvaged {} records from {}, skipped {} damaged bytes in {} ranges",
+                report.records.len(),
+                path.display(),
+                report.damaged_bytes(),
+                report.damaged.len()
+            );
+        }
+        Ok(report)
+    }
+}
+
+fn add_tags(metadata: &mut RecordingMetadata, tags: &[&str]) {
+    for tag in tags {
+        if !metadata.tags.iter().any(|t| t == tag) {
+            metadata.tags.push(tag.to_string());
+        }
+    }
+}
+
+/// Uncompressed, unencrypted WAL config under `path` that tests adjust with
+/// struct-update syntax
+#[cfg(test)]
+pub(crate) fn test_config(path: &Path, wal_segment_size: usize) -> StorageConfig {
+    StorageConfig {
+        path: path.to_path_buf(),
+        backend: crate::config::StorageBackendKind::Wal,
+        mcap: crate::config::McapConfig::default(),
+        wal_segment_size,
+        max_segment_duration_secs: None,
+        max_segment_messages: None,
+        compress: false,
+        compression: CompressionCodec::Zstd,
+        compression_level: 3,
+        durability: DurabilityPolicy::PerRecord,
+        write_queue_capacity: 64,
+        index_stride: 64,
+        retention: RetentionConfig::default(),
+        blackbox: BlackBoxConfig::default(),
+        blobs: crate::config::BlobConfig::default(),
+        compaction: CompactionConfig::default(),
+        encryption: None,
+        enable_aes_gcm: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BlobConfig, CompressionCodec};
+    use frame::{FRAME_HEADER_LEN, MESSAGE_PREFIX_LEN};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn next_rotation(events: &mut broadcast::Receiver<SegmentEvent>) -> Result<SegmentRotated> {
+        match events.try_recv()? {
+            SegmentEvent::Rotated(event) => Ok(event),
+            other => Err(anyhow!("expected a rotation, got {:?}", other)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_storage_append_and_replay() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+
+        storage.append_record("topic1", "robot1", b"hello", now).await?;
+        storage.append_record("topic2", "robot1", b"world", now + 1).await?;
+
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 1);
+
+        let records = Storage::replay_segment(&segments[0]).await?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].topic, "topic1");
+        assert_eq!(records[0].payload, b"hello");
+        assert_eq!(records[1].topic, "topic2");
+        assert_eq!(records[1].payload, b"world");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segment_rotation() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 512);
+
+        let storage = Storage::new(&cfg).await?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+
+        storage.append_record("topic1", "robot1", b"record1", now).await?;
+        let segments_before = storage.list_segments().await?;
+        assert_eq!(segments_before.len(), 1);
+
+        storage.rotate_segment().await?;
+
+        storage.append_record("topic2", "robot1", b"record2", now + 1).await?;
+        let segments_after = storage.list_segments().await?;
+        assert_eq!(segments_after.len(), 2);
+
+        let records0 = Storage::replay_segment(&segments_before[0]).await?;
+        assert_eq!(records0[0].topic, "topic1");
+
+        let records1 = Storage::replay_segment(&segments_after[1]).await?;
+        assert_eq!(records1[0].topic, "topic2");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_recovery() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 512);
+
+        let storage = Storage::new(&cfg).await?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+
+        storage.append_record("topic1", "robot1", b"data1", now).await?;
+        storage.rotate_segment().await?;
+        storage.append_record("topic2", "robot1", b"data2", now + 1).await?;
+
+        let storage2 = Storage::new(&cfg).await?;
+
+        let segments = storage2.list_segments().await?;
+        assert_eq!(segments.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_payload_corruption_detection() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+
+        storage.append_record("topic1", "robot1", b"original_data", now).await?;
+        storage.append_record("topic1", "robot1", b"next_record", now + 1).await?;
+
+        let segments = storage.list_segments().await?;
+        let segment_path = &segments[0];
+
+        let mut data = fs::read(segment_path)?;
+        let second_frame = (data.len() - (FRAME_HEADER_LEN + MESSAGE_PREFIX_LEN + 11)) as u64;
+        let first_frame = second_frame - (FRAME_HEADER_LEN + MESSAGE_PREFIX_LEN + 13) as u64;
+        data[second_frame as usize - 5] ^= 0xFF;
+        fs::write(segment_path, data)?;
+
+        let result = Storage::replay_segment(segment_path).await;
+        assert!(format!("{:#}", result.unwrap_err()).contains("CRC mismatch"));
+
+        let salvaged = Storage::salvage_segment(segment_path).await?;
+        assert_eq!(salvaged.records.len(), 1);
+        assert_eq!(salvaged.records[0].payload, b"next_record");
+        assert_eq!(salvaged.damaged, vec![first_frame..second_frame]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segment_checksum() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+
+        storage.append_record("topic1", "robot1", b"test_data", now).await?;
+        // Only sealed segments have a footer to answer from
+        assert_eq!(Storage::segment_checksum(&storage.active_segment_path().await).await?, None);
+        storage.rotate_segment().await?;
+
+        let segments = storage.list_segments().await?;
+        let checksum1 = Storage::segment_checksum(&segments[0]).await?;
+        let checksum2 = Storage::segment_checksum(&segments[0]).await?;
+
+        assert!(checksum1.is_some());
+        assert_eq!(checksum1, checksum2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_payload_roundtrip() -> Result<()> {
+        for codec in [CompressionCodec::Lz4, CompressionCodec::Zstd] {
+            let tmpdir = TempDir::new()?;
+            let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+            cfg.compress = true;
+            cfg.compression = codec;
+
+            let storage = Storage::new(&cfg).await?;
+            let scan: Vec<u8> = (0..4096u32).map(|i| (i % 7) as u8).collect();
+            storage.append_record("/sensor/lidar", "robot1", &scan, 1).await?;
+            storage.append_record("/tf", "robot1", b"tiny", 2).await?;
+
+            let segments = storage.list_segments().await?;
+            let on_disk = fs::metadata(&segments[0])?.len();
+            assert!(on_disk < scan.len() as u64, "{:?} segment not compressed", codec);
+
+            let records = Storage::replay_segment(&segments[0]).await?;
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].payload, scan);
+            assert_eq!(records[1].payload, b"tiny");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_legacy_uncompressed_frame() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let payload = b"legacy payload";
+        let meta = serde_json::json!({
+            "magic": frame::LEGACY_FRAME_HEADER,
+            "timestamp": 42u128,
+            "topic": "/odometry",
+            "namespace": "robot2",
+            "payload_len": payload.len(),
+            "payload_crc32": crc32fast::hash(payload),
+        })
+        .to_string();
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&frame::LEGACY_FRAME_HEADER.to_le_bytes());
+        bytes.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(meta.as_bytes());
+        bytes.extend_from_slice(payload);
+        let path = tmpdir.path().join("segment-0.log");
+        fs::write(&path, bytes)?;
+
+        let records = Storage::replay_segment(&path).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].topic, "/odometry");
+        // Legacy frames stored milliseconds
+        assert_eq!(records[0].info.receive_ns, 42_000_000);
+        assert_eq!(records[0].payload, payload);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_binary_frames_intern_names_once() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        let imu = [0u8; 50];
+        storage.append_record("/imu", "robot1", &imu, 1).await?;
+        let segments = storage.list_segments().await?;
+        let first_len = fs::metadata(&segments[0])?.len();
+
+        for ts in 2..=10 {
+            storage.append_record("/imu", "robot1", &imu, ts).await?;
+        }
+        let total_len = fs::metadata(&segments[0])?.len();
+        let per_message = (total_len - first_len) / 9;
+        assert_eq!(per_message, (FRAME_HEADER_LEN + MESSAGE_PREFIX_LEN + imu.len()) as u64);
+
+        let records = Storage::replay_segment(&segments[0]).await?;
+        assert_eq!(records.len(), 10);
+        assert!(records.iter().all(|r| r.topic == "/imu" && r.namespace == "robot1"));
+        assert_eq!(records[9].info.receive_ns, 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_size_rotation_uses_config() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 512);
+
+        let storage = Storage::new(&cfg).await?;
+        let mut rotations = storage.subscribe_segment_events();
+        let payload = [7u8; 150];
+        for ts in 0..5 {
+            storage.append_record("/camera", "robot1", &payload, ts).await?;
+        }
+
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 3);
+        for segment in &segments[..2] {
+            // The limit applies to record frames; the footer is appended on seal
+            let footer = Storage::segment_footer(segment).await?.expect("sealed segment has a footer");
+            assert!(footer.data_bytes <= 512);
+        }
+
+        let event = next_rotation(&mut rotations)?;
+        assert_eq!(event.sealed_segment, 0);
+        assert_eq!(event.reason, RotationReason::Size);
+        assert_eq!(event.message_count, 2);
+        assert_eq!(event.new_path, segments[1]);
+
+        let total: usize = futures::future::try_join_all(segments.iter().map(|s| Storage::replay_segment(s)))
+            .await?
+            .iter()
+            .map(|r| r.len())
+            .sum();
+        assert_eq!(total, 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_count_and_duration_rotation() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.max_segment_messages = Some(3);
+        cfg.max_segment_duration_secs = Some(1);
+
+        let storage = Storage::new(&cfg).await?;
+        let mut rotations = storage.subscribe_segment_events();
+        for ts in 0..4 {
+            storage.append_record("/tf", "robot1", b"tf", ts).await?;
+        }
+        assert_eq!(next_rotation(&mut rotations)?.reason, RotationReason::MessageCount);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        storage.append_record("/tf", "robot1", b"tf", 4).await?;
+        let event = next_rotation(&mut rotations)?;
+        assert_eq!(event.reason, RotationReason::Duration);
+        assert_eq!(event.sealed_segment, 1);
+        assert_eq!(event.message_count, 1);
+
+        assert_eq!(storage.list_segments().await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_rolls_back_to_last_good_frame() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"first", 1).await?;
+        let path = storage.active_segment_path().await;
+        let good_len = fs::metadata(&path)?.len();
+
+        // A new topic, so the lost batch also declared a dictionary entry
+        storage.fail_next_write().await;
+        let err = storage.append_record("/gps", "robot1", b"lost", 2).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("segment write failed"), "{:#}", err);
+        assert_eq!(fs::metadata(&path)?.len(), good_len);
+        assert_eq!(storage.lost_messages(), 1);
+
+        storage.append_record("/gps", "robot1", b"second", 3).await?;
+        storage.append_record("/tf", "robot1", b"third", 4).await?;
+        let records = Storage::replay_segment(&path).await?;
+        let payloads: Vec<_> = records.iter().map(|r| (r.topic.as_str(), r.payload.as_slice())).collect();
+        assert_eq!(payloads, vec![("/tf", &b"first"[..]), ("/gps", b"second"), ("/tf", b"third")]);
+
+        storage.rotate_segment().await?;
+        let footer = Storage::segment_footer(&path).await?.expect("footer written on seal");
+        assert_eq!(footer.message_count, 3);
+        assert_eq!((footer.min_timestamp, footer.max_timestamp), (Some(1), Some(4)));
+        let data = fs::read(&path)?;
+        assert_eq!(footer.sha256, format!("{:x}", Sha256::digest(&data[..footer.data_bytes as usize])));
+        let index = storage.segment_index(&path).await?;
+        assert_eq!(index.messages.map(|m| m.count), Some(3));
+        assert_eq!(index.size_bytes, data.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_overlong_names_are_rejected() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&test_config(tmpdir.path(), 1024 * 1024)).await?;
+        let long = "x".repeat(u16::MAX as usize + 1);
+        assert!(storage.append_record(&long, "robot1", b"lost", 1).await.is_err());
+        assert!(storage.append_record("/tf", &long, b"lost", 2).await.is_err());
+        storage.append_record(&long[1..], "robot1", b"kept", 3).await?;
+
+        let records = Storage::replay_segment(&storage.active_segment_path().await).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].topic.len(), u16::MAX as usize);
+        assert_eq!(storage.lost_messages(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_rotation_leaves_segment_unsealed() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"first", 1).await?;
+        storage.append_record("/tf", "robot1", b"second", 2).await?;
+        let path = storage.active_segment_path().await;
+        let data_len = fs::metadata(&path)?.len();
+
+        // A directory in the way makes creating the next segment fail
+        let next = tmpdir.path().join("segment-1.log");
+        fs::create_dir(&next)?;
+        assert!(storage.rotate_segment().await.is_err());
+        assert_eq!(fs::metadata(&path)?.len(), data_len);
+        assert!(Storage::segment_footer(&path).await?.is_none());
+        assert_eq!(storage.active_segment_path().await, path);
+
+        fs::remove_dir(&next)?;
+        storage.append_record("/tf", "robot1", b"third", 3).await?;
+        let data_len = fs::metadata(&path)?.len();
+        assert_eq!(storage.rotate_segment().await?, next);
+        let footer = Storage::segment_footer(&path).await?.expect("footer written on seal");
+        assert_eq!(footer.message_count, 3);
+        let data = fs::read(&path)?;
+        assert_eq!(footer.data_bytes, data_len);
+        assert_eq!(footer.sha256, format!("{:x}", Sha256::digest(&data[..footer.data_bytes as usize])));
+        assert_eq!(Storage::replay_segment(&path).await?.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_rotation_counts_queued_record_as_lost() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.max_segment_messages = Some(1);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"first", 1).await?;
+
+        // The footer write of the rotation this append triggers fails
+        storage.fail_next_write().await;
+        storage
+            .append_record_with("/tf", "robot1", b"lost", MessageInfo::received(2), DurabilityPoint::Queued)
+            .await?;
+        storage.sync().await?;
+        assert_eq!(storage.lost_messages(), 1);
+
+        storage.append_record("/tf", "robot1", b"second", 3).await?;
+        assert_eq!(storage.lost_messages(), 1);
+        assert_eq!(storage.list_segments().await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_resumes_active_segment() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("topic1", "robot1", b"sealed", 1).await?;
+        storage.rotate_segment().await?;
+        drop(storage);
+
+        let storage2 = Storage::new(&cfg).await?;
+        storage2.append_record("topic2", "robot1", b"active", 2).await?;
+
+        let segments = storage2.list_segments().await?;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(Storage::replay_segment(&segments[0]).await?.len(), 1);
+        assert_eq!(Storage::replay_segment(&segments[1]).await?[0].payload, b"active");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_group_commit_concurrent_appenders() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 16 * 1024 * 1024);
+        let storage = Storage::new(&cfg).await?;
+
+        let mut handles = Vec::new();
+        for producer in 0..8u128 {
+            let storage = storage.clone();
+            handles.push(tokio::spawn(async move {
+                for seq in 0..50u128 {
+                    let point = if seq % 2 == 0 { DurabilityPoint::Queued } else { DurabilityPoint::Synced };
+                    let topic = format!("/producer{}", producer);
+                    storage.append_record_with(&topic, "robot1", &seq.to_le_bytes(), MessageInfo::received(seq), point).await?;
+                }
+                anyhow::Ok(())
+            }));
+        }
+        for h in handles {
+            h.await??;
+        }
+        storage.sync().await?;
+
+        let segments = storage.list_segments().await?;
+        let records = Storage::replay_segment(&segments[0]).await?;
+        assert_eq!(records.len(), 400);
+        for producer in 0..8 {
+            let topic = format!("/producer{}", producer);
+            let seqs: Vec<u128> = records.iter().filter(|r| r.topic == topic).map(|r| r.info.receive_ns).collect();
+            assert_eq!(seqs, (0..50).collect::<Vec<_>>());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_periodic_and_buffered_durability() -> Result<()> {
+        for policy in [
+            DurabilityPolicy::Periodic { interval_ms: 20, max_bytes: 1024 * 1024 },
+            DurabilityPolicy::OsBuffered,
+        ] {
+            let tmpdir = TempDir::new()?;
+            let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+            cfg.durability = policy;
+            let storage = Storage::new(&cfg).await?;
+
+            // Default point under batched policies only waits for the OS write
+            storage.append_record("/imu", "robot1", b"written", 1).await?;
+            let segments = storage.list_segments().await?;
+            assert_eq!(Storage::replay_segment(&segments[0]).await?.len(), 1);
+
+            // Synced waiters are released by the periodic fsync or an explicit request
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                storage.append_record_with("/imu", "robot1", b"synced", MessageInfo::received(2), DurabilityPoint::Synced),
+            )
+            .await
+            .map_err(|_| anyhow!("{:?}: synced append never acknowledged", policy))??;
+            storage.sync().await?;
+            assert_eq!(Storage::replay_segment(&segments[0]).await?.len(), 2);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_tail_truncated_on_startup() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/imu", "robot1", b"sample1", 1).await?;
+        storage.append_record("/imu", "robot1", b"sample2", 2).await?;
+        drop(storage);
+
+        let path = tmpdir.path().join("segment-0.log");
+        let good_len = fs::metadata(&path)?.len();
+        let mut data = fs::read(&path)?;
+        // Half of a copy of the last frame, as left by a power cut mid-write
+        let last_frame = data[data.len() - 43..].to_vec();
+        data.extend_from_slice(&last_frame[..20]);
+        fs::write(&path, &data)?;
+
+        let storage = Storage::new(&cfg).await?;
+        let report = storage.recovery_report().expect("torn tail reported");
+        assert_eq!(report.valid_bytes, good_len);
+        assert_eq!(report.messages, 2);
+        assert_eq!(report.discarded_bytes, 20);
+        assert!(!report.damaged_mid_segment);
+        assert_eq!(fs::read(report.quarantined_to.as_ref().unwrap())?, &last_frame[..20]);
+        assert_eq!(fs::metadata(&path)?.len(), good_len);
+
+        storage.append_record("/imu", "robot1", b"sample3", 3).await?;
+        let records = Storage::replay_segment(&path).await?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].payload, b"sample3");
+        // Interned ids were restored, so no second dictionary frame was written
+        assert_eq!(fs::metadata(&path)?.len(), good_len + (FRAME_HEADER_LEN + MESSAGE_PREFIX_LEN + 7) as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recovery_restores_segment_size() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 500);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/camera", "robot1", &[1u8; 150], 1).await?;
+        drop(storage);
+        let path = tmpdir.path().join("segment-0.log");
+        let mut data = fs::read(&path)?;
+        data.extend_from_slice(&[0u8; 512]);
+        fs::write(&path, &data)?;
+
+        let storage = Storage::new(&cfg).await?;
+        assert_eq!(storage.recovery_report().unwrap().discarded_bytes, 512);
+        storage.append_record("/camera", "robot1", &[2u8; 150], 2).await?;
+        storage.append_record("/camera", "robot1", &[3u8; 150], 3).await?;
+
+        // Two frames fit in 500 bytes, the third must start a new segment
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(Storage::replay_segment(&segments[0]).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mid_segment_damage_seals_instead_of_truncating() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"first", 1).await?;
+        storage.append_record("/tf", "robot1", b"second", 2).await?;
+        drop(storage);
+
+        let path = tmpdir.path().join("segment-0.log");
+        let mut data = fs::read(&path)?;
+        let original_len = data.len();
+        // Flip a payload byte of the first message frame
+        let first_payload = data.len() - 2 * (FRAME_HEADER_LEN + MESSAGE_PREFIX_LEN) - 6 - 1;
+        data[first_payload] ^= 0xFF;
+        fs::write(&path, &data)?;
+
+        let storage = Storage::new(&cfg).await?;
+        assert!(storage.recovery_report().unwrap().damaged_mid_segment);
+        assert_eq!(fs::metadata(&path)?.len(), original_len as u64);
+
+        storage.append_record("/tf", "robot1", b"third", 3).await?;
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(Storage::replay_segment(&segments[1]).await?[0].payload, b"third");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_salvage_skips_garbage_between_frames() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/odometry", "robot1", b"before", 1).await?;
+        let path = storage.active_segment_path().await;
+        let split = fs::metadata(&path)?.len() as usize;
+        storage.append_record("/odometry", "robot1", b"after", 2).await?;
+        drop(storage);
+
+        // Splice garbage, including a fake magic, between the two frames
+        let mut data = fs::read(&path)?;
+        let mut garbage = frame::RECORD_FRAME_HEADER.to_le_bytes().to_vec();
+        garbage.extend_from_slice(&[0xAB; 60]);
+        data.splice(split..split, garbage);
+        // And a truncated frame at the end
+        data.extend_from_slice(&frame::RECORD_FRAME_HEADER.to_le_bytes());
+        data.extend_from_slice(&[1, 0]);
+        fs::write(&path, &data)?;
+
+        let salvaged = Storage::salvage_segment(&path).await?;
+        let payloads: Vec<&[u8]> = salvaged.records.iter().map(|r| r.payload.as_slice()).collect();
+        assert_eq!(payloads, vec![b"before".as_slice(), b"after".as_slice()]);
+        let end = data.len() as u64;
+        assert_eq!(salvaged.damaged, vec![split as u64..split as u64 + 64, end - 6..end]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streaming_reader_crosses_segments() -> Result<()> {
+        use futures::StreamExt;
+
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.max_segment_messages = Some(4);
+
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..10u128 {
+            let topic = if ts % 2 == 0 { "/tf" } else { "/odometry" };
+            storage.append_record(topic, "robot1", &ts.to_le_bytes(), ts).await?;
+        }
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 3);
+
+        let from_iter: Vec<Record> = RecordingReader::new(segments.clone()).collect::<Result<_>>()?;
+        assert_eq!(from_iter.len(), 10);
+        assert_eq!(from_iter.iter().map(|r| r.info.receive_ns).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        assert_eq!(from_iter[9].topic, "/odometry");
+
+        let from_stream: Vec<Record> = storage.stream_records().await?.map(|r| r.unwrap()).collect().await;
+        assert_eq!(from_stream, from_iter);
+
+        // Dropping a stream early must not wedge the decoding thread
+        let first = storage.stream_records().await?.next().await.unwrap()?;
+        assert_eq!(first.info.receive_ns, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sealed_segments_indexed_for_range_reads() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.max_segment_messages = Some(20);
+        cfg.index_stride = 4;
+
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..50u128 {
+            let topic = if ts % 2 == 0 { "/tf" } else { "/odometry" };
+            storage.append_record(topic, "robot1", &ts.to_le_bytes(), ts).await?;
+        }
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 3);
+        assert!(index_path(&segments[0]).exists());
+        assert!(index_path(&segments[1]).exists());
+        assert!(!index_path(&segments[2]).exists());
+
+        let index = storage.segment_index(&segments[1]).await?;
+        assert_eq!(index.segment, 1);
+        assert_eq!(index.size_bytes, fs::metadata(&segments[1])?.len());
+        let messages = index.messages.as_ref().unwrap();
+        assert_eq!((messages.count, messages.min_timestamp, messages.max_timestamp), (20, 20, 39));
+        assert_eq!(messages.entries.len(), 5);
+        let odometry = &index.topics["/odometry"];
+        assert_eq!((odometry.count, odometry.min_timestamp, odometry.max_timestamp), (10, 21, 39));
+        assert_eq!(odometry.entries.len(), 3);
+
+        let odometry = storage.read_range(&["/odometry"], 15, 27).await?;
+        assert_eq!(odometry.iter().map(|r| r.info.receive_ns).collect::<Vec<_>>(), vec![15, 17, 19, 21, 23, 25]);
+        assert!(odometry.iter().all(|r| r.payload == r.info.receive_ns.to_le_bytes()));
+
+        // The active segment has no index yet and is scanned
+        let tail = storage.read_range(&[], 38, u128::MAX).await?;
+        assert_eq!(tail.iter().map(|r| r.info.receive_ns).collect::<Vec<_>>(), (38..50).collect::<Vec<_>>());
+        assert!(storage.read_range(&["/scan"], 0, u128::MAX).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_or_stale_index_rebuilt() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.index_stride = 3;
+
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..30u128 {
+            let topic = ["/tf", "/odometry", "/imu"][ts as usize % 3];
+            storage.append_record(topic, "robot1", b"sample", ts).await?;
+        }
+        let sealed = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        let written = storage.segment_index(&sealed).await?;
+
+        // A rebuilt index matches the one kept by the writer
+        fs::remove_file(index_path(&sealed))?;
+        let range = storage.read_range(&["/imu", "/tf"], 10, 20).await?;
+        assert_eq!(range.iter().map(|r| r.info.receive_ns).collect::<Vec<_>>(), vec![11, 12, 14, 15, 17, 18]);
+        assert!(index_path(&sealed).exists());
+        assert_eq!(storage.segment_index(&sealed).await?, written);
+
+        fs::write(index_path(&sealed), b"not json")?;
+        assert_eq!(storage.segment_index(&sealed).await?, written);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_keeps_indexing_active_segment() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"before", 1).await?;
+        drop(storage);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"after", 2).await?;
+        let sealed = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+
+        let sidecar: SegmentIndex = serde_json::from_slice(&fs::read(index_path(&sealed))?)?;
+        assert_eq!(sidecar.topics["/tf"].count, 2);
+        assert_eq!(sidecar.topics["/tf"].entries[0].offset, 0);
+        assert_eq!(sidecar.dictionary.topics[&0], "/tf");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sealed_segment_footer_matches_contents() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"first", 5).await?;
+        drop(storage);
+
+        // The running hash is restored from the recovered segment on restart
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/odometry", "robot1", b"second", 9).await?;
+        storage.append_record("/tf", "robot1", b"third", 7).await?;
+        let sealed = storage.active_segment_path().await;
+        assert!(Storage::segment_footer(&sealed).await?.is_none());
+        storage.rotate_segment().await?;
+
+        let footer = Storage::segment_footer(&sealed).await?.expect("footer written on seal");
+        let data = fs::read(&sealed)?;
+        assert!(footer.data_bytes < data.len() as u64);
+        assert_eq!(footer.sha256, format!("{:x}", Sha256::digest(&data[..footer.data_bytes as usize])));
+        assert_eq!(footer.message_count, 3);
+        assert_eq!((footer.min_timestamp, footer.max_timestamp), (Some(5), Some(9)));
+        assert_eq!(footer.topics, vec!["/odometry".to_string(), "/tf".to_string()]);
+        assert_eq!(Storage::segment_checksum(&sealed).await?, Some(footer.sha256));
+
+        // Readers skip the footer and the index covers the full file
+        assert_eq!(Storage::replay_segment(&sealed).await?.len(), 3);
+        assert_eq!(storage.segment_index(&sealed).await?.size_bytes, data.len() as u64);
+        assert!(Storage::segment_footer(&storage.active_segment_path().await).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_1_footer_read_as_nanoseconds() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"first", 1_700_000_000_123_000_000).await?;
+        let path = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        let footer = Storage::segment_footer(&path).await?.expect("footer written on seal");
+        assert_eq!(footer.version, footer::FOOTER_VERSION);
+
+        // Rewrite it the way segments sealed before nanosecond timestamps were
+        let mut summary = serde_json::to_value(&footer)?;
+        summary.as_object_mut().unwrap().remove("version");
+        summary["min_timestamp"] = 1_700_000_000_123u64.into();
+        summary["max_timestamp"] = 1_700_000_000_123u64.into();
+        let mut data = fs::read(&path)?;
+        data.truncate(footer.data_bytes as usize);
+        data.extend(frame::encode_footer(&serde_json::to_vec(&summary)?));
+        fs::write(&path, &data)?;
+
+        let legacy = Storage::segment_footer(&path).await?.expect("version 1 footer still read");
+        assert_eq!(legacy, footer);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_footer_marks_segment_sealed_after_crash() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"sealed", 1).await?;
+        storage.rotate_segment().await?;
+        drop(storage);
+
+        // Crash after the footer was written but before the next segment existed
+        fs::remove_file(tmpdir.path().join("segment-1.log"))?;
+        fs::remove_file(tmpdir.path().join(".checkpoint"))?;
+        let sealed = tmpdir.path().join("segment-0.log");
+        let sealed_len = fs::metadata(&sealed)?.len();
+
+        let storage = Storage::new(&cfg).await?;
+        assert!(storage.recovery_report().is_none());
+        storage.append_record("/tf", "robot1", b"active", 2).await?;
+        assert_eq!(storage.active_segment_path().await, tmpdir.path().join("segment-1.log"));
+        assert_eq!(fs::metadata(&sealed)?.len(), sealed_len);
+        assert!(Storage::segment_footer(&sealed).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"loose", 1).await?;
+
+        let started = storage.start_session("run-1", Some("robot1"), &["field-test"]).await?;
+        assert_eq!(started.robot.as_deref(), Some("robot1"));
+        assert_eq!(storage.active_session().await.as_deref(), Some("run-1"));
+        assert!(storage.active_segment_path().await.starts_with(tmpdir.path().join("sessions/run-1")));
+        assert!(storage.start_session("run-2", None, &[]).await.is_err());
+        for ts in 10..13 {
+            storage.append_record("/odometry", "robot1", b"pose", ts * 1_000_000).await?;
+        }
+        storage.append_record("/tf", "robot1", b"tf", 13).await?;
+
+        let stopped = storage.stop_session().await?;
+        assert!(stopped.end_time_unix_ms.is_some());
+        let counts: Vec<(&str, u64)> = stopped.topics.iter().map(|t| (t.topic.as_str(), t.message_count)).collect();
+        assert_eq!(counts, vec![("/odometry", 3), ("/tf", 1)]);
+        assert_eq!(stopped.topics[0].sample_rate_hz, Some(1000.0));
+        assert!(storage.active_session().await.is_none());
+        assert!(storage.stop_session().await.is_err());
+
+        let session_segments = storage.session_segments("run-1").await?;
+        assert_eq!(session_segments.len(), 1);
+        assert_eq!(Storage::replay_segment(&session_segments[0]).await?.len(), 4);
+        // Segment numbers stay global across the data directory and sessions
+        let numbers: Vec<u64> = storage.list_segments().await?.iter().filter_map(|p| segment_number(p)).collect();
+        assert_eq!(numbers, vec![0, 1, 2]);
+
+        let tagged = storage.tag_session("run-1", &["field-test", "rain"]).await?;
+        assert_eq!(tagged.tags, vec!["field-test".to_string(), "rain".to_string()]);
+        assert_eq!(storage.list_sessions().await?[0].tags, tagged.tags);
+
+        storage.start_session("run-2", None, &[]).await?;
+        assert!(storage.delete_session("run-2").await.is_err());
+        storage.stop_session().await?;
+        storage.delete_session("run-1").await?;
+        assert!(storage.session("run-1").await.is_err());
+        let ids: Vec<String> = storage.list_sessions().await?.into_iter().map(|m| m.recording_id).collect();
+        assert_eq!(ids, vec!["run-2".to_string()]);
+        assert!(!storage.list_segments().await?.contains(&session_segments[0]));
+
+        assert!(storage.start_session("../escape", None, &[]).await.is_err());
+        assert!(storage.start_session("run-2", None, &[]).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_resumes_after_restart() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+        let storage = Storage::new(&cfg).await?;
+
+        // An empty active segment moves into the session instead of being sealed
+        storage.start_session("overnight", None, &[]).await?;
+        assert!(!tmpdir.path().join("segment-0.log").exists());
+        storage.append_record("/imu", "robot1", b"before", 1).await?;
+        drop(storage);
+
+        let storage = Storage::new(&cfg).await?;
+        assert_eq!(storage.active_session().await.as_deref(), Some("overnight"));
+        storage.append_record("/imu", "robot1", b"after", 2).await?;
+        let stopped = storage.stop_session().await?;
+        assert_eq!(stopped.topics[0].message_count, 2);
+
+        let segments = storage.session_segments("overnight").await?;
+        assert_eq!(segments, vec![tmpdir.path().join("sessions/overnight/segment-0.log")]);
+        storage.append_record("/imu", "robot1", b"loose", 3).await?;
+        assert_eq!(storage.active_segment_path().await, tmpdir.path().join("segment-1.log"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_session_closed_on_startup() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = test_config(tmpdir.path(), 1024 * 1024);
+        let storage = Storage::new(&cfg).await?;
+        storage.start_session("crashed", None, &[]).await?;
+        storage.append_record("/tf", "robot1", b"tf", 1).await?;
+        storage.stop_session().await?;
+        drop(storage);
+
+        // Crash between sealing the last segment and writing the manifest
+        let manifest_path = tmpdir.path().join("sessions/crashed/session.json");
+        let mut manifest: RecordingMetadata = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+        manifest.end_time_unix_ms = None;
+        manifest.topics.clear();
+        fs::write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+
+        let storage = Storage::new(&cfg).await?;
+        assert!(storage.active_session().await.is_none());
+        let closed = storage.session("crashed").await?;
+        let footer = Storage::segment_footer(&storage.session_segments("crashed").await?[0]).await?.unwrap();
+        assert_eq!(closed.end_time_unix_ms, Some(footer.sealed_at));
+        assert_eq!(closed.topics.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retention_deletes_oldest_unprotected_segments() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.max_segment_messages = Some(1);
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..5 {
+            storage.append_record("/tf", "robot1", b"tf", ts).await?;
+        }
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 5);
+        let segment_size = fs::metadata(&segments[0])?.len() + fs::metadata(index_path(&segments[0]))?.len();
+
+        // Nothing configured, nothing deleted
+        assert!(storage.enforce_retention().await?.deleted.is_empty());
+
+        let mut cfg = cfg.clone();
+        cfg.retention.max_total_bytes = Some(fs::metadata(&segments[4])?.len() + 2 * segment_size);
+        drop(storage);
+        let storage = Storage::new(&cfg).await?;
+        storage.pin_segment(&segments[0]).await;
+        let report = storage.enforce_retention().await?;
+        assert_eq!(report.deleted, vec![segments[1].clone(), segments[2].clone()]);
+        assert_eq!(report.freed_bytes, 2 * segment_size);
+        assert!(!report.unsatisfied);
+        assert!(!index_path(&segments[1]).exists());
+
+        // The active segment and pinned segments survive an impossible quota
+        cfg.retention.max_total_bytes = Some(1);
+        drop(storage);
+        let storage = Storage::new(&cfg).await?;
+        storage.pin_segment(&segments[0]).await;
+        let report = storage.enforce_retention().await?;
+        assert_eq!(report.deleted, vec![segments[3].clone()]);
+        assert!(report.unsatisfied);
+        assert_eq!(storage.list_segments().await?, vec![segments[0].clone(), segments[4].clone()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retention_requires_upload_before_deleting() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.max_segment_messages = Some(1);
+        cfg.retention.max_age_secs = Some(0);
+        cfg.retention.min_free_bytes = Some(u64::MAX);
+        cfg.retention.require_upload = true;
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..4 {
+            storage.append_record("/tf", "robot1", b"tf", ts).await?;
+        }
+        let segments = storage.list_segments().await?;
+        storage.mark_uploaded(&segments[1]).await?;
+        assert!(Storage::is_uploaded(&segments[1]).await);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let report = storage.enforce_retention().await?;
+        assert_eq!(report.deleted, vec![segments[1].clone()]);
+        assert!(!uploaded_marker(&segments[1]).exists());
+        assert!(report.unsatisfied);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_default_config_without_sync_still_deletes() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = crate::config::AppConfig::load_default()?.storage;
+        cfg.path = tmpdir.path().to_path_buf();
+        cfg.retention.max_age_secs = Some(0);
+        // What startup does when there are no S3 credentials
+        assert!(cfg.waive_upload_requirement());
+        assert!(!cfg.retention.require_upload && !cfg.compaction.require_upload);
+        assert!(!cfg.waive_upload_requirement());
+
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"tf", 1).await?;
+        let sealed = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let report = storage.enforce_retention().await?;
+        assert_eq!(report.deleted, vec![sealed.clone()]);
+        assert!(!sealed.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blackbox_ring_trims_segments_outside_window() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.blackbox = BlackBoxConfig { enabled: true, window_secs: 1, ..Default::default() };
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..2 {
+            storage.append_record("/tf", "robot1", b"old", ts).await?;
+            storage.rotate_segment().await?;
+        }
+        let old = storage.list_segments().await?;
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        storage.append_record("/tf", "robot1", b"recent", 2).await?;
+        let recent = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        storage.pin_segment(&old[0]).await;
+
+        let deleted = storage.trim_ring_buffer().await?;
+        assert_eq!(deleted, vec![old[1].clone()]);
+        assert!(old[0].exists() && recent.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blackbox_trigger_freezes_window_into_session() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        let storage = Storage::new(&cfg).await?;
+        assert!(storage.trigger_capture(None, "test").await.is_err());
+        drop(storage);
+
+        cfg.blackbox = BlackBoxConfig { enabled: true, window_secs: 1, post_trigger_secs: 1, ..Default::default() };
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"stale", 0).await?;
+        let stale = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        storage.append_record("/tf", "robot1", b"pre", 1).await?;
+
+        let metadata = storage.trigger_capture(Some("incident-1"), "test").await?;
+        let capture = metadata.capture.as_ref().unwrap();
+        assert_eq!(capture.source, "test");
+        assert_eq!(capture.stop_at_unix_ms, capture.triggered_at_unix_ms + 1000);
+        assert_eq!(storage.active_session().await.as_deref(), Some("incident-1"));
+        assert!(stale.exists(), "segments older than the window stay in the ring");
+        assert!(storage.trigger_capture(None, "test").await.is_err());
+        storage.append_record("/tf", "robot1", b"post", 2).await?;
+
+        // The post-trigger timer returns the writer to the ring
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(storage.active_session().await, None);
+        let session = storage.session("incident-1").await?;
+        assert!(session.end_time_unix_ms.is_some());
+        assert_eq!(session.topics[0].message_count, 2);
+
+        let mut payloads = Vec::new();
+        for path in storage.session_segments("incident-1").await? {
+            payloads.extend(Storage::replay_segment(&path).await?.into_iter().map(|r| r.payload));
+        }
+        assert_eq!(payloads, vec![b"pre".to_vec(), b"post".to_vec()]);
+
+        Ok(())
+    }
+
+    fn test_keyring() -> Keyring {
+        // Fixed key so a "restarted" Storage can unwrap what an earlier one wrote
+        let mut creds = crate::security::StoredCredentials::default();
+        let key = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, [9u8; 32]);
+        creds.master_keys.insert("site-1".to_string(), key);
+        Keyring::from_credentials(&creds).unwrap()
+    }
+
+    fn encrypted_config(path: &Path) -> StorageConfig {
+        let mut cfg = test_config(path, 1024 * 1024);
+        cfg.encryption = Some("site-1".to_string());
+        cfg.enable_aes_gcm = true;
+        cfg.index_stride = 1;
+        cfg
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_segments_roundtrip() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = encrypted_config(tmpdir.path());
+        cfg.compress = true;
+        let payload = b"lidar sweep ".repeat(16);
+        {
+            let storage = Storage::with_keyring(&cfg, test_keyring()).await?;
+            for ts in 0..4 {
+                storage.append_record("/scan", "robot1", &payload, ts).await?;
+            }
+            storage.rotate_segment().await?;
+            storage.append_record("/scan", "robot1", b"active", 4).await?;
+        }
+        let segments = Storage::scan_segments(tmpdir.path()).await?;
+        let sealed = std::fs::read(&segments[0])?;
+        assert!(!sealed.windows(5).any(|w| w == b"lidar"));
+        let footer = Storage::segment_footer(&segments[0]).await?.expect("footer written on seal");
+        let expected = format!("{:x}", Sha256::digest(&sealed[..footer.data_bytes as usize]));
+        assert_eq!(Storage::segment_checksum(&segments[0]).await?, Some(expected));
+
+        // Unreadable without the key, transparent with it
+        assert!(Storage::replay_segment(&segments[0]).await.is_err());
+        let records = Storage::replay_segment_with_keys(&segments[0], &test_keyring()).await?;
+        assert_eq!(records.len(), 4);
+        assert!(records.iter().all(|r| r.payload == payload));
+
+        // After a restart the active segment keeps its data key
+        let storage = Storage::with_keyring(&cfg, test_keyring()).await?;
+        storage.append_record("/scan", "robot1", b"resumed", 5).await?;
+        assert_eq!(storage.list_segments().await?.len(), 2);
+        let records = storage.read_range(&["/scan"], 2, 6).await?;
+        let payloads: Vec<&[u8]> = records.iter().map(|r| r.payload.as_slice()).collect();
+        assert_eq!(payloads, vec![payload.as_slice(), payload.as_slice(), b"active", b"resumed"]);
+
+        // Turning encryption off starts a new plaintext segment
+        drop(storage);
+        let storage = Storage::with_keyring(&test_config(tmpdir.path(), 1024 * 1024), test_keyring()).await?;
+        storage.append_record("/scan", "robot1", b"plain", 6).await?;
+        let segments = storage.list_segments().await?;
+        assert_eq!(segments.len(), 3);
+        assert_eq!(Storage::replay_segment(&segments[2]).await?[0].payload, b"plain");
+        assert_eq!(storage.read_range(&[], 0, 10).await?.len(), 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_frame_headers_are_authenticated() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::with_keyring(&encrypted_config(tmpdir.path()), test_keyring()).await?;
+        storage.append_record("/tf", "robot1", b"transform", 42).await?;
+        let path = storage.active_segment_path().await;
+        drop(storage);
+
+        // Rewrite the message timestamp and fix up the CRC: only the AEAD tag catches it
+        let mut bytes = std::fs::read(&path)?;
+        let key_len = FRAME_HEADER_LEN + u32::from_le_bytes(bytes[8..12].try_into()?) as usize;
+        let dict_len = FRAME_HEADER_LEN + u32::from_le_bytes(bytes[key_len + 8..key_len + 12].try_into()?) as usize;
+        let msg = key_len + dict_len;
+        let body_len = u32::from_le_bytes(bytes[msg + 8..msg + 12].try_into()?) as usize;
+        bytes[msg + FRAME_HEADER_LEN + 8] ^= 1;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[msg + 4..msg + 12]);
+        hasher.update(&bytes[msg + FRAME_HEADER_LEN..msg + FRAME_HEADER_LEN + body_len]);
+        bytes[msg + 12..msg + 16].copy_from_slice(&hasher.finalize().to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+
+        let err = Storage::replay_segment_with_keys(&path, &test_keyring()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("authentication"), "{:#}", err);
+        let salvaged = Storage::salvage_segment_with_keys(&path, &test_keyring()).await?;
+        assert!(salvaged.records.is_empty());
+
+        Ok(())
+    }
+
+    fn blob_files(root: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(blob::blob_dir(root))
+            .into_iter()
+            .flatten()
+            .flat_map(|shard| fs::read_dir(shard.unwrap().path()).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn test_large_payloads_deduplicated_in_blob_store() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.blobs = BlobConfig { enabled: true, min_size_bytes: 1024 };
+        let map = vec![7u8; 64 * 1024];
+        let cloud = vec![9u8; 2048];
+        let map_id = blob::blob_id(&map);
+
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..3 {
+            storage.append_record("/map", "robot1", &map, ts).await?;
+        }
+        storage.append_record("/tf", "robot1", b"small", 3).await?;
+        let first = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        storage.append_record("/map", "robot1", &map, 4).await?;
+        storage.append_record("/points", "robot1", &cloud, 5).await?;
+
+        // One copy per distinct payload; the WAL only holds references
+        assert_eq!(blob_files(tmpdir.path()).len(), 2);
+        assert!(fs::metadata(&first)?.len() < 1024);
+        let records = Storage::replay_segment(&first).await?;
+        assert_eq!(records.len(), 4);
+        assert!(records[..3].iter().all(|r| r.payload == map));
+        assert_eq!(records[3].payload, b"small");
+        assert_eq!(storage.read_range(&["/points"], 0, 10).await?[0].payload, cloud);
+        assert_eq!(storage.segment_index(&first).await?.blobs[&blob::to_hex(&map_id)], 3);
+        assert_eq!(storage.blobs.as_ref().unwrap().lock().unwrap().references(&map_id), 4);
+
+        // Counts are rebuilt on startup and unreferenced blobs are swept
+        drop(storage);
+        let orphan = blob::blob_dir(tmpdir.path()).join("ab").join("ab".repeat(32));
+        fs::create_dir_all(orphan.parent().unwrap())?;
+        fs::write(&orphan, b"left over")?;
+        cfg.retention.max_total_bytes = Some(1);
+        let storage = Storage::new(&cfg).await?;
+        assert!(!orphan.exists());
+        assert_eq!(storage.blobs.as_ref().unwrap().lock().unwrap().references(&map_id), 4);
+
+        // Deleting a segment only deletes blobs no other segment refers to
+        let report = storage.enforce_retention().await?;
+        assert_eq!(report.deleted, vec![first]);
+        assert_eq!(blob_files(tmpdir.path()).len(), 2);
+        storage.rotate_segment().await?;
+        let report = storage.enforce_retention().await?;
+        assert_eq!(report.deleted.len(), 1);
+        assert!(report.freed_bytes >= (map.len() + cloud.len()) as u64);
+        assert!(blob_files(tmpdir.path()).is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_appends_release_blob_references() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = test_config(tmpdir.path(), 1024 * 1024);
+        cfg.blobs = BlobConfig { enabled: true, min_size_bytes: 1024 };
+        cfg.max_segment_messages = Some(2);
+        let map = vec![7u8; 64 * 1024];
+        let cloud = vec![9u8; 2048];
+        let (map_id, cloud_id) = (blob::blob_id(&map), blob::blob_id(&cloud));
+
+        let storage = Storage::new(&cfg).await?;
+        let references = |id| storage.blobs.as_ref().unwrap().lock().unwrap().references(id);
+        storage.append_record("/map", "robot1", &map, 0).await?;
+        assert_eq!(references(&map_id), 1);
+
+        // Lost in a failed write: the shared blob stays, the new one goes
+        storage.fail_next_write().await;
+        assert!(storage.append_record("/map", "robot1", &map, 1).await.is_err());
+        storage.fail_next_write().await;
+        assert!(storage.append_record("/points", "robot1", &cloud, 2).await.is_err());
+        assert_eq!((references(&map_id), references(&cloud_id)), (1, 0));
+        assert_eq!(blob_files(tmpdir.path()).len(), 1);
+
+        // Rejected by a failed rotation
+        storage.append_record("/map", "robot1", &map, 3).await?;
+        let next = tmpdir.path().join("segment-1.log");
+        fs::create_dir(&next)?;
+        assert!(storage.append_record("/points", "robot1", &cloud, 4).await.is_err());
+        assert_eq!((references(&map_id), references(&cloud_id)), (2, 0));
+        assert_eq!(blob_files(tmpdir.path()).len(), 1);
+        assert_eq!(storage.lost_messages(), 3);
+
+        fs::remove_dir(&next)?;
+        storage.append_record("/points", "robot1", &cloud, 5).await?;
+        assert_eq!((references(&map_id), references(&cloud_id)), (2, 1));
         Ok(())
     }
 }
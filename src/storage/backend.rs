@@ -0,0 +1,372 @@
+//! Common interface of the storage formats the recorder can write.
+//!
+//! `StorageBackend` is what the ingest queue drains into. `Storage` (the WAL)
+//! implements it, and so does `McapStorage`, which writes
+//! `<data root>/mcap/segment-N.mcap` directly so recordings open in Foxglove
+//! or `ros2 bag` without a conversion step. The MCAP backend has no
+//! sessions, retention or sync; those stay WAL features.
+
+use super::mcap::{self, McapOptions, McapWriter};
+use super::{MessageInfo, Record, Storage};
+use crate::config::StorageConfig;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where recorded messages go
+pub trait StorageBackend: Send + Sync {
+    /// Hand a message to the backend. It is durable after the next `sync`.
+    fn append<'a>(
+        &'a self,
+        topic: &'a str,
+        namespace: &'a str,
+        data: &'a [u8],
+        info: MessageInfo,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Record the message type of `topic` in `namespace`, ahead of its first
+    /// message. Backends that keep no types ignore it.
+    fn declare_topic<'a>(&'a self, topic: &'a str, namespace: &'a str, message_type: &'a str) -> BoxFuture<'a, Result<()>> {
+        let _ = (topic, namespace, message_type);
+        async { Ok(()) }.boxed()
+    }
+
+    /// Wait until everything appended so far is on disk
+    fn sync(&self) -> BoxFuture<'_, Result<()>>;
+
+    /// Messages `append` accepted that a later write or fsync lost
+    fn lost_messages(&self) -> u64 {
+        0
+    }
+
+    /// Close the current file and start the next one; returns the new path
+    #[allow(dead_code)]
+    fn rotate(&self) -> BoxFuture<'_, Result<PathBuf>>;
+
+    /// Files written so far, oldest first
+    #[allow(dead_code)]
+    fn segments(&self) -> BoxFuture<'_, Result<Vec<PathBuf>>>;
+
+    /// Every record of one of `segments`
+    #[allow(dead_code)]
+    fn read_segment<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Vec<Record>>>;
+
+    /// Flush and close for shutdown
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        self.sync()
+    }
+}
+
+impl StorageBackend for Storage {
+    fn append<'a>(
+        &'a self,
+        topic: &'a str,
+        namespace: &'a str,
+        data: &'a [u8],
+        info: MessageInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        // `Queued` returns as soon as the segment writer takes the record, so
+        // its own group commit stays effective; write errors show up in
+        // `lost_messages` instead
+        self.append_record_with(topic, namespace, data, info, super::DurabilityPoint::Queued)
+            .boxed()
+    }
+
+    fn declare_topic<'a>(&'a self, topic: &'a str, _namespace: &'a str, message_type: &'a str) -> BoxFuture<'a, Result<()>> {
+        Storage::declare_topic(self, topic, message_type).boxed()
+    }
+
+    fn sync(&self) -> BoxFuture<'_, Result<()>> {
+        Storage::sync(self).boxed()
+    }
+
+    fn lost_messages(&self) -> u64 {
+        Storage::lost_messages(self)
+    }
+
+    fn rotate(&self) -> BoxFuture<'_, Result<PathBuf>> {
+        self.rotate_segment().boxed()
+    }
+
+    fn segments(&self) -> BoxFuture<'_, Result<Vec<PathBuf>>> {
+        self.list_segments().boxed()
+    }
+
+    fn read_segment<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Vec<Record>>> {
+        Storage::replay_segment_with_keys(path, &self.keys).boxed()
+    }
+}
+
+struct McapInner {
+    segment: u64,
+    /// Open file of `segment`, created by the first append after a rotation
+    writer: Option<McapWriter<BufWriter<File>>>,
+    /// Declared message types by topic and namespace; every new file gets
+    /// their schemas
+    types: BTreeMap<(String, String), String>,
+}
+
+/// Writes recorded messages straight into MCAP files, rotating them by size
+/// and message count like WAL segments
+#[derive(Clone)]
+pub struct McapStorage {
+    dir: Arc<PathBuf>,
+    inner: Arc<Mutex<McapInner>>,
+    opts: McapOptions,
+    max_bytes: u64,
+    max_messages: Option<u64>,
+}
+
+impl McapStorage {
+    /// Open `<cfg.path>/mcap`, finishing files a crash left without a summary
+    pub async fn open(cfg: &StorageConfig) -> Result<Self> {
+        let dir = cfg.path.join("mcap");
+        let opts = McapOptions {
+            compression: cfg.compress.then_some(cfg.compression),
+            compression_level: cfg.compression_level,
+            chunk_size: cfg.mcap.chunk_size.max(1),
+        };
+        let scan_dir = dir.clone();
+        let segment = tokio::task::spawn_blocking(move || -> Result<u64> {
+            std::fs::create_dir_all(&scan_dir)?;
+            let mut next = 0;
+            for path in scan(&scan_dir)? {
+                if let Some(kept) = mcap::recover(&path, opts)? {
+                    tracing::warn!("finished interrupted {} with {} messages", path.display(), kept);
+                }
+                next = next.max(mcap_segment_number(&path).map_or(0, |n| n + 1));
+            }
+            Ok(next)
+        })
+        .await??;
+        Ok(McapStorage {
+            dir: Arc::new(dir),
+            inner: Arc::new(Mutex::new(McapInner { segment, writer: None, types: BTreeMap::new() })),
+            opts,
+            max_bytes: cfg.wal_segment_size as u64,
+            max_messages: cfg.max_segment_messages,
+        })
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("segment-{}.mcap", segment))
+    }
+
+    /// Run `f` on the inner state on the blocking pool
+    async fn with_inner<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&McapStorage, &mut McapInner) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut inner = this.inner.lock().map_err(|_| anyhow!("MCAP writer poisoned"))?;
+            f(&this, &mut inner)
+        })
+        .await?
+    }
+
+    fn seal(&self, inner: &mut McapInner) -> Result<()> {
+        if let Some(writer) = inner.writer.take() {
+            let file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            inner.segment += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the number out of a `segment-N.mcap` file name
+fn mcap_segment_number(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("segment-")?
+        .strip_suffix(".mcap")?
+        .parse()
+        .ok()
+}
+
+fn scan(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if mcap_segment_number(&path).is_some() {
+            out.push(path);
+        } else if path.extension().is_some_and(|e| e == "tmp") {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    out.sort_by_key(|p| mcap_segment_number(p));
+    Ok(out)
+}
+
+impl StorageBackend for McapStorage {
+    fn append<'a>(
+        &'a self,
+        topic: &'a str,
+        namespace: &'a str,
+        data: &'a [u8],
+        info: MessageInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        let record = Record {
+            topic: topic.to_string(),
+            namespace: namespace.to_string(),
+            info,
+            payload: data.to_vec(),
+        };
+        self.with_inner(move |this, inner| {
+            let writer = match &mut inner.writer {
+                Some(writer) => writer,
+                None => {
+                    let file = File::create(this.segment_path(inner.segment))?;
+                    let mut writer = McapWriter::new(BufWriter::new(file), this.opts)?;
+                    for ((topic, namespace), message_type) in &inner.types {
+                        writer.set_message_type(topic, namespace, message_type);
+                    }
+                    inner.writer.insert(writer)
+                }
+            };
+            writer.write(&record)?;
+            let full = writer.position() >= this.max_bytes
+                || this.max_messages.is_some_and(|max| writer.message_count() >= max);
+            if full {
+                this.seal(inner)?;
+            }
+            Ok(())
+        })
+        .boxed()
+    }
+
+    fn declare_topic<'a>(&'a self, topic: &'a str, namespace: &'a str, message_type: &'a str) -> BoxFuture<'a, Result<()>> {
+        let key = (topic.to_string(), namespace.to_string());
+        let message_type = message_type.to_string();
+        self.with_inner(move |_, inner| {
+            if let Some(writer) = &mut inner.writer {
+                writer.set_message_type(&key.0, &key.1, &message_type);
+            }
+            inner.types.insert(key, message_type);
+            Ok(())
+        })
+        .boxed()
+    }
+
+    fn sync(&self) -> BoxFuture<'_, Result<()>> {
+        self.with_inner(|_, inner| {
+            if let Some(writer) = &mut inner.writer {
+                writer.flush()?.get_ref().sync_data()?;
+            }
+            Ok(())
+        })
+        .boxed()
+    }
+
+    fn rotate(&self) -> BoxFuture<'_, Result<PathBuf>> {
+        self.with_inner(|this, inner| {
+            this.seal(inner)?;
+            Ok(this.segment_path(inner.segment))
+        })
+        .boxed()
+    }
+
+    fn segments(&self) -> BoxFuture<'_, Result<Vec<PathBuf>>> {
+        let dir = self.dir.clone();
+        async move { tokio::task::spawn_blocking(move || scan(&dir)).await? }.boxed()
+    }
+
+    fn read_segment<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Vec<Record>>> {
+        let path = path.to_path_buf();
+        async move { tokio::task::spawn_blocking(move || mcap::read_records(&path)).await? }.boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        self.with_inner(|this, inner| this.seal(inner)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CompressionCodec, DurabilityPolicy, McapConfig, StorageBackendKind};
+    use crate::storage::test_config;
+    use tempfile::TempDir;
+
+    fn mcap_config(path: &Path) -> StorageConfig {
+        StorageConfig {
+            backend: StorageBackendKind::Mcap,
+            mcap: McapConfig { chunk_size: 256 },
+            max_segment_messages: Some(40),
+            compress: true,
+            compression: CompressionCodec::Lz4,
+            durability: DurabilityPolicy::OsBuffered,
+            ..test_config(path, 1024 * 1024)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mcap_backend_rotates_and_finishes_files() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let backend: Arc<dyn StorageBackend> = Arc::new(McapStorage::open(&mcap_config(tmpdir.path())).await?);
+        for ts in 0..100u128 {
+            backend.append("/tf", "robot1", format!("tf {}", ts).as_bytes(), MessageInfo::received(ts)).await?;
+        }
+        backend.close().await?;
+
+        let segments = backend.segments().await?;
+        assert_eq!(segments.len(), 3);
+        let mut records = Vec::new();
+        for path in &segments {
+            assert!(mcap::read_summary(path)?.is_some(), "{} has no summary", path.display());
+            records.extend(backend.read_segment(path).await?);
+        }
+        assert_eq!(records.iter().map(|r| r.info.receive_ns).collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+        assert_eq!(records[42].payload, b"tf 42");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mcap_backend_finishes_interrupted_file_on_open() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = mcap_config(tmpdir.path());
+        let backend = McapStorage::open(&cfg).await?;
+        for ts in 0..20u128 {
+            backend.append("/tf", "robot1", b"before crash", MessageInfo::received(ts)).await?;
+        }
+        backend.sync().await?;
+        // Dropped without `close`, as in a crash: the file has no summary
+        drop(backend);
+        let path = tmpdir.path().join("mcap").join("segment-0.mcap");
+        assert!(mcap::read_summary(&path)?.is_none());
+
+        let backend = McapStorage::open(&cfg).await?;
+        assert_eq!(mcap::read_summary(&path)?.map(|s| s.message_count), Some(20));
+        backend.append("/tf", "robot1", b"after restart", MessageInfo::received(20)).await?;
+        assert_eq!(backend.rotate().await?, tmpdir.path().join("mcap").join("segment-2.mcap"));
+        assert_eq!(backend.read_segment(&tmpdir.path().join("mcap").join("segment-1.mcap")).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mcap_backend_writes_declared_schemas() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let backend: Arc<dyn StorageBackend> = Arc::new(McapStorage::open(&mcap_config(tmpdir.path())).await?);
+        backend.declare_topic("/tf", "robot1", "tf2_msgs/msg/TFMessage").await?;
+        for ts in 0..60u128 {
+            backend.append("/tf", "robot1", b"tf", MessageInfo::received(ts)).await?;
+            backend.append("/odom", "robot1", b"odom", MessageInfo::received(ts)).await?;
+        }
+        backend.close().await?;
+
+        // Every file declares the schema again, and only for the declared topic
+        let segments = backend.segments().await?;
+        assert_eq!(segments.len(), 3);
+        for path in &segments {
+            let summary = mcap::read_summary(path)?.expect("closed files have a summary");
+            assert_eq!((summary.schema_count, summary.channel_count), (1, 2));
+        }
+        Ok(())
+    }
+}
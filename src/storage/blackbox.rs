@@ -0,0 +1,73 @@
+//! Black-box ring buffer.
+//!
+//! In black-box mode the loose segments in the data root form a rolling
+//! window: the janitor deletes every sealed one that was sealed longer than
+//! `window_secs` ago. A trigger freezes the window by moving those segments
+//! into a new session directory, and the writer keeps recording into that
+//! session for `post_trigger_secs` before returning to the ring. Segments in
+//! session directories are never trimmed.
+
+use super::index::index_path;
+use super::retention::{delete_segment, segment_age, uploaded_marker};
+use super::segment_number;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn in_window(path: &Path, window: Duration) -> bool {
+    segment_age(path).is_some_and(|age| age <= window)
+}
+
+/// Delete sealed loose segments that fell out of the window. Segments pinned
+/// by the sync queue stay until they are unpinned.
+pub(super) fn trim(segments: &[PathBuf], active: u64, pinned: &HashSet<PathBuf>, window: Duration) -> Vec<PathBuf> {
+    let mut deleted = Vec::new();
+    for path in segments {
+        let sealed = segment_number(path).is_some_and(|n| n < active);
+        if !sealed || pinned.contains(path) || in_window(path, window) {
+            continue;
+        }
+        match delete_segment(path) {
+            Ok(()) => deleted.push(path.clone()),
+            Err(e) => tracing::warn!("black-box ring could not delete {}: {:#}", path.display(), e),
+        }
+    }
+    deleted
+}
+
+/// Move the sealed segments still inside the window into a capture
+/// directory and return their (old, new) paths. Pins follow the segments to
+/// their new path.
+pub(super) fn freeze(
+    segments: &[PathBuf],
+    active: u64,
+    dir: &Path,
+    pinned: &mut HashSet<PathBuf>,
+    window: Duration,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut frozen = Vec::new();
+    for path in segments {
+        let sealed = segment_number(path).is_some_and(|n| n < active);
+        let Some(name) = path.file_name() else { continue };
+        if !sealed || !in_window(path, window) {
+            continue;
+        }
+        let target = dir.join(name);
+        std::fs::rename(path, &target)?;
+        for (from, to) in [
+            (index_path(path), index_path(&target)),
+            (uploaded_marker(path), uploaded_marker(&target)),
+        ] {
+            match std::fs::rename(&from, &to) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        if pinned.remove(path) {
+            pinned.insert(target.clone());
+        }
+        frozen.push((path.clone(), target));
+    }
+    Ok(frozen)
+}
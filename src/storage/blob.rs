@@ -0,0 +1,219 @@
+//! Content-addressed store for large payloads.
+//!
+//! With `blobs.enabled`, payloads of at least `blobs.min_size_bytes` go to
+//! `<data root>/blobs/<aa>/<sha256 hex>` and the message frame only carries
+//! the 32-byte digest, flagged with `FLAG_BLOB`. Identical payloads (static
+//! maps, repeated `/tf_static`) are stored once. Readers resolve references
+//! transparently and check the digest of what they load.
+//!
+//! Every reference is counted once its frame reaches the segment file;
+//! until then the append holds the blob, and an append that fails lets go
+//! of it, deleting the blob if it was new. A segment's references are
+//! listed in its index sidecar; on startup the counts are rebuilt from the
+//! indexes and blobs no segment refers to are removed. When retention, the
+//! black-box ring or `delete_session` deletes a segment, its references are
+//! released and blobs whose count drops to zero are deleted with it.
+//!
+//! Blobs are stored unencrypted, so segments under an encryption key keep
+//! their payloads inline.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Bytes of a blob reference in a message frame
+pub(super) const BLOB_REF_LEN: usize = 32;
+
+/// Blob directory of the data root holding `segment`, which sits either in
+/// the root or in `sessions/<id>/`
+pub(super) fn blob_dir_for(segment: &Path) -> PathBuf {
+    let dir = segment.parent().unwrap_or(Path::new("."));
+    let root = match dir.parent() {
+        Some(sessions) if sessions.file_name().is_some_and(|n| n == "sessions") => {
+            sessions.parent().unwrap_or(Path::new("."))
+        }
+        _ => dir,
+    };
+    blob_dir(root)
+}
+
+pub(super) fn blob_dir(root: &Path) -> PathBuf {
+    root.join("blobs")
+}
+
+/// Content address of a payload
+pub(super) fn blob_id(data: &[u8]) -> [u8; BLOB_REF_LEN] {
+    Sha256::digest(data).into()
+}
+
+pub(super) fn to_hex(id: &[u8]) -> String {
+    hex::encode(id)
+}
+
+fn blob_path(dir: &Path, hex: &str) -> PathBuf {
+    dir.join(&hex[..2]).join(hex)
+}
+
+/// Whether the blob `id` is present in `dir`
+pub(super) fn blob_exists(dir: &Path, id: &[u8]) -> bool {
+    blob_path(dir, &to_hex(id)).exists()
+}
+
+/// Load a referenced payload, checking its length and digest
+pub(super) fn read_blob(dir: &Path, id: &[u8], raw_len: usize) -> Result<Vec<u8>> {
+    let hex = to_hex(id);
+    let data = std::fs::read(blob_path(dir, &hex)).with_context(|| format!("reading blob {}", hex))?;
+    if data.len() != raw_len {
+        return Err(anyhow!("blob {} is {} bytes, expected {}", hex, data.len(), raw_len));
+    }
+    if blob_id(&data)[..] != id[..] {
+        return Err(anyhow!("blob {} does not match its digest", hex));
+    }
+    Ok(data)
+}
+
+/// Reference counts of the blob directory
+#[derive(Debug)]
+pub(super) struct BlobStore {
+    dir: PathBuf,
+    /// fsync new blobs before their reference can reach the segment
+    sync: bool,
+    /// References per blob written to sealed segments and the active one
+    counts: HashMap<String, u64>,
+    /// Appends stored but not yet written, which keep their blob alive
+    in_flight: HashMap<String, u64>,
+    /// References held by each sealed segment, released when it is deleted
+    segments: HashMap<u64, BTreeMap<String, u64>>,
+}
+
+impl BlobStore {
+    pub(super) fn new(dir: PathBuf, sync: bool) -> Self {
+        BlobStore { dir, sync, counts: HashMap::new(), in_flight: HashMap::new(), segments: HashMap::new() }
+    }
+
+    /// Store `data` under its digest `id` unless it is there already, and
+    /// hold it for the append in flight until `commit` or `discard`
+    pub(super) fn put(&mut self, id: &[u8], data: &[u8]) -> Result<()> {
+        let hex = to_hex(id);
+        let path = blob_path(&self.dir, &hex);
+        if !self.is_referenced(&hex) || !path.exists() {
+            std::fs::create_dir_all(path.parent().expect("blob paths have a parent"))?;
+            let tmp_path = path.with_extension("tmp");
+            std::fs::write(&tmp_path, data)?;
+            if self.sync {
+                std::fs::File::open(&tmp_path)?.sync_all()?;
+            }
+            std::fs::rename(&tmp_path, &path)?;
+        }
+        *self.in_flight.entry(hex).or_default() += 1;
+        Ok(())
+    }
+
+    /// The frame referring to `id` reached the segment file
+    pub(super) fn commit(&mut self, id: &[u8]) {
+        let hex = to_hex(id);
+        self.take_in_flight(&hex);
+        *self.counts.entry(hex).or_default() += 1;
+    }
+
+    /// The append that stored `id` failed; deletes the blob if nothing else
+    /// refers to it
+    pub(super) fn discard(&mut self, id: &[u8]) {
+        let hex = to_hex(id);
+        self.take_in_flight(&hex);
+        if !self.is_referenced(&hex) {
+            self.delete(&hex);
+        }
+    }
+
+    fn take_in_flight(&mut self, hex: &str) {
+        if let Some(n) = self.in_flight.get_mut(hex) {
+            *n -= 1;
+            if *n == 0 {
+                self.in_flight.remove(hex);
+            }
+        }
+    }
+
+    fn is_referenced(&self, hex: &str) -> bool {
+        self.counts.contains_key(hex) || self.in_flight.contains_key(hex)
+    }
+
+    /// Count references found on startup; sealed segments also remember theirs
+    pub(super) fn load(&mut self, segment: Option<u64>, refs: &BTreeMap<String, u64>) {
+        for (hex, n) in refs {
+            *self.counts.entry(hex.clone()).or_default() += n;
+        }
+        if let Some(segment) = segment.filter(|_| !refs.is_empty()) {
+            self.segments.insert(segment, refs.clone());
+        }
+    }
+
+    /// A segment was sealed; its references were counted when appended
+    pub(super) fn assign(&mut self, segment: u64, refs: BTreeMap<String, u64>) {
+        if !refs.is_empty() {
+            self.segments.insert(segment, refs);
+        }
+    }
+
+    /// Drop the references of a deleted segment and delete blobs nothing
+    /// refers to any more. Returns the bytes freed.
+    pub(super) fn release(&mut self, segment: u64) -> u64 {
+        let mut freed = 0;
+        for (hex, n) in self.segments.remove(&segment).unwrap_or_default() {
+            let Some(count) = self.counts.get_mut(&hex) else { continue };
+            *count = count.saturating_sub(n);
+            if *count == 0 {
+                self.counts.remove(&hex);
+                if !self.in_flight.contains_key(&hex) {
+                    freed += self.delete(&hex);
+                }
+            }
+        }
+        freed
+    }
+
+    /// Delete blobs without references, e.g. written right before a crash
+    pub(super) fn sweep(&mut self) -> Result<u64> {
+        let mut freed = 0;
+        let shards = match std::fs::read_dir(&self.dir) {
+            Ok(shards) => shards,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        for shard in shards {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for entry in std::fs::read_dir(&shard)? {
+                let path = entry?.path();
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                let hex = name.strip_suffix(".tmp").unwrap_or(&name).to_string();
+                if name.ends_with(".tmp") || !self.is_referenced(&hex) {
+                    freed += self.delete(&name);
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    fn delete(&self, name: &str) -> u64 {
+        let path = blob_path(&self.dir, name);
+        let len = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        match std::fs::remove_file(&path) {
+            Ok(()) => len,
+            Err(e) => {
+                tracing::warn!("could not delete blob {}: {}", path.display(), e);
+                0
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn references(&self, id: &[u8]) -> u64 {
+        let hex = to_hex(id);
+        self.counts.get(&hex).copied().unwrap_or(0) + self.in_flight.get(&hex).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,572 @@
+//! Segment compaction.
+//!
+//! Loose segments interleave every topic and namespace, so reading one topic
+//! means decoding all of them. The compactor rewrites sealed segments into a
+//! batch directory `compacted/batch-<first>-<last>/` holding one
+//! `part-N.log` per topic or namespace, sorted by receive time and sealed
+//! with a footer and index like any segment. Downsampling rules thin out old
+//! data on the way; payloads kept in the blob store are inlined.
+//!
+//! A batch is written under a `.tmp` name and renamed into place in one step.
+//! Its `batch.json` lists the segments it replaces, which are deleted only
+//! after the rename, so readers and the sync queue see either the segments or
+//! the complete batch. Deletions interrupted by a crash are finished on the
+//! next start.
+
+use super::crypto::DataKey;
+use super::footer::SegmentFooter;
+use super::frame::{self, SegmentDictionary};
+use super::index::{self, IndexBuilder};
+use super::reader::{self, SegmentReader};
+use super::retention::{delete_segment, segment_age, uploaded_marker};
+use super::{segment_number, session, Record};
+use crate::config::{CompactionConfig, CompactionPartition, CompressionCodec};
+use crate::security::Keyring;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const COMPACTED_DIR: &str = "compacted";
+const MANIFEST: &str = "batch.json";
+
+/// Directory batches are kept in
+pub(super) fn compacted_dir(root: &Path) -> PathBuf {
+    root.join(COMPACTED_DIR)
+}
+
+/// Contents of a batch's `batch.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchManifest {
+    /// File names of the loose segments the batch replaces
+    pub sources: Vec<String>,
+    pub partitions: Vec<Partition>,
+    /// Latest seal time of the sources, in ms since the epoch; retention
+    /// ages the batch by it
+    pub sealed_at: u128,
+    pub compacted_at: u128,
+}
+
+/// One time-sorted file of a batch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partition {
+    pub file: String,
+    /// Topic or namespace the file holds
+    pub key: String,
+    pub topics: Vec<String>,
+    pub message_count: u64,
+    /// Messages dropped by downsampling rules
+    pub downsampled: u64,
+}
+
+/// Outcome of one compaction pass
+#[derive(Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct CompactionReport {
+    /// Batch written; `None` when too few segments were eligible
+    pub batch: Option<PathBuf>,
+    /// Segments the batch replaced, now deleted
+    pub sources: Vec<PathBuf>,
+    pub messages: u64,
+    pub downsampled: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// How partition files are encoded
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PartitionOptions {
+    pub compression: Option<CompressionCodec>,
+    pub compression_level: i32,
+    pub index_stride: usize,
+}
+
+/// A batch written under its temporary name, waiting for `commit`
+pub(super) struct PreparedBatch {
+    tmp: PathBuf,
+    dest: PathBuf,
+    /// Every source carries an upload marker, so the batch gets one too
+    uploaded: bool,
+    report: CompactionReport,
+}
+
+/// Segment numbers in a `batch-<first>-<last>` directory name
+fn batch_range(path: &Path) -> Option<(u64, u64)> {
+    let (first, last) = path.file_name()?.to_str()?.strip_prefix("batch-")?.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?))
+}
+
+pub(super) fn is_batch(path: &Path) -> bool {
+    batch_range(path).is_some()
+}
+
+/// Committed batches, oldest first
+pub(super) fn batches(root: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(compacted_dir(root)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut out = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if is_batch(&path) && path.is_dir() {
+            out.push(path);
+        }
+    }
+    out.sort_by_key(|p| batch_range(p));
+    Ok(out)
+}
+
+pub(super) fn read_manifest(batch: &Path) -> Result<BatchManifest> {
+    let data = std::fs::read(batch.join(MANIFEST)).with_context(|| format!("reading {}", batch.display()))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Partition files of a batch, in manifest order
+pub(super) fn partition_files(batch: &Path) -> Result<Vec<PathBuf>> {
+    Ok(read_manifest(batch)?.partitions.iter().map(|p| batch.join(&p.file)).collect())
+}
+
+/// Records of `topics` (all when empty) within `[t_start, t_end)` from one
+/// batch, in receive time order across its partitions
+pub(super) fn read_span(
+    batch: &Path,
+    stride: usize,
+    keys: &Keyring,
+    topics: &[String],
+    t_start: u128,
+    t_end: u128,
+) -> Result<Vec<Record>> {
+    let mut out = Vec::new();
+    for partition in read_manifest(batch)?.partitions {
+        if !topics.is_empty() && !partition.topics.iter().any(|t| topics.contains(t)) {
+            continue;
+        }
+        let path = batch.join(&partition.file);
+        let index = index::load_or_rebuild(&path, stride)?;
+        out.extend(index::read_span(&path, Some(&index), keys, topics, t_start, t_end)?);
+    }
+    out.sort_by_key(|r| r.info.receive_ns);
+    Ok(out)
+}
+
+/// Clean up after a crash: drop half-written batches and their markers, and
+/// delete sources of committed batches that are still around
+pub(super) fn recover(root: &Path) -> Result<()> {
+    let dir = compacted_dir(root);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "tmp") {
+            tracing::warn!("removing interrupted compaction {}", path.display());
+            std::fs::remove_dir_all(&path)?;
+        } else if path.extension().is_some_and(|e| e == "uploaded") && !path.with_extension("").exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    for batch in batches(root)? {
+        for source in read_manifest(&batch)?.sources {
+            let path = root.join(&source);
+            if path.exists() {
+                tracing::warn!("finishing compaction into {}: deleting {}", batch.display(), source);
+                delete_segment(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Segments of one pass: sealed, unpinned, old enough and (if required)
+/// uploaded, oldest first and up to `max_batch_bytes`. Empty when fewer
+/// than `min_segments` qualify and the batch is not full.
+fn select(segments: &[PathBuf], active: u64, pinned: &HashSet<PathBuf>, cfg: &CompactionConfig) -> Result<Vec<PathBuf>> {
+    let min_age = Duration::from_secs(cfg.min_age_secs);
+    let mut selected = Vec::new();
+    let mut bytes = 0;
+    let mut full = false;
+    for path in segments {
+        let sealed = segment_number(path).is_some_and(|n| n < active);
+        let uploaded = !cfg.require_upload || uploaded_marker(path).exists();
+        let old_enough = segment_age(path).is_some_and(|age| age >= min_age);
+        if !sealed || pinned.contains(path) || !uploaded || !old_enough {
+            continue;
+        }
+        let len = std::fs::metadata(path)?.len();
+        if !selected.is_empty() && bytes + len > cfg.max_batch_bytes {
+            full = true;
+            break;
+        }
+        bytes += len;
+        selected.push(path.clone());
+    }
+    if !full && selected.len() < cfg.min_segments.max(1) {
+        selected.clear();
+    }
+    Ok(selected)
+}
+
+/// Read the eligible loose `segments` of `root` and write them as a batch
+/// under a temporary name. `None` when there is nothing to compact.
+pub(super) fn prepare(
+    root: &Path,
+    segments: &[PathBuf],
+    active: u64,
+    pinned: &HashSet<PathBuf>,
+    cfg: &CompactionConfig,
+    keys: &Keyring,
+    opts: PartitionOptions,
+) -> Result<Option<PreparedBatch>> {
+    let now = session::now_ms()?;
+    let mut report = CompactionReport::default();
+    let mut sealed_at = 0;
+    // Records per partition key, each with the downsampling interval (ns) that applies to it
+    let mut partitions: BTreeMap<String, Vec<(Record, Option<u128>)>> = BTreeMap::new();
+    for path in select(segments, active, pinned, cfg)? {
+        // Batches are encrypted only under the active key, so without one
+        // encrypted segments would come out in plaintext
+        if keys.active().is_none() {
+            match reader::master_key_id(&path) {
+                Ok(None) => {}
+                Ok(Some(id)) => {
+                    tracing::warn!("not compacting {}: encrypted under {} but no master key is active", path.display(), id);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("not compacting {}: {:#}", path.display(), e);
+                    continue;
+                }
+            }
+        }
+        // Damaged segments stay where they are, for salvage or repair
+        let records = match SegmentReader::open_with_keys(&path, keys).and_then(|r| r.collect::<Result<Vec<_>>>()) {
+            Ok(records) => records,
+            Err(e) => {
+                tracing::warn!("not compacting {}: {:#}", path.display(), e);
+                continue;
+            }
+        };
+        let age = segment_age(&path).unwrap_or_default();
+        sealed_at = sealed_at.max(now.saturating_sub(age.as_millis()));
+        for record in records {
+            let interval = cfg
+                .downsample
+                .iter()
+                .filter(|rule| rule.topic == record.topic && age >= Duration::from_secs(rule.older_than_secs))
+                .map(|rule| rule.min_interval_ms as u128 * 1_000_000)
+                .max();
+            let key = match cfg.partition_by {
+                CompactionPartition::Topic => record.topic.clone(),
+                CompactionPartition::Namespace => record.namespace.clone(),
+            };
+            partitions.entry(key).or_default().push((record, interval));
+        }
+        report.bytes_before += std::fs::metadata(&path)?.len();
+        report.sources.push(path);
+    }
+    let (Some(first), Some(last)) = (
+        report.sources.first().and_then(|p| segment_number(p)),
+        report.sources.last().and_then(|p| segment_number(p)),
+    ) else {
+        return Ok(None);
+    };
+
+    let dir = compacted_dir(root);
+    let dest = dir.join(format!("batch-{}-{}", first, last));
+    let tmp = dir.join(format!("batch-{}-{}.tmp", first, last));
+    if dest.exists() {
+        return Err(anyhow!("{} already exists", dest.display()));
+    }
+    if tmp.exists() {
+        std::fs::remove_dir_all(&tmp)?;
+    }
+    std::fs::create_dir_all(&tmp)?;
+
+    let mut manifest = BatchManifest { sources: Vec::new(), partitions: Vec::new(), sealed_at, compacted_at: now };
+    for (n, (key, mut records)) in partitions.into_iter().enumerate() {
+        records.sort_by_key(|(record, _)| record.info.receive_ns);
+        let file = format!("part-{}.log", n);
+        let path = tmp.join(&file);
+        let mut writer = SegmentFileWriter::create(&path, keys, opts)?;
+        let mut last_kept: HashMap<(String, String), u128> = HashMap::new();
+        let mut downsampled = 0;
+        for (record, interval) in records {
+            if let Some(interval) = interval {
+                let stream = (record.namespace.clone(), record.topic.clone());
+                if last_kept.get(&stream).is_some_and(|last| record.info.receive_ns < last + interval) {
+                    downsampled += 1;
+                    continue;
+                }
+                last_kept.insert(stream, record.info.receive_ns);
+            }
+            writer.write(&record)?;
+        }
+        let topics = writer.index.topics();
+        let message_count = writer.index.message_count();
+        report.bytes_after += writer.finish(&path, 0)?;
+        report.messages += message_count;
+        report.downsampled += downsampled;
+        manifest.partitions.push(Partition { file, key, topics, message_count, downsampled });
+    }
+    manifest.sources = report
+        .sources
+        .iter()
+        .filter_map(|p| Some(p.file_name()?.to_string_lossy().to_string()))
+        .collect();
+    // `recover` trusts the manifest of every renamed batch
+    let mut file = File::create(tmp.join(MANIFEST))?;
+    file.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    file.sync_all()?;
+    File::open(&tmp)?.sync_all()?;
+
+    let uploaded = report.sources.iter().all(|p| uploaded_marker(p).exists());
+    Ok(Some(PreparedBatch { tmp, dest, uploaded, report }))
+}
+
+/// Swap a prepared batch in for its sources. The batch is discarded if a
+/// source was pinned or deleted while it was written.
+pub(super) fn commit(batch: PreparedBatch, pinned: &HashSet<PathBuf>) -> Result<CompactionReport> {
+    if let Some(path) = batch.report.sources.iter().find(|p| pinned.contains(*p) || !p.exists()) {
+        tracing::info!("{} changed during compaction, discarding {}", path.display(), batch.tmp.display());
+        std::fs::remove_dir_all(&batch.tmp)?;
+        return Ok(CompactionReport::default());
+    }
+    // Written first: an orphaned marker is removed by `recover`, a batch
+    // missing its marker would never be eligible for retention
+    if batch.uploaded {
+        std::fs::write(uploaded_marker(&batch.dest), session::now_ms()?.to_string())?;
+    }
+    std::fs::rename(&batch.tmp, &batch.dest)?;
+    if let Some(dir) = batch.dest.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    for source in &batch.report.sources {
+        delete_segment(source)?;
+    }
+    Ok(CompactionReport { batch: Some(batch.dest), ..batch.report })
+}
+
+/// Writes a complete sealed file in segment format: compacted partitions
+/// and segments rewritten by `verify --repair`
+pub(super) struct SegmentFileWriter {
+    file: BufWriter<File>,
+    opts: PartitionOptions,
+    dictionary: SegmentDictionary,
+    index: IndexBuilder,
+    digest: Sha256,
+    size: u64,
+    data_key: Option<DataKey>,
+    /// Messages written so far
+    messages: u64,
+}
+
+impl SegmentFileWriter {
+    /// Encrypted under a fresh data key when `keys` has an active master key
+    pub(super) fn create(path: &Path, keys: &Keyring, opts: PartitionOptions) -> Result<Self> {
+        let mut writer = SegmentFileWriter {
+            file: BufWriter::new(File::create(path)?),
+            opts,
+            dictionary: SegmentDictionary::default(),
+            index: IndexBuilder::new(opts.index_stride),
+            digest: Sha256::new(),
+            size: 0,
+            data_key: None,
+            messages: 0,
+        };
+        if keys.active().is_some() {
+            let (data_key, body) = DataKey::generate(keys)?;
+            writer.put(&frame::encode_key(&body))?;
+            writer.data_key = Some(data_key);
+        }
+        Ok(writer)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)?;
+        self.digest.update(bytes);
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub(super) fn write(&mut self, record: &Record) -> Result<()> {
+        let (codec, stored) =
+            frame::compress_payload(self.opts.compression, self.opts.compression_level, &record.payload)?;
+        let offset = self.size;
+        let (topic_id, namespace_id, declaration) = self.dictionary.intern(&record.topic, &record.namespace);
+        let seal = self.data_key.as_ref().map(|key| frame::Seal {
+            key,
+            counter: self.messages,
+            topic: &record.topic,
+            namespace: &record.namespace,
+        });
+        let message =
+            frame::encode_message(topic_id, namespace_id, &record.info, codec, &stored, record.payload.len(), 0, seal)?;
+        if let Some(declaration) = declaration {
+            self.put(&declaration)?;
+        }
+        self.put(&message)?;
+        self.messages += 1;
+        self.index.observe(offset, &record.topic, record.info.receive_ns);
+        Ok(())
+    }
+
+    /// Seal with a footer, fsync and write the index sidecar of `path`
+    /// (numbered `segment`); returns the file length
+    pub(super) fn finish(mut self, path: &Path, segment: u64) -> Result<u64> {
+        let footer = SegmentFooter::new(std::mem::take(&mut self.digest), self.size, &self.index)?.encode()?;
+        self.file.write_all(&footer)?;
+        let size = self.size + footer.len() as u64;
+        self.file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        index::write(path, &self.index.finish(segment, size, &self.dictionary))?;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DownsampleRule, DurabilityPolicy, StorageConfig};
+    use crate::storage::{test_config, MessageInfo, Storage};
+    use futures::StreamExt;
+    use tempfile::TempDir;
+
+    fn compaction_config(path: &Path, compaction: CompactionConfig) -> StorageConfig {
+        StorageConfig {
+            max_segment_messages: Some(10),
+            compress: true,
+            compression: CompressionCodec::Lz4,
+            durability: DurabilityPolicy::OsBuffered,
+            index_stride: 4,
+            compaction: CompactionConfig { enabled: true, min_age_secs: 0, min_segments: 2, ..compaction },
+            ..test_config(path, 1024 * 1024)
+        }
+    }
+
+    /// Three sealed segments of interleaved /tf and /odometry messages, each
+    /// segment slightly out of time order, plus an active one
+    async fn record(storage: &Storage) -> Result<()> {
+        for ts in 0..30u128 {
+            let (topic, ns) = if ts % 3 == 0 { ("/odometry", "robot2") } else { ("/tf", "robot1") };
+            let at = if ts % 10 == 4 { ts * 1_000_000 + 1_500_000 } else { ts * 1_000_000 };
+            storage.append_message(topic, ns, format!("{} {}", topic, ts).as_bytes(), MessageInfo::received(at)).await?;
+        }
+        storage.append_record("/tf", "robot1", b"active", 100_000_000).await?;
+        storage.sync().await
+    }
+
+    #[tokio::test]
+    async fn test_compaction_partitions_sorts_and_swaps_segments() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&compaction_config(tmpdir.path(), CompactionConfig::default())).await?;
+        record(&storage).await?;
+        let before = storage.read_range(&["/odometry"], 0, u128::MAX).await?;
+        storage.pin_segment(&tmpdir.path().join("segment-2.log")).await;
+
+        let report = storage.compact().await?;
+        let batch = report.batch.clone().expect("batch written");
+        assert_eq!(batch, tmpdir.path().join("compacted").join("batch-0-1"));
+        assert_eq!(report.messages, 20);
+        // The pinned segment and the active one stay loose
+        let loose = storage.list_segments().await?;
+        assert_eq!(loose, vec![tmpdir.path().join("segment-2.log"), tmpdir.path().join("segment-3.log")]);
+
+        let manifest = Storage::batch_manifest(&batch).await?;
+        assert_eq!(manifest.sources, vec!["segment-0.log", "segment-1.log"]);
+        assert_eq!(manifest.partitions.iter().map(|p| p.key.as_str()).collect::<Vec<_>>(), vec!["/odometry", "/tf"]);
+        assert_eq!(manifest.partitions[0].message_count, 7);
+        let tf = Storage::replay_segment(&batch.join(&manifest.partitions[1].file)).await?;
+        assert!(tf.windows(2).all(|w| w[0].info.receive_ns <= w[1].info.receive_ns));
+        assert!(tf.iter().all(|r| r.topic == "/tf"));
+
+        // Readers see the same data, now in time order per batch
+        assert_eq!(storage.read_range(&["/odometry"], 0, u128::MAX).await?, before);
+        let all: Vec<Record> = storage.stream_records().await?.map(|r| r.unwrap()).collect().await;
+        assert_eq!(all.len(), 31);
+        assert_eq!(all[0].payload, b"/odometry 0");
+
+        // Nothing left to compact until the pin goes
+        assert!(storage.compact().await?.batch.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compaction_keeps_encrypted_segments_without_active_key() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut creds = crate::security::StoredCredentials::default();
+        creds.add_master_key("site-1");
+        let keys = Keyring::from_credentials(&creds)?;
+        let mut cfg = compaction_config(tmpdir.path(), CompactionConfig::default());
+        cfg.encryption = Some("site-1".to_string());
+        cfg.enable_aes_gcm = true;
+        let storage = Storage::with_keyring(&cfg, keys.clone()).await?;
+        record(&storage).await?;
+        storage.rotate_segment().await?;
+        drop(storage);
+
+        // Encryption turned off, the old master key still in the vault
+        cfg.encryption = None;
+        let storage = Storage::with_keyring(&cfg, keys).await?;
+        record(&storage).await?;
+        let report = storage.compact().await?;
+        let names: Vec<_> = report.sources.iter().filter_map(|p| segment_number(p)).collect();
+        assert_eq!(names, vec![4, 5, 6]);
+        let loose: Vec<_> = storage.list_segments().await?.iter().filter_map(|p| segment_number(p)).collect();
+        assert_eq!(loose, vec![0, 1, 2, 3, 7]);
+        assert!(Storage::replay_segment(&tmpdir.path().join("segment-0.log")).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compaction_downsamples_and_recovers_interrupted_swap() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let compaction = CompactionConfig {
+            partition_by: CompactionPartition::Namespace,
+            downsample: vec![DownsampleRule { topic: "/tf".to_string(), older_than_secs: 0, min_interval_ms: 5 }],
+            ..Default::default()
+        };
+        let cfg = compaction_config(tmpdir.path(), compaction);
+        let storage = Storage::new(&cfg).await?;
+        record(&storage).await?;
+        let segment_0 = std::fs::read(tmpdir.path().join("segment-0.log"))?;
+
+        let report = storage.compact().await?;
+        assert_eq!(report.sources.len(), 3);
+        assert_eq!(report.messages + report.downsampled, 30);
+        let tf = storage.read_range(&["/tf"], 0, 30_000_000).await?;
+        assert!(tf.windows(2).all(|w| w[1].info.receive_ns >= w[0].info.receive_ns + 5_000_000));
+        assert_eq!(storage.read_range(&["/odometry"], 0, u128::MAX).await?.len(), 10);
+        drop(storage);
+
+        // A crash right after the rename left a source behind, and another
+        // pass died while writing its batch
+        std::fs::write(tmpdir.path().join("segment-0.log"), segment_0)?;
+        std::fs::create_dir_all(tmpdir.path().join("compacted").join("batch-4-5.tmp"))?;
+        let storage = Storage::new(&cfg).await?;
+        assert_eq!(storage.list_segments().await?, vec![tmpdir.path().join("segment-3.log")]);
+        assert_eq!(storage.compacted_batches().await?.len(), 1);
+        assert!(!tmpdir.path().join("compacted").join("batch-4-5.tmp").exists());
+        assert_eq!(storage.read_range(&["/odometry"], 0, u128::MAX).await?.len(), 10);
+
+        // Retention removes the batch as a whole
+        let mut retention = storage.retention.clone();
+        retention.max_total_bytes = Some(0);
+        let report = super::super::retention::enforce(
+            tmpdir.path(),
+            &storage.compacted_batches().await?,
+            4,
+            &HashSet::new(),
+            &retention,
+        )?;
+        assert_eq!(report.deleted.len(), 1);
+        assert!(storage.compacted_batches().await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+//! At-rest encryption of segment payloads.
+//!
+//! An encrypted segment starts with a key frame holding a random segment id
+//! and a fresh AES-256 data key, wrapped by a master key from the credential
+//! vault and tagged with that key's id. Every message frame after it stores
+//! its payload as `frame counter || nonce || AES-256-GCM(payload)` and sets
+//! `FLAG_ENCRYPTED`. The frame header, the message prefix (ids, stamps, raw
+//! length), the frame counter, the segment id and the resolved topic and
+//! namespace names are the associated data: they stay readable, so recovery,
+//! indexes, footers and retention work without the key, but any change to
+//! them fails authentication. The counter numbers the segment's messages from
+//! 0, so dropped, repeated or reordered frames show up as a break in it.
+
+use crate::security::{aes_gcm_decrypt, aes_gcm_encrypt, Keyring};
+use aes_gcm::aead::KeyInit;
+use aes_gcm::{Aes256Gcm, Key};
+use anyhow::{anyhow, Result};
+use rand::Rng;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Bytes of the frame counter in front of the nonce
+pub(super) const COUNTER_LEN: usize = 8;
+pub(super) const SEGMENT_ID_LEN: usize = 16;
+/// Bytes an encrypted payload grows by
+pub(super) const SEAL_OVERHEAD: usize = COUNTER_LEN + NONCE_LEN + TAG_LEN;
+
+/// Data key of one segment
+#[derive(Clone)]
+pub(super) struct DataKey {
+    cipher: Aes256Gcm,
+    segment_id: [u8; SEGMENT_ID_LEN],
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl DataKey {
+    /// New data key for a segment, plus the key frame body announcing it:
+    /// `id_len u8 | master key id | segment id | wrapped key`
+    pub(super) fn generate(keys: &Keyring) -> Result<(Self, Vec<u8>)> {
+        let mut key = [0u8; 32];
+        let mut segment_id = [0u8; SEGMENT_ID_LEN];
+        rand::thread_rng().fill(&mut key);
+        rand::thread_rng().fill(&mut segment_id);
+        let (id, wrapped) = keys.wrap_data_key(&key)?;
+        let id_len = u8::try_from(id.len()).map_err(|_| anyhow!("master key id {} is too long", id))?;
+        let mut body = vec![id_len];
+        body.extend_from_slice(id.as_bytes());
+        body.extend_from_slice(&segment_id);
+        body.extend_from_slice(&wrapped);
+        Ok((Self::from_bytes(&key, segment_id)?, body))
+    }
+
+    /// Id of the master key that wrapped the data key of a key frame body
+    pub(super) fn master_key_id(body: &[u8]) -> Result<&str> {
+        let id_len = *body.first().ok_or_else(|| anyhow!("empty key frame"))? as usize;
+        let id = body.get(1..1 + id_len).ok_or_else(|| anyhow!("truncated key frame"))?;
+        Ok(std::str::from_utf8(id)?)
+    }
+
+    /// Data key announced by a key frame body
+    pub(super) fn open(keys: &Keyring, body: &[u8]) -> Result<Self> {
+        let id = Self::master_key_id(body)?;
+        let rest = &body[1 + id.len()..];
+        let segment_id = rest
+            .get(..SEGMENT_ID_LEN)
+            .and_then(|segment_id| segment_id.try_into().ok())
+            .ok_or_else(|| anyhow!("truncated key frame"))?;
+        Self::from_bytes(&keys.unwrap_data_key(id, &rest[SEGMENT_ID_LEN..])?, segment_id)
+    }
+
+    fn from_bytes(key: &[u8], segment_id: [u8; SEGMENT_ID_LEN]) -> Result<Self> {
+        let key = <[u8; 32]>::try_from(key).map_err(|_| anyhow!("data key is {} bytes, expected 32", key.len()))?;
+        Ok(DataKey { cipher: Aes256Gcm::new(&Key::<Aes256Gcm>::from(key)), segment_id })
+    }
+
+    /// Random id of the segment, from its key frame
+    pub(super) fn segment_id(&self) -> &[u8; SEGMENT_ID_LEN] {
+        &self.segment_id
+    }
+
+    /// `nonce || ciphertext || tag` of `plaintext`, bound to `aad`
+    pub(super) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        aes_gcm_encrypt(&self.cipher, plaintext, aad).map_err(|e| anyhow!("payload encryption failed: {}", e))
+    }
+
+    pub(super) fn unseal(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(anyhow!("encrypted payload too short: {} bytes", sealed.len()));
+        }
+        aes_gcm_decrypt(&self.cipher, sealed, aad).map_err(|_| anyhow!("encrypted frame failed authentication"))
+    }
+}
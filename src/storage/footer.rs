@@ -0,0 +1,107 @@
+//! Footer written at the end of a sealed segment.
+//!
+//! The writer hashes every byte it appends, so sealing a segment costs one
+//! small frame instead of a re-read. The footer records that SHA-256 together
+//! with the segment's message count, time span and topics; sync and
+//! verification read it from the tail of the file instead of rehashing.
+
+use super::frame::{self, FrameCheck, FrameKind, FRAME_HEADER_LEN};
+use super::index::IndexBuilder;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Footer layout; version 1 footers (no `version` field) kept their time
+/// span in ms
+pub(super) const FOOTER_VERSION: u32 = 2;
+
+fn footer_v1() -> u32 {
+    1
+}
+
+/// Summary stored in the footer frame of a sealed segment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentFooter {
+    #[serde(default = "footer_v1")]
+    pub version: u32,
+    /// SHA-256 (hex) of every byte before the footer frame
+    pub sha256: String,
+    /// Length of the segment without the footer frame
+    pub data_bytes: u64,
+    pub message_count: u64,
+    /// Receive time span of the messages, in ns
+    pub min_timestamp: Option<u128>,
+    pub max_timestamp: Option<u128>,
+    pub topics: Vec<String>,
+    /// Wall-clock time the segment was sealed, in ms since the epoch
+    pub sealed_at: u128,
+}
+
+impl SegmentFooter {
+    pub(super) fn new(digest: Sha256, data_bytes: u64, index: &IndexBuilder) -> Result<Self> {
+        let span = index.time_span();
+        Ok(SegmentFooter {
+            version: FOOTER_VERSION,
+            sha256: format!("{:x}", digest.finalize()),
+            data_bytes,
+            message_count: index.message_count(),
+            min_timestamp: span.map(|(min, _)| min),
+            max_timestamp: span.map(|(_, max)| max),
+            topics: index.topics(),
+            sealed_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_millis(),
+        })
+    }
+
+    pub(super) fn encode(&self) -> Result<Vec<u8>> {
+        Ok(frame::encode_footer(&serde_json::to_vec(self)?))
+    }
+}
+
+/// SHA-256 of the first `len` bytes of a file, for segments whose running
+/// digest was lost
+pub(super) fn hash_prefix(path: &Path, len: u64) -> Result<Sha256> {
+    let mut reader = File::open(path)?.take(len);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher)
+}
+
+/// Read the footer from the tail of a segment. `None` if the segment was
+/// never sealed with one (active, legacy or damaged segments).
+pub(super) fn read_footer(path: &Path) -> Result<Option<SegmentFooter>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < (FRAME_HEADER_LEN + 4) as u64 {
+        return Ok(None);
+    }
+    let mut summary_len = [0u8; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut summary_len)?;
+    let frame_len = (FRAME_HEADER_LEN + 4) as u64 + u32::from_le_bytes(summary_len) as u64;
+    if frame_len > len {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0u8; frame_len as usize];
+    file.seek(SeekFrom::Start(len - frame_len))?;
+    file.read_exact(&mut buf)?;
+    match frame::check_frame(&buf) {
+        FrameCheck::Valid { len, kind: Some(FrameKind::Footer) } if len == buf.len() => {}
+        _ => return Ok(None),
+    }
+    let mut footer: SegmentFooter = serde_json::from_slice(&buf[FRAME_HEADER_LEN..buf.len() - 4])?;
+    if footer.data_bytes != len - frame_len {
+        return Ok(None);
+    }
+    if footer.version < FOOTER_VERSION {
+        footer.min_timestamp = footer.min_timestamp.map(|ms| ms * 1_000_000);
+        footer.max_timestamp = footer.max_timestamp.map(|ms| ms * 1_000_000);
+        footer.version = FOOTER_VERSION;
+    }
+    Ok(Some(footer))
+}
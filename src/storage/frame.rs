@@ -0,0 +1,880 @@
+//! On-disk frame layout for WAL segments.
+//!
+//! Every frame starts with a fixed 16-byte little-endian header:
+//!
+//! ```text
+//! ┌────────┬─────────┬──────┬───────┬───────┬──────────┬───────┐
+//! │ magic  │ version │ kind │ codec │ flags │ body_len │ crc32 │
+//! │ u32    │ u8      │ u8   │ u8    │ u8    │ u32      │ u32   │
+//! └────────┴─────────┴──────┴───────┴───────┴──────────┴───────┘
+//! ```
+//!
+//! The CRC covers `version..body_len` plus the body, so a corrupted length is
+//! caught as well as a corrupted payload. A message body starts with a fixed
+//! prefix before its payload:
+//!
+//! ```text
+//! ┌──────────┬──────────────┬────────────┬─────────┬───────┬────────┬───────────┬──────────┐
+//! │ topic_id │ namespace_id │ receive_ns │ raw_len │ clock │ stamps │ header_ns │ sequence │
+//! │ u32      │ u32          │ u64        │ u32     │ u8    │ u8     │ u64       │ u64      │
+//! └──────────┴──────────────┴────────────┴─────────┴───────┴────────┴───────────┴──────────┘
+//! ```
+//!
+//! `stamps` says which of `header_ns` and `sequence` are set. Version 1
+//! frames carry only the first four fields, with the receive time in
+//! milliseconds; they are still readable. Topic and namespace names are
+//! interned per segment: a dictionary frame declares `id -> name` before the
+//! first message that uses it, and message frames only carry the ids.
+//!
+//! Encrypted segments start with a key frame and set `FLAG_ENCRYPTED` on
+//! their message frames; see `crypto`. Messages whose payload lives in the
+//! blob store set `FLAG_BLOB` and carry its digest instead; see `blob`.
+//!
+//! A sealed segment ends with a footer frame whose body is a JSON summary
+//! followed by its own length as a `u32`, so the footer can be located from
+//! the end of the file without scanning.
+//!
+//! Segments written before the binary layout use JSON metadata frames behind
+//! `LEGACY_FRAME_HEADER`; those are still readable but never written.
+
+use super::blob::{self, BLOB_REF_LEN};
+use super::crypto::{DataKey, COUNTER_LEN, SEAL_OVERHEAD};
+use super::{MessageInfo, Record};
+use crate::config::{ClockSource, CompressionCodec};
+use crate::security::Keyring;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::PathBuf;
+
+pub(super) const RECORD_FRAME_HEADER: u32 = 0xFEEDFACE;
+pub(super) const LEGACY_FRAME_HEADER: u32 = 0xDEADBEEF;
+pub(super) const FRAME_VERSION: u8 = 2;
+/// Frames with a millisecond receive time and no header stamp or sequence
+const FRAME_VERSION_V1: u8 = 1;
+pub(super) const FRAME_HEADER_LEN: usize = 16;
+/// topic_id u32, namespace_id u32, receive_ns u64, raw_len u32, clock u8,
+/// stamps u8, header_ns u64, sequence u64
+pub(super) const MESSAGE_PREFIX_LEN: usize = 38;
+/// topic_id u32, namespace_id u32, timestamp (ms) u64, raw_len u32
+const MESSAGE_PREFIX_LEN_V1: usize = 20;
+/// `stamps` bit: `header_ns` is set
+const STAMP_HEADER: u8 = 0x01;
+/// `stamps` bit: `sequence` is set
+const STAMP_SEQUENCE: u8 = 0x02;
+const NANOS_PER_MILLI: u128 = 1_000_000;
+/// Frame flag: the message payload is encrypted with the segment's data key
+pub(super) const FLAG_ENCRYPTED: u8 = 0x01;
+/// Frame flag: the stored payload is the digest of a blob holding the message
+pub(super) const FLAG_BLOB: u8 = 0x02;
+/// Upper bound on a single frame body, guards against allocating on a corrupt length
+const MAX_FRAME_BODY: usize = 256 * 1024 * 1024;
+/// Payloads smaller than this are stored raw; the codec overhead outweighs the gain
+const MIN_COMPRESS_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(super) enum FrameKind {
+    Message = 0,
+    Dictionary = 1,
+    Footer = 2,
+    /// Wrapped data key of an encrypted segment
+    Key = 3,
+}
+
+impl FrameKind {
+    fn from_u8(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(FrameKind::Message),
+            1 => Ok(FrameKind::Dictionary),
+            2 => Ok(FrameKind::Footer),
+            3 => Ok(FrameKind::Key),
+            other => Err(anyhow!("unknown frame kind {}", other)),
+        }
+    }
+}
+
+/// Codec applied to a single frame payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub(super) enum PayloadCodec {
+    #[default]
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl PayloadCodec {
+    fn from_u8(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(PayloadCodec::None),
+            1 => Ok(PayloadCodec::Lz4),
+            2 => Ok(PayloadCodec::Zstd),
+            other => Err(anyhow!("unknown payload codec {}", other)),
+        }
+    }
+}
+
+fn clock_from_u8(v: u8) -> Result<ClockSource> {
+    match v {
+        0 => Ok(ClockSource::System),
+        1 => Ok(ClockSource::RosSim),
+        2 => Ok(ClockSource::Monotonic),
+        other => Err(anyhow!("unknown clock source {}", other)),
+    }
+}
+
+/// Compress `data` with the configured codec, falling back to raw storage when
+/// compression does not shrink the payload.
+pub(super) fn compress_payload(codec: Option<CompressionCodec>, level: i32, data: &[u8]) -> Result<(PayloadCodec, Vec<u8>)> {
+    let codec = match codec {
+        Some(c) if data.len() >= MIN_COMPRESS_LEN => c,
+        _ => return Ok((PayloadCodec::None, data.to_vec())),
+    };
+    let (tag, compressed) = match codec {
+        CompressionCodec::Lz4 => (PayloadCodec::Lz4, lz4::block::compress(data, None, false)?),
+        CompressionCodec::Zstd => (PayloadCodec::Zstd, zstd::bulk::compress(data, level)?),
+    };
+    if compressed.len() >= data.len() {
+        return Ok((PayloadCodec::None, data.to_vec()));
+    }
+    Ok((tag, compressed))
+}
+
+fn decompress_payload(codec: PayloadCodec, stored: Vec<u8>, raw_len: usize) -> Result<Vec<u8>> {
+    let raw = match codec {
+        PayloadCodec::None => return Ok(stored),
+        PayloadCodec::Lz4 => lz4::block::decompress(&stored, Some(raw_len as i32))?,
+        PayloadCodec::Zstd => zstd::bulk::decompress(&stored, raw_len)?,
+    };
+    if raw.len() != raw_len {
+        return Err(anyhow!("decompressed length mismatch: expected {}, got {}", raw_len, raw.len()));
+    }
+    Ok(raw)
+}
+
+/// Header bytes between the magic and the CRC
+fn header_fields(kind: FrameKind, codec: PayloadCodec, flags: u8, body_len: usize) -> [u8; 8] {
+    let mut fields = [FRAME_VERSION, kind as u8, codec as u8, flags, 0, 0, 0, 0];
+    fields[4..].copy_from_slice(&(body_len as u32).to_le_bytes());
+    fields
+}
+
+fn encode_frame(kind: FrameKind, codec: PayloadCodec, flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + body.len());
+    buf.extend_from_slice(&RECORD_FRAME_HEADER.to_le_bytes());
+    buf.extend_from_slice(&header_fields(kind, codec, flags, body.len()));
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[4..12]);
+    hasher.update(body);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// How to encrypt a message frame: the segment's data key, the frame's place
+/// among the segment's messages and the names the frame's ids resolve to,
+/// which are authenticated along with it
+pub(super) struct Seal<'a> {
+    pub key: &'a DataKey,
+    pub counter: u64,
+    pub topic: &'a str,
+    pub namespace: &'a str,
+}
+
+/// Reject names a dictionary entry cannot hold and payloads whose length
+/// does not fit the message prefix, before anything is stored
+pub(super) fn check_message(topic: &str, namespace: &str, raw_len: usize) -> Result<()> {
+    for name in [topic, namespace] {
+        if name.len() > u16::MAX as usize {
+            return Err(anyhow!("name of {} bytes is longer than the {} a segment holds", name.len(), u16::MAX));
+        }
+    }
+    if u32::try_from(raw_len).is_err() {
+        return Err(anyhow!("payload of {} bytes is larger than the {} a segment holds", raw_len, u32::MAX));
+    }
+    Ok(())
+}
+
+/// Reject a stored payload whose frame the reader would refuse; counts the
+/// encryption overhead whether or not the segment is encrypted
+pub(super) fn check_stored_len(stored_len: usize) -> Result<()> {
+    let body_len = MESSAGE_PREFIX_LEN + stored_len + SEAL_OVERHEAD;
+    if body_len > MAX_FRAME_BODY {
+        return Err(anyhow!("message frame of {} bytes exceeds the {} byte limit", body_len, MAX_FRAME_BODY));
+    }
+    Ok(())
+}
+
+/// Encode a message frame around an already-compressed payload
+#[allow(clippy::too_many_arguments)]
+pub(super) fn encode_message(
+    topic_id: u32,
+    namespace_id: u32,
+    info: &MessageInfo,
+    codec: PayloadCodec,
+    stored: &[u8],
+    raw_len: usize,
+    flags: u8,
+    seal: Option<Seal<'_>>,
+) -> Result<Vec<u8>> {
+    let to_u64 = |ns: u128| u64::try_from(ns).map_err(|_| anyhow!("timestamp {} ns out of range", ns));
+    let mut stamps = 0;
+    if info.header_ns.is_some() {
+        stamps |= STAMP_HEADER;
+    }
+    if info.sequence.is_some() {
+        stamps |= STAMP_SEQUENCE;
+    }
+    check_stored_len(stored.len())?;
+    let raw_len = u32::try_from(raw_len).map_err(|_| anyhow!("payload of {} bytes is too large", raw_len))?;
+    let mut body = Vec::with_capacity(MESSAGE_PREFIX_LEN + stored.len() + SEAL_OVERHEAD);
+    body.extend_from_slice(&topic_id.to_le_bytes());
+    body.extend_from_slice(&namespace_id.to_le_bytes());
+    body.extend_from_slice(&to_u64(info.receive_ns)?.to_le_bytes());
+    body.extend_from_slice(&raw_len.to_le_bytes());
+    body.push(info.clock as u8);
+    body.push(stamps);
+    body.extend_from_slice(&to_u64(info.header_ns.unwrap_or(0))?.to_le_bytes());
+    body.extend_from_slice(&info.sequence.unwrap_or(0).to_le_bytes());
+    let Some(seal) = seal else {
+        body.extend_from_slice(stored);
+        return Ok(encode_frame(FrameKind::Message, codec, flags, &body));
+    };
+    let flags = flags | FLAG_ENCRYPTED;
+    let header = header_fields(FrameKind::Message, codec, flags, body.len() + stored.len() + SEAL_OVERHEAD);
+    body.extend_from_slice(&seal.counter.to_le_bytes());
+    let aad = message_aad(&header, &body, seal.key, seal.topic, seal.namespace);
+    body.extend(seal.key.seal(&aad, stored)?);
+    Ok(encode_frame(FrameKind::Message, codec, flags, &body))
+}
+
+/// Associated data of an encrypted message: header fields, message prefix
+/// and frame counter, the segment id and the resolved names
+fn message_aad(header: &[u8], prefix: &[u8], key: &DataKey, topic: &str, namespace: &str) -> Vec<u8> {
+    let segment_id = key.segment_id();
+    let mut aad =
+        Vec::with_capacity(header.len() + prefix.len() + segment_id.len() + 8 + topic.len() + namespace.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(prefix);
+    aad.extend_from_slice(segment_id);
+    for name in [topic, namespace] {
+        aad.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        aad.extend_from_slice(name.as_bytes());
+    }
+    aad
+}
+
+/// Encode the key frame that opens an encrypted segment
+pub(super) fn encode_key(body: &[u8]) -> Vec<u8> {
+    encode_frame(FrameKind::Key, PayloadCodec::None, 0, body)
+}
+
+/// Encode the footer frame that seals a segment around its JSON summary
+pub(super) fn encode_footer(summary: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(summary.len() + 4);
+    body.extend_from_slice(summary);
+    body.extend_from_slice(&(summary.len() as u32).to_le_bytes());
+    encode_frame(FrameKind::Footer, PayloadCodec::None, 0, &body)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum NameKind {
+    Topic = 0,
+    Namespace = 1,
+}
+
+/// Per-segment interning table for topic and namespace names
+#[derive(Debug, Default)]
+pub(super) struct SegmentDictionary {
+    topic_ids: HashMap<String, u32>,
+    namespace_ids: HashMap<String, u32>,
+    topics: HashMap<u32, String>,
+    namespaces: HashMap<u32, String>,
+}
+
+impl SegmentDictionary {
+    /// Look up or assign ids for a topic/namespace pair. Returns a dictionary
+    /// frame to write ahead of the message when either name is new.
+    pub(super) fn intern(&mut self, topic: &str, namespace: &str) -> (u32, u32, Option<Vec<u8>>) {
+        let mut body = Vec::new();
+        let topic_id = match self.topic_ids.get(topic) {
+            Some(id) => *id,
+            None => {
+                let id = self.topics.len() as u32;
+                self.declare(NameKind::Topic, id, topic.to_string());
+                encode_entry(&mut body, NameKind::Topic, id, topic);
+                id
+            }
+        };
+        let namespace_id = match self.namespace_ids.get(namespace) {
+            Some(id) => *id,
+            None => {
+                let id = self.namespaces.len() as u32;
+                self.declare(NameKind::Namespace, id, namespace.to_string());
+                encode_entry(&mut body, NameKind::Namespace, id, namespace);
+                id
+            }
+        };
+        let frame = (!body.is_empty()).then(|| encode_frame(FrameKind::Dictionary, PayloadCodec::None, 0, &body));
+        (topic_id, namespace_id, frame)
+    }
+
+    /// Size of the dictionary frame `intern` would emit for this pair
+    pub(super) fn declaration_len(&self, topic: &str, namespace: &str) -> usize {
+        let mut body_len = 0;
+        if !self.topic_ids.contains_key(topic) {
+            body_len += 7 + topic.len();
+        }
+        if !self.namespace_ids.contains_key(namespace) {
+            body_len += 7 + namespace.len();
+        }
+        if body_len == 0 {
+            0
+        } else {
+            FRAME_HEADER_LEN + body_len
+        }
+    }
+
+    /// Declared `id -> name` tables for topics and namespaces
+    pub(super) fn names(&self) -> (BTreeMap<u32, String>, BTreeMap<u32, String>) {
+        let topics = self.topics.iter().map(|(id, name)| (*id, name.clone())).collect();
+        let namespaces = self.namespaces.iter().map(|(id, name)| (*id, name.clone())).collect();
+        (topics, namespaces)
+    }
+
+    /// Rebuild a dictionary from tables saved by `names`
+    pub(super) fn from_names(topics: &BTreeMap<u32, String>, namespaces: &BTreeMap<u32, String>) -> Self {
+        let mut dictionary = SegmentDictionary::default();
+        for (id, name) in topics {
+            dictionary.declare(NameKind::Topic, *id, name.clone());
+        }
+        for (id, name) in namespaces {
+            dictionary.declare(NameKind::Namespace, *id, name.clone());
+        }
+        dictionary
+    }
+
+    fn declare(&mut self, kind: NameKind, id: u32, name: String) {
+        match kind {
+            NameKind::Topic => {
+                self.topic_ids.insert(name.clone(), id);
+                self.topics.insert(id, name);
+            }
+            NameKind::Namespace => {
+                self.namespace_ids.insert(name.clone(), id);
+                self.namespaces.insert(id, name);
+            }
+        }
+    }
+
+    pub(super) fn apply(&mut self, mut body: &[u8]) -> Result<()> {
+        while !body.is_empty() {
+            if body.len() < 7 {
+                return Err(anyhow!("truncated dictionary entry"));
+            }
+            let kind = match body[0] {
+                0 => NameKind::Topic,
+                1 => NameKind::Namespace,
+                other => return Err(anyhow!("unknown dictionary entry kind {}", other)),
+            };
+            let id = u32::from_le_bytes(body[1..5].try_into()?);
+            let len = u16::from_le_bytes(body[5..7].try_into()?) as usize;
+            let name = body
+                .get(7..7 + len)
+                .ok_or_else(|| anyhow!("truncated dictionary entry"))?;
+            self.declare(kind, id, String::from_utf8(name.to_vec())?);
+            body = &body[7 + len..];
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, topic_id: u32, namespace_id: u32) -> Result<(String, String)> {
+        let topic = self
+            .topics
+            .get(&topic_id)
+            .ok_or_else(|| anyhow!("undeclared topic id {}", topic_id))?;
+        let namespace = self
+            .namespaces
+            .get(&namespace_id)
+            .ok_or_else(|| anyhow!("undeclared namespace id {}", namespace_id))?;
+        Ok((topic.clone(), namespace.clone()))
+    }
+}
+
+fn encode_entry(body: &mut Vec<u8>, kind: NameKind, id: u32, name: &str) {
+    body.push(kind as u8);
+    body.extend_from_slice(&id.to_le_bytes());
+    body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    body.extend_from_slice(name.as_bytes());
+}
+
+/// Result of validating the bytes at the start of a buffer as one frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FrameCheck {
+    /// A complete frame of `len` bytes whose CRC matches; `kind` is `None` for legacy frames
+    Valid { len: usize, kind: Option<FrameKind> },
+    /// Plausible frame start, but the buffer ends before the frame does
+    Truncated,
+    /// Not a frame: unknown magic, malformed header or CRC mismatch
+    Corrupt,
+}
+
+/// Validate the frame starting at `buf[0]` without decoding its payload
+pub(super) fn check_frame(buf: &[u8]) -> FrameCheck {
+    if buf.len() < 4 {
+        return FrameCheck::Truncated;
+    }
+    match u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) {
+        RECORD_FRAME_HEADER => check_binary(buf),
+        LEGACY_FRAME_HEADER => check_legacy(buf),
+        _ => FrameCheck::Corrupt,
+    }
+}
+
+fn check_binary(buf: &[u8]) -> FrameCheck {
+    if buf.len() < FRAME_HEADER_LEN {
+        return FrameCheck::Truncated;
+    }
+    let kind = match FrameKind::from_u8(buf[5]) {
+        Ok(kind) => kind,
+        Err(_) => return FrameCheck::Corrupt,
+    };
+    if !matches!(buf[4], FRAME_VERSION | FRAME_VERSION_V1) || PayloadCodec::from_u8(buf[6]).is_err() {
+        return FrameCheck::Corrupt;
+    }
+    let body_len = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize;
+    if body_len > MAX_FRAME_BODY {
+        return FrameCheck::Corrupt;
+    }
+    let len = FRAME_HEADER_LEN + body_len;
+    if buf.len() < len {
+        return FrameCheck::Truncated;
+    }
+    let expected_crc = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[4..12]);
+    hasher.update(&buf[FRAME_HEADER_LEN..len]);
+    if hasher.finalize() != expected_crc {
+        return FrameCheck::Corrupt;
+    }
+    FrameCheck::Valid { len, kind: Some(kind) }
+}
+
+fn check_legacy(buf: &[u8]) -> FrameCheck {
+    if buf.len() < 8 {
+        return FrameCheck::Truncated;
+    }
+    let meta_len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+    if meta_len > MAX_FRAME_BODY {
+        return FrameCheck::Corrupt;
+    }
+    let Some(meta) = buf.get(8..8 + meta_len) else {
+        return FrameCheck::Truncated;
+    };
+    let frame: LegacyFrame = match serde_json::from_slice(meta) {
+        Ok(frame) => frame,
+        Err(_) => return FrameCheck::Corrupt,
+    };
+    let len = 8 + meta_len + frame.payload_len as usize;
+    let Some(payload) = buf.get(8 + meta_len..len) else {
+        return FrameCheck::Truncated;
+    };
+    if crc32fast::hash(payload) != frame.payload_crc32 {
+        return FrameCheck::Corrupt;
+    }
+    FrameCheck::Valid { len, kind: None }
+}
+
+/// Fill `buf` with the next frame magic. `false` at a clean end of the
+/// stream, an error if it ends inside the magic.
+fn read_magic(reader: &mut dyn Read, buf: &mut [u8; 4]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(anyhow!("segment ends inside a frame magic")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Offset of the next valid frame at or after `from`, found by scanning for a magic
+pub(super) fn find_next_frame(buf: &[u8], from: usize) -> Option<usize> {
+    let magics = [RECORD_FRAME_HEADER.to_le_bytes(), LEGACY_FRAME_HEADER.to_le_bytes()];
+    (from..buf.len().saturating_sub(3)).find(|&offset| {
+        magics.iter().any(|m| buf[offset..offset + 4] == *m)
+            && matches!(check_frame(&buf[offset..]), FrameCheck::Valid { .. })
+    })
+}
+
+/// JSON metadata of frames written before the binary layout
+#[derive(Debug, Deserialize)]
+struct LegacyFrame {
+    timestamp: u128,
+    topic: String,
+    namespace: String,
+    payload_len: u32,
+    payload_crc32: u32,
+    #[serde(default)]
+    codec: PayloadCodec,
+    #[serde(default)]
+    raw_len: u32,
+}
+
+/// Sequential frame decoder that tracks the segment dictionary and, for
+/// encrypted segments, the data key
+#[derive(Debug, Default)]
+pub(super) struct FrameDecoder {
+    dictionary: SegmentDictionary,
+    keys: Keyring,
+    data_key: Option<DataKey>,
+    /// Skip payload decryption and decompression; records carry no payload
+    headers_only: bool,
+    /// Blob directory blob references are resolved against
+    blob_dir: Option<PathBuf>,
+    /// Blob referenced by the last message decoded, if any
+    last_blob: Option<[u8; BLOB_REF_LEN]>,
+    /// Frame counter the next encrypted message should carry
+    next_counter: Option<u64>,
+    /// Expected and actual counter of the last message decoded, if they differ
+    last_gap: Option<(u64, u64)>,
+}
+
+impl FrameDecoder {
+    /// Decoder that starts mid-segment with the names declared before that point
+    pub(super) fn with_dictionary(dictionary: SegmentDictionary) -> Self {
+        FrameDecoder { dictionary, ..Default::default() }
+    }
+
+    /// Master keys used to open the segment's key frame
+    pub(super) fn with_keys(mut self, keys: Keyring) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    pub(super) fn headers_only(mut self) -> Self {
+        self.headers_only = true;
+        self
+    }
+
+    pub(super) fn with_blobs(mut self, dir: PathBuf) -> Self {
+        self.blob_dir = Some(dir);
+        self
+    }
+
+    pub(super) fn last_blob(&self) -> Option<[u8; BLOB_REF_LEN]> {
+        self.last_blob
+    }
+
+    /// (expected, found) when the last message decoded broke the segment's
+    /// frame counter: frames before it were dropped, or it repeats or
+    /// precedes one already read
+    pub(super) fn last_gap(&self) -> Option<(u64, u64)> {
+        self.last_gap
+    }
+
+    /// Unwrap the data key of a key frame body. Decoders that start
+    /// mid-segment call this with the segment's first frame.
+    pub(super) fn load_key(&mut self, body: &[u8]) -> Result<()> {
+        self.next_counter = None;
+        if !self.headers_only {
+            self.data_key = Some(DataKey::open(&self.keys, body)?);
+        }
+        Ok(())
+    }
+
+    pub(super) fn into_dictionary(self) -> SegmentDictionary {
+        self.dictionary
+    }
+
+    /// Read the next message, consuming any dictionary frames before it.
+    /// `None` only at a clean end of the stream; a partial or unknown magic
+    /// is an error.
+    pub(super) fn next_record(&mut self, reader: &mut dyn Read) -> Result<Option<Record>> {
+        loop {
+            let mut magic_buf = [0u8; 4];
+            if !read_magic(reader, &mut magic_buf)? {
+                return Ok(None);
+            }
+            match u32::from_le_bytes(magic_buf) {
+                RECORD_FRAME_HEADER => {
+                    if let Some(record) = self.read_binary(reader)? {
+                        return Ok(Some(record));
+                    }
+                }
+                LEGACY_FRAME_HEADER => return Self::read_legacy(reader).map(Some),
+                magic => return Err(anyhow!("unknown frame magic {:#010x}", magic)),
+            }
+        }
+    }
+
+    fn read_binary(&mut self, reader: &mut dyn Read) -> Result<Option<Record>> {
+        let mut header = [0u8; FRAME_HEADER_LEN - 4];
+        reader.read_exact(&mut header)?;
+        let version = header[0];
+        if !matches!(version, FRAME_VERSION | FRAME_VERSION_V1) {
+            return Err(anyhow!("unsupported frame version {}", version));
+        }
+        let kind = FrameKind::from_u8(header[1])?;
+        let codec = PayloadCodec::from_u8(header[2])?;
+        let flags = header[3];
+        let body_len = u32::from_le_bytes(header[4..8].try_into()?) as usize;
+        let expected_crc = u32::from_le_bytes(header[8..12].try_into()?);
+        if body_len > MAX_FRAME_BODY {
+            return Err(anyhow!("frame body length {} exceeds limit", body_len));
+        }
+
+        let mut body = vec![0u8; body_len];
+        reader.read_exact(&mut body)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[..8]);
+        hasher.update(&body);
+        let crc = hasher.finalize();
+        if crc != expected_crc {
+            return Err(anyhow!("frame CRC mismatch: expected {}, got {}", expected_crc, crc));
+        }
+
+        match kind {
+            FrameKind::Dictionary => {
+                self.dictionary.apply(&body)?;
+                Ok(None)
+            }
+            FrameKind::Footer => Ok(None),
+            FrameKind::Key => {
+                self.load_key(&body)?;
+                Ok(None)
+            }
+            FrameKind::Message => {
+                let prefix_len = match version {
+                    FRAME_VERSION_V1 => MESSAGE_PREFIX_LEN_V1,
+                    _ => MESSAGE_PREFIX_LEN,
+                };
+                if body.len() < prefix_len {
+                    return Err(anyhow!("message frame too short: {} bytes", body.len()));
+                }
+                let topic_id = u32::from_le_bytes(body[0..4].try_into()?);
+                let namespace_id = u32::from_le_bytes(body[4..8].try_into()?);
+                let receive_time = u64::from_le_bytes(body[8..16].try_into()?) as u128;
+                let raw_len = u32::from_le_bytes(body[16..20].try_into()?) as usize;
+                let info = match version {
+                    FRAME_VERSION_V1 => MessageInfo::received(receive_time * NANOS_PER_MILLI),
+                    _ => {
+                        let stamps = body[21];
+                        let header_ns = u64::from_le_bytes(body[22..30].try_into()?) as u128;
+                        let sequence = u64::from_le_bytes(body[30..38].try_into()?);
+                        MessageInfo {
+                            receive_ns: receive_time,
+                            header_ns: (stamps & STAMP_HEADER != 0).then_some(header_ns),
+                            sequence: (stamps & STAMP_SEQUENCE != 0).then_some(sequence),
+                            clock: clock_from_u8(body[20])?,
+                        }
+                    }
+                };
+                let (topic, namespace) = self.dictionary.resolve(topic_id, namespace_id)?;
+                // Blob references are never encrypted (see `blob`), so they are
+                // readable even when only headers are decoded
+                self.last_blob = None;
+                if flags & FLAG_BLOB != 0 && flags & FLAG_ENCRYPTED == 0 {
+                    let id = body
+                        .get(prefix_len..)
+                        .and_then(|id| <[u8; BLOB_REF_LEN]>::try_from(id).ok())
+                        .ok_or_else(|| anyhow!("malformed blob reference"))?;
+                    self.last_blob = Some(id);
+                }
+                self.last_gap = None;
+                if flags & FLAG_ENCRYPTED != 0 {
+                    let counter = body
+                        .get(prefix_len..prefix_len + COUNTER_LEN)
+                        .and_then(|counter| counter.try_into().ok())
+                        .map(u64::from_le_bytes)
+                        .ok_or_else(|| anyhow!("encrypted message frame too short: {} bytes", body.len()))?;
+                    // Decoders that start mid-segment pick the count up at their first message
+                    if let Some(expected) = self.next_counter.filter(|expected| *expected != counter) {
+                        self.last_gap = Some((expected, counter));
+                    }
+                    self.next_counter = Some(counter + 1);
+                }
+                if self.headers_only {
+                    return Ok(Some(Record { topic, namespace, info, payload: Vec::new() }));
+                }
+                let encrypted = flags & FLAG_ENCRYPTED != 0;
+                let mut stored = body.split_off(prefix_len + if encrypted { COUNTER_LEN } else { 0 });
+                if encrypted {
+                    let key = self
+                        .data_key
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("encrypted message before the segment's key frame"))?;
+                    stored = key.unseal(&message_aad(&header[..8], &body, key, &topic, &namespace), &stored)?;
+                    if let Some((expected, found)) = self.last_gap {
+                        tracing::warn!("encrypted message {} read where message {} was expected", found, expected);
+                    }
+                }
+                if flags & FLAG_BLOB != 0 {
+                    let dir = self.blob_dir.as_ref().ok_or_else(|| anyhow!("message stored in a blob store"))?;
+                    let payload = blob::read_blob(dir, &stored, raw_len)?;
+                    return Ok(Some(Record { topic, namespace, info, payload }));
+                }
+                let payload = decompress_payload(codec, stored, raw_len)?;
+                Ok(Some(Record { topic, namespace, info, payload }))
+            }
+        }
+    }
+
+    fn read_legacy(reader: &mut dyn Read) -> Result<Record> {
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf)?;
+        let meta_len = u32::from_le_bytes(len_buf) as usize;
+        if meta_len > MAX_FRAME_BODY {
+            return Err(anyhow!("legacy metadata length {} exceeds limit", meta_len));
+        }
+
+        let mut meta_buf = vec![0u8; meta_len];
+        reader.read_exact(&mut meta_buf)?;
+        let frame: LegacyFrame = serde_json::from_slice(&meta_buf)?;
+
+        let mut payload = vec![0u8; frame.payload_len as usize];
+        reader.read_exact(&mut payload)?;
+
+        let crc = crc32fast::hash(&payload);
+        if crc != frame.payload_crc32 {
+            return Err(anyhow!("payload CRC mismatch: expected {}, got {}", frame.payload_crc32, crc));
+        }
+
+        let payload = decompress_payload(frame.codec, payload, frame.raw_len as usize)?;
+        Ok(Record {
+            topic: frame.topic,
+            namespace: frame.namespace,
+            info: MessageInfo::received(frame.timestamp * NANOS_PER_MILLI),
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Result<Vec<Record>> {
+        let mut reader = bytes;
+        let mut decoder = FrameDecoder::default();
+        let mut out = Vec::new();
+        while let Some(record) = decoder.next_record(&mut reader)? {
+            out.push(record);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_dictionary_declared_once_per_name() -> Result<()> {
+        let mut dict = SegmentDictionary::default();
+        let (t0, n0, first) = dict.intern("/tf", "robot1");
+        let (t1, n1, second) = dict.intern("/tf", "robot1");
+        let (t2, n2, third) = dict.intern("/odometry", "robot1");
+
+        assert!(first.is_some());
+        assert!(second.is_none());
+        assert!(third.is_some());
+        assert_eq!((t0, n0), (t1, n1));
+        assert_ne!(t0, t2);
+        assert_eq!(n0, n2);
+
+        let mut bytes = first.unwrap();
+        let info = MessageInfo::received(7).with_header_stamp(5).with_sequence(41).with_clock(ClockSource::RosSim);
+        bytes.extend(encode_message(t0, n0, &info, PayloadCodec::None, b"a", 1, 0, None)?);
+        bytes.extend(third.unwrap());
+        bytes.extend(encode_message(t2, n2, &MessageInfo::received(8), PayloadCodec::None, b"b", 1, 0, None)?);
+
+        let records = decode_all(&bytes)?;
+        assert_eq!(records[0].topic, "/tf");
+        assert_eq!(records[1].topic, "/odometry");
+        assert_eq!(records[1].namespace, "robot1");
+        assert_eq!(records[0].info, info);
+        assert_eq!(records[1].info, MessageInfo::received(8));
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames_the_reader_rejects_are_not_written() {
+        let largest = MAX_FRAME_BODY - MESSAGE_PREFIX_LEN - SEAL_OVERHEAD;
+        assert!(check_stored_len(largest).is_ok());
+        assert!(check_stored_len(largest + 1).is_err());
+        assert!(check_message("/tf", "robot1", u32::MAX as usize).is_ok());
+        assert!(check_message("/tf", "robot1", u32::MAX as usize + 1).is_err());
+    }
+
+    #[test]
+    fn test_version_1_frames_read_as_nanoseconds() -> Result<()> {
+        let mut dict = SegmentDictionary::default();
+        let (t, n, declare) = dict.intern("/imu", "robot1");
+        let mut body = Vec::new();
+        body.extend_from_slice(&t.to_le_bytes());
+        body.extend_from_slice(&n.to_le_bytes());
+        body.extend_from_slice(&1_700_000_000_123u64.to_le_bytes());
+        body.extend_from_slice(&6u32.to_le_bytes());
+        body.extend_from_slice(b"sample");
+        let mut frame = encode_frame(FrameKind::Message, PayloadCodec::None, 0, &body);
+        frame[4] = FRAME_VERSION_V1;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&frame[4..12]);
+        hasher.update(&body);
+        frame[12..16].copy_from_slice(&hasher.finalize().to_le_bytes());
+
+        let mut bytes = declare.unwrap();
+        bytes.extend(frame);
+        let records = decode_all(&bytes)?;
+        assert_eq!(records[0].info, MessageInfo::received(1_700_000_000_123_000_000));
+        assert_eq!(records[0].payload, b"sample");
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_length_fails_crc() -> Result<()> {
+        let mut dict = SegmentDictionary::default();
+        let (t, n, declare) = dict.intern("/imu", "robot1");
+        let mut bytes = declare.unwrap();
+        let msg_start = bytes.len();
+        bytes.extend(encode_message(t, n, &MessageInfo::received(1), PayloadCodec::None, b"sample", 6, 0, None)?);
+        bytes.extend_from_slice(&[0u8; 8]);
+        // Shrink body_len by one byte; the header CRC must reject it
+        bytes[msg_start + 8] -= 1;
+
+        assert!(decode_all(&bytes).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_magic_is_an_error_not_eof() -> Result<()> {
+        let mut dict = SegmentDictionary::default();
+        let (t, n, declare) = dict.intern("/imu", "robot1");
+        let mut bytes = declare.unwrap();
+        let mut starts = Vec::new();
+        for (i, payload) in [b"first", b"midst", b"final"].iter().enumerate() {
+            starts.push(bytes.len());
+            bytes.extend(encode_message(t, n, &MessageInfo::received(i as u128), PayloadCodec::None, *payload, 5, 0, None)?);
+        }
+        assert_eq!(decode_all(&bytes)?.len(), 3);
+
+        bytes[starts[1]] ^= 0xff;
+        let mut reader = bytes.as_slice();
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(decoder.next_record(&mut reader)?.unwrap().payload, b"first");
+        let err = decoder.next_record(&mut reader).unwrap_err();
+        assert!(format!("{:#}", err).contains("unknown frame magic"), "{:#}", err);
+
+        // A stream cut inside the magic is damage too
+        let mut reader = &bytes[..starts[1] + 2];
+        let mut decoder = FrameDecoder::default();
+        decoder.next_record(&mut reader)?;
+        assert!(decoder.next_record(&mut reader).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+//! Sidecar indexes for sealed segments.
+//!
+//! When a segment is sealed the writer stores `segment-N.idx` next to it: the
+//! offset of every Nth message, the segment's time span, and per topic the
+//! message count, time span and the offset of every Nth message of that
+//! topic. The index also carries the segment dictionary so a reader can start
+//! decoding mid-segment without the dictionary frames before that point, and
+//! the blob references of the segment for the blob store's reference counts.
+//!
+//! Indexes are only an accelerator. A missing, stale or unreadable sidecar is
+//! rebuilt by scanning the segment.
+
+use super::blob;
+use super::frame::SegmentDictionary;
+use super::reader::SegmentReader;
+use super::{segment_number, Record};
+use crate::security::Keyring;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever the sidecar layout changes; older sidecars are rebuilt.
+/// Version 2 indexes receive times in nanoseconds instead of milliseconds.
+pub(super) const INDEX_VERSION: u32 = 2;
+
+/// Sidecar path for a segment: `segment-N.log` -> `segment-N.idx`
+pub fn index_path(segment: &Path) -> PathBuf {
+    segment.with_extension("idx")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Frame boundary at or before the message (dictionary frames included)
+    pub offset: u64,
+    /// Receive time in nanoseconds
+    pub timestamp: u128,
+}
+
+/// Time span and sparse offsets of a run of messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeIndex {
+    pub count: u64,
+    pub min_timestamp: u128,
+    pub max_timestamp: u128,
+    pub last_offset: u64,
+    /// Timestamps never decrease, so `entries` can be bisected by time
+    pub ordered: bool,
+    /// Every Nth message, starting with the first
+    pub entries: Vec<IndexEntry>,
+}
+
+impl TimeIndex {
+    fn new(offset: u64, timestamp: u128) -> Self {
+        TimeIndex {
+            count: 1,
+            min_timestamp: timestamp,
+            max_timestamp: timestamp,
+            last_offset: offset,
+            ordered: true,
+            entries: vec![IndexEntry { offset, timestamp }],
+        }
+    }
+
+    fn observe(&mut self, stride: usize, offset: u64, timestamp: u128) {
+        if timestamp < self.max_timestamp {
+            self.ordered = false;
+        }
+        if self.count.is_multiple_of(stride as u64) {
+            self.entries.push(IndexEntry { offset, timestamp });
+        }
+        self.count += 1;
+        self.min_timestamp = self.min_timestamp.min(timestamp);
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+        self.last_offset = offset;
+    }
+
+    /// Byte range holding every message of this run within `[t_start, t_end)`
+    fn span(&self, t_start: u128, t_end: u128) -> Option<Range<u64>> {
+        if self.max_timestamp < t_start || self.min_timestamp >= t_end {
+            return None;
+        }
+        let first = self.entries[0].offset;
+        if !self.ordered {
+            return Some(first..self.last_offset + 1);
+        }
+        let before = self.entries.partition_point(|e| e.timestamp < t_start);
+        let start = if before == 0 { first } else { self.entries[before - 1].offset };
+        let after = self.entries.partition_point(|e| e.timestamp < t_end);
+        let end = self.entries.get(after).map_or(self.last_offset + 1, |e| e.offset);
+        Some(start..end)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexDictionary {
+    pub topics: BTreeMap<u32, String>,
+    pub namespaces: BTreeMap<u32, String>,
+}
+
+/// Contents of a `segment-N.idx` sidecar
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentIndex {
+    pub version: u32,
+    /// Segment number; 0 for partitions of a compacted batch
+    pub segment: u64,
+    /// Segment length the index was built from; a mismatch marks it stale
+    pub size_bytes: u64,
+    pub stride: usize,
+    /// All messages of the segment; `None` when it is empty
+    pub messages: Option<TimeIndex>,
+    pub topics: BTreeMap<String, TimeIndex>,
+    pub dictionary: IndexDictionary,
+    /// Reference count per blob digest (hex); older sidecars have none
+    #[serde(default)]
+    pub blobs: BTreeMap<String, u64>,
+}
+
+impl SegmentIndex {
+    /// Byte range to decode for `topics` (all topics when empty) within
+    /// `[t_start, t_end)`, or `None` when the segment holds no match
+    pub fn span(&self, topics: &[String], t_start: u128, t_end: u128) -> Option<Range<u64>> {
+        if topics.is_empty() {
+            return self.messages.as_ref()?.span(t_start, t_end);
+        }
+        topics
+            .iter()
+            .filter_map(|topic| self.topics.get(topic)?.span(t_start, t_end))
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+    }
+
+    fn segment_dictionary(&self) -> SegmentDictionary {
+        SegmentDictionary::from_names(&self.dictionary.topics, &self.dictionary.namespaces)
+    }
+}
+
+/// Accumulates the index of a segment as its messages are written or scanned
+#[derive(Debug)]
+pub(super) struct IndexBuilder {
+    stride: usize,
+    messages: Option<TimeIndex>,
+    topics: BTreeMap<String, TimeIndex>,
+    blobs: BTreeMap<String, u64>,
+}
+
+impl IndexBuilder {
+    pub(super) fn new(stride: usize) -> Self {
+        IndexBuilder { stride: stride.max(1), messages: None, topics: BTreeMap::new(), blobs: BTreeMap::new() }
+    }
+
+    /// Count a blob reference of the segment
+    pub(super) fn observe_blob(&mut self, id: &[u8]) {
+        *self.blobs.entry(blob::to_hex(id)).or_default() += 1;
+    }
+
+    pub(super) fn blobs(&self) -> &BTreeMap<String, u64> {
+        &self.blobs
+    }
+
+    /// Index a message whose frames start at `offset`
+    pub(super) fn observe(&mut self, offset: u64, topic: &str, timestamp: u128) {
+        match self.messages.as_mut() {
+            Some(messages) => messages.observe(self.stride, offset, timestamp),
+            None => self.messages = Some(TimeIndex::new(offset, timestamp)),
+        }
+        match self.topics.get_mut(topic) {
+            Some(index) => index.observe(self.stride, offset, timestamp),
+            None => {
+                self.topics.insert(topic.to_string(), TimeIndex::new(offset, timestamp));
+            }
+        }
+    }
+
+    pub(super) fn message_count(&self) -> u64 {
+        self.messages.as_ref().map_or(0, |m| m.count)
+    }
+
+    pub(super) fn time_span(&self) -> Option<(u128, u128)> {
+        self.messages.as_ref().map(|m| (m.min_timestamp, m.max_timestamp))
+    }
+
+    pub(super) fn topics(&self) -> Vec<String> {
+        self.topics.keys().cloned().collect()
+    }
+
+    pub(super) fn finish(self, segment: u64, size_bytes: u64, dictionary: &SegmentDictionary) -> SegmentIndex {
+        let (topics, namespaces) = dictionary.names();
+        SegmentIndex {
+            version: INDEX_VERSION,
+            segment,
+            size_bytes,
+            stride: self.stride,
+            messages: self.messages,
+            topics: self.topics,
+            dictionary: IndexDictionary { topics, namespaces },
+            blobs: self.blobs,
+        }
+    }
+
+    /// Index every record of an existing segment
+    pub(super) fn scan(path: &Path, stride: usize) -> Result<(Self, SegmentDictionary)> {
+        let mut builder = IndexBuilder::new(stride);
+        let mut reader = SegmentReader::open(path)?.headers_only();
+        loop {
+            let offset = reader.offset();
+            match reader.next() {
+                Some(record) => {
+                    let record = record?;
+                    builder.observe(offset, &record.topic, record.info.receive_ns);
+                    if let Some(id) = reader.last_blob() {
+                        builder.observe_blob(&id);
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok((builder, reader.into_dictionary()))
+    }
+}
+
+/// Build the index of a sealed segment or compacted partition by scanning it
+pub(super) fn rebuild(path: &Path, stride: usize) -> Result<SegmentIndex> {
+    let segment = segment_number(path).unwrap_or_default();
+    let size_bytes = std::fs::metadata(path)?.len();
+    let (builder, dictionary) = IndexBuilder::scan(path, stride)?;
+    Ok(builder.finish(segment, size_bytes, &dictionary))
+}
+
+/// Write a sidecar atomically next to its segment
+pub(super) fn write(segment_path: &Path, index: &SegmentIndex) -> Result<()> {
+    let path = index_path(segment_path);
+    let tmp_path = path.with_extension("idx.tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(index)?)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+fn read(segment_path: &Path) -> Result<Option<SegmentIndex>> {
+    match std::fs::read(index_path(segment_path)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load the sidecar of a sealed segment, rebuilding it when it is missing,
+/// unreadable or does not match the segment
+pub(super) fn load_or_rebuild(path: &Path, stride: usize) -> Result<SegmentIndex> {
+    let size_bytes = std::fs::metadata(path)
+        .with_context(|| format!("opening segment {}", path.display()))?
+        .len();
+    match read(path) {
+        Ok(Some(index)) if index.version == INDEX_VERSION && index.size_bytes == size_bytes => return Ok(index),
+        Ok(Some(_)) => tracing::info!("index of {} is stale, rebuilding", path.display()),
+        Ok(None) => tracing::info!("index of {} is missing, rebuilding", path.display()),
+        Err(e) => tracing::warn!("index of {} is unreadable ({:#}), rebuilding", path.display(), e),
+    }
+    let index = rebuild(path, stride).with_context(|| format!("indexing segment {}", path.display()))?;
+    if let Err(e) = write(path, &index) {
+        tracing::warn!("could not save index of {}: {:#}", path.display(), e);
+    }
+    Ok(index)
+}
+
+/// Records of `topics` (all when empty) within `[t_start, t_end)`, in file
+/// order. Without an index the whole segment is scanned.
+pub(super) fn read_span(
+    path: &Path,
+    index: Option<&SegmentIndex>,
+    keys: &Keyring,
+    topics: &[String],
+    t_start: u128,
+    t_end: u128,
+) -> Result<Vec<Record>> {
+    let (mut reader, end) = match index {
+        Some(index) => match index.span(topics, t_start, t_end) {
+            Some(span) => (SegmentReader::open_at(path, span.start, index.segment_dictionary(), keys)?, span.end),
+            None => return Ok(Vec::new()),
+        },
+        None => (SegmentReader::open_with_keys(path, keys)?, u64::MAX),
+    };
+    let mut out = Vec::new();
+    while reader.offset() < end {
+        let Some(record) = reader.next() else { break };
+        let record = record?;
+        if matches_range(&record, topics, t_start, t_end) {
+            out.push(record);
+        }
+    }
+    Ok(out)
+}
+
+pub(super) fn matches_range(record: &Record, topics: &[String], t_start: u128, t_end: u128) -> bool {
+    (topics.is_empty() || topics.contains(&record.topic))
+        && record.info.receive_ns >= t_start
+        && record.info.receive_ns < t_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_bisects_ordered_topics() {
+        let mut builder = IndexBuilder::new(2);
+        for ts in 0..10u128 {
+            let topic = if ts % 2 == 0 { "/tf" } else { "/odometry" };
+            builder.observe(ts as u64 * 100, topic, ts);
+        }
+        builder.observe(1000, "/scan", 3);
+        builder.observe(1100, "/scan", 1);
+        let index = builder.finish(0, 1200, &SegmentDictionary::default());
+
+        let odometry = &index.topics["/odometry"];
+        assert_eq!(odometry.count, 5);
+        assert_eq!(odometry.entries.iter().map(|e| e.timestamp).collect::<Vec<_>>(), vec![1, 5, 9]);
+
+        // Starts at the last entry before t_start, stops at the first entry at or past t_end
+        let topics = vec!["/odometry".to_string()];
+        assert_eq!(index.span(&topics, 6, 8), Some(500..900));
+        assert_eq!(index.span(&topics, 0, 2), Some(100..500));
+        assert_eq!(index.span(&topics, 10, 20), None);
+
+        // Out-of-order timestamps fall back to the topic's full extent
+        let scan = vec!["/scan".to_string()];
+        assert!(!index.topics["/scan"].ordered);
+        assert_eq!(index.span(&scan, 2, 3), Some(1000..1101));
+
+        assert!(!index.messages.as_ref().unwrap().ordered);
+        assert_eq!(index.span(&[], 0, 1), Some(0..1101));
+        let both = vec!["/odometry".to_string(), "/tf".to_string()];
+        assert_eq!(index.span(&both, 3, 4), Some(0..500));
+    }
+}
@@ -0,0 +1,920 @@
+//! MCAP files (https://mcap.dev/spec), written and read without external
+//! crates.
+//!
+//! A file is `magic, Header, data section, DataEnd, summary, summary offsets,
+//! Footer, magic`. Messages are collected into chunks of `chunk_size`
+//! uncompressed bytes, compressed with the storage codec, and every chunk is
+//! followed by one MessageIndex per channel in it. The summary repeats the
+//! schemas and channels and adds Statistics and a ChunkIndex per chunk, so
+//! readers can skip to a time range without scanning the data section.
+//!
+//! Each (topic, namespace) pair is one channel; the namespace is kept in the
+//! channel metadata. The log time is the receive time, the publish time the
+//! header stamp (the receive time for messages without one) and the sequence
+//! the publisher's sequence number, truncated to 32 bits, or a per-channel
+//! counter. MCAP has no field for the clock source; records read back are on
+//! the system clock. Topics with a known message type get a `ros2msg` schema
+//! carrying the type name only.
+
+use super::{MessageInfo, Record};
+use crate::config::CompressionCodec;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_CHUNK: u8 = 0x06;
+const OP_MESSAGE_INDEX: u8 = 0x07;
+const OP_CHUNK_INDEX: u8 = 0x08;
+const OP_STATISTICS: u8 = 0x0B;
+const OP_SUMMARY_OFFSET: u8 = 0x0E;
+const OP_DATA_END: u8 = 0x0F;
+
+/// Opcode and length in front of every record
+const RECORD_PREFIX_LEN: usize = 9;
+const PROFILE: &str = "ros2";
+const LIBRARY: &str = concat!("rust_ros2_recorder ", env!("CARGO_PKG_VERSION"));
+const MESSAGE_ENCODING: &str = "cdr";
+const SCHEMA_ENCODING: &str = "ros2msg";
+/// Channel metadata key holding the record namespace
+const NAMESPACE_KEY: &str = "namespace";
+
+/// How a writer lays out chunks
+#[derive(Debug, Clone, Copy)]
+pub struct McapOptions {
+    pub compression: Option<CompressionCodec>,
+    pub compression_level: i32,
+    pub chunk_size: usize,
+}
+
+/// What the summary section says about a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct McapSummary {
+    pub message_count: u64,
+    pub schema_count: u16,
+    pub channel_count: u32,
+    pub chunk_count: u32,
+    pub message_start_time: u64,
+    pub message_end_time: u64,
+    /// Topic and namespace of every channel, by channel id
+    pub channels: BTreeMap<u16, (String, String)>,
+    pub channel_message_counts: BTreeMap<u16, u64>,
+    pub chunk_indexes: Vec<ChunkIndex>,
+}
+
+/// Where a chunk is and which times it covers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkIndex {
+    pub message_start_time: u64,
+    pub message_end_time: u64,
+    pub chunk_start_offset: u64,
+    pub chunk_length: u64,
+    /// Offset of each channel's MessageIndex after the chunk
+    pub message_index_offsets: BTreeMap<u16, u64>,
+    pub compression: String,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, b: &[u8]) {
+    put_u32(buf, b.len() as u32);
+    buf.extend_from_slice(b);
+}
+
+fn put_record(buf: &mut Vec<u8>, op: u8, body: &[u8]) {
+    buf.push(op);
+    put_u64(buf, body.len() as u64);
+    buf.extend_from_slice(body);
+}
+
+fn to_mcap_time(ns: u128) -> Result<u64> {
+    u64::try_from(ns).map_err(|_| anyhow!("timestamp {} ns does not fit an MCAP time", ns))
+}
+
+/// Messages of the chunk being filled
+#[derive(Default)]
+struct OpenChunk {
+    records: Vec<u8>,
+    start: u64,
+    end: u64,
+    messages: u64,
+    /// `(log_time, offset in records)` per channel
+    indexes: BTreeMap<u16, Vec<(u64, u64)>>,
+}
+
+struct Channel {
+    id: u16,
+    sequence: u32,
+}
+
+/// Streaming MCAP writer. Nothing is readable by other tools until `finish`
+/// writes the summary; a file that never got one is repaired by `recover`.
+pub struct McapWriter<W: Write> {
+    out: W,
+    opts: McapOptions,
+    position: u64,
+    data_crc: crc32fast::Hasher,
+    schemas: HashMap<String, u16>,
+    channels: HashMap<(String, String), Channel>,
+    /// Schema and Channel records, repeated in the summary
+    schema_records: Vec<u8>,
+    channel_records: Vec<u8>,
+    chunk: OpenChunk,
+    chunk_index_records: Vec<u8>,
+    message_count: u64,
+    chunk_count: u32,
+    channel_counts: BTreeMap<u16, u64>,
+    start_time: Option<u64>,
+    end_time: u64,
+}
+
+impl<W: Write> McapWriter<W> {
+    /// Start a file with the magic and the Header record
+    pub fn new(out: W, opts: McapOptions) -> Result<Self> {
+        let mut writer = McapWriter {
+            out,
+            opts,
+            position: 0,
+            data_crc: crc32fast::Hasher::new(),
+            schemas: HashMap::new(),
+            channels: HashMap::new(),
+            schema_records: Vec::new(),
+            channel_records: Vec::new(),
+            chunk: OpenChunk::default(),
+            chunk_index_records: Vec::new(),
+            message_count: 0,
+            chunk_count: 0,
+            channel_counts: BTreeMap::new(),
+            start_time: None,
+            end_time: 0,
+        };
+        let mut header = Vec::new();
+        put_str(&mut header, PROFILE);
+        put_str(&mut header, LIBRARY);
+        let mut buf = MAGIC.to_vec();
+        put_record(&mut buf, OP_HEADER, &header);
+        writer.emit(&buf)?;
+        Ok(writer)
+    }
+
+    /// Bytes written to `out` so far, excluding the open chunk
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Messages written so far, including the open chunk
+    pub fn message_count(&self) -> u64 {
+        self.message_count
+    }
+
+    /// Declare the message type of a channel before its first message
+    pub fn set_message_type(&mut self, topic: &str, namespace: &str, message_type: &str) {
+        self.channel(topic, namespace, Some(message_type));
+    }
+
+    /// Append a record to the open chunk, writing the chunk out once it is full
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        let log_time = to_mcap_time(record.info.receive_ns)?;
+        let publish_time = record.info.header_ns.map_or(Ok(log_time), to_mcap_time)?;
+        let channel = self.channel(&record.topic, &record.namespace, None);
+        let (id, sequence) = {
+            let channel = self.channels.get_mut(&channel).expect("channel was just declared");
+            channel.sequence = channel.sequence.wrapping_add(1);
+            (channel.id, record.info.sequence.map_or(channel.sequence, |s| s as u32))
+        };
+
+        let mut body = Vec::with_capacity(22 + record.payload.len());
+        put_u16(&mut body, id);
+        put_u32(&mut body, sequence);
+        put_u64(&mut body, log_time);
+        put_u64(&mut body, publish_time);
+        body.extend_from_slice(&record.payload);
+
+        let chunk = &mut self.chunk;
+        if chunk.messages == 0 {
+            chunk.start = log_time;
+        }
+        chunk.start = chunk.start.min(log_time);
+        chunk.end = chunk.end.max(log_time);
+        chunk.messages += 1;
+        chunk.indexes.entry(id).or_default().push((log_time, chunk.records.len() as u64));
+        put_record(&mut chunk.records, OP_MESSAGE, &body);
+
+        self.message_count += 1;
+        *self.channel_counts.entry(id).or_default() += 1;
+        self.start_time = Some(self.start_time.map_or(log_time, |t| t.min(log_time)));
+        self.end_time = self.end_time.max(log_time);
+
+        if self.chunk.records.len() >= self.opts.chunk_size {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Key of the channel for `topic` in `namespace`, declaring it (and its
+    /// schema) in the open chunk the first time it is used
+    fn channel(&mut self, topic: &str, namespace: &str, message_type: Option<&str>) -> (String, String) {
+        let key = (topic.to_string(), namespace.to_string());
+        if self.channels.contains_key(&key) {
+            return key;
+        }
+        let schema_id = match message_type {
+            Some(name) => match self.schemas.get(name) {
+                Some(id) => *id,
+                None => {
+                    let id = self.schemas.len() as u16 + 1;
+                    self.schemas.insert(name.to_string(), id);
+                    let mut body = Vec::new();
+                    put_u16(&mut body, id);
+                    put_str(&mut body, name);
+                    put_str(&mut body, SCHEMA_ENCODING);
+                    put_bytes(&mut body, &[]);
+                    put_record(&mut self.chunk.records, OP_SCHEMA, &body);
+                    put_record(&mut self.schema_records, OP_SCHEMA, &body);
+                    id
+                }
+            },
+            None => 0,
+        };
+        let id = self.channels.len() as u16;
+        let mut metadata = Vec::new();
+        put_str(&mut metadata, NAMESPACE_KEY);
+        put_str(&mut metadata, namespace);
+        let mut body = Vec::new();
+        put_u16(&mut body, id);
+        put_u16(&mut body, schema_id);
+        put_str(&mut body, topic);
+        put_str(&mut body, MESSAGE_ENCODING);
+        put_bytes(&mut body, &metadata);
+        put_record(&mut self.chunk.records, OP_CHANNEL, &body);
+        put_record(&mut self.channel_records, OP_CHANNEL, &body);
+        self.channels.insert(key.clone(), Channel { id, sequence: 0 });
+        key
+    }
+
+    /// Compress and write the open chunk and its message indexes
+    pub fn flush_chunk(&mut self) -> Result<()> {
+        if self.chunk.records.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.chunk);
+        let (compression, compressed) = compress(self.opts.compression, self.opts.compression_level, &chunk.records)?;
+
+        let mut body = Vec::with_capacity(compressed.len() + 64);
+        put_u64(&mut body, chunk.start);
+        put_u64(&mut body, chunk.end);
+        put_u64(&mut body, chunk.records.len() as u64);
+        put_u32(&mut body, crc32fast::hash(&chunk.records));
+        put_str(&mut body, compression);
+        put_u64(&mut body, compressed.len() as u64);
+        body.extend_from_slice(&compressed);
+        let mut buf = Vec::with_capacity(body.len() + RECORD_PREFIX_LEN);
+        put_record(&mut buf, OP_CHUNK, &body);
+        let chunk_start_offset = self.position;
+        let chunk_length = buf.len() as u64;
+
+        let mut message_index_offsets = BTreeMap::new();
+        for (channel, entries) in &chunk.indexes {
+            message_index_offsets.insert(*channel, chunk_start_offset + buf.len() as u64);
+            let mut body = Vec::with_capacity(6 + entries.len() * 16);
+            put_u16(&mut body, *channel);
+            put_u32(&mut body, (entries.len() * 16) as u32);
+            for (log_time, offset) in entries {
+                put_u64(&mut body, *log_time);
+                put_u64(&mut body, *offset);
+            }
+            put_record(&mut buf, OP_MESSAGE_INDEX, &body);
+        }
+        let message_index_length = buf.len() as u64 - chunk_length;
+        self.emit(&buf)?;
+
+        let mut body = Vec::new();
+        put_u64(&mut body, chunk.start);
+        put_u64(&mut body, chunk.end);
+        put_u64(&mut body, chunk_start_offset);
+        put_u64(&mut body, chunk_length);
+        put_u32(&mut body, (message_index_offsets.len() * 10) as u32);
+        for (channel, offset) in &message_index_offsets {
+            put_u16(&mut body, *channel);
+            put_u64(&mut body, *offset);
+        }
+        put_u64(&mut body, message_index_length);
+        put_str(&mut body, compression);
+        put_u64(&mut body, compressed.len() as u64);
+        put_u64(&mut body, chunk.records.len() as u64);
+        put_record(&mut self.chunk_index_records, OP_CHUNK_INDEX, &body);
+        self.chunk_count += 1;
+        Ok(())
+    }
+
+    /// Flush the open chunk and hand back the output, e.g. to fsync it
+    pub fn flush(&mut self) -> Result<&mut W> {
+        self.flush_chunk()?;
+        self.out.flush()?;
+        Ok(&mut self.out)
+    }
+
+    /// Write the last chunk, DataEnd, the summary and the footer
+    pub fn finish(mut self) -> Result<W> {
+        self.flush_chunk()?;
+        let mut data_end = Vec::new();
+        put_u32(&mut data_end, self.data_crc.clone().finalize());
+        let mut buf = Vec::new();
+        put_record(&mut buf, OP_DATA_END, &data_end);
+        self.emit(&buf)?;
+
+        let summary_start = self.position;
+        let mut statistics = Vec::new();
+        put_u64(&mut statistics, self.message_count);
+        put_u16(&mut statistics, self.schemas.len() as u16);
+        put_u32(&mut statistics, self.channels.len() as u32);
+        put_u32(&mut statistics, 0); // attachments
+        put_u32(&mut statistics, 0); // metadata
+        put_u32(&mut statistics, self.chunk_count);
+        put_u64(&mut statistics, self.start_time.unwrap_or(0));
+        put_u64(&mut statistics, self.end_time);
+        put_u32(&mut statistics, (self.channel_counts.len() * 10) as u32);
+        for (channel, count) in &self.channel_counts {
+            put_u16(&mut statistics, *channel);
+            put_u64(&mut statistics, *count);
+        }
+        let mut statistics_record = Vec::new();
+        put_record(&mut statistics_record, OP_STATISTICS, &statistics);
+
+        let mut summary = Vec::new();
+        let mut offsets = Vec::new();
+        for (op, group) in [
+            (OP_SCHEMA, &self.schema_records),
+            (OP_CHANNEL, &self.channel_records),
+            (OP_STATISTICS, &statistics_record),
+            (OP_CHUNK_INDEX, &self.chunk_index_records),
+        ] {
+            if group.is_empty() {
+                continue;
+            }
+            let mut body = vec![op];
+            put_u64(&mut body, summary_start + summary.len() as u64);
+            put_u64(&mut body, group.len() as u64);
+            put_record(&mut offsets, OP_SUMMARY_OFFSET, &body);
+            summary.extend_from_slice(group);
+        }
+        let summary_offset_start = summary_start + summary.len() as u64;
+        summary.extend_from_slice(&offsets);
+
+        // The summary CRC covers everything from the summary to the footer's own field
+        summary.push(OP_FOOTER);
+        put_u64(&mut summary, 20);
+        put_u64(&mut summary, summary_start);
+        put_u64(&mut summary, summary_offset_start);
+        let crc = crc32fast::hash(&summary);
+        put_u32(&mut summary, crc);
+        summary.extend_from_slice(MAGIC);
+        self.out.write_all(&summary)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn emit(&mut self, buf: &[u8]) -> Result<()> {
+        self.out.write_all(buf)?;
+        self.data_crc.update(buf);
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn compress(codec: Option<CompressionCodec>, level: i32, data: &[u8]) -> Result<(&'static str, Vec<u8>)> {
+    Ok(match codec {
+        None => ("", data.to_vec()),
+        Some(CompressionCodec::Zstd) => ("zstd", zstd::bulk::compress(data, level)?),
+        Some(CompressionCodec::Lz4) => {
+            let mut encoder = lz4::EncoderBuilder::new().build(Vec::with_capacity(data.len() / 2))?;
+            encoder.write_all(data)?;
+            let (out, result) = encoder.finish();
+            result?;
+            ("lz4", out)
+        }
+    })
+}
+
+fn decompress(compression: &str, data: &[u8], uncompressed_size: u64) -> Result<Vec<u8>> {
+    let size = usize::try_from(uncompressed_size)?;
+    let out = match compression {
+        "" => data.to_vec(),
+        "zstd" => zstd::bulk::decompress(data, size)?,
+        "lz4" => {
+            let mut out = Vec::with_capacity(size);
+            lz4::Decoder::new(data)?.read_to_end(&mut out)?;
+            out
+        }
+        other => return Err(anyhow!("unsupported chunk compression {:?}", other)),
+    };
+    if out.len() != size {
+        return Err(anyhow!("chunk decompressed to {} bytes, expected {}", out.len(), size));
+    }
+    Ok(out)
+}
+
+/// Little-endian field reader over one record body
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(anyhow!("truncated MCAP record"));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(std::str::from_utf8(self.bytes()?)?.to_string())
+    }
+
+    fn u64_len(&mut self) -> Result<&'a [u8]> {
+        let len = usize::try_from(self.u64()?)?;
+        self.take(len)
+    }
+}
+
+/// Records `(opcode, body)` of a byte range; yields an error on a truncated
+/// record and then ends
+struct Records<'a> {
+    fields: Fields<'a>,
+}
+
+impl<'a> Records<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Records { fields: Fields { buf } }
+    }
+
+    /// Bytes not consumed yet
+    fn remaining(&self) -> usize {
+        self.fields.buf.len()
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<(u8, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.fields.buf.is_empty() {
+            return None;
+        }
+        let record = (|| {
+            let op = self.fields.take(1)?[0];
+            Ok((op, self.fields.u64_len()?))
+        })();
+        if record.is_err() {
+            self.fields.buf = &[];
+        }
+        Some(record)
+    }
+}
+
+fn parse_channel(body: &[u8]) -> Result<(u16, String, String)> {
+    let mut f = Fields { buf: body };
+    let id = f.u16()?;
+    let _schema_id = f.u16()?;
+    let topic = f.string()?;
+    let _encoding = f.string()?;
+    let mut metadata = Fields { buf: f.bytes()? };
+    let mut namespace = String::new();
+    while !metadata.buf.is_empty() {
+        let (key, value) = (metadata.string()?, metadata.string()?);
+        if key == NAMESPACE_KEY {
+            namespace = value;
+        }
+    }
+    Ok((id, topic, namespace))
+}
+
+fn parse_chunk(body: &[u8]) -> Result<Vec<u8>> {
+    let mut f = Fields { buf: body };
+    let _start = f.u64()?;
+    let _end = f.u64()?;
+    let uncompressed_size = f.u64()?;
+    let crc = f.u32()?;
+    let compression = f.string()?;
+    let records = decompress(&compression, f.u64_len()?, uncompressed_size)?;
+    if crc != 0 && crc32fast::hash(&records) != crc {
+        return Err(anyhow!("chunk CRC mismatch"));
+    }
+    Ok(records)
+}
+
+/// Messages of a data section, resolving channels as they are declared
+#[derive(Default)]
+struct Scan {
+    channels: HashMap<u16, (String, String)>,
+    /// Message types of channels with a schema
+    types: HashMap<(String, String), String>,
+    schemas: HashMap<u16, String>,
+    records: Vec<Record>,
+    /// Offset just past the last record read completely
+    good_until: usize,
+    /// DataEnd was reached
+    complete: bool,
+}
+
+impl Scan {
+    /// Read records from `buf` (starting after the Header), stopping at
+    /// DataEnd or at the first damaged record
+    fn run(&mut self, buf: &[u8], base: usize, filter: &dyn Fn(u64) -> bool) -> Result<()> {
+        let mut records = Records::new(buf);
+        while let Some(record) = records.next() {
+            let (op, body) = record?;
+            self.apply(op, body, filter)?;
+            self.good_until = base + buf.len() - records.remaining();
+            if op == OP_DATA_END {
+                self.complete = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, op: u8, body: &[u8], filter: &dyn Fn(u64) -> bool) -> Result<()> {
+        match op {
+            OP_SCHEMA => {
+                let mut f = Fields { buf: body };
+                let id = f.u16()?;
+                self.schemas.insert(id, f.string()?);
+            }
+            OP_CHANNEL => {
+                let (id, topic, namespace) = parse_channel(body)?;
+                let schema_id = u16::from_le_bytes(body[2..4].try_into()?);
+                if let Some(name) = self.schemas.get(&schema_id) {
+                    self.types.insert((topic.clone(), namespace.clone()), name.clone());
+                }
+                self.channels.insert(id, (topic, namespace));
+            }
+            OP_MESSAGE => {
+                let mut f = Fields { buf: body };
+                let channel = f.u16()?;
+                let sequence = f.u32()?;
+                let log_time = f.u64()?;
+                let publish_time = f.u64()?;
+                if !filter(log_time) {
+                    return Ok(());
+                }
+                let (topic, namespace) = self
+                    .channels
+                    .get(&channel)
+                    .ok_or_else(|| anyhow!("message on undeclared channel {}", channel))?;
+                self.records.push(Record {
+                    topic: topic.clone(),
+                    namespace: namespace.clone(),
+                    info: MessageInfo {
+                        receive_ns: log_time as u128,
+                        header_ns: (publish_time != log_time).then_some(publish_time as u128),
+                        sequence: Some(sequence as u64),
+                        ..Default::default()
+                    },
+                    payload: f.buf.to_vec(),
+                });
+            }
+            OP_CHUNK => {
+                let records = parse_chunk(body)?;
+                for record in Records::new(&records) {
+                    let (op, body) = record?;
+                    self.apply(op, body, filter)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn check_magic(buf: &[u8], path: &Path) -> Result<()> {
+    if buf.len() < MAGIC.len() || &buf[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!("{} is not an MCAP file", path.display()));
+    }
+    Ok(())
+}
+
+/// Summary of a finished file, `None` if it has no footer
+#[allow(dead_code)]
+pub fn read_summary(path: &Path) -> Result<Option<McapSummary>> {
+    let buf = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    check_magic(&buf, path)?;
+    parse_summary(&buf)
+}
+
+fn parse_summary(buf: &[u8]) -> Result<Option<McapSummary>> {
+    const FOOTER_LEN: usize = RECORD_PREFIX_LEN + 20 + MAGIC.len();
+    if buf.len() < MAGIC.len() + FOOTER_LEN || &buf[buf.len() - MAGIC.len()..] != MAGIC {
+        return Ok(None);
+    }
+    let footer = &buf[buf.len() - FOOTER_LEN..];
+    if footer[0] != OP_FOOTER {
+        return Ok(None);
+    }
+    let mut f = Fields { buf: &footer[RECORD_PREFIX_LEN..] };
+    let summary_start = usize::try_from(f.u64()?)?;
+    let _summary_offset_start = f.u64()?;
+    let crc = f.u32()?;
+    let footer_start = buf.len() - FOOTER_LEN;
+    if summary_start == 0 || summary_start > footer_start {
+        return Ok(None);
+    }
+    if crc != 0 && crc32fast::hash(&buf[summary_start..footer_start + RECORD_PREFIX_LEN + 16]) != crc {
+        return Err(anyhow!("summary CRC mismatch"));
+    }
+
+    let mut summary = McapSummary::default();
+    for record in Records::new(&buf[summary_start..footer_start]) {
+        let (op, body) = record?;
+        let mut f = Fields { buf: body };
+        match op {
+            OP_CHANNEL => {
+                let (id, topic, namespace) = parse_channel(body)?;
+                summary.channels.insert(id, (topic, namespace));
+            }
+            OP_STATISTICS => {
+                summary.message_count = f.u64()?;
+                summary.schema_count = f.u16()?;
+                summary.channel_count = f.u32()?;
+                let _attachments = f.u32()?;
+                let _metadata = f.u32()?;
+                summary.chunk_count = f.u32()?;
+                summary.message_start_time = f.u64()?;
+                summary.message_end_time = f.u64()?;
+                let mut counts = Fields { buf: f.bytes()? };
+                while !counts.buf.is_empty() {
+                    summary.channel_message_counts.insert(counts.u16()?, counts.u64()?);
+                }
+            }
+            OP_CHUNK_INDEX => {
+                let message_start_time = f.u64()?;
+                let message_end_time = f.u64()?;
+                let chunk_start_offset = f.u64()?;
+                let chunk_length = f.u64()?;
+                let mut offsets = Fields { buf: f.bytes()? };
+                let mut message_index_offsets = BTreeMap::new();
+                while !offsets.buf.is_empty() {
+                    message_index_offsets.insert(offsets.u16()?, offsets.u64()?);
+                }
+                let _message_index_length = f.u64()?;
+                summary.chunk_indexes.push(ChunkIndex {
+                    message_start_time,
+                    message_end_time,
+                    chunk_start_offset,
+                    chunk_length,
+                    message_index_offsets,
+                    compression: f.string()?,
+                    compressed_size: f.u64()?,
+                    uncompressed_size: f.u64()?,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(summary))
+}
+
+/// Every message of a file in file order
+#[allow(dead_code)]
+pub fn read_records(path: &Path) -> Result<Vec<Record>> {
+    let buf = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    check_magic(&buf, path)?;
+    let mut scan = Scan::default();
+    scan.run(&buf[MAGIC.len()..], MAGIC.len(), &|_| true)?;
+    Ok(scan.records)
+}
+
+/// Messages received within `[t_start, t_end)` nanoseconds. Finished
+/// files only decompress the chunks their chunk indexes place in the range.
+#[allow(dead_code)]
+pub fn read_range(path: &Path, t_start: u128, t_end: u128) -> Result<Vec<Record>> {
+    let buf = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    check_magic(&buf, path)?;
+    let start = to_mcap_time(t_start).unwrap_or(u64::MAX);
+    let end = to_mcap_time(t_end).unwrap_or(u64::MAX);
+    let in_range = move |t: u64| t >= start && t < end;
+    let mut scan = Scan::default();
+    let Some(summary) = parse_summary(&buf)? else {
+        scan.run(&buf[MAGIC.len()..], MAGIC.len(), &in_range)?;
+        return Ok(scan.records);
+    };
+    scan.channels = summary.channels.into_iter().collect();
+    for chunk in summary.chunk_indexes {
+        if chunk.message_end_time < start || chunk.message_start_time >= end {
+            continue;
+        }
+        let offset = usize::try_from(chunk.chunk_start_offset)?;
+        let length = usize::try_from(chunk.chunk_length)?;
+        let record = buf
+            .get(offset..offset + length)
+            .ok_or_else(|| anyhow!("chunk index points past the end of {}", path.display()))?;
+        let mut records = Records::new(record);
+        match records.next() {
+            Some(Ok((OP_CHUNK, body))) => scan.apply(OP_CHUNK, body, &in_range)?,
+            _ => return Err(anyhow!("chunk index of {} does not point at a chunk", path.display())),
+        }
+    }
+    Ok(scan.records)
+}
+
+/// Rewrite a file that was cut off before its summary (a crash while
+/// recording) so other tools can open it. Messages of complete chunks are
+/// kept; a chunk that was being written is lost. Returns the messages kept,
+/// or `None` if the file was already finished.
+pub fn recover(path: &Path, opts: McapOptions) -> Result<Option<u64>> {
+    let buf = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    if buf.is_empty() {
+        std::fs::remove_file(path)?;
+        return Ok(Some(0));
+    }
+    check_magic(&buf, path)?;
+    if parse_summary(&buf).is_ok_and(|s| s.is_some()) {
+        return Ok(None);
+    }
+    let mut scan = Scan::default();
+    if let Err(e) = scan.run(&buf[MAGIC.len()..], MAGIC.len(), &|_| true) {
+        tracing::warn!("{} is damaged after byte {}: {:#}", path.display(), scan.good_until, e);
+    }
+    let kept = scan.records.len() as u64;
+    let tmp_path = path.with_extension("mcap.tmp");
+    let file = std::fs::File::create(&tmp_path)?;
+    let mut writer = McapWriter::new(std::io::BufWriter::new(file), opts)?;
+    for ((topic, namespace), message_type) in &scan.types {
+        writer.set_message_type(topic, namespace, message_type);
+    }
+    for record in &scan.records {
+        writer.write(record)?;
+    }
+    let file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(Some(kept))
+}
+
+/// Write every record of `records` to a new MCAP file at `dst`, atomically.
+/// `types` maps topics to their message type where it is known.
+pub fn write_file(
+    dst: &Path,
+    records: impl Iterator<Item = Result<Record>>,
+    types: &HashMap<String, String>,
+    opts: McapOptions,
+) -> Result<u64> {
+    let tmp_path = dst.with_extension("mcap.tmp");
+    let file = std::fs::File::create(&tmp_path).with_context(|| format!("creating {}", tmp_path.display()))?;
+    let mut writer = McapWriter::new(std::io::BufWriter::new(file), opts)?;
+    let result = (|| {
+        for record in records {
+            let record = record?;
+            if let Some(message_type) = types.get(&record.topic) {
+                writer.set_message_type(&record.topic, &record.namespace, message_type);
+            }
+            writer.write(&record)?;
+        }
+        Ok::<_, anyhow::Error>(writer.message_count())
+    })();
+    let count = match result {
+        Ok(count) => count,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    let file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, dst)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn opts(compression: Option<CompressionCodec>, chunk_size: usize) -> McapOptions {
+        McapOptions { compression, compression_level: 3, chunk_size }
+    }
+
+    fn records() -> Vec<Record> {
+        (0..300u128)
+            .map(|i| Record {
+                topic: if i % 3 == 0 { "/scan".to_string() } else { "/tf".to_string() },
+                namespace: format!("robot{}", i % 2),
+                info: MessageInfo {
+                    receive_ns: 1_700_000_000_000_000_000 + i * 1_000_000,
+                    // Every other message has a header stamp a bit before it was received
+                    header_ns: (i % 2 == 0).then_some(1_700_000_000_000_000_000 + i * 1_000_000 - 250_000),
+                    sequence: Some(i as u64 % 150 + 1),
+                    ..Default::default()
+                },
+                payload: format!("payload {}", i).repeat(4).into_bytes(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip_with_summary() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        for compression in [None, Some(CompressionCodec::Zstd), Some(CompressionCodec::Lz4)] {
+            let path = tmpdir.path().join("out.mcap");
+            let types = HashMap::from([("/scan".to_string(), "sensor_msgs/msg/LaserScan".to_string())]);
+            let count = write_file(&path, records().into_iter().map(Ok), &types, opts(compression, 4096))?;
+            assert_eq!(count, 300);
+            assert_eq!(read_records(&path)?, records());
+
+            let summary = read_summary(&path)?.expect("finished file has a summary");
+            assert_eq!(summary.message_count, 300);
+            assert_eq!(summary.schema_count, 1);
+            assert_eq!(summary.channel_count, 4);
+            assert_eq!(summary.channels.len(), 4);
+            assert!(summary.chunk_count > 1);
+            assert_eq!(summary.chunk_indexes.len(), summary.chunk_count as usize);
+            assert_eq!(summary.channel_message_counts.values().sum::<u64>(), 300);
+            assert_eq!(summary.message_start_time, 1_700_000_000_000_000_000);
+            assert_eq!(summary.message_end_time, 1_700_000_000_299_000_000);
+            let expected = compression.map_or("", |c| match c {
+                CompressionCodec::Zstd => "zstd",
+                CompressionCodec::Lz4 => "lz4",
+            });
+            assert!(summary.chunk_indexes.iter().all(|c| c.compression == expected));
+
+            // Message indexes sit right after their chunk
+            let buf = std::fs::read(&path)?;
+            let first = &summary.chunk_indexes[0];
+            for offset in first.message_index_offsets.values() {
+                assert_eq!(buf[*offset as usize], OP_MESSAGE_INDEX);
+                assert!(*offset >= first.chunk_start_offset + first.chunk_length);
+            }
+            assert_eq!(buf[first.chunk_start_offset as usize], OP_CHUNK);
+
+            let range = read_range(&path, 1_700_000_000_100_000_000, 1_700_000_000_110_000_000)?;
+            assert_eq!(range, records()[100..110].to_vec());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_truncated_file() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let path = tmpdir.path().join("cut.mcap");
+        let mut writer = McapWriter::new(Vec::new(), opts(Some(CompressionCodec::Zstd), 1024))?;
+        for record in &records() {
+            writer.write(record)?;
+        }
+        let flushed = writer.position() as usize;
+        let mut buf = writer.finish()?;
+        // Cut inside the first record after the flushed chunks
+        buf.truncate(flushed + 5);
+        std::fs::write(&path, &buf)?;
+        assert!(read_summary(&path)?.is_none());
+
+        let kept = recover(&path, opts(Some(CompressionCodec::Zstd), 1024))?.expect("file needed recovery");
+        assert!(kept > 0 && kept < 300);
+        let summary = read_summary(&path)?.expect("recovered file has a summary");
+        assert_eq!(summary.message_count, kept);
+        assert_eq!(read_records(&path)?, records()[..kept as usize].to_vec());
+        assert_eq!(recover(&path, opts(None, 1024))?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Streaming readers over WAL segments.
+//!
+//! `SegmentReader` decodes one segment through a buffered file handle, one
+//! record at a time. `RecordingReader` chains segments in order so a whole
+//! recording can be walked without holding more than one record in memory.
+//! `record_stream` runs a `RecordingReader` on the blocking pool and exposes
+//! it as an async `Stream` for replay, export and sync. Encrypted segments
+//! need the `Keyring` holding their master key.
+
+use super::blob::{self, BLOB_REF_LEN};
+use super::crypto::DataKey;
+use super::frame::{self, FrameCheck, FrameDecoder, FrameKind, SegmentDictionary, FRAME_HEADER_LEN};
+use super::Record;
+use crate::security::Keyring;
+use anyhow::{Context, Result};
+use futures::Stream;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Read buffer per open segment
+const READ_BUFFER: usize = 256 * 1024;
+/// Records decoded ahead of a slow stream consumer
+pub(super) const STREAM_BUFFER: usize = 256;
+
+/// Buffered iterator over the records of one segment.
+///
+/// Yields `Err` once on the first undecodable frame and then ends; use
+/// `Storage::salvage_segment` to read past damage.
+pub struct SegmentReader {
+    path: PathBuf,
+    reader: CountingReader,
+    decoder: FrameDecoder,
+    done: bool,
+}
+
+impl SegmentReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_keys(path, &Keyring::default())
+    }
+
+    /// Open a segment that may be encrypted under one of `keys`
+    pub fn open_with_keys(path: &Path, keys: &Keyring) -> Result<Self> {
+        Self::open_at(path, 0, SegmentDictionary::default(), keys)
+    }
+
+    /// Start decoding at a frame boundary `offset`, with the names the
+    /// segment declared before it
+    pub(super) fn open_at(path: &Path, offset: u64, dictionary: SegmentDictionary, keys: &Keyring) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("opening segment {}", path.display()))?;
+        let mut decoder = FrameDecoder::with_dictionary(dictionary)
+            .with_keys(keys.clone())
+            .with_blobs(blob::blob_dir_for(path));
+        if offset > 0 {
+            // The data key is declared once, at the start of the segment
+            if let Some(body) = read_key_frame(&mut file)? {
+                decoder.load_key(&body).with_context(|| format!("opening segment {}", path.display()))?;
+            }
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(SegmentReader {
+            path: path.to_path_buf(),
+            reader: CountingReader { inner: BufReader::with_capacity(READ_BUFFER, file), position: offset },
+            decoder,
+            done: false,
+        })
+    }
+
+    /// Decode frame headers only: records carry topic, namespace and
+    /// timestamp but no payload, and encrypted segments need no key
+    pub(super) fn headers_only(mut self) -> Self {
+        self.decoder = std::mem::take(&mut self.decoder).headers_only();
+        self
+    }
+
+    /// Blob referenced by the record `next` returned last
+    pub(super) fn last_blob(&self) -> Option<[u8; BLOB_REF_LEN]> {
+        self.decoder.last_blob()
+    }
+
+    #[allow(dead_code)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Byte offset of the frame the next call to `next` starts decoding at
+    pub fn offset(&self) -> u64 {
+        self.reader.position
+    }
+
+    pub(super) fn into_dictionary(self) -> SegmentDictionary {
+        self.decoder.into_dictionary()
+    }
+}
+
+/// Id of the master key an encrypted segment is written under; `None` for a
+/// plaintext segment
+pub(super) fn master_key_id(path: &Path) -> Result<Option<String>> {
+    let mut file = File::open(path)?;
+    read_key_frame(&mut file)?
+        .map(|body| DataKey::master_key_id(&body).map(str::to_string))
+        .transpose()
+}
+
+/// Body of the key frame at the start of an encrypted segment
+fn read_key_frame(file: &mut File) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    if file.read_exact(&mut header).is_err() || header[5] != FrameKind::Key as u8 {
+        return Ok(None);
+    }
+    let body_len = u32::from_le_bytes(header[8..12].try_into()?) as usize;
+    let mut buf = header.to_vec();
+    buf.resize(FRAME_HEADER_LEN + body_len, 0);
+    file.read_exact(&mut buf[FRAME_HEADER_LEN..])?;
+    match frame::check_frame(&buf) {
+        FrameCheck::Valid { kind: Some(FrameKind::Key), .. } => Ok(Some(buf.split_off(FRAME_HEADER_LEN))),
+        _ => Ok(None),
+    }
+}
+
+/// Buffered file reader that tracks how far into the segment it has read
+struct CountingReader {
+    inner: BufReader<File>,
+    position: u64,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.decoder.next_record(&mut self.reader) {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.context(format!("reading segment {}", self.path.display()))))
+            }
+        }
+    }
+}
+
+/// Records of several segments, in the order given. A damaged segment yields
+/// its error inline and reading continues with the next one.
+pub struct RecordingReader {
+    pending: VecDeque<PathBuf>,
+    current: Option<SegmentReader>,
+    keys: Keyring,
+}
+
+impl RecordingReader {
+    pub fn new(segments: impl IntoIterator<Item = PathBuf>) -> Self {
+        RecordingReader { pending: segments.into_iter().collect(), current: None, keys: Keyring::default() }
+    }
+
+    /// Master keys for encrypted segments
+    pub fn with_keys(mut self, keys: Keyring) -> Self {
+        self.keys = keys;
+        self
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(reader) = self.current.as_mut() {
+                match reader.next() {
+                    Some(item) => return Some(item),
+                    None => self.current = None,
+                }
+            }
+            let path = self.pending.pop_front()?;
+            match SegmentReader::open_with_keys(&path, &self.keys) {
+                Ok(reader) => self.current = Some(reader),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Stream the records of `segments` without blocking the async runtime.
+/// Decoding stops when the stream is dropped.
+pub fn record_stream(segments: Vec<PathBuf>, keys: Keyring) -> impl Stream<Item = Result<Record>> + Unpin {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        for item in RecordingReader::new(segments).with_keys(keys) {
+            if tx.blocking_send(item).is_err() {
+                break;
+            }
+        }
+    });
+    futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
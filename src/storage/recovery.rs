@@ -0,0 +1,132 @@
+//! Startup recovery of the active segment.
+//!
+//! A power cut mid-write leaves a partial frame (or a run of zeroes from
+//! preallocated blocks) at the end of the active segment. Before the writer
+//! appends to it again, the segment is scanned frame by frame; the first byte
+//! that does not start a valid frame marks the end of the good data. If no
+//! valid frame follows, the tail is copied to `quarantine/` and truncated. If
+//! valid frames do follow, the damage is not a torn write: the segment is left
+//! untouched and sealed so the salvage reader can deal with it. A segment that
+//! already ends in a footer was sealed right before the crash and is left as is.
+
+use super::frame::{self, FrameCheck, FrameKind, SegmentDictionary, FRAME_HEADER_LEN};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+pub(super) const QUARANTINE_DIR: &str = "quarantine";
+
+/// State of the active segment after recovery
+#[derive(Debug, Default)]
+pub(super) struct RecoveredSegment {
+    pub size: u64,
+    pub messages: u64,
+    pub dictionary: SegmentDictionary,
+    /// Running SHA-256 of the `size` bytes kept
+    pub digest: Sha256,
+    /// Damage is followed by valid frames; writing must continue in a new segment
+    pub seal: bool,
+    /// Body of the segment's key frame if it is encrypted
+    pub key_frame: Option<Vec<u8>>,
+}
+
+/// What recovery found and did, logged on startup
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct RecoveryReport {
+    pub segment: u64,
+    pub path: PathBuf,
+    pub valid_bytes: u64,
+    pub messages: u64,
+    pub discarded_bytes: u64,
+    pub quarantined_to: Option<PathBuf>,
+    /// Valid frames follow the damaged range, so nothing was truncated
+    pub damaged_mid_segment: bool,
+}
+
+pub(super) async fn recover_active_segment(root: &Path, segment: u64) -> Result<(RecoveredSegment, Option<RecoveryReport>)> {
+    let path = root.join(format!("segment-{}.log", segment));
+    let buf = match tokio::fs::read(&path).await {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((RecoveredSegment::default(), None)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut recovered = RecoveredSegment::default();
+    let mut offset = 0;
+    while offset < buf.len() {
+        let FrameCheck::Valid { len, kind } = frame::check_frame(&buf[offset..]) else {
+            break;
+        };
+        match kind {
+            Some(FrameKind::Dictionary) => recovered.dictionary.apply(&buf[offset + FRAME_HEADER_LEN..offset + len])?,
+            Some(FrameKind::Message) | None => recovered.messages += 1,
+            Some(FrameKind::Footer) => recovered.seal = true,
+            Some(FrameKind::Key) => {
+                recovered.key_frame = Some(buf[offset + FRAME_HEADER_LEN..offset + len].to_vec());
+            }
+        }
+        offset += len;
+    }
+    recovered.size = offset as u64;
+    recovered.digest.update(&buf[..offset]);
+
+    if recovered.seal && offset == buf.len() {
+        tracing::info!("segment {} was sealed before shutdown", segment);
+        return Ok((recovered, None));
+    }
+    if offset == buf.len() {
+        tracing::info!(
+            "recovered segment {}: {} bytes, {} messages",
+            segment,
+            recovered.size,
+            recovered.messages
+        );
+        return Ok((recovered, None));
+    }
+
+    let mut report = RecoveryReport {
+        segment,
+        path: path.clone(),
+        valid_bytes: offset as u64,
+        messages: recovered.messages,
+        discarded_bytes: 0,
+        quarantined_to: None,
+        damaged_mid_segment: false,
+    };
+
+    if let Some(next) = frame::find_next_frame(&buf, offset + 1) {
+        tracing::warn!(
+            "segment {} is damaged at bytes {}..{} but has valid frames after it; sealing it for salvage",
+            segment,
+            offset,
+            next
+        );
+        report.damaged_mid_segment = true;
+        recovered.size = buf.len() as u64;
+        recovered.seal = true;
+        return Ok((recovered, Some(report)));
+    }
+
+    let tail = &buf[offset..];
+    let quarantine = root.join(QUARANTINE_DIR);
+    tokio::fs::create_dir_all(&quarantine).await?;
+    let tail_path = quarantine.join(format!("segment-{}.log.{}.tail", segment, offset));
+    tokio::fs::write(&tail_path, tail).await?;
+
+    let file = tokio::fs::OpenOptions::new().write(true).open(&path).await?;
+    file.set_len(offset as u64).await?;
+    file.sync_all().await?;
+
+    report.discarded_bytes = tail.len() as u64;
+    report.quarantined_to = Some(tail_path);
+    tracing::warn!(
+        "recovered segment {} after torn write: kept {} bytes ({} messages), moved {} byte tail to {}",
+        segment,
+        report.valid_bytes,
+        report.messages,
+        report.discarded_bytes,
+        report.quarantined_to.as_ref().map(|p| p.display().to_string()).unwrap_or_default()
+    );
+    Ok((recovered, Some(report)))
+}
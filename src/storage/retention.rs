@@ -0,0 +1,138 @@
+//! Retention janitor.
+//!
+//! Deletes sealed segments, oldest first, until the configured limits on total
+//! size, age and free disk space hold again. The active segment and segments
+//! pinned by the sync queue are never touched, and with `require_upload` only
+//! segments carrying an upload marker are eligible. Compacted batches are
+//! older than any loose segment and go first, a whole batch at a time.
+
+use super::compaction;
+use super::footer;
+use super::index::index_path;
+use super::segment_number;
+use crate::config::RetentionConfig;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marker written next to a segment once it has been uploaded
+pub fn uploaded_marker(segment: &Path) -> PathBuf {
+    segment.with_extension("uploaded")
+}
+
+/// Outcome of one janitor pass
+#[derive(Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct RetentionReport {
+    pub deleted: Vec<PathBuf>,
+    pub freed_bytes: u64,
+    /// Size of the remaining segments and their sidecars
+    pub total_bytes: u64,
+    /// A size or free-space limit still does not hold because every remaining
+    /// segment is active, pinned or not uploaded yet
+    pub unsatisfied: bool,
+}
+
+/// Bytes a segment or batch occupies on disk, sidecars included
+fn segment_bytes(path: &Path) -> u64 {
+    let mut files = vec![uploaded_marker(path)];
+    if compaction::is_batch(path) {
+        files.extend(std::fs::read_dir(path).into_iter().flatten().filter_map(|e| Some(e.ok()?.path())));
+    } else {
+        files.extend([path.to_path_buf(), index_path(path)]);
+    }
+    files
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Time since the segment was sealed, from its footer or else its mtime. A
+/// batch is as old as the newest segment it replaced.
+pub(super) fn segment_age(path: &Path) -> Option<Duration> {
+    let now = SystemTime::now();
+    let sealed_at = if compaction::is_batch(path) {
+        Some(compaction::read_manifest(path).ok()?.sealed_at)
+    } else {
+        footer::read_footer(path).ok().flatten().map(|footer| footer.sealed_at)
+    };
+    if let Some(sealed_at) = sealed_at {
+        let sealed = UNIX_EPOCH + Duration::from_millis(sealed_at as u64);
+        return Some(now.duration_since(sealed).unwrap_or_default());
+    }
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(now.duration_since(modified).unwrap_or_default())
+}
+
+pub(super) fn delete_segment(path: &Path) -> Result<()> {
+    if compaction::is_batch(path) {
+        std::fs::remove_dir_all(path)?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+    for sidecar in [index_path(path), uploaded_marker(path)] {
+        match std::fs::remove_file(&sidecar) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// One pass over `segments` (oldest first, batches included). Segments
+/// numbered `active` or higher and those in `pinned` are kept regardless of
+/// the limits.
+pub(super) fn enforce(
+    root: &Path,
+    segments: &[PathBuf],
+    active: u64,
+    pinned: &HashSet<PathBuf>,
+    cfg: &RetentionConfig,
+) -> Result<RetentionReport> {
+    let mut report = RetentionReport {
+        total_bytes: segments.iter().map(|p| segment_bytes(p)).sum(),
+        ..Default::default()
+    };
+    let mut free_bytes = match cfg.min_free_bytes {
+        Some(_) => Some(fs2::available_space(root)?),
+        None => None,
+    };
+    let max_age = cfg.max_age_secs.map(Duration::from_secs);
+
+    let over_size = |total: u64| cfg.max_total_bytes.is_some_and(|max| total > max);
+    let low_space = |free: Option<u64>| free.zip(cfg.min_free_bytes).is_some_and(|(free, min)| free < min);
+
+    for path in segments {
+        let sealed = compaction::is_batch(path) || segment_number(path).is_some_and(|n| n < active);
+        let uploaded = !cfg.require_upload || uploaded_marker(path).exists();
+        if !sealed || pinned.contains(path) || !uploaded {
+            continue;
+        }
+        let expired = max_age.is_some_and(|max| segment_age(path).is_some_and(|age| age > max));
+        if !(expired || over_size(report.total_bytes) || low_space(free_bytes)) {
+            continue;
+        }
+
+        let bytes = segment_bytes(path);
+        if let Err(e) = delete_segment(path) {
+            tracing::warn!("retention could not delete {}: {:#}", path.display(), e);
+            continue;
+        }
+        tracing::info!(
+            "retention deleted {} ({} bytes, {})",
+            path.display(),
+            bytes,
+            if expired { "expired" } else { "over quota" }
+        );
+        report.deleted.push(path.clone());
+        report.freed_bytes += bytes;
+        report.total_bytes = report.total_bytes.saturating_sub(bytes);
+        // Reclaimed space may show up late in statvfs; count it ourselves
+        free_bytes = free_bytes.map(|free| free + bytes);
+    }
+
+    report.unsatisfied = over_size(report.total_bytes) || low_space(free_bytes);
+    Ok(report)
+}
@@ -0,0 +1,484 @@
+//! rosbag2 bag directories with the sqlite3 storage plugin.
+//!
+//! A bag is a directory holding `metadata.yaml` and `.db3` files with `topics`
+//! and `messages` tables. Bags are written in the ROS 2 Humble layout (db
+//! schema version 3, metadata version 5), which `ros2 bag play` and
+//! `ros2 bag info` of Humble and later read. Reading also accepts older bags
+//! whose `topics` table has no QoS column and bags compressed per message
+//! with zstd.
+//!
+//! rosbag2 has no namespaces: a record's namespace becomes the first part of
+//! the bag topic name (`robot1` and `/tf` give `/robot1/tf`). Written bags list
+//! their namespaces in `custom_data` so reading can split them off again;
+//! bags from elsewhere need the namespaces passed in.
+//!
+//! A bag message has a single timestamp, the receive time in nanoseconds;
+//! header stamps, sequence numbers and the clock source are not kept.
+
+use super::{MessageInfo, Record};
+use crate::utils::TopicManifestEntry;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+pub(super) const METADATA_FILE: &str = "metadata.yaml";
+const METADATA_VERSION: u32 = 5;
+const SCHEMA_VERSION: i64 = 3;
+const ROS_DISTRO: &str = "humble";
+const STORAGE_IDENTIFIER: &str = "sqlite3";
+const SERIALIZATION_FORMAT: &str = "cdr";
+/// `custom_data` key listing the namespaces folded into topic names
+const NAMESPACES_KEY: &str = "rust_ros2_recorder.namespaces";
+
+/// QoS written for topics whose offered profiles were never recorded: what
+/// `ros2 bag record` stores for a reliable, volatile publisher
+pub(super) const DEFAULT_QOS: &str = "- history: 3
+  depth: 0
+  reliability: 1
+  durability: 2
+  deadline:
+    sec: 2147483647
+    nsec: 4294967295
+  lifespan:
+    sec: 2147483647
+    nsec: 4294967295
+  liveliness: 1
+  liveliness_lease_duration:
+    sec: 2147483647
+    nsec: 4294967295
+  avoid_ros_namespace_conventions: false
+";
+
+const CREATE_TABLES: &str = "
+    CREATE TABLE schema(schema_version INTEGER PRIMARY KEY, ros_distro TEXT NOT NULL);
+    CREATE TABLE topics(id INTEGER PRIMARY KEY, name TEXT NOT NULL, type TEXT NOT NULL,
+        serialization_format TEXT NOT NULL, offered_qos_profiles TEXT NOT NULL);
+    CREATE TABLE messages(id INTEGER PRIMARY KEY, topic_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL, data BLOB NOT NULL);
+    CREATE INDEX timestamp_idx ON messages (timestamp ASC);
+";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataFile {
+    rosbag2_bagfile_information: BagMetadata,
+}
+
+/// Contents of `metadata.yaml`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BagMetadata {
+    pub version: u32,
+    pub storage_identifier: String,
+    pub duration: Duration,
+    pub starting_time: StartingTime,
+    pub message_count: u64,
+    pub topics_with_message_count: Vec<TopicWithCount>,
+    #[serde(default)]
+    pub compression_format: String,
+    #[serde(default)]
+    pub compression_mode: String,
+    pub relative_file_paths: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<BagFile>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom_data: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Duration {
+    pub nanoseconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartingTime {
+    pub nanoseconds_since_epoch: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicWithCount {
+    pub topic_metadata: TopicMetadata,
+    pub message_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicMetadata {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub serialization_format: String,
+    #[serde(default)]
+    pub offered_qos_profiles: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BagFile {
+    pub path: String,
+    pub starting_time: StartingTime,
+    pub duration: Duration,
+    pub message_count: u64,
+}
+
+impl BagMetadata {
+    /// Namespaces a bag written by this recorder folded into its topic names
+    pub fn namespaces(&self) -> Vec<String> {
+        self.custom_data
+            .get(NAMESPACES_KEY)
+            .map(|list| list.split(',').filter(|ns| !ns.is_empty()).map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Bag topic name of `topic` recorded in `namespace`
+fn bag_topic_name(namespace: &str, topic: &str) -> String {
+    let namespace = namespace.trim_matches('/');
+    match (namespace.is_empty(), topic.starts_with('/')) {
+        (true, _) => topic.to_string(),
+        (false, true) => format!("/{}{}", namespace, topic),
+        (false, false) => format!("/{}/{}", namespace, topic),
+    }
+}
+
+/// Namespace and topic of a bag topic name, given the known namespaces
+pub(super) fn split_topic_name(name: &str, namespaces: &[String]) -> (String, String) {
+    namespaces
+        .iter()
+        .filter(|ns| !ns.is_empty())
+        .filter_map(|ns| {
+            let rest = name.strip_prefix('/')?.strip_prefix(ns.as_str())?;
+            rest.starts_with('/').then(|| (ns.clone(), rest.to_string()))
+        })
+        .max_by_key(|(ns, _)| ns.len())
+        .unwrap_or_else(|| (String::new(), name.to_string()))
+}
+
+fn to_bag_time(ns: u128) -> Result<i64> {
+    i64::try_from(ns).map_err(|_| anyhow!("timestamp {} ns does not fit a rosbag2 timestamp", ns))
+}
+
+/// Write `records` into a new bag directory `dir`. `topics` supplies message
+/// types and QoS profiles by topic; unknown ones are written empty and with
+/// `DEFAULT_QOS`. `metadata.yaml` is written last, so a bag without one was
+/// interrupted.
+pub(super) fn write_bag(
+    dir: &Path,
+    records: impl Iterator<Item = Result<Record>>,
+    topics: &[TopicManifestEntry],
+) -> Result<BagMetadata> {
+    if dir.exists() {
+        return Err(anyhow!("bag directory {} already exists", dir.display()));
+    }
+    std::fs::create_dir_all(dir)?;
+    let result = write_db(dir, records, topics);
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+    result
+}
+
+fn write_db(
+    dir: &Path,
+    records: impl Iterator<Item = Result<Record>>,
+    topics: &[TopicManifestEntry],
+) -> Result<BagMetadata> {
+    let name = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "bag".to_string());
+    let db_name = format!("{}_0.db3", name);
+    let mut conn = Connection::open(dir.join(&db_name))?;
+    conn.execute_batch("PRAGMA journal_mode = MEMORY; PRAGMA synchronous = OFF;")?;
+    conn.execute_batch(CREATE_TABLES)?;
+    conn.execute("INSERT INTO schema VALUES (?1, ?2)", params![SCHEMA_VERSION, ROS_DISTRO])?;
+
+    let known: HashMap<&str, &TopicManifestEntry> = topics.iter().map(|t| (t.topic.as_str(), t)).collect();
+    // Bag topic name -> (id, metadata, count)
+    let mut bag_topics: BTreeMap<String, (i64, TopicMetadata, u64)> = BTreeMap::new();
+    let mut namespaces = BTreeSet::new();
+    let (mut first, mut last, mut count) = (i64::MAX, i64::MIN, 0u64);
+
+    let tx = conn.transaction()?;
+    {
+        let mut insert_topic = tx.prepare(
+            "INSERT INTO topics (id, name, type, serialization_format, offered_qos_profiles) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        let mut insert_message = tx.prepare("INSERT INTO messages (topic_id, timestamp, data) VALUES (?1, ?2, ?3)")?;
+        for record in records {
+            let record = record?;
+            let name = bag_topic_name(&record.namespace, &record.topic);
+            if !bag_topics.contains_key(&name) {
+                let id = bag_topics.len() as i64 + 1;
+                let info = known.get(record.topic.as_str());
+                let metadata = TopicMetadata {
+                    name: name.clone(),
+                    msg_type: info.map(|t| t.msg_type.clone()).unwrap_or_default(),
+                    serialization_format: SERIALIZATION_FORMAT.to_string(),
+                    offered_qos_profiles: info
+                        .and_then(|t| t.qos_profiles.clone())
+                        .unwrap_or_else(|| DEFAULT_QOS.to_string()),
+                };
+                if metadata.msg_type.is_empty() {
+                    tracing::warn!("message type of {} is unknown, `ros2 bag play` will skip it", name);
+                }
+                insert_topic.execute(params![
+                    id,
+                    metadata.name,
+                    metadata.msg_type,
+                    metadata.serialization_format,
+                    metadata.offered_qos_profiles
+                ])?;
+                if !record.namespace.is_empty() {
+                    namespaces.insert(record.namespace.trim_matches('/').to_string());
+                }
+                bag_topics.insert(name.clone(), (id, metadata, 0));
+            }
+            let (topic_id, _, topic_count) = bag_topics.get_mut(&name).expect("topic was just added");
+            let timestamp = to_bag_time(record.info.receive_ns)?;
+            insert_message.execute(params![*topic_id, timestamp, record.payload])?;
+            *topic_count += 1;
+            count += 1;
+            first = first.min(timestamp);
+            last = last.max(timestamp);
+        }
+    }
+    tx.commit()?;
+    drop(conn);
+    std::fs::File::open(dir.join(&db_name))?.sync_all()?;
+
+    let (starting_time, duration) = match count {
+        0 => (StartingTime::default(), Duration::default()),
+        _ => (
+            StartingTime { nanoseconds_since_epoch: first },
+            Duration { nanoseconds: (last - first) as u64 },
+        ),
+    };
+    let mut custom_data = BTreeMap::new();
+    if !namespaces.is_empty() {
+        custom_data.insert(NAMESPACES_KEY.to_string(), namespaces.into_iter().collect::<Vec<_>>().join(","));
+    }
+    let mut topics_with_message_count: Vec<_> = bag_topics
+        .into_values()
+        .map(|(id, topic_metadata, message_count)| (id, TopicWithCount { topic_metadata, message_count }))
+        .collect();
+    topics_with_message_count.sort_by_key(|(id, _)| *id);
+    let metadata = BagMetadata {
+        version: METADATA_VERSION,
+        storage_identifier: STORAGE_IDENTIFIER.to_string(),
+        duration,
+        starting_time,
+        message_count: count,
+        topics_with_message_count: topics_with_message_count.into_iter().map(|(_, t)| t).collect(),
+        compression_format: String::new(),
+        compression_mode: String::new(),
+        relative_file_paths: vec![db_name.clone()],
+        files: vec![BagFile { path: db_name, starting_time, duration, message_count: count }],
+        custom_data,
+    };
+    let yaml = serde_yaml::to_string(&MetadataFile { rosbag2_bagfile_information: metadata.clone() })?;
+    let tmp_path = dir.join(format!("{}.tmp", METADATA_FILE));
+    std::fs::write(&tmp_path, yaml)?;
+    std::fs::rename(&tmp_path, dir.join(METADATA_FILE))?;
+    Ok(metadata)
+}
+
+/// Parse a bag's `metadata.yaml`
+pub(super) fn read_metadata(dir: &Path) -> Result<BagMetadata> {
+    let path = dir.join(METADATA_FILE);
+    let data = std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+    let file: MetadataFile = serde_yaml::from_str(&data).with_context(|| format!("parsing {}", path.display()))?;
+    let metadata = file.rosbag2_bagfile_information;
+    if metadata.storage_identifier != STORAGE_IDENTIFIER {
+        return Err(anyhow!("bag uses the {} storage plugin, only sqlite3 is supported", metadata.storage_identifier));
+    }
+    if metadata.compression_mode.eq_ignore_ascii_case("file") {
+        return Err(anyhow!("file-compressed bags are not supported, run `ros2 bag decompress` first"));
+    }
+    Ok(metadata)
+}
+
+/// Topic metadata of the `topics` table by id. Bags older than Humble have
+/// no QoS column.
+fn read_topics(conn: &Connection) -> Result<HashMap<i64, TopicMetadata>> {
+    let mut stmt = conn.prepare("SELECT * FROM topics")?;
+    let column = |name: &str| stmt.column_names().iter().position(|c| *c == name);
+    let qos_column = column("offered_qos_profiles");
+    let (id, name, msg_type, format) = (
+        column("id").ok_or_else(|| anyhow!("topics table has no id column"))?,
+        column("name").ok_or_else(|| anyhow!("topics table has no name column"))?,
+        column("type").ok_or_else(|| anyhow!("topics table has no type column"))?,
+        column("serialization_format").ok_or_else(|| anyhow!("topics table has no serialization_format column"))?,
+    );
+    let mut topics = HashMap::new();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        topics.insert(
+            row.get::<_, i64>(id)?,
+            TopicMetadata {
+                name: row.get(name)?,
+                msg_type: row.get(msg_type)?,
+                serialization_format: row.get(format)?,
+                offered_qos_profiles: match qos_column {
+                    Some(column) => row.get(column)?,
+                    None => String::new(),
+                },
+            },
+        );
+    }
+    Ok(topics)
+}
+
+/// Feed every message of the bag at `dir` to `sink`, file by file in
+/// timestamp order. Topic names are split into namespace and topic using
+/// `namespaces`. Returns the topics of the bag's files, with QoS profiles
+/// from `metadata` where a file has none.
+pub(super) fn read_messages(
+    dir: &Path,
+    metadata: &BagMetadata,
+    namespaces: &[String],
+    mut sink: impl FnMut(Record) -> Result<()>,
+) -> Result<Vec<TopicMetadata>> {
+    let decompress = match (metadata.compression_mode.to_ascii_lowercase().as_str(), metadata.compression_format.as_str()) {
+        ("" | "none", _) => false,
+        ("message", "zstd") => true,
+        (mode, format) => return Err(anyhow!("unsupported bag compression {} ({})", format, mode)),
+    };
+    let mut seen: BTreeMap<String, TopicMetadata> = BTreeMap::new();
+    for file in &metadata.relative_file_paths {
+        // Older bags list files without their extension
+        let mut path = dir.join(file);
+        if !path.exists() && path.extension().is_none() {
+            path.set_extension("db3");
+        }
+        let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("opening {}", path.display()))?;
+        let topics = read_topics(&conn)?;
+        let names: HashMap<i64, (String, String)> = topics
+            .iter()
+            .map(|(id, topic)| (*id, split_topic_name(&topic.name, namespaces)))
+            .collect();
+        let mut stmt = conn.prepare("SELECT topic_id, timestamp, data FROM messages ORDER BY timestamp, id")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let topic_id: i64 = row.get(0)?;
+            let timestamp: i64 = row.get(1)?;
+            let (namespace, topic) = names
+                .get(&topic_id)
+                .ok_or_else(|| anyhow!("message on unknown topic id {} in {}", topic_id, path.display()))?;
+            let timestamp = u128::try_from(timestamp)
+                .map_err(|_| anyhow!("negative timestamp {} in {}", timestamp, path.display()))?;
+            let data = row.get_ref(2)?.as_blob()?;
+            let payload = match decompress {
+                true => zstd::stream::decode_all(data)?,
+                false => data.to_vec(),
+            };
+            sink(Record {
+                topic: topic.clone(),
+                namespace: namespace.clone(),
+                info: MessageInfo::received(timestamp),
+                payload,
+            })?;
+        }
+        for topic in topics.into_values() {
+            seen.entry(topic.name.clone()).or_insert(topic);
+        }
+    }
+    for topic in seen.values_mut().filter(|t| t.offered_qos_profiles.is_empty()) {
+        if let Some(listed) = metadata.topics_with_message_count.iter().find(|t| t.topic_metadata.name == topic.name) {
+            topic.offered_qos_profiles = listed.topic_metadata.offered_qos_profiles.clone();
+        }
+    }
+    Ok(seen.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_topic_names_carry_namespaces() {
+        let namespaces = vec!["robot1".to_string(), "fleet/robot2".to_string()];
+        assert_eq!(bag_topic_name("robot1", "/tf"), "/robot1/tf");
+        assert_eq!(bag_topic_name("", "/tf"), "/tf");
+        assert_eq!(split_topic_name("/robot1/tf", &namespaces), ("robot1".to_string(), "/tf".to_string()));
+        assert_eq!(
+            split_topic_name("/fleet/robot2/sensor/lidar", &namespaces),
+            ("fleet/robot2".to_string(), "/sensor/lidar".to_string())
+        );
+        assert_eq!(split_topic_name("/robot10/tf", &namespaces), (String::new(), "/robot10/tf".to_string()));
+        assert_eq!(split_topic_name("/robot1", &namespaces), (String::new(), "/robot1".to_string()));
+    }
+
+    #[test]
+    fn test_reads_foxy_bag_with_compressed_messages() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path().join("old_bag");
+        std::fs::create_dir(&dir)?;
+        let conn = Connection::open(dir.join("old_bag_0.db3"))?;
+        conn.execute_batch(
+            "CREATE TABLE topics(id INTEGER PRIMARY KEY, name TEXT NOT NULL, type TEXT NOT NULL,
+                 serialization_format TEXT NOT NULL);
+             CREATE TABLE messages(id INTEGER PRIMARY KEY, topic_id INTEGER NOT NULL,
+                 timestamp INTEGER NOT NULL, data BLOB NOT NULL);
+             INSERT INTO topics VALUES (1, '/robot1/odom', 'nav_msgs/msg/Odometry', 'cdr');",
+        )?;
+        for (ts, data) in [(3_000_000i64, b"late".as_slice()), (1_000_000, b"early")] {
+            conn.execute("INSERT INTO messages (topic_id, timestamp, data) VALUES (1, ?1, ?2)", params![
+                ts,
+                zstd::bulk::compress(data, 3)?
+            ])?;
+        }
+        drop(conn);
+        std::fs::write(
+            dir.join(METADATA_FILE),
+            "rosbag2_bagfile_information:
+  version: 4
+  storage_identifier: sqlite3
+  relative_file_paths:
+    - old_bag_0
+  duration:
+    nanoseconds: 2000000
+  starting_time:
+    nanoseconds_since_epoch: 1000000
+  message_count: 2
+  topics_with_message_count:
+    - topic_metadata:
+        name: /robot1/odom
+        type: nav_msgs/msg/Odometry
+        serialization_format: cdr
+        offered_qos_profiles: ''
+      message_count: 2
+  compression_format: zstd
+  compression_mode: MESSAGE
+",
+        )?;
+
+        let metadata = read_metadata(&dir)?;
+        assert!(metadata.namespaces().is_empty());
+        let mut records = Vec::new();
+        let topics = read_messages(&dir, &metadata, &["robot1".to_string()], |r| {
+            records.push(r);
+            Ok(())
+        })?;
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].msg_type, "nav_msgs/msg/Odometry");
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    topic: "/odom".into(),
+                    namespace: "robot1".into(),
+                    info: MessageInfo::received(1_000_000),
+                    payload: b"early".to_vec(),
+                },
+                Record {
+                    topic: "/odom".into(),
+                    namespace: "robot1".into(),
+                    info: MessageInfo::received(3_000_000),
+                    payload: b"late".to_vec(),
+                },
+            ]
+        );
+        Ok(())
+    }
+}
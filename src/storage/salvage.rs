@@ -0,0 +1,66 @@
+//! Salvage reader for damaged segments.
+//!
+//! Unlike `replay_segment`, which stops at the first bad frame, salvage skips
+//! over damage: after a frame fails validation it scans forward for the next
+//! frame magic whose header and CRC check out and resumes decoding there.
+
+use super::frame::{self, FrameCheck, FrameDecoder};
+use super::Record;
+use crate::security::Keyring;
+use std::ops::Range;
+use std::path::Path;
+
+/// Records recovered from a segment plus the byte ranges that had to be skipped
+#[derive(Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct SalvageReport {
+    pub records: Vec<Record>,
+    pub damaged: Vec<Range<u64>>,
+}
+
+impl SalvageReport {
+    #[allow(dead_code)]
+    pub fn damaged_bytes(&self) -> u64 {
+        self.damaged.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+/// Salvage the bytes of a segment; blob references resolve against `blob_dir`
+pub(super) fn salvage(buf: &[u8], keys: &Keyring, blob_dir: &Path) -> SalvageReport {
+    let mut report = SalvageReport::default();
+    let mut decoder = FrameDecoder::default().with_keys(keys.clone()).with_blobs(blob_dir.to_path_buf());
+    let mut offset = 0;
+
+    while offset < buf.len() {
+        if let FrameCheck::Valid { len, .. } = frame::check_frame(&buf[offset..]) {
+            let mut frame_bytes = &buf[offset..offset + len];
+            match decoder.next_record(&mut frame_bytes) {
+                Ok(Some(record)) => report.records.push(record),
+                Ok(None) => {}
+                // Intact bytes that still cannot be decoded, e.g. a message whose
+                // dictionary or key frame was lost
+                Err(e) => {
+                    tracing::debug!("undecodable frame at offset {}: {:#}", offset, e);
+                    push_range(&mut report.damaged, offset, offset + len);
+                }
+            }
+            offset += len;
+            continue;
+        }
+
+        let next = frame::find_next_frame(buf, offset + 1).unwrap_or(buf.len());
+        push_range(&mut report.damaged, offset, next);
+        offset = next;
+    }
+
+    report
+}
+
+/// Append a damaged range, merging it with the previous one when adjacent
+fn push_range(ranges: &mut Vec<Range<u64>>, start: usize, end: usize) {
+    let (start, end) = (start as u64, end as u64);
+    match ranges.last_mut() {
+        Some(last) if last.end == start => last.end = end,
+        _ => ranges.push(start..end),
+    }
+}
@@ -0,0 +1,138 @@
+//! Named recording sessions.
+//!
+//! A session is a directory under `sessions/` holding the segments written
+//! while it was active, plus a `session.json` manifest (`RecordingMetadata`).
+//! Segment numbers stay global across the data directory: starting or
+//! stopping a session only changes the directory the writer opens its next
+//! segment in, so everything that walks segments (replay, range reads, sync)
+//! sees session and loose segments alike.
+
+use super::index;
+use crate::utils::{RecordingMetadata, TopicManifestEntry};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub(super) const SESSIONS_DIR: &str = "sessions";
+const MANIFEST_FILE: &str = "session.json";
+const MAX_ID_LEN: usize = 128;
+
+pub(super) fn session_dir(root: &Path, id: &str) -> PathBuf {
+    root.join(SESSIONS_DIR).join(id)
+}
+
+/// Session ids double as directory names
+pub(super) fn validate_id(id: &str) -> Result<()> {
+    let valid_chars = id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if id.is_empty() || id.len() > MAX_ID_LEN || id.starts_with('.') || !valid_chars {
+        return Err(anyhow!("invalid session id {:?}: use up to {} of [A-Za-z0-9._-]", id, MAX_ID_LEN));
+    }
+    Ok(())
+}
+
+pub(super) fn now_ms() -> Result<u128> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_millis())
+}
+
+pub(super) async fn read_manifest(dir: &Path) -> Result<RecordingMetadata> {
+    let data = tokio::fs::read(dir.join(MANIFEST_FILE)).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+pub(super) async fn write_manifest(dir: &Path, metadata: &RecordingMetadata) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    tokio::fs::write(&tmp_path, serde_json::to_string_pretty(metadata)?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+/// Manifests of every session, oldest first. Directories without a readable
+/// manifest are skipped.
+pub(super) async fn list(root: &Path) -> Result<Vec<RecordingMetadata>> {
+    let mut out = Vec::new();
+    for dir in session_dirs(root).await? {
+        match read_manifest(&dir).await {
+            Ok(metadata) => out.push(metadata),
+            Err(e) => tracing::warn!("skipping session directory {}: {:#}", dir.display(), e),
+        }
+    }
+    out.sort_by_key(|m| m.start_time_unix_ms);
+    Ok(out)
+}
+
+pub(super) async fn session_dirs(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = match tokio::fs::read_dir(root.join(SESSIONS_DIR)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut dirs = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// Manifest entry of a topic whose type is known but that has no messages yet
+pub(super) fn declared_topic(topic: &str, message_type: &str) -> TopicManifestEntry {
+    TopicManifestEntry {
+        topic: topic.to_string(),
+        msg_type: message_type.to_string(),
+        sample_rate_hz: None,
+        message_count: 0,
+        qos_profiles: None,
+    }
+}
+
+/// Per-topic counts and rates of a session, from its segment indexes.
+/// Message types are not in the segments; `finalize` fills them in.
+pub(super) fn summarize_topics(segments: &[PathBuf], stride: usize) -> Vec<TopicManifestEntry> {
+    let mut topics: BTreeMap<String, (u64, u128, u128)> = BTreeMap::new();
+    for path in segments {
+        let segment_index = match index::load_or_rebuild(path, stride) {
+            Ok(segment_index) => segment_index,
+            Err(e) => {
+                tracing::warn!("leaving {} out of the session summary: {:#}", path.display(), e);
+                continue;
+            }
+        };
+        for (topic, stats) in segment_index.topics {
+            let entry = topics.entry(topic).or_insert((0, u128::MAX, 0));
+            entry.0 += stats.count;
+            entry.1 = entry.1.min(stats.min_timestamp);
+            entry.2 = entry.2.max(stats.max_timestamp);
+        }
+    }
+    topics
+        .into_iter()
+        .map(|(topic, (count, first, last))| TopicManifestEntry {
+            topic,
+            msg_type: String::new(),
+            sample_rate_hz: (count > 1 && last > first).then(|| (count - 1) as f32 * 1e9 / (last - first) as f32),
+            message_count: count,
+            qos_profiles: None,
+        })
+        .collect()
+}
+
+/// Close out a session: set its end time and topic summary. Types and QoS
+/// profiles the manifest already lists are kept.
+pub(super) async fn finalize(dir: &Path, segments: Vec<PathBuf>, stride: usize, end_time: u128) -> Result<RecordingMetadata> {
+    let mut metadata = read_manifest(dir).await?;
+    metadata.end_time_unix_ms = Some(end_time);
+    let mut topics = tokio::task::spawn_blocking(move || summarize_topics(&segments, stride)).await?;
+    for entry in &mut topics {
+        if let Some(known) = metadata.topics.iter().find(|t| t.topic == entry.topic) {
+            entry.msg_type = known.msg_type.clone();
+            entry.qos_profiles = known.qos_profiles.clone();
+        }
+    }
+    metadata.topics = topics;
+    write_manifest(dir, &metadata).await?;
+    Ok(metadata)
+}
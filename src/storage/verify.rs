@@ -0,0 +1,544 @@
+//! Integrity check of a data directory.
+//!
+//! `verify` walks every segment (loose and in sessions) frame by frame:
+//! magic, header and CRC of each frame, decodability of its header, blob
+//! references, per-topic receive time order, the frame counter of encrypted
+//! segments, and the footer's hash, length and message count. It also
+//! checks that `.checkpoint` points at the active segment. Nothing is
+//! changed unless repair is requested: sealed segments with damage are then
+//! rewritten from what salvage recovers, and segments nothing can be
+//! recovered from are moved to `quarantine/`. Encrypted segments whose key
+//! is not loaded are left alone, since salvage could not read them; the
+//! others are rewritten under their original master key. The active segment
+//! is left to startup recovery.
+
+use super::blob;
+use super::compaction::{PartitionOptions, SegmentFileWriter};
+use super::crypto::DataKey;
+use super::footer;
+use super::frame::{self, FrameCheck, FrameDecoder, FrameKind, FRAME_HEADER_LEN};
+use super::index::index_path;
+use super::recovery::QUARANTINE_DIR;
+use super::retention::uploaded_marker;
+use super::{salvage, segment_number, session};
+use crate::security::Keyring;
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Machine-readable result of `Storage::verify`
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub root: PathBuf,
+    pub checkpoint: CheckpointStatus,
+    pub segments: Vec<SegmentStatus>,
+    /// Every segment is `ok`, `warning` or `active` and the checkpoint is consistent
+    pub healthy: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckpointStatus {
+    pub current_segment: Option<u64>,
+    pub session: Option<String>,
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentHealth {
+    Ok,
+    /// Readable in full, but e.g. without footer or out of time order
+    Warning,
+    /// Some frames are corrupt or undecodable, the rest can be salvaged
+    Damaged,
+    /// Nothing can be recovered
+    Unrecoverable,
+    /// Still being written; only checked, never repaired
+    Active,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RepairAction {
+    /// Rewritten from `kept_messages` salvaged records
+    Rewritten { kept_messages: u64 },
+    Quarantined { to: PathBuf },
+    /// Left alone because the sync queue holds it
+    SkippedPinned,
+    /// Left alone because it is encrypted under a master key that is not loaded
+    SkippedMissingKey,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentStatus {
+    pub path: PathBuf,
+    pub segment: u64,
+    pub status: SegmentHealth,
+    pub size_bytes: u64,
+    pub frames: u64,
+    pub messages: u64,
+    /// Byte ranges of corrupt or undecodable frames
+    pub damaged: Vec<Range<u64>>,
+    /// Messages received earlier than the previous message of their topic
+    pub out_of_order: u64,
+    /// Breaks in an encrypted segment's frame counter, where messages were
+    /// dropped, repeated or reordered
+    pub counter_gaps: u64,
+    pub problems: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair: Option<RepairAction>,
+}
+
+/// Check the `.checkpoint` contents against the writer's state and the
+/// segments on disk
+pub(super) fn check_checkpoint(
+    root: &Path,
+    active: u64,
+    active_session: Option<&str>,
+    segments: &[PathBuf],
+) -> CheckpointStatus {
+    let mut status = CheckpointStatus::default();
+    let manifest = match std::fs::read(root.join(".checkpoint")) {
+        Ok(data) => serde_json::from_slice::<serde_json::Value>(&data),
+        // Written on the first rotation; until then segment 0 is active
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && active == 0 => return status,
+        Err(e) => {
+            status.problems.push(format!("cannot read .checkpoint: {}", e));
+            return status;
+        }
+    };
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            status.problems.push(format!(".checkpoint is not valid JSON: {}", e));
+            return status;
+        }
+    };
+    status.current_segment = manifest["current_segment"].as_u64();
+    status.session = manifest["session"].as_str().map(str::to_string);
+    match status.current_segment {
+        Some(n) if n != active => status
+            .problems
+            .push(format!("checkpoint names segment {} but segment {} is active", n, active)),
+        Some(_) => {}
+        None => status.problems.push("checkpoint has no current_segment".to_string()),
+    }
+    if status.session.as_deref() != active_session {
+        status.problems.push(format!(
+            "checkpoint names session {:?} but {:?} is recording",
+            status.session, active_session
+        ));
+    }
+    if let Some(ahead) = segments.iter().filter_map(|p| segment_number(p)).find(|n| *n > active) {
+        status.problems.push(format!("segment {} is newer than the active segment", ahead));
+    }
+    status
+}
+
+/// Check one segment; `sealed` segments must end in a matching footer
+pub(super) fn check_segment(path: &Path, sealed: bool) -> SegmentStatus {
+    let mut status = SegmentStatus {
+        path: path.to_path_buf(),
+        segment: segment_number(path).unwrap_or_default(),
+        status: SegmentHealth::Ok,
+        size_bytes: 0,
+        frames: 0,
+        messages: 0,
+        damaged: Vec::new(),
+        out_of_order: 0,
+        counter_gaps: 0,
+        problems: Vec::new(),
+        repair: None,
+    };
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) => {
+            status.problems.push(format!("cannot read segment: {}", e));
+            status.status = SegmentHealth::Unrecoverable;
+            return status;
+        }
+    };
+    status.size_bytes = buf.len() as u64;
+
+    let blob_dir = blob::blob_dir_for(path);
+    let mut decoder = FrameDecoder::default().headers_only();
+    let mut latest: HashMap<String, u128> = HashMap::new();
+    let mut footer_at = None;
+    let mut offset = 0;
+    while offset < buf.len() {
+        let len = match frame::check_frame(&buf[offset..]) {
+            FrameCheck::Valid { len, kind } => {
+                if kind == Some(FrameKind::Footer) {
+                    footer_at = Some(offset);
+                }
+                len
+            }
+            check => {
+                let next = frame::find_next_frame(&buf, offset + 1).unwrap_or(buf.len());
+                status.problems.push(format!("{:?} frame at offset {}, skipped {} bytes", check, offset, next - offset));
+                push_range(&mut status.damaged, offset, next);
+                offset = next;
+                continue;
+            }
+        };
+        status.frames += 1;
+        match decoder.next_record(&mut &buf[offset..offset + len]) {
+            Ok(Some(record)) => {
+                status.messages += 1;
+                let last = latest.entry(record.topic).or_default();
+                if record.info.receive_ns < *last {
+                    status.out_of_order += 1;
+                }
+                *last = (*last).max(record.info.receive_ns);
+                if let Some((expected, found)) = decoder.last_gap() {
+                    status.counter_gaps += 1;
+                    let what = match found.checked_sub(expected) {
+                        Some(missing) => format!("{} messages missing before it", missing),
+                        None => "it repeats or precedes an earlier one".to_string(),
+                    };
+                    status.problems.push(format!(
+                        "encrypted message at offset {} is number {}, expected {}: {}",
+                        offset, found, expected, what
+                    ));
+                }
+                if let Some(id) = decoder.last_blob().filter(|id| !blob::blob_exists(&blob_dir, id)) {
+                    status.problems.push(format!("blob {} at offset {} is missing", blob::to_hex(&id), offset));
+                    push_range(&mut status.damaged, offset, offset + len);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                status.problems.push(format!("undecodable frame at offset {}: {:#}", offset, e));
+                push_range(&mut status.damaged, offset, offset + len);
+            }
+        }
+        offset += len;
+    }
+    if status.out_of_order > 0 {
+        status.problems.push(format!("{} messages out of receive time order", status.out_of_order));
+    }
+
+    let mut footer_mismatch = false;
+    match (footer::read_footer(path), footer_at) {
+        (Ok(Some(footer)), Some(at)) => {
+            let digest = format!("{:x}", Sha256::digest(&buf[..at]));
+            let data_messages = status.messages;
+            let mut check = |ok: bool, what: &str| {
+                if !ok {
+                    status.problems.push(format!("footer {} does not match the segment", what));
+                    footer_mismatch = true;
+                }
+            };
+            check(footer.data_bytes == at as u64, "length");
+            check(footer.sha256 == digest, "SHA-256");
+            check(footer.message_count == data_messages, "message count");
+        }
+        (Err(e), _) => {
+            status.problems.push(format!("unreadable footer: {:#}", e));
+            footer_mismatch = true;
+        }
+        (Ok(None), Some(at)) => {
+            status.problems.push(format!("footer at offset {} does not close the segment", at));
+            footer_mismatch = true;
+        }
+        _ if sealed => status.problems.push("sealed segment has no footer".to_string()),
+        _ => {}
+    }
+
+    status.status = if !sealed {
+        SegmentHealth::Active
+    } else if !status.damaged.is_empty() && status.messages == 0 {
+        SegmentHealth::Unrecoverable
+    } else if !status.damaged.is_empty() || footer_mismatch || status.counter_gaps > 0 {
+        SegmentHealth::Damaged
+    } else if !status.problems.is_empty() {
+        SegmentHealth::Warning
+    } else {
+        SegmentHealth::Ok
+    };
+    status
+}
+
+/// Rewrite a damaged segment from its salvageable records, or quarantine it
+/// when there are none. Pinned segments and encrypted ones whose key is not
+/// in `keys` are skipped.
+pub(super) fn repair(
+    root: &Path,
+    status: &mut SegmentStatus,
+    keys: &Keyring,
+    opts: PartitionOptions,
+    pinned: &HashSet<PathBuf>,
+) -> Result<()> {
+    let path = status.path.clone();
+    if pinned.contains(&path) {
+        status.repair = Some(RepairAction::SkippedPinned);
+        return Ok(());
+    }
+    let buf = std::fs::read(&path).unwrap_or_default();
+    let mut master_key = None;
+    if let FrameCheck::Valid { len, kind: Some(FrameKind::Key) } = frame::check_frame(&buf) {
+        let body = &buf[FRAME_HEADER_LEN..len];
+        if let Err(e) = DataKey::open(keys, body) {
+            status.problems.push(format!("not repaired: {:#}", e));
+            status.repair = Some(RepairAction::SkippedMissingKey);
+            return Ok(());
+        }
+        master_key = Some(DataKey::master_key_id(body)?);
+    }
+    let records = salvage::salvage(&buf, keys, &blob::blob_dir_for(&path)).records;
+    if records.is_empty() {
+        let quarantine = root.join(QUARANTINE_DIR);
+        std::fs::create_dir_all(&quarantine)?;
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let mut to = quarantine.join(&name);
+        if to.exists() {
+            to = quarantine.join(format!("{}.{}", name, session::now_ms()?));
+        }
+        std::fs::rename(&path, &to)?;
+        for sidecar in [index_path(&path), uploaded_marker(&path)] {
+            match std::fs::remove_file(&sidecar) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        tracing::warn!("moved unrecoverable segment {} to {}", path.display(), to.display());
+        status.repair = Some(RepairAction::Quarantined { to });
+        return Ok(());
+    }
+
+    // Written next to the segment and renamed over it; the sidecar goes
+    // straight to the segment's `.idx`, which is stale until the rename.
+    // The rewrite keeps the master key the segment was written under (or
+    // none), whatever key is active now
+    let tmp = path.with_extension("repair");
+    let keys = keys.clone().with_active(master_key)?;
+    let mut writer = SegmentFileWriter::create(&tmp, &keys, opts)?;
+    for record in &records {
+        writer.write(record)?;
+    }
+    writer.finish(&tmp, status.segment)?;
+    std::fs::rename(&tmp, &path)?;
+    if let Some(dir) = path.parent() {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    tracing::warn!(
+        "rewrote {} with {} of {} messages",
+        path.display(),
+        records.len(),
+        status.messages
+    );
+    status.repair = Some(RepairAction::Rewritten { kept_messages: records.len() as u64 });
+    Ok(())
+}
+
+fn push_range(ranges: &mut Vec<Range<u64>>, start: usize, end: usize) {
+    let (start, end) = (start as u64, end as u64);
+    match ranges.last_mut() {
+        Some(last) if last.end >= start => last.end = last.end.max(end),
+        _ => ranges.push(start..end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DurabilityPolicy, StorageConfig};
+    use crate::storage::{reader, test_config, Storage};
+    use tempfile::TempDir;
+
+    fn verify_config(path: &Path) -> StorageConfig {
+        StorageConfig {
+            max_segment_messages: Some(10),
+            durability: DurabilityPolicy::OsBuffered,
+            index_stride: 4,
+            ..test_config(path, 1024 * 1024)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_and_repairs_damage() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let storage = Storage::new(&verify_config(tmpdir.path())).await?;
+        for ts in 0..35u128 {
+            storage.append_record("/odometry", "robot1", format!("odom {}", ts).as_bytes(), ts * 1_000_000).await?;
+        }
+        storage.sync().await?;
+
+        let report = storage.verify(false).await?;
+        assert!(report.healthy, "{:#?}", report);
+        assert!(report.checkpoint.problems.is_empty());
+        assert_eq!(report.checkpoint.current_segment, Some(3));
+        let statuses: Vec<SegmentHealth> = report.segments.iter().map(|s| s.status).collect();
+        assert_eq!(statuses, [SegmentHealth::Ok, SegmentHealth::Ok, SegmentHealth::Ok, SegmentHealth::Active]);
+        assert_eq!(report.segments[0].messages, 10);
+
+        // One flipped byte in a message of segment 0, segment 1 overwritten entirely
+        let segment_0 = tmpdir.path().join("segment-0.log");
+        let mut bytes = std::fs::read(&segment_0)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&segment_0, &bytes)?;
+        std::fs::write(tmpdir.path().join("segment-1.log"), vec![0u8; 512])?;
+
+        let report = storage.verify(false).await?;
+        assert!(!report.healthy);
+        let damaged = &report.segments[0];
+        assert_eq!(damaged.status, SegmentHealth::Damaged);
+        assert_eq!(damaged.messages, 9);
+        assert_eq!(damaged.damaged.len(), 1);
+        assert!(damaged.problems.iter().any(|p| p.contains("message count")));
+        assert_eq!(report.segments[1].status, SegmentHealth::Unrecoverable);
+        assert!(report.segments[0].repair.is_none());
+        let json = serde_json::to_value(&report)?;
+        assert_eq!(json["segments"][1]["status"], "unrecoverable");
+
+        let report = storage.verify(true).await?;
+        assert_eq!(report.segments[0].repair, Some(RepairAction::Rewritten { kept_messages: 9 }));
+        let quarantined = tmpdir.path().join("quarantine").join("segment-1.log");
+        assert_eq!(report.segments[1].repair, Some(RepairAction::Quarantined { to: quarantined.clone() }));
+        assert!(quarantined.exists());
+
+        let report = storage.verify(false).await?;
+        assert!(report.healthy, "{:#?}", report);
+        assert_eq!(report.segments.len(), 3);
+        let records = Storage::replay_segment(&segment_0).await?;
+        assert_eq!(records.len(), 9);
+        assert_eq!(storage.read_range(&["/odometry"], 0, 10_000_000).await?.len(), 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_without_repair_changes_nothing() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = verify_config(tmpdir.path());
+        let storage = Storage::new(&cfg).await?;
+        for ts in 0..3u128 {
+            storage.append_record("/odometry", "robot1", b"odom", ts).await?;
+        }
+        storage.sync().await?;
+        let active = storage.active_segment_path().await;
+        drop(storage);
+
+        // A torn tail, which startup recovery would cut off
+        let mut bytes = std::fs::read(&active)?;
+        bytes.extend_from_within(..20);
+        std::fs::write(&active, &bytes)?;
+
+        let storage = Storage::open_read_only(&cfg, Keyring::default()).await?;
+        let report = storage.verify(false).await?;
+        assert_eq!(report.segments[0].status, SegmentHealth::Active);
+        assert_eq!(report.segments[0].damaged.len(), 1, "{:#?}", report);
+        assert!(storage.verify(true).await.is_err());
+        assert!(storage.append_record("/odometry", "robot1", b"odom", 3).await.is_err());
+        assert_eq!(std::fs::read(&active)?, bytes);
+        assert!(!tmpdir.path().join(QUARANTINE_DIR).exists());
+
+        drop(storage);
+        Storage::new(&cfg).await?;
+        assert_eq!(std::fs::metadata(&active)?.len() as usize, bytes.len() - 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_segment_is_not_repaired_without_its_key() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut creds = crate::security::StoredCredentials::default();
+        creds.add_master_key("site-1");
+        let mut cfg = verify_config(tmpdir.path());
+        cfg.encryption = Some("site-1".to_string());
+        cfg.enable_aes_gcm = true;
+        let storage = Storage::with_keyring(&cfg, Keyring::from_credentials(&creds)?).await?;
+        for ts in 0..4u128 {
+            storage.append_record("/odometry", "robot1", b"odom", ts).await?;
+        }
+        let segment = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        drop(storage);
+        let mut bytes = std::fs::read(&segment)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&segment, &bytes)?;
+
+        let storage = Storage::new(&verify_config(tmpdir.path())).await?;
+        let report = storage.verify(true).await?;
+        assert_eq!(report.segments[0].status, SegmentHealth::Damaged);
+        assert_eq!(report.segments[0].repair, Some(RepairAction::SkippedMissingKey));
+        assert!(report.segments[0].problems.iter().any(|p| p.contains("site-1")), "{:#?}", report.segments[0]);
+        assert_eq!(std::fs::read(&segment)?, bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repair_keeps_segment_encryption() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut creds = crate::security::StoredCredentials::default();
+        creds.add_master_key("site-1");
+        let keys = Keyring::from_credentials(&creds)?;
+        let mut cfg = verify_config(tmpdir.path());
+        cfg.encryption = Some("site-1".to_string());
+        cfg.enable_aes_gcm = true;
+        let storage = Storage::with_keyring(&cfg, keys.clone()).await?;
+        for ts in 0..4u128 {
+            storage.append_record("/odometry", "robot1", format!("odom {}", ts).as_bytes(), ts).await?;
+        }
+        let segment = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        drop(storage);
+        let mut bytes = std::fs::read(&segment)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&segment, &bytes)?;
+
+        // The key is loaded but no longer active: the rewrite must not come out in plaintext
+        let storage = Storage::with_keyring(&verify_config(tmpdir.path()), keys.clone()).await?;
+        let report = storage.verify(true).await?;
+        assert_eq!(report.segments[0].repair, Some(RepairAction::Rewritten { kept_messages: 3 }));
+        assert_eq!(reader::master_key_id(&segment)?.as_deref(), Some("site-1"));
+        assert!(Storage::replay_segment(&segment).await.is_err());
+        assert_eq!(Storage::replay_segment_with_keys(&segment, &keys).await?.len(), 3);
+        assert!(storage.verify(false).await?.healthy);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_dropped_encrypted_frames() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut creds = crate::security::StoredCredentials::default();
+        creds.add_master_key("site-1");
+        let keys = Keyring::from_credentials(&creds)?;
+        let mut cfg = verify_config(tmpdir.path());
+        cfg.encryption = Some("site-1".to_string());
+        cfg.enable_aes_gcm = true;
+        let storage = Storage::with_keyring(&cfg, keys.clone()).await?;
+        for ts in 0..4u128 {
+            storage.append_record("/odometry", "robot1", format!("odom {}", ts).as_bytes(), ts).await?;
+        }
+        let segment = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        assert!(storage.verify(false).await?.healthy);
+
+        // Cut the second message out; every frame left is intact and authentic
+        let bytes = std::fs::read(&segment)?;
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while let FrameCheck::Valid { len, kind } = frame::check_frame(&bytes[offset..]) {
+            frames.push((offset..offset + len, kind));
+            offset += len;
+        }
+        let messages: Vec<Range<usize>> =
+            frames.into_iter().filter(|(_, kind)| *kind == Some(FrameKind::Message)).map(|(r, _)| r).collect();
+        std::fs::write(&segment, [&bytes[..messages[1].start], &bytes[messages[1].end..]].concat())?;
+        assert_eq!(Storage::replay_segment_with_keys(&segment, &keys).await?.len(), 3);
+
+        let report = storage.verify(false).await?;
+        let status = &report.segments[0];
+        assert_eq!(status.status, SegmentHealth::Damaged);
+        assert_eq!(status.counter_gaps, 1);
+        let gap = "number 2, expected 1: 1 messages missing";
+        assert!(status.problems.iter().any(|p| p.contains(gap)), "{:#?}", status);
+        Ok(())
+    }
+}
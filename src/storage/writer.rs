@@ -0,0 +1,662 @@
+//! Group-commit segment writer.
+//!
+//! A single task owns the active segment file. `append_record` callers push
+//! already-compressed frames into a bounded channel; the writer drains whatever
+//! is queued, writes it with one `write_all`, and fsyncs according to the
+//! configured `DurabilityPolicy`. Waiters are acknowledged once their frame
+//! reaches the point they asked for. A failed write cuts the segment back to
+//! the last batch that reached the file. Every byte written is also fed to a
+//! running SHA-256 that ends up in the footer when the segment is sealed.
+//! With an active master key each segment gets a fresh data key, declared in
+//! a key frame ahead of its first message. The blob references of a segment
+//! are handed to the blob store when it is sealed.
+
+use super::blob::{BlobStore, BLOB_REF_LEN};
+use super::crypto::{DataKey, SEAL_OVERHEAD};
+use super::footer::{self, SegmentFooter};
+use super::frame::{self, PayloadCodec, SegmentDictionary, FRAME_HEADER_LEN, MESSAGE_PREFIX_LEN};
+use super::index::{self, IndexBuilder};
+use super::{DurabilityPoint, MessageInfo, RotationReason, SegmentEvent, SegmentRotated, Storage, StorageInner};
+use crate::config::{DurabilityPolicy, StorageConfig};
+use crate::security::Keyring;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+
+/// Maximum number of queued commands folded into one write/fsync
+const MAX_BATCH: usize = 512;
+/// Housekeeping tick when no periodic fsync interval is configured
+const IDLE_TICK: Duration = Duration::from_secs(1);
+
+pub(super) type Ack = oneshot::Sender<Result<()>>;
+
+pub(super) struct PendingRecord {
+    pub topic: String,
+    pub namespace: String,
+    pub info: MessageInfo,
+    pub codec: PayloadCodec,
+    pub stored: Vec<u8>,
+    pub raw_len: usize,
+    /// `stored` is the digest of a blob already in the blob store
+    pub blob: Option<[u8; BLOB_REF_LEN]>,
+    pub wait: DurabilityPoint,
+    pub done: Option<Ack>,
+}
+
+/// Directory and session the next segment is opened in
+pub(super) struct RotateTarget {
+    pub dir: PathBuf,
+    pub session: Option<String>,
+}
+
+pub(super) enum WriteCommand {
+    Append(PendingRecord),
+    Rotate {
+        target: Option<RotateTarget>,
+        done: oneshot::Sender<Result<PathBuf>>,
+    },
+    Sync { done: Ack },
+    /// Write half of the next batch, then fail it with ENOSPC
+    #[cfg(test)]
+    FailNextWrite,
+}
+
+/// Thresholds that trigger segment rotation
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RotationPolicy {
+    max_bytes: u64,
+    max_duration: Option<Duration>,
+    max_messages: Option<u64>,
+}
+
+impl RotationPolicy {
+    pub(super) fn from_config(cfg: &StorageConfig) -> Self {
+        RotationPolicy {
+            max_bytes: cfg.wal_segment_size as u64,
+            max_duration: cfg
+                .max_segment_duration_secs
+                .or_else(|| cfg.blackbox.enabled.then(|| cfg.blackbox.segment_secs()))
+                .map(Duration::from_secs),
+            max_messages: cfg.max_segment_messages,
+        }
+    }
+
+    /// Decide whether the next frame of `frame_len` bytes must go to a new segment
+    fn check(&self, inner: &StorageInner, frame_len: u64) -> Option<RotationReason> {
+        // An empty segment always takes the frame, even an oversized one
+        if inner.current_segment_messages == 0 {
+            return None;
+        }
+        if inner.current_segment_size + frame_len > self.max_bytes {
+            return Some(RotationReason::Size);
+        }
+        if self.max_messages.is_some_and(|max| inner.current_segment_messages >= max) {
+            return Some(RotationReason::MessageCount);
+        }
+        self.check_age(inner)
+    }
+
+    fn check_age(&self, inner: &StorageInner) -> Option<RotationReason> {
+        let expired = self.max_duration.is_some_and(|max| inner.segment_opened.elapsed() >= max);
+        (expired && inner.current_segment_messages > 0).then_some(RotationReason::Duration)
+    }
+}
+
+pub(super) struct SegmentWriter {
+    root: Arc<PathBuf>,
+    inner: Arc<Mutex<StorageInner>>,
+    rotation: RotationPolicy,
+    durability: DurabilityPolicy,
+    index_stride: usize,
+    events: broadcast::Sender<SegmentEvent>,
+    file: Option<File>,
+    /// Running hash of the active segment; `None` after a failed write left
+    /// the file contents unknown, in which case it is rehashed on seal
+    digest: Option<Sha256>,
+    /// Encrypts new segments when it has an active master key
+    keys: Keyring,
+    /// Data key of the active segment, once its key frame is written
+    data_key: Option<DataKey>,
+    blobs: Option<Arc<std::sync::Mutex<BlobStore>>>,
+    pending: Vec<u8>,
+    /// Messages in `pending`
+    pending_messages: u64,
+    /// Blob references in `pending`, counted in the blob store once written
+    pending_blobs: Vec<[u8; BLOB_REF_LEN]>,
+    /// Bytes of the active segment that reached the file
+    written: u64,
+    unsynced_bytes: u64,
+    unsynced_messages: u64,
+    /// Messages lost to a failed rotation, encode, write or fsync, including
+    /// ones already acknowledged at `Queued`
+    lost_messages: Arc<AtomicU64>,
+    last_sync: Instant,
+    awaiting_write: Vec<Ack>,
+    awaiting_sync: Vec<Ack>,
+    #[cfg(test)]
+    fail_next_write: bool,
+}
+
+impl SegmentWriter {
+    pub(super) fn new(
+        root: Arc<PathBuf>,
+        inner: Arc<Mutex<StorageInner>>,
+        cfg: &StorageConfig,
+        events: broadcast::Sender<SegmentEvent>,
+        digest: Sha256,
+        keys: Keyring,
+        data_key: Option<DataKey>,
+    ) -> Self {
+        SegmentWriter {
+            root,
+            inner,
+            rotation: RotationPolicy::from_config(cfg),
+            durability: cfg.durability,
+            index_stride: cfg.index_stride,
+            events,
+            file: None,
+            digest: Some(digest),
+            keys,
+            data_key,
+            blobs: None,
+            pending: Vec::new(),
+            pending_messages: 0,
+            pending_blobs: Vec::new(),
+            written: 0,
+            unsynced_bytes: 0,
+            unsynced_messages: 0,
+            lost_messages: Arc::new(AtomicU64::new(0)),
+            last_sync: Instant::now(),
+            awaiting_write: Vec::new(),
+            awaiting_sync: Vec::new(),
+            #[cfg(test)]
+            fail_next_write: false,
+        }
+    }
+
+    /// Blob store the references of sealed segments are reported to
+    pub(super) fn with_blobs(mut self, blobs: Option<Arc<std::sync::Mutex<BlobStore>>>) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    /// Count of messages lost to failed writes and fsyncs, kept up to date
+    /// while the writer runs
+    pub(super) fn lost_messages(&self) -> Arc<AtomicU64> {
+        self.lost_messages.clone()
+    }
+
+    /// Run until every `Storage` handle is dropped, then make the tail durable
+    pub(super) async fn run(mut self, mut rx: mpsc::Receiver<WriteCommand>) {
+        // Recovery left the active segment at its last good frame
+        self.written = self.inner.lock().await.current_segment_size;
+        let period = match self.durability {
+            DurabilityPolicy::Periodic { interval_ms, .. } => Duration::from_millis(interval_ms.max(1)).min(IDLE_TICK),
+            _ => IDLE_TICK,
+        };
+        let mut tick = tokio::time::interval(period);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                cmd = rx.recv() => match cmd {
+                    Some(cmd) => {
+                        let mut batch = vec![cmd];
+                        while batch.len() < MAX_BATCH {
+                            match rx.try_recv() {
+                                Ok(cmd) => batch.push(cmd),
+                                Err(_) => break,
+                            }
+                        }
+                        self.process_batch(batch).await;
+                    }
+                    None => break,
+                },
+                _ = tick.tick() => self.on_tick().await,
+            }
+        }
+
+        if let Err(e) = self.sync().await {
+            tracing::error!("final segment sync failed: {:#}", e);
+        }
+    }
+
+    async fn process_batch(&mut self, batch: Vec<WriteCommand>) {
+        let inner = self.inner.clone();
+        let mut inner = inner.lock().await;
+        let mut sync_requested = false;
+
+        for cmd in batch {
+            match cmd {
+                WriteCommand::Append(record) => self.append(&mut inner, record).await,
+                WriteCommand::Rotate { target, done } => {
+                    let result = match target {
+                        Some(target) => self.rotate_into(&mut inner, target).await,
+                        None => self.rotate(&mut inner, RotationReason::Manual).await,
+                    };
+                    let _ = done.send(result);
+                }
+                WriteCommand::Sync { done } => {
+                    self.awaiting_sync.push(done);
+                    sync_requested = true;
+                }
+                #[cfg(test)]
+                WriteCommand::FailNextWrite => self.fail_next_write = true,
+            }
+        }
+
+        if self.flush_pending(&mut inner).await.is_err() {
+            return;
+        }
+        let sync_due = match self.durability {
+            DurabilityPolicy::PerRecord => self.unsynced_bytes > 0 || !self.awaiting_sync.is_empty(),
+            // `Synced` waiters ride along with the next periodic fsync
+            DurabilityPolicy::Periodic { interval_ms, max_bytes } => {
+                self.unsynced_bytes >= max_bytes
+                    || (self.unsynced_bytes > 0 && self.last_sync.elapsed() >= Duration::from_millis(interval_ms))
+            }
+            DurabilityPolicy::OsBuffered => !self.awaiting_sync.is_empty(),
+        };
+        if sync_due || sync_requested {
+            if let Err(e) = self.sync_locked(&mut inner).await {
+                tracing::error!("segment sync failed: {:#}", e);
+            }
+        }
+    }
+
+    async fn on_tick(&mut self) {
+        let inner = self.inner.clone();
+        let mut inner = inner.lock().await;
+
+        if let Some(reason) = self.rotation.check_age(&inner) {
+            if let Err(e) = self.rotate(&mut inner, reason).await {
+                tracing::error!("timed segment rotation failed: {:#}", e);
+            }
+        }
+
+        if let DurabilityPolicy::Periodic { interval_ms, .. } = self.durability {
+            let dirty = self.unsynced_bytes > 0 || !self.awaiting_sync.is_empty();
+            if dirty && self.last_sync.elapsed() >= Duration::from_millis(interval_ms) {
+                if let Err(e) = self.sync_locked(&mut inner).await {
+                    tracing::error!("periodic segment sync failed: {:#}", e);
+                }
+            }
+        }
+    }
+
+    async fn append(&mut self, inner: &mut StorageInner, record: PendingRecord) {
+        let encrypted = self.keys.active().is_some();
+        let frame_len = inner.dictionary.declaration_len(&record.topic, &record.namespace)
+            + FRAME_HEADER_LEN
+            + MESSAGE_PREFIX_LEN
+            + record.stored.len()
+            + if encrypted { SEAL_OVERHEAD } else { 0 };
+        if let Some(reason) = self.rotation.check(inner, frame_len as u64) {
+            if let Err(e) = self.rotate(inner, reason).await {
+                self.reject(record, e);
+                return;
+            }
+        }
+
+        if encrypted && inner.current_segment_size == 0 {
+            match DataKey::generate(&self.keys) {
+                Ok((data_key, body)) => {
+                    let key_frame = frame::encode_key(&body);
+                    inner.current_segment_size += key_frame.len() as u64;
+                    self.pending.extend(key_frame);
+                    self.data_key = Some(data_key);
+                }
+                Err(e) => {
+                    self.reject(record, e);
+                    return;
+                }
+            }
+        }
+
+        let (topic_id, namespace_id, dictionary_frame) = inner.dictionary.intern(&record.topic, &record.namespace);
+        let seal = self.data_key.as_ref().map(|key| frame::Seal {
+            key,
+            counter: inner.current_segment_messages,
+            topic: &record.topic,
+            namespace: &record.namespace,
+        });
+        let message = match frame::encode_message(
+            topic_id,
+            namespace_id,
+            &record.info,
+            record.codec,
+            &record.stored,
+            record.raw_len,
+            if record.blob.is_some() { frame::FLAG_BLOB } else { 0 },
+            seal,
+        ) {
+            Ok(message) => message,
+            Err(e) => {
+                self.reject(record, e);
+                return;
+            }
+        };
+
+        let mut written = message.len();
+        if let Some(dictionary_frame) = dictionary_frame {
+            written += dictionary_frame.len();
+            self.pending.extend(dictionary_frame);
+        }
+        self.pending.extend(message);
+        self.pending_messages += 1;
+        inner.index.observe(inner.current_segment_size, &record.topic, record.info.receive_ns);
+        if let Some(id) = record.blob {
+            inner.index.observe_blob(&id);
+            self.pending_blobs.push(id);
+        }
+        inner.current_segment_size += written as u64;
+        inner.current_segment_messages += 1;
+
+        if let Some(done) = record.done {
+            match record.wait {
+                DurabilityPoint::Queued => {
+                    let _ = done.send(Ok(()));
+                }
+                DurabilityPoint::Written => self.awaiting_write.push(done),
+                DurabilityPoint::Synced => self.awaiting_sync.push(done),
+            }
+        }
+    }
+
+    /// Hand buffered frames to the OS and acknowledge `Written` waiters
+    async fn flush_pending(&mut self, inner: &mut StorageInner) -> Result<()> {
+        if !self.pending.is_empty() {
+            let result = self.write_pending(inner).await;
+            if let Err(e) = result {
+                tracing::error!("segment write failed: {:#}", e);
+                self.file = None;
+                self.pending.clear();
+                self.lost_messages.fetch_add(std::mem::take(&mut self.pending_messages), Ordering::Relaxed);
+                match self.roll_back(inner).await {
+                    Ok(()) => self.settle_blobs(false),
+                    Err(e) => {
+                        // Rehash on seal; the tail is repaired on the next startup, and
+                        // its blobs stay held until the counts are rebuilt there
+                        tracing::error!("could not roll back segment {}: {:#}", inner.current_segment, e);
+                        self.digest = None;
+                        self.pending_blobs.clear();
+                    }
+                }
+                let msg = format!("{:#}", e);
+                for done in self.awaiting_write.drain(..).chain(self.awaiting_sync.drain(..)) {
+                    let _ = done.send(Err(anyhow!("segment write failed: {}", msg)));
+                }
+                return Err(e);
+            }
+        }
+        for done in self.awaiting_write.drain(..) {
+            let _ = done.send(Ok(()));
+        }
+        Ok(())
+    }
+
+    async fn write_pending(&mut self, inner: &StorageInner) -> Result<()> {
+        if self.file.is_none() {
+            let path = inner.segment_path(inner.current_segment);
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&path).await?);
+        }
+        let file = self.file.as_mut().expect("segment file opened above");
+        #[cfg(test)]
+        if std::mem::take(&mut self.fail_next_write) {
+            file.write_all(&self.pending[..self.pending.len() / 2]).await?;
+            file.flush().await?;
+            return Err(std::io::Error::from_raw_os_error(28).into());
+        }
+        file.write_all(&self.pending).await?;
+        file.flush().await?;
+        if let Some(digest) = self.digest.as_mut() {
+            digest.update(&self.pending);
+        }
+        self.written += self.pending.len() as u64;
+        self.unsynced_bytes += self.pending.len() as u64;
+        self.unsynced_messages += std::mem::take(&mut self.pending_messages);
+        self.pending.clear();
+        self.settle_blobs(true);
+        Ok(())
+    }
+
+    /// Cut the active segment back to the bytes that reached the file and
+    /// rebuild the size, message count, dictionary and index from them
+    async fn roll_back(&mut self, inner: &mut StorageInner) -> Result<()> {
+        let path = inner.segment_path(inner.current_segment);
+        let len = self.written;
+        let stride = self.index_stride;
+        let (index, dictionary) = tokio::task::spawn_blocking(move || {
+            std::fs::OpenOptions::new().write(true).open(&path)?.set_len(len)?;
+            IndexBuilder::scan(&path, stride)
+        })
+        .await??;
+        tracing::warn!(
+            "rolled segment {} back from {} to {} bytes",
+            inner.current_segment,
+            inner.current_segment_size,
+            len
+        );
+        inner.current_segment_size = len;
+        inner.current_segment_messages = index.message_count();
+        inner.index = index;
+        inner.dictionary = dictionary;
+        if len == 0 {
+            // The key frame was lost with the batch; the next append writes a new one
+            self.data_key = None;
+        }
+        Ok(())
+    }
+
+    /// fsync the active segment and acknowledge every `Synced` waiter
+    async fn sync(&mut self) -> Result<()> {
+        let inner = self.inner.clone();
+        let mut inner = inner.lock().await;
+        self.sync_locked(&mut inner).await
+    }
+
+    async fn sync_locked(&mut self, inner: &mut StorageInner) -> Result<()> {
+        self.flush_pending(inner).await?;
+        if self.unsynced_bytes > 0 {
+            if let Some(file) = self.file.as_mut() {
+                if let Err(e) = file.sync_all().await {
+                    // The kernel may have dropped the dirty pages already
+                    self.lost_messages.fetch_add(std::mem::take(&mut self.unsynced_messages), Ordering::Relaxed);
+                    let msg = format!("{:#}", e);
+                    for done in self.awaiting_sync.drain(..) {
+                        let _ = done.send(Err(anyhow!("segment sync failed: {}", msg)));
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        self.unsynced_bytes = 0;
+        self.unsynced_messages = 0;
+        self.last_sync = Instant::now();
+        for done in self.awaiting_sync.drain(..) {
+            let _ = done.send(Ok(()));
+        }
+        Ok(())
+    }
+
+    /// Continue in another directory. An active segment that is still empty
+    /// is moved there instead of being sealed.
+    async fn rotate_into(&mut self, inner: &mut StorageInner, target: RotateTarget) -> Result<PathBuf> {
+        if inner.current_segment_size > 0 {
+            return self.seal_and_open(inner, RotationReason::Session, Some(target)).await;
+        }
+
+        let empty_path = inner.segment_path(inner.current_segment);
+        let new_path = target.dir.join(format!("segment-{}.log", inner.current_segment));
+        let _ = File::create(&new_path).await?;
+        Storage::write_checkpoint(&self.root, inner.current_segment, target.session.as_deref()).await?;
+        self.file = None;
+        self.digest = Some(Sha256::new());
+        self.data_key = None;
+        inner.dir = target.dir;
+        inner.session = target.session;
+        match tokio::fs::remove_file(&empty_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        inner.segment_opened = Instant::now();
+        Ok(new_path)
+    }
+
+    /// Seal the active segment with a footer and open the next one
+    async fn rotate(&mut self, inner: &mut StorageInner, reason: RotationReason) -> Result<PathBuf> {
+        self.seal_and_open(inner, reason, None).await
+    }
+
+    async fn seal_and_open(
+        &mut self,
+        inner: &mut StorageInner,
+        reason: RotationReason,
+        target: Option<RotateTarget>,
+    ) -> Result<PathBuf> {
+        // Everything acknowledged so far must be durable before the segment is sealed
+        self.sync_locked(inner).await?;
+
+        let sealed_segment = inner.current_segment;
+        let sealed_path = inner.segment_path(sealed_segment);
+        let target = target.unwrap_or_else(|| RotateTarget { dir: inner.dir.clone(), session: inner.session.clone() });
+        let new_path = target.dir.join(format!("segment-{}.log", sealed_segment + 1));
+        let data_len = inner.current_segment_size;
+        let digest = self.digest.clone();
+        if let Err(e) = self.seal(inner, &new_path, target.session.as_deref()).await {
+            self.unseal(inner, data_len, digest, &new_path).await;
+            return Err(e);
+        }
+        self.file = None;
+        self.digest = Some(Sha256::new());
+        self.data_key = None;
+        self.written = 0;
+        inner.dir = target.dir;
+        inner.session = target.session;
+
+        let event = SegmentRotated {
+            sealed_segment,
+            sealed_path,
+            size_bytes: inner.current_segment_size,
+            message_count: inner.current_segment_messages,
+            reason,
+            new_path,
+        };
+
+        let builder = std::mem::replace(&mut inner.index, IndexBuilder::new(self.index_stride));
+        if let Some(blobs) = &self.blobs {
+            blobs.lock().unwrap().assign(sealed_segment, builder.blobs().clone());
+        }
+        let sealed_index = builder.finish(sealed_segment, inner.current_segment_size, &inner.dictionary);
+        let sealed_path = event.sealed_path.clone();
+        // The index is rebuilt on demand if this fails, so sealing still succeeds
+        match tokio::task::spawn_blocking(move || index::write(&sealed_path, &sealed_index)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("could not write index of segment {}: {:#}", sealed_segment, e),
+            Err(e) => tracing::warn!("could not write index of segment {}: {}", sealed_segment, e),
+        }
+
+        inner.current_segment = sealed_segment + 1;
+        inner.current_segment_size = 0;
+        inner.current_segment_messages = 0;
+        inner.segment_opened = Instant::now();
+        inner.dictionary = SegmentDictionary::default();
+        tracing::info!("rotated to segment {} ({:?})", inner.current_segment, reason);
+
+        let new_path = event.new_path.clone();
+        // No subscribers is not an error
+        let _ = self.events.send(SegmentEvent::Rotated(event));
+        Ok(new_path)
+    }
+
+    /// Write the footer, create the next segment file and point the
+    /// checkpoint at it; the fallible half of a rotation
+    async fn seal(&mut self, inner: &mut StorageInner, new_path: &Path, session: Option<&str>) -> Result<()> {
+        self.write_footer(inner).await?;
+        let _ = File::create(new_path).await?;
+        Storage::write_checkpoint(&self.root, inner.current_segment + 1, session).await
+    }
+
+    /// Undo a failed `seal`: cut the footer back off so the active segment
+    /// takes more frames, and drop the next segment file
+    async fn unseal(&mut self, inner: &mut StorageInner, data_len: u64, digest: Option<Sha256>, new_path: &Path) {
+        self.file = None;
+        self.pending.clear();
+        let path = inner.segment_path(inner.current_segment);
+        match tokio::task::spawn_blocking(move || std::fs::OpenOptions::new().write(true).open(&path)?.set_len(data_len))
+            .await
+        {
+            Ok(Ok(())) => {
+                self.written = data_len;
+                self.digest = digest;
+            }
+            // The footer stays in place; the tail is repaired on the next startup
+            Ok(Err(e)) => tracing::error!("could not unseal segment {}: {:#}", inner.current_segment, e),
+            Err(e) => tracing::error!("could not unseal segment {}: {}", inner.current_segment, e),
+        }
+        inner.current_segment_size = data_len;
+        if let Err(e) = tokio::fs::remove_file(new_path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("could not remove {}: {}", new_path.display(), e);
+            }
+        }
+        if let Err(e) = Storage::write_checkpoint(&self.root, inner.current_segment, inner.session.as_deref()).await {
+            tracing::error!("could not restore checkpoint: {:#}", e);
+        }
+    }
+
+    async fn write_footer(&mut self, inner: &mut StorageInner) -> Result<()> {
+        let digest = match self.digest.take() {
+            Some(digest) => digest,
+            None => {
+                let path = inner.segment_path(inner.current_segment);
+                let len = inner.current_segment_size;
+                tokio::task::spawn_blocking(move || footer::hash_prefix(&path, len)).await??
+            }
+        };
+        let footer = SegmentFooter::new(digest, inner.current_segment_size, &inner.index)?;
+        let frame = footer.encode()?;
+        let frame_len = frame.len() as u64;
+        self.pending.extend(frame);
+        // The running digest is gone, so the footer bytes are not hashed
+        self.sync_locked(inner).await?;
+        inner.current_segment_size += frame_len;
+        Ok(())
+    }
+
+    /// Count the blob references of `pending` once it is written, or let go
+    /// of them when it is dropped
+    fn settle_blobs(&mut self, written: bool) {
+        let ids = std::mem::take(&mut self.pending_blobs);
+        if let Some(blobs) = self.blobs.as_ref().filter(|_| !ids.is_empty()) {
+            let mut blobs = blobs.lock().unwrap();
+            for id in &ids {
+                if written {
+                    blobs.commit(id);
+                } else {
+                    blobs.discard(id);
+                }
+            }
+        }
+    }
+
+    /// Fail a record that never reached the pending batch
+    fn reject(&self, record: PendingRecord, e: anyhow::Error) {
+        self.lost_messages.fetch_add(1, Ordering::Relaxed);
+        if let (Some(blobs), Some(id)) = (&self.blobs, record.blob) {
+            blobs.lock().unwrap().discard(&id);
+        }
+        match record.done {
+            Some(done) => {
+                let _ = done.send(Err(e));
+            }
+            None => tracing::error!("queued record dropped: {:#}", e),
+        }
+    }
+}
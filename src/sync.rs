@@ -1,22 +1,64 @@
+mod throttle;
+
 use crate::config::SyncConfig;
-use crate::storage::Storage;
-use anyhow::Result;
+use crate::s3::{CompletedPart, S3Client};
+use crate::storage::{SegmentEvent, Storage};
+use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
+pub use throttle::Throttle;
+
+/// Upload journal in the data directory, rewritten after every part
+const JOURNAL_FILE: &str = ".upload-journal";
+/// Segments that failed `max_retries` times, kept for inspection and requeueing
+const DEAD_LETTER_FILE: &str = ".upload-dead-letters";
+/// Longest sleep while the queue is empty or backing off
+const IDLE_POLL: Duration = Duration::from_secs(5);
+/// Shortest sleep after a failed attempt, whether or not it was recorded
+const ERROR_PAUSE: Duration = Duration::from_secs(1);
+
 /// Resumable upload state persisted to disk
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadState {
     pub segment_path: String,
     pub segment_sha256: String,
+    /// Multipart upload in progress, set once it has been created
+    #[serde(default)]
+    pub upload_id: Option<String>,
+    /// Parts stored so far, in the order they finished
     pub chunks_uploaded: Vec<UploadedChunk>,
+    /// The object is complete; only the uploaded marker is left to write
+    #[serde(default)]
+    pub completed: bool,
     pub timestamp: u128,
+    /// Failed attempts so far
+    #[serde(default)]
+    pub attempts: u32,
+    /// Unix ms before which the segment is not retried
+    #[serde(default)]
+    pub next_attempt_ms: u128,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+/// A segment the daemon gave up on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub upload: UploadState,
+    pub failed_at: u128,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,14 +67,22 @@ pub struct UploadedChunk {
     pub chunk_size: usize,
     pub sha256: String,
     pub upload_id: Option<String>,
+    /// ETag of the part, listed again by CompleteMultipartUpload
+    #[serde(default)]
+    pub etag: String,
 }
 
 #[derive(Clone)]
 pub struct SyncDaemon {
-    #[allow(dead_code)]
     storage: Storage,
     config: SyncConfig,
+    /// `None` when the credential vault holds no S3 credentials
+    client: Option<S3Client>,
+    /// Upload bandwidth cap, shared with `client`
+    throttle: Throttle,
     upload_queue: Arc<Mutex<Vec<UploadState>>>,
+    /// Locked after `upload_queue` when both are needed
+    dead_letters: Arc<Mutex<Vec<DeadLetter>>>,
     sync_status: Arc<Mutex<SyncStatus>>,
 }
 
@@ -42,143 +92,1080 @@ pub struct SyncStatus {
     pub last_sync_time: Option<u128>,
     pub upload_errors: usize,
     pub total_segments_synced: usize,
+    pub queued: usize,
+    pub dead_letters: usize,
 }
 
 impl SyncDaemon {
-    pub fn new(storage: Storage, config: SyncConfig) -> Self {
+    pub fn new(storage: Storage, config: SyncConfig, client: Option<S3Client>) -> Self {
+        let throttle = Throttle::new(config.max_upload_bytes_per_sec);
         SyncDaemon {
             storage,
             config,
+            client: client.map(|client| client.with_throttle(throttle.clone())),
+            throttle,
             upload_queue: Arc::new(Mutex::new(Vec::new())),
+            dead_letters: Arc::new(Mutex::new(Vec::new())),
             sync_status: Arc::new(Mutex::new(SyncStatus {
                 is_syncing: false,
                 last_sync_time: None,
                 upload_errors: 0,
                 total_segments_synced: 0,
+                queued: 0,
+                dead_letters: 0,
             })),
         }
     }
 
     #[allow(dead_code)]
     pub async fn get_status(&self) -> SyncStatus {
-        self.sync_status.lock().await.clone()
+        let queued = self.upload_queue.lock().await.len();
+        let dead_letters = self.dead_letters.lock().await.len();
+        SyncStatus { queued, dead_letters, ..self.sync_status.lock().await.clone() }
     }
 
-    /// Queue a segment for upload
+    /// Reload the upload journal and dead-letter list after a restart,
+    /// pinning their segments again. Segments that were uploaded or deleted
+    /// meanwhile are dropped.
+    pub async fn restore(&self) -> Result<usize> {
+        let states: Vec<UploadState> = read_json(&self.journal_path()).await?;
+        let dead: Vec<DeadLetter> = read_json(&self.dead_letter_path()).await?;
+        let mut queue = self.upload_queue.lock().await;
+        let mut dead_letters = self.dead_letters.lock().await;
+        for state in states {
+            let segment_path = PathBuf::from(&state.segment_path);
+            if Storage::is_uploaded(&segment_path).await || !segment_path.exists() {
+                continue;
+            }
+            if queue.iter().any(|queued| queued.segment_path == state.segment_path) {
+                continue;
+            }
+            self.storage.pin_segment(&segment_path).await;
+            if !state.chunks_uploaded.is_empty() {
+                tracing::info!(
+                    "resuming upload of {} after {} parts",
+                    state.segment_path,
+                    state.chunks_uploaded.len()
+                );
+            }
+            queue.push(state);
+        }
+        // A crash while moving a segment between the lists leaves it in both;
+        // it is retried rather than given up on
+        for letter in dead {
+            let segment_path = PathBuf::from(&letter.upload.segment_path);
+            if Storage::is_uploaded(&segment_path).await || !segment_path.exists() {
+                continue;
+            }
+            if queue.iter().any(|queued| queued.segment_path == letter.upload.segment_path) {
+                continue;
+            }
+            self.storage.pin_segment(&segment_path).await;
+            dead_letters.push(letter);
+        }
+        if !dead_letters.is_empty() {
+            tracing::warn!("{} segments on the upload dead-letter list", dead_letters.len());
+        }
+        self.write_journal(&queue).await?;
+        self.write_dead_letters(&dead_letters).await?;
+        Ok(queue.len())
+    }
+
+    /// Current upload bandwidth cap in bytes per second
+    #[allow(dead_code)]
+    pub fn bandwidth_limit(&self) -> Option<u64> {
+        self.throttle.rate()
+    }
+
+    /// Change the upload bandwidth cap, `None` lifting it; running uploads follow
     #[allow(dead_code)]
+    pub fn set_bandwidth_limit(&self, bytes_per_sec: Option<u64>) {
+        self.throttle.set_rate(bytes_per_sec);
+        tracing::debug!("upload bandwidth cap set to {:?} bytes/s", bytes_per_sec);
+    }
+
+    /// Segments the daemon gave up on, oldest failure first
+    #[allow(dead_code)]
+    pub async fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().await.clone()
+    }
+
+    /// Move a dead-lettered segment back to the queue with a fresh retry budget
+    #[allow(dead_code)]
+    pub async fn requeue_dead_letter(&self, segment_path: &str) -> Result<()> {
+        let mut queue = self.upload_queue.lock().await;
+        let mut dead_letters = self.dead_letters.lock().await;
+        let idx = dead_letters
+            .iter()
+            .position(|letter| letter.upload.segment_path == segment_path)
+            .ok_or_else(|| anyhow!("{} is not on the dead-letter list", segment_path))?;
+        let mut state = dead_letters[idx].upload.clone();
+        state.attempts = 0;
+        state.next_attempt_ms = 0;
+        queue.push(state);
+        // Journal first: a crash in between leaves it in both lists, which
+        // `restore` resolves in favour of the queue
+        self.write_journal(&queue).await?;
+        dead_letters.remove(idx);
+        self.write_dead_letters(&dead_letters).await?;
+        tracing::info!("requeued {} for upload", segment_path);
+        Ok(())
+    }
+
+    /// Whether there are credentials to upload with
+    pub fn is_configured(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Queue sealed segments that are neither uploaded nor journaled. One
+    /// already in the bucket with the same checksum only lost its uploaded
+    /// marker, e.g. to a crash right after the upload completed, and is
+    /// marked instead.
+    pub async fn reconcile(&self) -> Result<usize> {
+        let segments = self.storage.list_segments().await?;
+        // Read after listing, so a segment sealed in between counts as sealed
+        let active = self.storage.active_segment_path().await;
+        let remote: HashMap<String, u64> = match &self.client {
+            Some(client) => match client.list_objects(&self.key_prefix()).await {
+                Ok(objects) => objects.into_iter().map(|o| (o.key, o.size)).collect(),
+                Err(e) => {
+                    tracing::warn!("cannot list the bucket, queueing without checking it: {:#}", e);
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let mut queued = 0;
+        for path in segments {
+            if path == active || Storage::is_uploaded(&path).await {
+                continue;
+            }
+            if self.is_tracked(&path.to_string_lossy()).await {
+                continue;
+            }
+            let key = self.object_key(&path);
+            let size = tokio::fs::metadata(&path).await?.len();
+            if remote.get(&key) == Some(&size) && self.is_in_bucket(&key, &path).await? {
+                tracing::info!("{} is already in the bucket", path.display());
+                self.storage.mark_uploaded(&path).await?;
+                continue;
+            }
+            self.queue_segment(path).await?;
+            queued += 1;
+        }
+        Ok(queued)
+    }
+
+    /// Whether the object at `key` holds `path`, by the footer digest it was
+    /// uploaded with. Same-sized objects without one are uploaded again.
+    async fn is_in_bucket(&self, key: &str, path: &Path) -> Result<bool> {
+        let Some(client) = &self.client else {
+            return Ok(false);
+        };
+        let remote = match client.head_object(key).await {
+            Ok(head) => head.and_then(|head| head.data_sha256),
+            Err(e) => {
+                tracing::warn!("cannot check {} in the bucket, uploading it again: {:#}", key, e);
+                None
+            }
+        };
+        match remote {
+            Some(sha256) => Ok(sha256 == segment_data_sha256(path).await?),
+            None => Ok(false),
+        }
+    }
+
+    /// Queue segments left over from earlier runs, then each segment as it is
+    /// sealed, following segments a black-box trigger moves
+    pub async fn watch_segments(&self) {
+        // Subscribed first so no rotation slips in between
+        let mut events = self.storage.subscribe_segment_events();
+        match self.reconcile().await {
+            Ok(0) => {}
+            Ok(queued) => tracing::info!("queued {} sealed segments for upload", queued),
+            Err(e) => tracing::error!("upload reconciliation failed: {:#}", e),
+        }
+        loop {
+            match events.recv().await {
+                Ok(event) => self.handle_segment_event(event).await,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("missed {} rotations, rescanning segments", missed);
+                    if let Err(e) = self.reconcile().await {
+                        tracing::error!("upload reconciliation failed: {:#}", e);
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    async fn handle_segment_event(&self, event: SegmentEvent) {
+        match event {
+            SegmentEvent::Rotated(event) => {
+                if let Err(e) = self.queue_segment(event.sealed_path.clone()).await {
+                    tracing::error!("failed to queue {}: {:#}", event.sealed_path.display(), e);
+                }
+            }
+            SegmentEvent::Moved(renames) => {
+                if let Err(e) = self.segments_moved(&renames).await {
+                    tracing::error!("failed to follow {} moved segments: {:#}", renames.len(), e);
+                }
+            }
+        }
+    }
+
+    /// Re-key queued and dead-lettered segments a black-box trigger moved.
+    /// The object key follows the path, so the segment is uploaded again
+    /// under its new key and an unfinished upload under the old one is
+    /// aborted. Moved segments that are neither tracked nor uploaded, e.g.
+    /// because they moved before their rotation was handled, are queued.
+    async fn segments_moved(&self, renames: &[(PathBuf, PathBuf)]) -> Result<()> {
+        let mut abandoned = Vec::new();
+        let mut untracked = Vec::new();
+        let mut queue = self.upload_queue.lock().await;
+        let mut dead_letters = self.dead_letters.lock().await;
+        for (from, to) in renames {
+            let key = from.to_string_lossy();
+            let tracked = queue
+                .iter_mut()
+                .chain(dead_letters.iter_mut().map(|letter| &mut letter.upload))
+                .find(|state| state.segment_path == key);
+            let Some(state) = tracked else {
+                untracked.push(to.clone());
+                continue;
+            };
+            if let (false, Some(upload_id)) = (state.completed, state.upload_id.take()) {
+                abandoned.push((self.object_key(from), upload_id));
+            }
+            state.segment_path = to.to_string_lossy().to_string();
+            state.upload_id = None;
+            state.chunks_uploaded.clear();
+            state.completed = false;
+            tracing::info!("{} moved to {}, uploading it under the new key", from.display(), to.display());
+        }
+        self.write_journal(&queue).await?;
+        self.write_dead_letters(&dead_letters).await?;
+        drop(dead_letters);
+        drop(queue);
+
+        if let Some(client) = &self.client {
+            for (key, upload_id) in abandoned {
+                if let Err(e) = client.abort_multipart_upload(&key, &upload_id).await {
+                    tracing::warn!("failed to abort upload {} of {}: {:#}", upload_id, key, e);
+                }
+            }
+        }
+        for path in untracked {
+            if !Storage::is_uploaded(&path).await {
+                self.queue_segment(path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a segment is queued or dead-lettered
+    async fn is_tracked(&self, segment_path: &str) -> bool {
+        self.upload_queue.lock().await.iter().any(|s| s.segment_path == segment_path)
+            || self.dead_letters.lock().await.iter().any(|l| l.upload.segment_path == segment_path)
+    }
+
+    /// Queue a segment for upload, unless it is queued or dead-lettered already
     pub async fn queue_segment(&self, segment_path: PathBuf) -> Result<()> {
-        let sha256 = Storage::segment_checksum(&segment_path).await?;
+        let mut queue = self.upload_queue.lock().await;
+        let key = segment_path.to_string_lossy().to_string();
+        let dead = self.dead_letters.lock().await.iter().any(|l| l.upload.segment_path == key);
+        if dead || queue.iter().any(|queued| queued.segment_path == key) {
+            return Ok(());
+        }
+        // Retention must not delete the segment before it is uploaded
+        self.storage.pin_segment(&segment_path).await;
+        let sha256 = match segment_data_sha256(&segment_path).await {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                self.storage.unpin_segment(&segment_path).await;
+                return Err(e);
+            }
+        };
         let state = UploadState {
-            segment_path: segment_path.to_string_lossy().to_string(),
+            segment_path: key,
             segment_sha256: sha256,
+            upload_id: None,
             chunks_uploaded: Vec::new(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_millis(),
+            completed: false,
+            timestamp: now_ms(),
+            attempts: 0,
+            next_attempt_ms: 0,
+            last_error: None,
         };
-        self.upload_queue.lock().await.push(state);
+        queue.push(state);
+        self.write_journal(&queue).await?;
         tracing::info!("queued segment for upload: {}", segment_path.display());
         Ok(())
     }
 
-    /// Main sync loop: process queue with exponential backoff and retries
-    pub async fn sync_loop(&self, mut max_retries: usize) {
+    /// Main sync loop: upload due segments, backing off per segment after failures
+    pub async fn sync_loop(&self) {
+        if self.client.is_none() {
+            // Nothing can be uploaded; journaled segments wait for a restart with credentials
+            return;
+        }
         loop {
-            {
-                let queue = self.upload_queue.lock().await;
-                if !queue.is_empty() {
-                    let mut status = self.sync_status.lock().await;
-                    status.is_syncing = true;
-                } else {
-                    let mut status = self.sync_status.lock().await;
-                    status.is_syncing = false;
-                    drop(status);
-                    sleep(Duration::from_secs(5)).await;
-                    continue;
-                }
-            }
-
-            // Process first item in queue
-            let result = self.process_next_upload(max_retries).await;
-
-            match result {
-                Ok(()) => {
+            match self.process_next_upload().await {
+                Ok(true) => {
                     let mut status = self.sync_status.lock().await;
                     status.total_segments_synced += 1;
-                    status.last_sync_time = Some(
-                        std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap_or_default()
-                            .as_millis(),
-                    );
+                    status.last_sync_time = Some(now_ms());
                     tracing::info!(
                         "segment uploaded successfully (total: {})",
                         status.total_segments_synced
                     );
-                    max_retries = 7; // Reset retries after success
+                }
+                Ok(false) => {
+                    // Idle, or every queued segment is backing off
+                    let now = now_ms();
+                    let queue = self.upload_queue.lock().await;
+                    let wait = queue
+                        .iter()
+                        .map(|state| Duration::from_millis(state.next_attempt_ms.saturating_sub(now) as u64))
+                        .min()
+                        .map_or(IDLE_POLL, |wait| wait.min(IDLE_POLL));
+                    let is_syncing = !queue.is_empty();
+                    drop(queue);
+                    self.sync_status.lock().await.is_syncing = is_syncing;
+                    sleep(wait).await;
                 }
                 Err(e) => {
-                    let mut status = self.sync_status.lock().await;
-                    status.upload_errors += 1;
-                    tracing::error!("upload failed: {:#?}, retrying...", e);
-                    // Apply exponential backoff
-                    let backoff_secs = 2_u64.min((max_retries as u64).saturating_mul(2)).min(120);
-                    sleep(Duration::from_secs(backoff_secs)).await;
-                    max_retries = max_retries.saturating_sub(1);
-                    if max_retries == 0 {
-                        max_retries = 7; // Reset for next item
-                    }
+                    self.sync_status.lock().await.upload_errors += 1;
+                    tracing::error!("upload failed: {:#}", e);
+                    sleep(ERROR_PAUSE).await;
                 }
             }
         }
     }
 
-    async fn process_next_upload(&self, _retries: usize) -> Result<()> {
-        let mut queue = self.upload_queue.lock().await;
-        if queue.is_empty() {
+    /// Upload the first segment that is due. `Ok(false)` if none is; a
+    /// failure is recorded against the segment before it is returned.
+    async fn process_next_upload(&self) -> Result<bool> {
+        let client = self.client.as_ref().ok_or_else(|| anyhow!("no S3 credentials in the credential vault"))?;
+        // The entry stays in the queue, and the journal, until it is uploaded
+        let now = now_ms();
+        let due = self.upload_queue.lock().await.iter().find(|state| state.next_attempt_ms <= now).cloned();
+        let Some(state) = due else {
+            return Ok(false);
+        };
+        self.sync_status.lock().await.is_syncing = true;
+        let segment_path = PathBuf::from(&state.segment_path);
+        let result = async {
+            // A completed upload is never repeated, only its bookkeeping
+            if !state.completed {
+                self.upload_segment(client, state).await?;
+            }
+            self.finish_upload(&segment_path).await
+        }
+        .await;
+        if let Err(e) = result {
+            if let Err(journal) = self.record_failure(&segment_path, &e).await {
+                tracing::error!("failed to record upload failure: {:#}", journal);
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Mark a segment whose object is complete as uploaded and drop it from the journal
+    async fn finish_upload(&self, segment_path: &Path) -> Result<()> {
+        // Moved into a capture during the upload, and queued under its new path
+        if !self.is_tracked(&segment_path.to_string_lossy()).await {
             return Ok(());
         }
+        self.storage.mark_uploaded(segment_path).await?;
+        let mut queue = self.upload_queue.lock().await;
+        queue.retain(|queued| Path::new(&queued.segment_path) != segment_path);
+        self.write_journal(&queue).await?;
+        drop(queue);
+        self.storage.unpin_segment(segment_path).await;
+        Ok(())
+    }
 
-        let state = queue.remove(0);
+    /// Schedule the next attempt, or move the segment to the dead-letter
+    /// list once it has failed `max_retries` times. It stays pinned there,
+    /// and its unfinished upload is aborted.
+    async fn record_failure(&self, segment_path: &Path, error: &anyhow::Error) -> Result<()> {
+        let mut queue = self.upload_queue.lock().await;
+        let Some(idx) = queue.iter().position(|state| Path::new(&state.segment_path) == segment_path) else {
+            return Ok(());
+        };
+        let state = &mut queue[idx];
+        state.attempts += 1;
+        state.last_error = Some(format!("{:#}", error));
+        let mut abandoned = None;
+        if state.attempts as usize >= self.config.max_retries.max(1) {
+            tracing::error!(
+                "giving up on {} after {} attempts, moved to the dead-letter list",
+                segment_path.display(),
+                state.attempts
+            );
+            let mut upload = queue.remove(idx);
+            if !upload.completed {
+                abandoned = upload.upload_id.take();
+                upload.chunks_uploaded.clear();
+            }
+            let mut dead_letters = self.dead_letters.lock().await;
+            dead_letters.push(DeadLetter { upload, failed_at: now_ms() });
+            self.write_dead_letters(&dead_letters).await?;
+        } else {
+            let delay = backoff_delay(self.config.backoff_base_ms, self.config.backoff_max_ms, state.attempts);
+            state.next_attempt_ms = now_ms() + delay.as_millis();
+            tracing::warn!(
+                "attempt {} of {} for {} failed, retrying in {:.1} s",
+                state.attempts,
+                self.config.max_retries,
+                segment_path.display(),
+                delay.as_secs_f64()
+            );
+        }
+        self.write_journal(&queue).await?;
         drop(queue);
 
-        // Split segment into chunks
+        // Stored parts of an unfinished upload are billed until aborted
+        if let (Some(client), Some(upload_id)) = (&self.client, abandoned) {
+            let key = self.object_key(segment_path);
+            if let Err(e) = client.abort_multipart_upload(&key, &upload_id).await {
+                tracing::warn!("failed to abort upload {} of {}: {:#}", upload_id, key, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload a segment as one multipart object, one part per chunk, with
+    /// up to `concurrent_parts` in flight. Chunks are read from disk as parts
+    /// start, and parts the journal records and the store still lists are
+    /// skipped. A failed attempt leaves the upload open for the next one.
+    async fn upload_segment(&self, client: &S3Client, mut state: UploadState) -> Result<()> {
         let segment_path = PathBuf::from(&state.segment_path);
-        let data = tokio::fs::read(&segment_path).await?;
-        let chunk_size = self.config.chunk_size;
-        let chunks: Vec<Vec<u8>> = data
-            .chunks(chunk_size)
-            .map(|c| c.to_vec())
-            .collect();
+        let len = tokio::fs::metadata(&segment_path).await?.len();
+        let chunk_size = self.config.chunk_size.max(1) as u64;
+        // S3 completes no upload without parts, so an empty segment gets one
+        let chunk_count = len.div_ceil(chunk_size).max(1);
+        let chunk_len = move |idx: u64| (len.saturating_sub(idx * chunk_size)).min(chunk_size) as usize;
+        let key = self.object_key(&segment_path);
+
+        // Stored parts count only if they still match the file, e.g. not
+        // after a change of `chunk_size`
+        let mut resumable = true;
+        let mut seen = HashSet::new();
+        for chunk in &state.chunks_uploaded {
+            let idx = u64::from(chunk.chunk_index);
+            resumable = idx < chunk_count
+                && seen.insert(idx)
+                && chunk.chunk_size == chunk_len(idx)
+                && format!("{:x}", Sha256::digest(read_chunk(&segment_path, idx * chunk_size, chunk.chunk_size).await?))
+                    == chunk.sha256;
+            if !resumable {
+                break;
+            }
+        }
+        if !resumable {
+            tracing::warn!("discarding stored parts of {}, they no longer match the segment", key);
+            if let Some(stale) = state.upload_id.take() {
+                let _ = client.abort_multipart_upload(&key, &stale).await;
+            }
+            state.chunks_uploaded.clear();
+        }
 
-        tracing::info!("segment {} split into {} chunks", state.segment_path, chunks.len());
+        // The store may have dropped the upload, e.g. by a lifecycle rule
+        if let Some(upload_id) = state.upload_id.clone() {
+            match client.list_parts(&key, &upload_id).await? {
+                Some(listed) => {
+                    let listed: HashMap<u32, String> = listed.into_iter().map(|part| (part.part_number, part.etag)).collect();
+                    let journaled = state.chunks_uploaded.len();
+                    state.chunks_uploaded.retain(|chunk| listed.get(&(chunk.chunk_index + 1)) == Some(&chunk.etag));
+                    if state.chunks_uploaded.len() < journaled {
+                        tracing::warn!(
+                            "{} of the journaled parts of {} are not stored, uploading them again",
+                            journaled - state.chunks_uploaded.len(),
+                            key
+                        );
+                        self.record_progress(&state).await?;
+                    }
+                }
+                None => {
+                    tracing::warn!("upload {} of {} no longer exists, starting over", upload_id, key);
+                    state.upload_id = None;
+                    state.chunks_uploaded.clear();
+                    self.record_progress(&state).await?;
+                }
+            }
+        }
 
-        // Upload each chunk (simulate with local mock for now)
-        for (idx, chunk) in chunks.iter().enumerate() {
-            let chunk_sha256 = format!("{:x}", Sha256::digest(chunk));
+        let upload_id = match state.upload_id.clone() {
+            Some(upload_id) => {
+                tracing::info!(
+                    "resuming upload of {} with {} of {} parts stored",
+                    key,
+                    state.chunks_uploaded.len(),
+                    chunk_count
+                );
+                upload_id
+            }
+            None => {
+                tracing::info!("uploading {} as {} in {} parts", segment_path.display(), key, chunk_count);
+                let upload_id = client.create_multipart_upload(&key, &state.segment_sha256).await?;
+                state.upload_id = Some(upload_id.clone());
+                self.record_progress(&state).await?;
+                upload_id
+            }
+        };
 
-            // Mock upload: in a real system, this would call S3 multipart upload
-            self.mock_upload_chunk(idx as u32, chunk, &chunk_sha256).await?;
+        let stored: HashSet<u32> = state.chunks_uploaded.iter().map(|chunk| chunk.chunk_index).collect();
+        let missing: Vec<u64> = (0..chunk_count).filter(|idx| !stored.contains(&(*idx as u32))).collect();
+        // Each part in flight holds its chunk in memory. After a failure no
+        // further part starts, but those in flight are finished and journaled.
+        let failed = AtomicBool::new(false);
+        let mut parts = futures::stream::iter(missing)
+            .take_while(|_| futures::future::ready(!failed.load(Ordering::Relaxed)))
+            .map(|idx| {
+                let (segment_path, key, upload_id) = (&segment_path, &key, &upload_id);
+                async move {
+                    let size = chunk_len(idx);
+                    let data = read_chunk(segment_path, idx * chunk_size, size).await?;
+                    let sha256 = format!("{:x}", Sha256::digest(&data));
+                    let etag = client.upload_part(key, upload_id, idx as u32 + 1, data).await?;
+                    Ok::<_, anyhow::Error>(UploadedChunk {
+                        chunk_index: idx as u32,
+                        chunk_size: size,
+                        sha256,
+                        upload_id: Some(upload_id.clone()),
+                        etag,
+                    })
+                }
+            })
+            .buffer_unordered(self.config.concurrent_parts.max(1));
+        let mut error = None;
+        while let Some(chunk) = parts.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    tracing::debug!("uploaded part {} of {}", chunk.chunk_index + 1, chunk_count);
+                    state.chunks_uploaded.push(chunk);
+                    self.record_progress(&state).await?;
+                }
+                Err(e) => {
+                    failed.store(true, Ordering::Relaxed);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let mut parts: Vec<CompletedPart> = state
+            .chunks_uploaded
+            .iter()
+            .map(|chunk| CompletedPart { part_number: chunk.chunk_index + 1, etag: chunk.etag.clone() })
+            .collect();
+        parts.sort_by_key(|part| part.part_number);
+        client.complete_multipart_upload(&key, &upload_id, &parts).await?;
+        state.completed = true;
+        self.record_progress(&state).await
+    }
 
-            tracing::debug!("uploaded chunk {} of {}", idx, chunks.len());
+    /// Replace the queued entry of `state`'s segment and persist the journal
+    async fn record_progress(&self, state: &UploadState) -> Result<()> {
+        let mut queue = self.upload_queue.lock().await;
+        if let Some(queued) = queue.iter_mut().find(|queued| queued.segment_path == state.segment_path) {
+            *queued = state.clone();
         }
+        self.write_journal(&queue).await
+    }
 
-        Ok(())
+    fn journal_path(&self) -> PathBuf {
+        self.storage.root.join(JOURNAL_FILE)
     }
 
-    async fn mock_upload_chunk(&self, _idx: u32, _chunk: &[u8], _sha256: &str) -> Result<()> {
-        // Mock: simulate S3 multipart upload
-        // In real implementation: call reqwest with presigned URLs or multipart forms
-        // For now: just trace and succeed
-        tracing::debug!("mock upload chunk: sha256={}, size={}", _sha256, _chunk.len());
-        Ok(())
+    fn dead_letter_path(&self) -> PathBuf {
+        self.storage.root.join(DEAD_LETTER_FILE)
+    }
+
+    async fn write_journal(&self, queue: &[UploadState]) -> Result<()> {
+        write_json(self.journal_path(), serde_json::to_vec(queue)?).await
+    }
+
+    async fn write_dead_letters(&self, dead_letters: &[DeadLetter]) -> Result<()> {
+        write_json(self.dead_letter_path(), serde_json::to_vec(dead_letters)?).await
+    }
+
+    /// Start of every object key of this robot: `key_prefix` and a `/`
+    fn key_prefix(&self) -> String {
+        match self.config.key_prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        }
+    }
+
+    /// Object key: the key prefix, then the segment path relative to the
+    /// data directory
+    fn object_key(&self, segment_path: &Path) -> String {
+        let relative = segment_path
+            .strip_prefix(&*self.storage.root)
+            .ok()
+            .or_else(|| segment_path.file_name().map(Path::new))
+            .unwrap_or(segment_path);
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        format!("{}{}", self.key_prefix(), relative)
     }
 }
 
 #[allow(dead_code)]
-pub fn start_sync_daemon(storage: Storage, cfg: SyncConfig) -> JoinHandle<()> {
+pub fn start_sync_daemon(storage: Storage, cfg: SyncConfig, client: Option<S3Client>) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let daemon = SyncDaemon::new(storage, cfg);
-        daemon.sync_loop(7).await;
+        let daemon = SyncDaemon::new(storage, cfg, client);
+        daemon.sync_loop().await;
+    })
+}
+
+/// Delay before retry `attempt` (from 1): doubling from `base_ms` up to
+/// `max_ms`, then jittered over its upper half so robots that lost the
+/// network together do not retry in lockstep
+fn backoff_delay(base_ms: u64, max_ms: u64, attempt: u32) -> Duration {
+    let exponential = base_ms.saturating_mul(1 << attempt.saturating_sub(1).min(32));
+    let capped = exponential.min(max_ms);
+    Duration::from_millis(capped - capped / 2 + fastrand::u64(0..=capped / 2))
+}
+
+/// `len` bytes of `path` from `offset`
+async fn read_chunk(path: &Path, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut data = vec![0; len];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// SHA-256 of a sealed segment's frames: its footer's digest, or for a
+/// segment sealed without a footer, where every byte is a frame, the
+/// digest of the whole file
+async fn segment_data_sha256(path: &Path) -> Result<String> {
+    if let Some(sha256) = Storage::segment_checksum(path).await? {
+        return Ok(sha256);
+    }
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(&path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+fn now_ms() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// JSON list at `path`, empty if the file does not exist
+async fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data).with_context(|| format!("corrupt upload journal {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Atomically replace `path`: write a temporary file, fsync it, rename it
+/// over the old one and fsync the directory
+async fn write_json(path: PathBuf, data: Vec<u8>) -> Result<()> {
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        std::io::Write::write_all(&mut file, &data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        if let Some(dir) = path.parent() {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
     })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BlackBoxConfig, DurabilityPolicy, StorageConfig};
+    use crate::s3::stand_in::StandIn;
+    use crate::storage::test_config;
+    use tempfile::TempDir;
+
+    fn storage_config(dir: &Path) -> StorageConfig {
+        StorageConfig {
+            durability: DurabilityPolicy::OsBuffered,
+            write_queue_capacity: 16,
+            ..test_config(dir, 1024 * 1024)
+        }
+    }
+
+    async fn storage_with_sealed_segment(dir: &Path) -> Result<(Storage, PathBuf)> {
+        let storage = Storage::new(&storage_config(dir)).await?;
+        for ts in 0..20 {
+            storage.append_record("/odom", "robot1", &[ts as u8; 40], ts).await?;
+        }
+        let sealed = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        Ok((storage, sealed))
+    }
+
+    #[tokio::test]
+    async fn test_failed_part_is_retried_in_the_same_upload() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, sealed) = storage_with_sealed_segment(tmpdir.path()).await?;
+
+        let s3 = StandIn::start().await?;
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+        daemon.queue_segment(sealed.clone()).await?;
+
+        s3.state().fail_parts.insert(2);
+        assert!(daemon.process_next_upload().await.is_err());
+        assert!(s3.state().aborted.is_empty());
+        assert_eq!(s3.state().uploads.len(), 1);
+        assert!(!Storage::is_uploaded(&sealed).await);
+        let state = daemon.upload_queue.lock().await[0].clone();
+        assert!(state.upload_id.is_some());
+        assert!(!state.chunks_uploaded.is_empty());
+        assert!(state.chunks_uploaded.iter().all(|chunk| chunk.chunk_index != 1));
+
+        s3.state().fail_parts.clear();
+        let received = s3.state().parts_received;
+        daemon.process_next_upload().await?;
+        let object = s3.state().objects.get("robot-7/segment-0.log").cloned();
+        assert_eq!(object, Some(tokio::fs::read(&sealed).await?));
+        // Journaled parts were kept, only the rest was sent again
+        let chunks = object.unwrap().len().div_ceil(256);
+        assert_eq!(s3.state().parts_received - received, chunks - state.chunks_uploaded.len());
+        assert!(s3.state().aborted.is_empty());
+        assert!(Storage::is_uploaded(&sealed).await);
+        assert!(daemon.upload_queue.lock().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_completed_upload_is_not_repeated_when_marking_fails() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, sealed) = storage_with_sealed_segment(tmpdir.path()).await?;
+        let s3 = StandIn::start().await?;
+        let mut config = s3.sync_config(256);
+        config.backoff_base_ms = 60_000;
+        config.backoff_max_ms = 60_000;
+        let daemon = SyncDaemon::new(storage.clone(), config, Some(s3.client()));
+        daemon.queue_segment(sealed.clone()).await?;
+
+        // The uploaded marker cannot be written where a directory is in the way
+        let marker = sealed.with_extension("uploaded");
+        tokio::fs::create_dir(&marker).await?;
+        assert!(daemon.process_next_upload().await.is_err());
+        let received = s3.state().parts_received;
+        assert_eq!(s3.state().objects.len(), 1);
+        let state = daemon.upload_queue.lock().await[0].clone();
+        assert!(state.completed);
+        assert_eq!(state.attempts, 1);
+        assert!(state.next_attempt_ms > now_ms());
+        // Backing off, not picked up again at once
+        assert!(!daemon.process_next_upload().await?);
+
+        tokio::fs::remove_dir(&marker).await?;
+        daemon.upload_queue.lock().await[0].next_attempt_ms = 0;
+        assert!(daemon.process_next_upload().await?);
+        assert_eq!(s3.state().parts_received, received);
+        assert!(Storage::is_uploaded(&sealed).await);
+        assert!(daemon.upload_queue.lock().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vanished_upload_is_started_over() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, sealed) = storage_with_sealed_segment(tmpdir.path()).await?;
+        let s3 = StandIn::start().await?;
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+        daemon.queue_segment(sealed.clone()).await?;
+
+        s3.state().fail_parts.insert(2);
+        assert!(daemon.process_next_upload().await.is_err());
+        assert!(daemon.upload_queue.lock().await[0].upload_id.is_some());
+
+        // A lifecycle rule expires the unfinished upload before the retry
+        s3.state().uploads.clear();
+        s3.state().fail_parts.clear();
+        daemon.process_next_upload().await?;
+        let data = tokio::fs::read(&sealed).await?;
+        assert_eq!(s3.state().objects.get("robot-7/segment-0.log"), Some(&data));
+        assert!(s3.state().aborted.is_empty());
+        assert!(Storage::is_uploaded(&sealed).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_resumes_from_journal_after_restart() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, sealed) = storage_with_sealed_segment(tmpdir.path()).await?;
+        let s3 = StandIn::start().await?;
+
+        // First run: the process dies after storing the first part
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+        daemon.queue_segment(sealed.clone()).await?;
+        let data = tokio::fs::read(&sealed).await?;
+        let client = s3.client();
+        let mut state = daemon.upload_queue.lock().await[0].clone();
+        let upload_id = client.create_multipart_upload("robot-7/segment-0.log", &state.segment_sha256).await?;
+        let etag = client.upload_part("robot-7/segment-0.log", &upload_id, 1, data[..256].to_vec()).await?;
+        state.upload_id = Some(upload_id.clone());
+        state.chunks_uploaded.push(UploadedChunk {
+            chunk_index: 0,
+            chunk_size: 256,
+            sha256: format!("{:x}", Sha256::digest(&data[..256])),
+            upload_id: Some(upload_id.clone()),
+            etag,
+        });
+        daemon.record_progress(&state).await?;
+        storage.unpin_segment(&sealed).await;
+        drop(daemon);
+
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+        assert_eq!(daemon.restore().await?, 1);
+        let restored = daemon.upload_queue.lock().await[0].clone();
+        assert_eq!(restored.upload_id.as_deref(), Some(upload_id.as_str()));
+        assert_eq!(restored.chunks_uploaded.len(), 1);
+
+        daemon.process_next_upload().await?;
+        let chunks = data.len().div_ceil(256);
+        assert_eq!(s3.state().parts_received, chunks);
+        assert_eq!(s3.state().objects.get("robot-7/segment-0.log"), Some(&data));
+        assert!(s3.state().aborted.is_empty());
+        assert!(Storage::is_uploaded(&sealed).await);
+
+        // Finished uploads leave the journal
+        let daemon = SyncDaemon::new(storage, s3.sync_config(256), Some(s3.client()));
+        assert_eq!(daemon.restore().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sealed_segments_are_queued_exactly_once() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, first) = storage_with_sealed_segment(tmpdir.path()).await?;
+        storage.append_record("/odom", "robot1", b"second", 100).await?;
+        let second = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        storage.append_record("/odom", "robot1", b"active", 200).await?;
+        let s3 = StandIn::start().await?;
+
+        // The first segment made it to the bucket, but its marker did not.
+        // The second is there at the same size but different contents, from
+        // before a reinstall, and the first again under another robot's prefix.
+        let client = s3.client();
+        let put = |key: &'static str, data: Vec<u8>, sha256: String| {
+            let client = client.clone();
+            async move {
+                let upload_id = client.create_multipart_upload(key, &sha256).await?;
+                let etag = client.upload_part(key, &upload_id, 1, data).await?;
+                client.complete_multipart_upload(key, &upload_id, &[CompletedPart { part_number: 1, etag }]).await
+            }
+        };
+        let first_sha256 = segment_data_sha256(&first).await?;
+        put("robot-7/segment-0.log", tokio::fs::read(&first).await?, first_sha256.clone()).await?;
+        let second_len = tokio::fs::metadata(&second).await?.len() as usize;
+        put("robot-7/segment-1.log", vec![0; second_len], "0".repeat(64)).await?;
+        put("robot-8/segment-0.log", tokio::fs::read(&first).await?, first_sha256).await?;
+
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(1024 * 1024), Some(client));
+        let watcher = {
+            let daemon = daemon.clone();
+            tokio::spawn(async move { daemon.watch_segments().await })
+        };
+        let queued = |daemon: SyncDaemon| async move {
+            daemon.upload_queue.lock().await.iter().map(|s| PathBuf::from(&s.segment_path)).collect::<Vec<_>>()
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while queued(daemon.clone()).await.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(Storage::is_uploaded(&first).await);
+        assert_eq!(queued(daemon.clone()).await, vec![second.clone()]);
+
+        // Sealing the active segment queues it; queueing it again is a no-op
+        let active = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while queued(daemon.clone()).await.len() < 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        daemon.queue_segment(active.clone()).await?;
+        assert_eq!(queued(daemon.clone()).await, [second.clone(), active.clone()]);
+        assert_eq!(daemon.reconcile().await?, 0);
+
+        daemon.process_next_upload().await?;
+        daemon.process_next_upload().await?;
+        assert!(Storage::is_uploaded(&second).await && Storage::is_uploaded(&active).await);
+        // Three parts for the earlier objects, one each since
+        assert_eq!(s3.state().parts_received, 5);
+        assert_eq!(s3.state().objects.len(), 4);
+        let second_data = tokio::fs::read(&second).await?;
+        assert_eq!(s3.state().objects.get("robot-7/segment-1.log"), Some(&second_data));
+        let newest = storage.active_segment_path().await;
+        assert!(!s3.state().objects.contains_key(&daemon.object_key(&newest)));
+        watcher.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_capture_moves_queued_segments_to_their_new_keys() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let mut cfg = storage_config(tmpdir.path());
+        cfg.blackbox = BlackBoxConfig { enabled: true, window_secs: 60, post_trigger_secs: 60, ..Default::default() };
+        let storage = Storage::new(&cfg).await?;
+        let mut events = storage.subscribe_segment_events();
+        let s3 = StandIn::start().await?;
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+
+        // One segment is queued with a part stored; the trigger seals the
+        // other and moves it before its rotation is handled
+        for ts in 0..20 {
+            storage.append_record("/odom", "robot1", &[ts as u8; 40], ts).await?;
+        }
+        let queued = storage.active_segment_path().await;
+        storage.rotate_segment().await?;
+        daemon.queue_segment(queued.clone()).await?;
+        s3.state().fail_parts.insert(2);
+        assert!(daemon.process_next_upload().await.is_err());
+        s3.state().fail_parts.clear();
+        daemon.upload_queue.lock().await[0].next_attempt_ms = 0;
+        storage.append_record("/odom", "robot1", b"sealed by the trigger", 100).await?;
+        let sealed = storage.active_segment_path().await;
+
+        storage.trigger_capture(Some("incident-1"), "test").await?;
+        while let Ok(event) = events.try_recv() {
+            daemon.handle_segment_event(event).await;
+        }
+        let frozen = storage.session_segments("incident-1").await?[..2].to_vec();
+        let paths =
+            |states: Vec<UploadState>| states.into_iter().map(|s| PathBuf::from(s.segment_path)).collect::<Vec<_>>();
+        assert_eq!(paths(daemon.upload_queue.lock().await.clone()), frozen);
+        assert_eq!(paths(read_json(&daemon.journal_path()).await?), frozen);
+        assert!(!storage.is_pinned(&queued).await && !storage.is_pinned(&sealed).await);
+        assert!(storage.is_pinned(&frozen[0]).await && storage.is_pinned(&frozen[1]).await);
+        // The part stored under the old key is discarded with its upload
+        assert_eq!(s3.state().aborted.len(), 1);
+
+        assert!(daemon.process_next_upload().await?);
+        assert!(daemon.process_next_upload().await?);
+        for path in &frozen {
+            let key = daemon.object_key(path);
+            assert!(key.starts_with("robot-7/") && key.contains("incident-1"), "{}", key);
+            let data = tokio::fs::read(path).await?;
+            assert_eq!(s3.state().objects.get(&key), Some(&data));
+            assert!(Storage::is_uploaded(path).await);
+            assert!(!storage.is_pinned(path).await);
+        }
+        assert!(!Storage::is_uploaded(&queued).await);
+        assert_eq!(s3.state().objects.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap_with_jitter() {
+        for _ in 0..100 {
+            let first = backoff_delay(2_000, 60_000, 1).as_millis();
+            assert!((1_000..=2_000).contains(&first), "{}", first);
+            let fourth = backoff_delay(2_000, 60_000, 4).as_millis();
+            assert!((8_000..=16_000).contains(&fourth), "{}", fourth);
+            let capped = backoff_delay(2_000, 60_000, 40).as_millis();
+            assert!((30_000..=60_000).contains(&capped), "{}", capped);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failing_segment_is_dead_lettered_and_requeued() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, sealed) = storage_with_sealed_segment(tmpdir.path()).await?;
+        let s3 = StandIn::start().await?;
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+        daemon.queue_segment(sealed.clone()).await?;
+
+        s3.state().fail_parts.insert(1);
+        for attempt in 1..=3 {
+            assert!(daemon.process_next_upload().await.is_err(), "attempt {}", attempt);
+        }
+        assert!(!daemon.process_next_upload().await?);
+        let status = daemon.get_status().await;
+        assert_eq!((status.queued, status.dead_letters), (0, 1));
+        let letter = &daemon.dead_letters().await[0];
+        assert_eq!(letter.upload.attempts, 3);
+        // Its upload is aborted once given up on, not after every attempt
+        assert_eq!(s3.state().aborted.len(), 1);
+        assert!(s3.state().uploads.is_empty());
+        assert!(letter.upload.upload_id.is_none() && letter.upload.chunks_uploaded.is_empty());
+        assert!(letter.upload.last_error.as_deref().unwrap_or_default().contains("InternalError"));
+
+        // The list survives a restart, and reconciling does not pick the segment up again
+        let daemon = SyncDaemon::new(storage.clone(), s3.sync_config(256), Some(s3.client()));
+        assert_eq!(daemon.restore().await?, 0);
+        assert_eq!(daemon.dead_letters().await.len(), 1);
+        assert_eq!(daemon.reconcile().await?, 0);
+
+        s3.state().fail_parts.clear();
+        daemon.requeue_dead_letter(&sealed.to_string_lossy()).await?;
+        assert!(daemon.dead_letters().await.is_empty());
+        assert!(daemon.process_next_upload().await?);
+        assert!(Storage::is_uploaded(&sealed).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_throttled_parts_fill_gaps_in_journal() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let (storage, sealed) = storage_with_sealed_segment(tmpdir.path()).await?;
+        let s3 = StandIn::start().await?;
+        let mut config = s3.sync_config(128);
+        config.concurrent_parts = 4;
+        config.max_upload_bytes_per_sec = Some(1024 * 1024);
+        let daemon = SyncDaemon::new(storage.clone(), config, Some(s3.client()));
+        assert_eq!(daemon.bandwidth_limit(), Some(1024 * 1024));
+        daemon.queue_segment(sealed.clone()).await?;
+
+        // Parts 1 and 3 finished before a crash, part 2 did not
+        let data = tokio::fs::read(&sealed).await?;
+        let client = s3.client();
+        let mut state = daemon.upload_queue.lock().await[0].clone();
+        let upload_id = client.create_multipart_upload("robot-7/segment-0.log", &state.segment_sha256).await?;
+        state.upload_id = Some(upload_id.clone());
+        for idx in [2usize, 0] {
+            let chunk = &data[idx * 128..(idx + 1) * 128];
+            let etag = client.upload_part("robot-7/segment-0.log", &upload_id, idx as u32 + 1, chunk.to_vec()).await?;
+            state.chunks_uploaded.push(UploadedChunk {
+                chunk_index: idx as u32,
+                chunk_size: 128,
+                sha256: format!("{:x}", Sha256::digest(chunk)),
+                upload_id: Some(upload_id.clone()),
+                etag,
+            });
+        }
+        daemon.record_progress(&state).await?;
+
+        daemon.set_bandwidth_limit(Some(256 * 1024));
+        assert!(daemon.process_next_upload().await?);
+        assert_eq!(s3.state().parts_received, data.len().div_ceil(128));
+        assert_eq!(s3.state().objects.get("robot-7/segment-0.log"), Some(&data));
+        assert!(s3.state().aborted.is_empty());
+        Ok(())
+    }
 }
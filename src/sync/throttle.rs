@@ -0,0 +1,132 @@
+//! Token-bucket bandwidth cap shared by all uploads.
+//!
+//! The bucket starts empty, refills at the configured rate and holds at most
+//! one second worth of tokens, so a link that was idle gets a short burst and
+//! then the steady rate. The rate can change while uploads are running.
+
+use futures::Stream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// Bytes handed to the HTTP body per acquisition
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+pub struct Throttle {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// Bytes per second, `None` when unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn capacity(rate: u64) -> f64 {
+        rate.max(BLOCK_SIZE as u64) as f64
+    }
+
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(Self::capacity(rate));
+        self.refilled = now;
+    }
+}
+
+impl Throttle {
+    /// A cap of `bytes_per_sec`; `None` or 0 is unlimited
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        let rate = bytes_per_sec.filter(|&r| r > 0);
+        Throttle {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Change the cap; waiting uploads pick it up on their next check
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        match bucket.rate {
+            Some(rate) => bucket.refill(rate),
+            // Tokens left from before the cap was lifted are stale
+            None => bucket.tokens = 0.0,
+        }
+        bucket.rate = bytes_per_sec.filter(|&r| r > 0);
+        if let Some(rate) = bucket.rate {
+            bucket.tokens = bucket.tokens.min(Bucket::capacity(rate));
+            bucket.refilled = Instant::now();
+        }
+    }
+
+    /// Wait until `bytes` (at most one block) may be sent
+    pub async fn acquire(&self, bytes: usize) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let Some(rate) = bucket.rate else {
+                    return;
+                };
+                bucket.refill(rate);
+                let wanted = (bytes as f64).min(Bucket::capacity(rate));
+                if bucket.tokens >= wanted {
+                    bucket.tokens -= wanted;
+                    return;
+                }
+                Duration::from_secs_f64((wanted - bucket.tokens) / rate as f64)
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// `data` as a stream of blocks released at the capped rate
+    pub fn stream(&self, data: bytes::Bytes) -> impl Stream<Item = std::io::Result<bytes::Bytes>> + Send + Sync + 'static {
+        futures::stream::unfold((self.clone(), data, 0), |(throttle, data, offset)| async move {
+            if offset >= data.len() {
+                return None;
+            }
+            let end = (offset + BLOCK_SIZE).min(data.len());
+            throttle.acquire(end - offset).await;
+            Some((Ok(data.slice(offset..end)), (throttle, data, end)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_is_held_and_can_change() {
+        let throttle = Throttle::new(Some(100_000));
+        let started = Instant::now();
+        let blocks: Vec<_> = throttle.stream(vec![0u8; 1_000_000].into()).collect().await;
+        assert_eq!(blocks.iter().map(|b| b.as_ref().unwrap().len()).sum::<usize>(), 1_000_000);
+        let elapsed = started.elapsed().as_secs_f64();
+        assert!((9.9..10.5).contains(&elapsed), "{}", elapsed);
+
+        throttle.set_rate(None);
+        let started = Instant::now();
+        throttle.acquire(BLOCK_SIZE).await;
+        throttle.acquire(BLOCK_SIZE).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        throttle.set_rate(Some(BLOCK_SIZE as u64));
+        let started = Instant::now();
+        for _ in 0..3 {
+            throttle.acquire(BLOCK_SIZE).await;
+        }
+        assert!((2.9..3.1).contains(&started.elapsed().as_secs_f64()));
+    }
+}
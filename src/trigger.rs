@@ -0,0 +1,167 @@
+//! Remote triggers for black-box captures.
+//!
+//! Besides the dashboard button, a capture can be triggered over plain HTTP
+//! (`POST /trigger`, optionally `?name=<session id>`) or, with the `ros2`
+//! feature, through a `std_srvs/srv/Trigger` service. All of them end up in
+//! `Storage::trigger_capture`.
+
+use crate::storage::Storage;
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Requests with a larger head are rejected
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Serve the HTTP trigger endpoint on `addr` until aborted
+pub async fn serve_http(storage: Storage, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("black-box trigger listening on http://{}/trigger", listener.local_addr()?);
+    serve_listener(storage, listener).await
+}
+
+async fn serve_listener(storage: Storage, listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &storage).await {
+                tracing::warn!("trigger request from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, storage: &Storage) -> Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| anyhow!("timed out reading request"))??;
+    let request_line = head.lines().next().unwrap_or_default();
+    let (status, body) = match parse_request_line(request_line) {
+        Ok(name) => match storage.trigger_capture(name.as_deref(), "http").await {
+            Ok(metadata) => ("200 OK", serde_json::to_string(&metadata)?),
+            Err(e) => ("409 Conflict", serde_json::json!({ "error": format!("{:#}", e) }).to_string()),
+        },
+        Err(status) => (status, serde_json::json!({ "error": status }).to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read up to the blank line ending the request head; bodies are ignored
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(anyhow!("request head larger than {} bytes", MAX_REQUEST_HEAD));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Capture name requested by `POST /trigger[?name=...]`, or the status line
+/// to answer with
+fn parse_request_line(line: &str) -> std::result::Result<Option<String>, &'static str> {
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/trigger" {
+        return Err("404 Not Found");
+    }
+    if method != "POST" {
+        return Err("405 Method Not Allowed");
+    }
+    // Session ids are plain [A-Za-z0-9._-], so no percent-decoding
+    Ok(query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("name="))
+        .filter(|name| !name.is_empty())
+        .map(str::to_string))
+}
+
+/// Answer `std_srvs/srv/Trigger` requests with a capture named after the
+/// trigger time
+#[cfg(feature = "ros2")]
+pub async fn serve_ros_service(
+    storage: Storage,
+    mut requests: impl futures::Stream<Item = r2r::ServiceRequest<r2r::std_srvs::srv::Trigger::Service>> + Unpin,
+) {
+    use futures::StreamExt;
+    use r2r::std_srvs::srv::Trigger;
+
+    while let Some(request) = requests.next().await {
+        let response = match storage.trigger_capture(None, "ros").await {
+            Ok(metadata) => Trigger::Response { success: true, message: metadata.recording_id },
+            Err(e) => Trigger::Response { success: false, message: format!("{:#}", e) },
+        };
+        if let Err(e) = request.respond(response) {
+            tracing::warn!("could not answer trigger service request: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BlackBoxConfig, StorageConfig};
+    use crate::storage::test_config;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_request_line() {
+        assert_eq!(parse_request_line("POST /trigger HTTP/1.1"), Ok(None));
+        assert_eq!(parse_request_line("POST /trigger?name=crash-7 HTTP/1.1"), Ok(Some("crash-7".to_string())));
+        assert_eq!(parse_request_line("POST /trigger?name= HTTP/1.1"), Ok(None));
+        assert_eq!(parse_request_line("GET /trigger HTTP/1.1"), Err("405 Method Not Allowed"));
+        assert_eq!(parse_request_line("POST / HTTP/1.1"), Err("404 Not Found"));
+        assert_eq!(parse_request_line(""), Err("404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn test_http_trigger_starts_capture() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = StorageConfig {
+            blackbox: BlackBoxConfig { enabled: true, ..Default::default() },
+            ..test_config(tmpdir.path(), 1024 * 1024)
+        };
+        let storage = Storage::new(&cfg).await?;
+        storage.append_record("/tf", "robot1", b"before", 1).await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(serve_listener(storage.clone(), listener));
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"POST /trigger?name=bump-1 HTTP/1.1\r\nHost: robot\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("\"recording_id\":\"bump-1\""));
+        assert_eq!(storage.active_session().await.as_deref(), Some("bump-1"));
+        assert_eq!(storage.session_segments("bump-1").await?.len(), 2);
+
+        // A second trigger while the capture records is refused
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"POST /trigger HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 409 Conflict"), "{}", response);
+
+        server.abort();
+        Ok(())
+    }
+}
@@ -6,6 +6,11 @@ pub struct TopicManifestEntry {
     pub topic: String,
     pub msg_type: String,
     pub sample_rate_hz: Option<f32>,
+    #[serde(default)]
+    pub message_count: u64,
+    /// Offered QoS profiles as rosbag2 stores them (YAML), when known
+    #[serde(default)]
+    pub qos_profiles: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,4 +20,21 @@ pub struct RecordingMetadata {
     pub start_time_unix_ms: u128,
     pub end_time_unix_ms: Option<u128>,
     pub topics: Vec<TopicManifestEntry>,
+    #[serde(default)]
+    pub robot: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Set on sessions created by a black-box trigger
+    #[serde(default)]
+    pub capture: Option<CaptureTrigger>,
+}
+
+/// What froze a black-box capture and when it stops recording
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(dead_code)]
+pub struct CaptureTrigger {
+    /// Where the trigger came from: "ros", "dashboard", "http", ...
+    pub source: String,
+    pub triggered_at_unix_ms: u128,
+    pub stop_at_unix_ms: u128,
 }